@@ -10,7 +10,7 @@ fn value_to_type_signature(value: Value) -> String {
 
     let sig = json_sig.join(", ");
 
-    return format!("&[{}]", sig);
+    format!("&[{}]", sig)
 }
 
 
@@ -19,17 +19,17 @@ fn value_to_grammar(value: Value) -> String {
     let json_sig: Vec<String> = value.as_array().unwrap().iter()
         .map(|x| format!("TERM_{}", x.as_str().unwrap().to_uppercase())).collect();
 
-    if json_sig.len() == 0 {
+    if json_sig.is_empty() {
         return "".to_string();
     }
 
     let sig = json_sig.join(", ");
 
-    return format!(", {}", sig);
+    format!(", {}", sig)
 }
 
 // Special symbols
-pub const SPECIAL_SIMS: [&str; 21] = [
+pub const SPECIAL_SIMS: [&str; 28] = [
     "START",
     "STMT",
     "STMTS",
@@ -51,20 +51,64 @@ pub const SPECIAL_SIMS: [&str; 21] = [
     "LOW_BYTE",
     "HIGH_BYTE",
     "ADD_I64_OP",
+    "TERM_TABLE",
+    "TERM_MEMORY",
+    "TERM_HEAPTYPE",
+    "TERM_MEMARG",
+    "TERM_BLOCKTYPE",
+    "TERM_TYPE",
+    "TERM_TAG",
 ];
 
+fn instr_info(prefix: u64, opcode: u64, instr: &Value) -> String {
+    // Instruction table JSON format is 
+    // <section> : { <instruction name> : {"name": <name>, 
+    //                  signature: [<in>, <out>, <constants>],
+    //                  opcode: <opcode>,
+    //                  prefix: <prefix byte, only for extensions>,
+    //                  align: <log2 of the natural alignment, only for memory accesses>
+    //                },...
+    //              }, ...
+    let name = instr["name"].as_str().unwrap();
+
+    let signature = instr["signature"].as_array().unwrap();
+    let in_types = value_to_type_signature(signature[0].clone());
+    let out_types = value_to_type_signature(signature[1].clone());
+    let constants = value_to_type_signature(signature[2].clone());
+    
+    let natural_align = match instr["align"].as_u64() {
+        Some(align) => format!("Some({})", align),
+        None => "None".to_string(),
+    };
+
+    format!(
+        r#"    InstrInfo{{prefix: {:#x}, instr: {:#x}, name: "{}", in_types: {}, out_types: {}, constants: {}, natural_align: {}}},"#,
+        prefix, opcode, name,
+        in_types, out_types, constants,
+        natural_align)
+}
+
 fn main() {
     println!("cargo::rerun-if-changed=instr_table.json");
+    println!("cargo::rerun-if-changed=instr_parser.json");
     let out_dir = env::var("OUT_DIR").unwrap();
     let mut extensions_enabled = false;
     
     // Core part of instruction set, pretty much 0-256
-    let core = vec!["CONTROL_OPCODE", "MISC_OPCODE", "LOAD_MEM_OPCODE", "STORE_MEM_OPCODE", 
+    let core = ["CONTROL_OPCODE", "MISC_OPCODE", "LOAD_MEM_OPCODE", "STORE_MEM_OPCODE", 
         "MISC_MEM_OPCODE", "SIMPLE_EXTENDED_CONST_OPCODE", "SIMPLE_NON_CONST_OPCODE",
         "ASMJS_COMPAT_OPCODE"];
     
     let mut core: Vec<String> = core.iter().map(|x| x.to_string()).collect();
 
+    // Prefixed opcodes (0xFB, 0xFC, 0xFD, 0xFE), each followed by a LEB128 sub-opcode
+    let extensions = ["SATURATING_TRUNC_OPCODE", "BULK_MEMORY_OPCODE", "SIMD_MEM_OPCODE",
+        "SIMD_MEM_1_OPERAND_OPCODE", "SIMD_CONST_OPCODE", "SIMD_MASK_OPERAND_OPCODE",
+        "SIMD_MVP_0_OPERAND_OPCODE", "RELAXED_SIMD_OPCODE", "SIMD_1_OPERAND_1_PARAM_OPCODE",
+        "SIMD_1_OPERAND_2_PARAM_OPCODE", "GC_OPCODE", "ATOMIC_OPCODE"];
+
+    let mut extensions: Vec<String> = extensions.iter().map(|x| x.to_string()).collect();
+
     {
         // Config letting us know what we want to be using
         // There are a few wasm extensions, don't really want to go through them all right now
@@ -81,6 +125,11 @@ fn main() {
 
             core = config["core"].as_array().unwrap().iter()
                     .map(|x| x.as_str().unwrap().to_string()).collect::<Vec<String>>();
+
+            if let Some(sections) = config["extension_sections"].as_array() {
+                extensions = sections.iter()
+                    .map(|x| x.as_str().unwrap().to_string()).collect::<Vec<String>>();
+            }
         }
     }
 
@@ -92,10 +141,11 @@ fn main() {
             let f = File::open(out_dir + "/instr_table.rs")
                 .expect("Could not open ouput instr_table.rs!!");
             let mut writer = BufWriter::new(f);
-            let _ = writer.write("
+            let _ = writer.write_all("
                 use crate::wasm_model::*;
 
-                pub const INSTRS: [InstrInfo; 0] = []
+                pub static INSTRS: [InstrInfo; 0] = [];
+                pub static PREFIXED_INSTRS: [InstrInfo; 0] = [];
             ".as_bytes());
 
             return;
@@ -115,8 +165,18 @@ fn main() {
         }
     }
 
+    // Keyed on (prefix, sub-opcode), sorted so lookups can binary search
+    let mut prefixed_list: Vec<(u64, u64, Value)> = Vec::new();
     if extensions_enabled {
-        todo!("WASM Extensions not yet supported");
+        for sec in extensions {
+            let sec_instrs = instructions[sec].as_object().unwrap();
+            for (_name, instr) in sec_instrs {
+                let prefix = instr["prefix"].as_u64().unwrap();
+                let opcode = instr["opcode"].as_u64().unwrap();
+                prefixed_list.push((prefix, opcode, instr.clone()));
+            }
+        }
+        prefixed_list.sort_by_key(|(prefix, opcode, _)| (*prefix, *opcode));
     }
 
     {
@@ -129,63 +189,36 @@ fn main() {
 
         for i in 0..=255 {
             instruction_list += "\n";            
-            let instr = instr_list.get(&i).clone();
+            let instr = instr_list.get(&i);
             if instr.is_none() {
                 // No instruction, but we need to keep the instructions in order...
                 let instr_string = format!(
-                r#"    InstrInfo{{instr: {:#x}, name: "", in_types: &[], out_types: &[], constants: &[], takes_align: false}},"#, i);
+                r#"    InstrInfo{{prefix: 0x0, instr: {:#x}, name: "", in_types: &[], out_types: &[], constants: &[], natural_align: None}},"#, i);
                 instruction_list += instr_string.as_str();                
                 continue;
             }
 
-            
-            // Instruction table JSON format is 
-            // <section> : { <instruction name> : {"name": <name>, 
-            //                  signature: [<in>, <out>, <constants>],
-            //                  opcode: <opcode>
-            //                },...
-            //              }, ...
-            let instr = instr.unwrap();
-
-            let name = instr["name"].as_str().unwrap();
-
-            let signature = instr["signature"].as_array().unwrap();
-            let in_types = value_to_type_signature(signature[0].clone());
-            let out_types = value_to_type_signature(signature[1].clone());
-            let constants = value_to_type_signature(signature[2].clone());
-            
-            // TODO: find takes_align information
-            let takes_align = false;
-
-
-            let instr_string = format!(
-                r#"    InstrInfo{{instr: {:#x}, name: "{}", in_types: {}, out_types: {}, constants: {}, takes_align: {}}},"#,
-                i, name,
-                in_types, out_types, constants,
-                takes_align);
+            let instr_string = instr_info(0, i as u64, instr.unwrap());
             instruction_list += instr_string.as_str();                   
         }
 
+        let prefixed_instruction_list = prefixed_list.iter()
+            .map(|(prefix, opcode, instr)| "\n".to_string() + instr_info(*prefix, *opcode, instr).as_str())
+            .collect::<String>();
+
         let mut symbols = "".to_string();        
         let mut all_symbols = Vec::<String>::new();
-        let mut stack_nops = Vec::<String>::new();
-        // Things that change the stack, but not up or down: effects what's on top
-        let mut stack_push1 = Vec::<String>::new();
-        let mut stack_pop1 = Vec::<String>::new();
-        let mut stack_push2 = Vec::<String>::new();
         for (_i, instr) in instr_list {
             let name = instr["name"].as_str().unwrap().to_string();
             let opcode = instr["opcode"].as_u64().unwrap();
             
             let normalized_ident = name.replace(".", "_").replace("@", "_").to_uppercase();
 
-            let instr_string = "#[allow(dead_code)]\n".to_string() + format!(r#"pub const {}: u64 = {};"#, normalized_ident, opcode.to_string()).as_str() + "\n";
+            let instr_string = "#[allow(dead_code)]\n".to_string() + format!(r#"pub const {}: u64 = {};"#, normalized_ident, opcode).as_str() + "\n";
             symbols += instr_string.as_str();
 
 
             let signature = instr["signature"].as_array().unwrap();
-            let in_types = signature[0].clone().as_array().unwrap();
-            let out_types = signature[1].clone().as_array().unwrap();
             let constants = value_to_grammar(signature[2].clone());
             
             let grammar_rule = normalized_ident.clone() + constants.clone().as_str();
@@ -208,41 +241,44 @@ fn main() {
 
         // Some useful stuff for the grammar
         let bytes = "[&[".to_string() 
-                                + (0..=255).into_iter().map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
+                                + (0..=255).map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
                                 + "]]";
         // For LEB128
         let lower_bytes = "[&[".to_string() 
-                                + (0..=127).into_iter().map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
+                                + (0..=127).map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
                                 + "]]";
         let upper_bytes = "[&[".to_string() 
-                                + (128..=255).into_iter().map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
+                                + (128..=255).map(|x| x.to_string()).collect::<Vec<String>>().join("], &[").as_str()
                                 + "]]";
         
 
         let num_symbols = all_symbols.len();
         let all_symbols = "[&[".to_string() + all_symbols.join("], &[").as_str() + "]]";
 
-        let _ = writer.write(format!("pub const INSTRS: [InstrInfo; 256] = [{}];
+        let _ = writer.write_all(format!("pub static INSTRS: [InstrInfo; 256] = [{}];
+                                            pub static PREFIXED_INSTRS: [InstrInfo; {}] = [{}];
                                             {}
-                                            pub const all_symbols: [&[u64]; {}] = {};
-                                            pub const bytes: [&[u64]; 256] = {};
-                                            pub const lower_bytes: [&[u64]; 128] = {};
-                                            pub const upper_bytes: [&[u64]; 128] = {};
+                                            pub const ALL_SYMBOLS: [&[u64]; {}] = {};
+                                            pub const BYTES: [&[u64]; 256] = {};
+                                            pub const LOWER_BYTES: [&[u64]; 128] = {};
+                                            pub const UPPER_BYTES: [&[u64]; 128] = {};
                                             ", instruction_list,
+                                            prefixed_list.len(), prefixed_instruction_list,
                                             symbols,
                                             num_symbols, all_symbols,
                                             bytes, lower_bytes, upper_bytes).as_bytes());
 
         for (i, name) in SPECIAL_SIMS.iter().enumerate() {
-            writer.write(format!("pub const {}: u64 = u64::MAX - {};\n", name, i).as_bytes());
+            let value = if i == 0 { "u64::MAX".to_string() } else { format!("u64::MAX - {}", i) };
+            let _ = writer.write_all(format!("pub const {}: u64 = {};\n", name, value).as_bytes());
         }
 
         // Here we generate an array of the names
-        writer.write(format!("const SPECIAL_SIMS: [&str; {}] = [", SPECIAL_SIMS.len()).as_bytes());
+        let _ = writer.write_all(format!("const SPECIAL_SIMS: [&str; {}] = [", SPECIAL_SIMS.len()).as_bytes());
         for name in SPECIAL_SIMS.iter() {
-            writer.write(format!("\"{}\", ", name).as_bytes());
+            let _ = writer.write_all(format!("\"{}\", ", name).as_bytes());
         }
-        writer.write("];".as_bytes());
+        let _ = writer.write_all("];".as_bytes());
 
     }
 }
\ No newline at end of file
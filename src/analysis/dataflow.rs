@@ -0,0 +1,424 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Debug;
+
+use crate::analysis::dominance::reverse_postorder;
+use crate::cfg::{Cfg, CfgInstr};
+use crate::wasm_model::*;
+
+/// Which way facts flow: from the entry along edges, or from the exit against them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Whether a fact has to hold on every path to a point, or on at least one of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paths {
+    Must,
+    May,
+}
+
+/// A data-flow problem: the lattice its facts live in, and how each instruction changes them.
+/// See notes/representing_analysis.md for the "timeline" this follows: each instruction's fact
+/// is worked out from the facts of the instructions that could run just before it, where "before"
+/// means after it for backward analyses.
+pub trait Analysis {
+    type Fact: Clone + PartialEq + Debug;
+    const DIRECTION: Direction;
+    const PATHS: Paths;
+
+    /// The fact where the flow starts: at the entry going forward, or at the exit going backward
+    fn boundary(&self) -> Self::Fact;
+
+    /// What every other block starts from, and what joining nothing gives. That's the top of the
+    /// lattice (everything) for must analyses, and the bottom (nothing) for may analyses.
+    fn initial(&self) -> Self::Fact;
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+
+    /// The fact on the far side of an instruction, in the analysis' direction: after it going
+    /// forward, before it going backward. `instr` indexes Cfg::instrs.
+    fn transfer(&self, cfg: &Cfg, instr: usize, fact: &Self::Fact) -> Self::Fact;
+}
+
+/// Joins sets the way `paths` says: facts that must hold are on every path, so they intersect
+pub fn join_sets<T: Ord + Clone>(paths: Paths, a: &BTreeSet<T>, b: &BTreeSet<T>) -> BTreeSet<T> {
+    match paths {
+        Paths::Must => a.intersection(b).cloned().collect(),
+        Paths::May => a.union(b).cloned().collect(),
+    }
+}
+
+/// The solved facts, always in program order whichever way the analysis runs
+#[derive(Debug, Clone)]
+pub struct Dataflow<F> {
+    /// The fact at the start of each block
+    pub block_in: Vec<F>,
+    /// The fact at the end of each block
+    pub block_out: Vec<F>,
+    /// The fact just before each instruction
+    pub before: Vec<F>,
+    /// The fact just after each instruction
+    pub after: Vec<F>,
+}
+
+// Runs the transfer function over a block's instructions in the analysis' direction, giving the
+// fact on each side of each instruction in program order
+fn run_block<A: Analysis>(cfg: &Cfg, analysis: &A, block: usize, start: &A::Fact) -> Vec<(A::Fact, A::Fact)> {
+    let range = cfg.blocks[block].instrs.clone();
+    let mut sides = Vec::with_capacity(range.len());
+    let mut fact = start.clone();
+    match A::DIRECTION {
+        Direction::Forward => {
+            for instr in range {
+                let next = analysis.transfer(cfg, instr, &fact);
+                sides.push((std::mem::replace(&mut fact, next.clone()), next));
+            }
+        }
+        Direction::Backward => {
+            for instr in range.rev() {
+                let next = analysis.transfer(cfg, instr, &fact);
+                sides.push((next.clone(), std::mem::replace(&mut fact, next)));
+            }
+            sides.reverse();
+        }
+    }
+    sides
+}
+
+/// Solves the analysis over the CFG with a worklist, until no block's facts change
+pub fn solve<A: Analysis>(cfg: &Cfg, analysis: &A) -> Dataflow<A::Fact> {
+    let num_blocks = cfg.num_blocks();
+    let (root, flow_preds, flow_succs): (usize, Vec<Vec<usize>>, Vec<Vec<usize>>) = {
+        let preds = (0..num_blocks).map(|block| cfg.predecessors(block)).collect();
+        let succs = (0..num_blocks).map(|block| cfg.successors(block)).collect();
+        match A::DIRECTION {
+            Direction::Forward => (Cfg::ENTRY, preds, succs),
+            Direction::Backward => (Cfg::EXIT, succs, preds),
+        }
+    };
+
+    // `start` is the fact where the flow enters a block, `end` where it leaves
+    let mut start = vec![analysis.initial(); num_blocks];
+    let mut end = vec![analysis.initial(); num_blocks];
+    start[root] = analysis.boundary();
+    end[root] = analysis.boundary();
+
+    // Reverse postorder needs the fewest passes, and blocks it doesn't reach are queued after
+    let mut order = reverse_postorder(root, &flow_succs);
+    let unreached: Vec<usize> = (0..num_blocks).filter(|block| !order.contains(block)).collect();
+    order.extend(unreached);
+    let mut queued = vec![true; num_blocks];
+    let mut worklist: VecDeque<usize> = order.into_iter().collect();
+    while let Some(block) = worklist.pop_front() {
+        queued[block] = false;
+        if block != root {
+            start[block] = match flow_preds[block].split_first() {
+                Some((first, rest)) => rest.iter().fold(end[*first].clone(), |fact, pred| analysis.join(&fact, &end[*pred])),
+                None => analysis.initial(),
+            };
+        }
+        let sides = run_block(cfg, analysis, block, &start[block]);
+        let new_end = match A::DIRECTION {
+            Direction::Forward => sides.last().map(|(_, after)| after.clone()),
+            Direction::Backward => sides.first().map(|(before, _)| before.clone()),
+        };
+        let new_end = new_end.unwrap_or_else(|| start[block].clone());
+        if new_end != end[block] {
+            end[block] = new_end;
+            for succ in flow_succs[block].iter() {
+                if !queued[*succ] {
+                    queued[*succ] = true;
+                    worklist.push_back(*succ);
+                }
+            }
+        }
+    }
+
+    let mut before = vec![analysis.initial(); cfg.instrs.len()];
+    let mut after = vec![analysis.initial(); cfg.instrs.len()];
+    for (block, fact) in start.iter().enumerate() {
+        let range = cfg.blocks[block].instrs.clone();
+        for (instr, (fact_before, fact_after)) in range.zip(run_block(cfg, analysis, block, fact)) {
+            before[instr] = fact_before;
+            after[instr] = fact_after;
+        }
+    }
+    let (block_in, block_out) = match A::DIRECTION {
+        Direction::Forward => (start, end),
+        Direction::Backward => (end, start),
+    };
+    Dataflow { block_in, block_out, before, after }
+}
+
+fn local_of(instr: &CfgInstr) -> Option<usize> {
+    match instr.immediates.first() {
+        Some(ExprSeg::Local(local)) => Some(*local as usize),
+        _ => None,
+    }
+}
+
+/// Which locals may still be read before they're next written
+pub struct Liveness;
+
+impl Analysis for Liveness {
+    type Fact = BTreeSet<usize>;
+    const DIRECTION: Direction = Direction::Backward;
+    const PATHS: Paths = Paths::May;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        join_sets(Self::PATHS, a, b)
+    }
+
+    fn transfer(&self, cfg: &Cfg, instr: usize, live: &Self::Fact) -> Self::Fact {
+        let instr = &cfg.instrs[instr];
+        let mut live = live.clone();
+        match (instr.info.name, local_of(instr)) {
+            ("local.get", Some(local)) => {
+                live.insert(local);
+            }
+            ("local.set" | "local.tee", Some(local)) => {
+                live.remove(&local);
+            }
+            _ => {}
+        }
+        live
+    }
+}
+
+/// A value a local can have: the one it starts the function with (a param, or zero), or the one
+/// a `local.set` or `local.tee` gave it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Definition {
+    pub local: usize,
+    /// The defining instruction, None for the starting value
+    pub instr: Option<usize>,
+}
+
+/// Which definitions of each local may reach each point without being overwritten
+pub struct ReachingDefs {
+    /// Params and locals together
+    pub num_locals: usize,
+}
+
+impl Analysis for ReachingDefs {
+    type Fact = BTreeSet<Definition>;
+    const DIRECTION: Direction = Direction::Forward;
+    const PATHS: Paths = Paths::May;
+
+    fn boundary(&self) -> Self::Fact {
+        (0..self.num_locals).map(|local| Definition { local, instr: None }).collect()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        join_sets(Self::PATHS, a, b)
+    }
+
+    fn transfer(&self, cfg: &Cfg, instr: usize, defs: &Self::Fact) -> Self::Fact {
+        match (cfg.instrs[instr].info.name, local_of(&cfg.instrs[instr])) {
+            ("local.set" | "local.tee", Some(local)) => {
+                let mut defs: BTreeSet<Definition> = defs.iter().filter(|def| def.local != local).copied().collect();
+                defs.insert(Definition { local, instr: Some(instr) });
+                defs
+            }
+            _ => defs.clone(),
+        }
+    }
+}
+
+/// A pure computation over locals and constants, e.g. `i32.add(local.get $var0, i32.const 1)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    pub text: String,
+    /// The locals it reads, so writing any of them kills it
+    pub locals: BTreeSet<usize>,
+}
+
+// Numeric instructions that only depend on their operands
+fn is_pure(info: &InstrInfo) -> bool {
+    let numeric = |prim: &Prim| matches!(prim, Prim::I32 | Prim::I64 | Prim::F32 | Prim::F64 | Prim::V128);
+    info.constants.is_empty() && !info.in_types.is_empty() && info.out_types.len() == 1 && info.in_types.iter().chain(info.out_types).all(numeric)
+}
+
+/// Which expressions have been computed on every path to each point, with none of their locals
+/// written since. Expressions are found by following the operand stack within each block, so
+/// operands that come from another block aren't tracked.
+pub struct AvailableExprs {
+    pub exprs: Vec<Expression>,
+    // The expression each instruction computes
+    computes: Vec<Option<usize>>,
+}
+
+impl AvailableExprs {
+    pub fn new(cfg: &Cfg) -> Self {
+        let mut exprs: Vec<Expression> = vec![];
+        let mut computes = vec![None; cfg.instrs.len()];
+        for block in cfg.blocks.iter() {
+            // None for values that aren't expressions over locals and constants
+            let mut stack: Vec<Option<Expression>> = vec![];
+            for i in block.instrs.clone() {
+                let instr = &cfg.instrs[i];
+                let info = &instr.info;
+                if info.name == "local.get" || info.name.ends_with(".const") {
+                    stack.push(Some(Expression { text: instr.text.clone(), locals: local_of(instr).into_iter().collect() }));
+                } else if is_pure(info) {
+                    let operands = stack.split_off(stack.len().saturating_sub(info.in_types.len()));
+                    let expr = match operands.into_iter().collect::<Option<Vec<Expression>>>() {
+                        Some(operands) if operands.len() == info.in_types.len() => {
+                            let text = format!("{}({})", info.name, operands.iter().map(|operand| operand.text.as_str()).collect::<Vec<&str>>().join(", "));
+                            let locals = operands.into_iter().flat_map(|operand| operand.locals).collect();
+                            let expr = Expression { text, locals };
+                            let id = exprs.iter().position(|known| *known == expr).unwrap_or_else(|| {
+                                exprs.push(expr.clone());
+                                exprs.len() - 1
+                            });
+                            computes[i] = Some(id);
+                            Some(expr)
+                        }
+                        _ => None,
+                    };
+                    stack.push(expr);
+                } else {
+                    match stack_effect(info) {
+                        Some((pops, pushes)) => {
+                            stack.truncate(stack.len().saturating_sub(pops));
+                            stack.extend((0..pushes).map(|_| None));
+                        }
+                        // Anything could be on the stack after this
+                        None => stack.clear(),
+                    }
+                }
+            }
+        }
+        AvailableExprs { exprs, computes }
+    }
+}
+
+impl Analysis for AvailableExprs {
+    /// Indices into `exprs`
+    type Fact = BTreeSet<usize>;
+    const DIRECTION: Direction = Direction::Forward;
+    const PATHS: Paths = Paths::Must;
+
+    fn boundary(&self) -> Self::Fact {
+        BTreeSet::new()
+    }
+
+    fn initial(&self) -> Self::Fact {
+        (0..self.exprs.len()).collect()
+    }
+
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact {
+        join_sets(Self::PATHS, a, b)
+    }
+
+    fn transfer(&self, cfg: &Cfg, instr: usize, available: &Self::Fact) -> Self::Fact {
+        let mut available = available.clone();
+        available.extend(self.computes[instr]);
+        if let ("local.set" | "local.tee", Some(local)) = (cfg.instrs[instr].info.name, local_of(&cfg.instrs[instr])) {
+            available.retain(|expr| !self.exprs[*expr].locals.contains(&local));
+        }
+        available
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    fn cfg_of(wat: &str) -> Cfg {
+        Cfg::new(&parse_wat(wat).unwrap().code_section.functions[0])
+    }
+
+    #[test]
+    fn finds_live_locals_around_a_loop() {
+        let cfg = cfg_of(
+            "(module
+              (func (param $n i32) (local $sum i32) (local $unused i32)
+                loop
+                  local.get $sum
+                  local.get $n
+                  i32.add
+                  local.set $sum
+                  local.get $n
+                  br_if 0
+                end
+                i32.const 0
+                local.set $unused))",
+        );
+        let live = solve(&cfg, &Liveness);
+        // Both stay live through the back edge, and $unused is only ever written
+        assert_eq!(live.block_in[Cfg::ENTRY], BTreeSet::from([0, 1]));
+        assert_eq!(live.before[1], BTreeSet::from([0, 1]));
+        assert_eq!(live.after[4], BTreeSet::from([0, 1]));
+        assert_eq!(live.before[10], BTreeSet::new());
+    }
+
+    #[test]
+    fn joins_reaching_definitions() {
+        let cfg = cfg_of(
+            "(module
+              (func (param $c i32) (local $x i32)
+                local.get $c
+                if
+                  i32.const 1
+                  local.set $x
+                end
+                local.get $x
+                drop))",
+        );
+        let defs = solve(&cfg, &ReachingDefs { num_locals: 2 });
+        let of_x = |fact: &BTreeSet<Definition>| fact.iter().filter(|def| def.local == 1).map(|def| def.instr).collect::<Vec<_>>();
+        assert_eq!(of_x(&defs.after[3]), vec![Some(3)]);
+        // The `if` may or may not have run
+        assert_eq!(of_x(&defs.before[6]), vec![None, Some(3)]);
+        assert!(defs.before[6].contains(&Definition { local: 0, instr: None }));
+    }
+
+    #[test]
+    fn kills_available_expressions_on_any_path() {
+        let cfg = cfg_of(
+            "(module
+              (func (param $a i32) (param $b i32) (param $c i32) (result i32)
+                local.get $a
+                local.get $b
+                i32.add
+                drop
+                local.get $c
+                if
+                  i32.const 0
+                  local.set $a
+                end
+                local.get $a
+                local.get $b
+                i32.add
+                local.get $b
+                i32.const 1
+                i32.mul
+                i32.add))",
+        );
+        let analysis = AvailableExprs::new(&cfg);
+        let texts: Vec<&str> = analysis.exprs.iter().map(|expr| expr.text.as_str()).collect();
+        assert_eq!(texts[..2], ["i32.add(local.get $var0, local.get $var1)", "i32.mul(local.get $var1, i32.const 1)"]);
+        assert_eq!(texts[2], "i32.add(i32.add(local.get $var0, local.get $var1), i32.mul(local.get $var1, i32.const 1))");
+        let available = solve(&cfg, &analysis);
+        assert_eq!(available.after[2], BTreeSet::from([0]));
+        // $a may have been written in the `if`, so the sum has to be worked out again
+        assert_eq!(available.before[11], BTreeSet::new());
+        assert_eq!(available.after[16], BTreeSet::from([0, 1, 2]));
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeSet;
+
+use crate::cfg::{block_name, Cfg};
+
+// Successors and predecessors of every block, in the direction an analysis walks
+struct Adjacency {
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>,
+}
+
+impl Adjacency {
+    fn forward(cfg: &Cfg) -> Self {
+        let succs = (0..cfg.num_blocks()).map(|block| cfg.successors(block)).collect();
+        let preds = (0..cfg.num_blocks()).map(|block| cfg.predecessors(block)).collect();
+        Adjacency { succs, preds }
+    }
+
+    fn backward(cfg: &Cfg) -> Self {
+        let Adjacency { succs, preds } = Self::forward(cfg);
+        Adjacency { succs: preds, preds: succs }
+    }
+}
+
+/// The blocks `root` reaches, in reverse postorder: every block comes before its successors,
+/// leaving out back edges
+pub fn reverse_postorder(root: usize, succs: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; succs.len()];
+    let mut postorder = vec![];
+    // Each entry is a block and how many of its successors have been looked at
+    let mut stack = vec![(root, 0)];
+    visited[root] = true;
+    while let Some((block, next)) = stack.pop() {
+        match succs[block].get(next) {
+            Some(&succ) => {
+                stack.push((block, next + 1));
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => postorder.push(block),
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// Which blocks dominate which, from the entry, or post-dominate which, from the exit. Built with
+/// the iterative algorithm of Cooper, Harvey and Kennedy.
+#[derive(Debug, Clone)]
+pub struct DomTree {
+    pub root: usize,
+    /// The immediate dominator of each block. None for the root, and for blocks the root never
+    /// reaches: dead code, or for post-dominators, code that never returns.
+    pub idom: Vec<Option<usize>>,
+    /// Where the block comes in reverse postorder, for the blocks the root reaches
+    order: Vec<Option<usize>>,
+    /// The blocks at which each block's dominance stops
+    pub frontiers: Vec<BTreeSet<usize>>,
+}
+
+impl DomTree {
+    pub fn dominators(cfg: &Cfg) -> Self {
+        Self::build(Cfg::ENTRY, &Adjacency::forward(cfg))
+    }
+
+    pub fn post_dominators(cfg: &Cfg) -> Self {
+        Self::build(Cfg::EXIT, &Adjacency::backward(cfg))
+    }
+
+    fn build(root: usize, graph: &Adjacency) -> Self {
+        let num_blocks = graph.succs.len();
+        let rpo = reverse_postorder(root, &graph.succs);
+        let mut order = vec![None; num_blocks];
+        for (i, block) in rpo.iter().enumerate() {
+            order[*block] = Some(i);
+        }
+
+        // The root is its own dominator while this runs
+        let mut idom: Vec<Option<usize>> = vec![None; num_blocks];
+        idom[root] = Some(root);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while order[a] > order[b] {
+                    a = idom[a].expect("processed blocks have a dominator");
+                }
+                while order[b] > order[a] {
+                    b = idom[b].expect("processed blocks have a dominator");
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in rpo.iter().skip(1) {
+                let mut processed = graph.preds[block].iter().copied().filter(|pred| idom[*pred].is_some());
+                let Some(first) = processed.next() else {
+                    continue;
+                };
+                let new_idom = processed.fold(first, |new_idom, pred| intersect(&idom, pred, new_idom));
+                if idom[block] != Some(new_idom) {
+                    idom[block] = Some(new_idom);
+                    changed = true;
+                }
+            }
+        }
+        idom[root] = None;
+
+        let mut tree = DomTree { root, idom, order, frontiers: vec![BTreeSet::new(); num_blocks] };
+        tree.frontiers = tree.build_frontiers(graph);
+        tree
+    }
+
+    // A block is in the frontier of every block on the way up from each of its predecessors to
+    // its immediate dominator, which only matters where control joins
+    fn build_frontiers(&self, graph: &Adjacency) -> Vec<BTreeSet<usize>> {
+        let mut frontiers = vec![BTreeSet::new(); graph.succs.len()];
+        for (block, preds) in graph.preds.iter().enumerate() {
+            if preds.len() < 2 || !self.reaches(block) {
+                continue;
+            }
+            for &pred in preds.iter().filter(|pred| self.reaches(**pred)) {
+                let mut runner = Some(pred);
+                while let Some(at) = runner.filter(|at| Some(*at) != self.idom[block]) {
+                    frontiers[at].insert(block);
+                    runner = self.idom[at];
+                }
+            }
+        }
+        frontiers
+    }
+
+    /// Whether the root reaches the block, so that it has dominators at all
+    pub fn reaches(&self, block: usize) -> bool {
+        self.order[block].is_some()
+    }
+
+    /// Whether every path from the root to `b` goes through `a`. Blocks dominate themselves.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.reaches(a) || !self.reaches(b) {
+            return false;
+        }
+        let mut at = Some(b);
+        while let Some(block) = at {
+            if block == a {
+                return true;
+            }
+            at = self.idom[block];
+        }
+        false
+    }
+
+    pub fn strictly_dominates(&self, a: usize, b: usize) -> bool {
+        a != b && self.dominates(a, b)
+    }
+
+    /// The blocks whose immediate dominator is `block`
+    pub fn children(&self, block: usize) -> Vec<usize> {
+        (0..self.idom.len()).filter(|child| self.idom[*child] == Some(block)).collect()
+    }
+}
+
+/// A natural loop: a header, and everything that branches back to it without going through it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    /// Every block in the loop, the header and nested loops included
+    pub blocks: BTreeSet<usize>,
+    /// The blocks that branch back to the header
+    pub latches: Vec<usize>,
+    /// The blocks outside the loop that it can go to
+    pub exits: BTreeSet<usize>,
+    /// How many loops it's in, itself included, so 1 for an outermost loop
+    pub depth: usize,
+    /// The innermost loop around this one, as an index into Dominance::loops
+    pub parent: Option<usize>,
+}
+
+fn find_loops(cfg: &Cfg, doms: &DomTree) -> Vec<Loop> {
+    let mut loops: Vec<Loop> = vec![];
+    // An edge is a back edge when its target dominates its source
+    for edge in cfg.edges.iter().filter(|edge| doms.dominates(edge.to, edge.from)) {
+        match loops.iter_mut().find(|found| found.header == edge.to) {
+            Some(found) => found.latches.push(edge.from),
+            None => loops.push(Loop { header: edge.to, blocks: BTreeSet::new(), latches: vec![edge.from], exits: BTreeSet::new(), depth: 0, parent: None }),
+        }
+    }
+
+    for found in loops.iter_mut() {
+        found.latches.sort_unstable();
+        found.latches.dedup();
+        found.blocks.insert(found.header);
+        let mut pending = found.latches.clone();
+        while let Some(block) = pending.pop() {
+            if found.blocks.insert(block) {
+                pending.extend(cfg.predecessors(block).into_iter().filter(|pred| doms.reaches(*pred)));
+            }
+        }
+        found.exits = found.blocks.iter().flat_map(|block| cfg.successors(*block)).filter(|succ| !found.blocks.contains(succ)).collect();
+    }
+
+    // Outer loops first, so a loop's parent is the last one before it that holds its header
+    loops.sort_by_key(|found| std::cmp::Reverse(found.blocks.len()));
+    for i in 0..loops.len() {
+        let parent = (0..i).rev().find(|outer| loops[*outer].blocks.contains(&loops[i].header) && loops[*outer].header != loops[i].header);
+        loops[i].parent = parent;
+        loops[i].depth = parent.map_or(1, |parent| loops[parent].depth + 1);
+    }
+    loops
+}
+
+/// Dominance and loops of a function's CFG, looked up by basic block
+#[derive(Debug, Clone)]
+pub struct Dominance {
+    pub doms: DomTree,
+    pub post_doms: DomTree,
+    /// Outer loops come before the loops they hold
+    pub loops: Vec<Loop>,
+}
+
+impl Dominance {
+    pub fn new(cfg: &Cfg) -> Self {
+        let doms = DomTree::dominators(cfg);
+        let post_doms = DomTree::post_dominators(cfg);
+        let loops = find_loops(cfg, &doms);
+        Dominance { doms, post_doms, loops }
+    }
+
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        self.doms.idom[block]
+    }
+
+    /// The immediate post-dominator: the first block every path from `block` to the exit goes through
+    pub fn ipdom(&self, block: usize) -> Option<usize> {
+        self.post_doms.idom[block]
+    }
+
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        self.doms.dominates(a, b)
+    }
+
+    pub fn post_dominates(&self, a: usize, b: usize) -> bool {
+        self.post_doms.dominates(a, b)
+    }
+
+    pub fn frontier(&self, block: usize) -> &BTreeSet<usize> {
+        &self.doms.frontiers[block]
+    }
+
+    pub fn post_frontier(&self, block: usize) -> &BTreeSet<usize> {
+        &self.post_doms.frontiers[block]
+    }
+
+    /// The innermost loop the block is in, as an index into `loops`
+    pub fn innermost_loop(&self, block: usize) -> Option<usize> {
+        // Inner loops come last
+        (0..self.loops.len()).rev().find(|i| self.loops[*i].blocks.contains(&block))
+    }
+
+    /// How many loops the block is in
+    pub fn loop_depth(&self, block: usize) -> usize {
+        self.innermost_loop(block).map_or(0, |i| self.loops[i].depth)
+    }
+
+    /// What's known about a block, a line for each fact, for showing next to it
+    pub fn describe(&self, block: usize) -> Vec<String> {
+        let names = |blocks: &BTreeSet<usize>| blocks.iter().map(|block| block_name(*block)).collect::<Vec<String>>().join(" ");
+        let mut lines = vec![];
+        if let Some(idom) = self.idom(block) {
+            lines.push(format!("idom {}", block_name(idom)));
+        }
+        if let Some(ipdom) = self.ipdom(block) {
+            lines.push(format!("ipdom {}", block_name(ipdom)));
+        }
+        if !self.frontier(block).is_empty() {
+            lines.push(format!("frontier {}", names(self.frontier(block))));
+        }
+        if let Some(found) = self.loops.iter().find(|found| found.header == block) {
+            lines.push(format!("loop header, depth {}, exits {}", found.depth, names(&found.exits)));
+        } else if self.loop_depth(block) > 0 {
+            lines.push(format!("loop depth {}", self.loop_depth(block)));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    fn cfg_of(wat: &str) -> Cfg {
+        Cfg::new(&parse_wat(wat).unwrap().code_section.functions[0])
+    }
+
+    #[test]
+    fn finds_dominators_and_frontiers() {
+        // bb2: if | bb3: then | bb4: else | bb5: end, end
+        let cfg = cfg_of(
+            "(module
+              (func (param i32)
+                local.get 0
+                if
+                  nop
+                else
+                  nop
+                end))",
+        );
+        let dominance = Dominance::new(&cfg);
+        assert_eq!(dominance.doms.idom, vec![None, Some(5), Some(0), Some(2), Some(2), Some(2)]);
+        assert_eq!(dominance.post_doms.idom, vec![Some(2), None, Some(5), Some(5), Some(5), Some(1)]);
+        assert!(dominance.dominates(2, 4) && !dominance.dominates(3, 5));
+        assert_eq!(dominance.frontier(3), &BTreeSet::from([5]));
+        assert!(dominance.frontier(2).is_empty());
+        // Which arm runs depends on the `if`
+        assert_eq!(dominance.post_frontier(3), &BTreeSet::from([2]));
+        assert!(dominance.loops.is_empty());
+    }
+
+    #[test]
+    fn finds_nested_loops() {
+        let cfg = cfg_of(
+            "(module
+              (func (param i32)
+                loop
+                  loop
+                    local.get 0
+                    br_if 0
+                  end
+                  local.get 0
+                  br_if 0
+                end))",
+        );
+        // bb2: loop | bb3: loop | bb4: inner body | bb5: end, outer body | bb6: end, end
+        let dominance = Dominance::new(&cfg);
+        let loops: Vec<(usize, Vec<usize>, usize, Option<usize>)> =
+            dominance.loops.iter().map(|found| (found.header, found.blocks.iter().copied().collect(), found.depth, found.parent)).collect();
+        assert_eq!(loops, vec![(3, vec![3, 4, 5], 1, None), (4, vec![4], 2, Some(0))]);
+        assert_eq!(dominance.loops[0].exits, BTreeSet::from([6]));
+        assert_eq!(dominance.loops[1].exits, BTreeSet::from([5]));
+        assert_eq!((dominance.loop_depth(2), dominance.loop_depth(4), dominance.loop_depth(5)), (0, 2, 1));
+        assert_eq!(dominance.describe(4), vec!["idom bb3", "ipdom bb5", "frontier bb3 bb4", "loop header, depth 2, exits bb5"]);
+    }
+}
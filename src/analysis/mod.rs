@@ -0,0 +1,2 @@
+pub mod dataflow;
+pub mod dominance;
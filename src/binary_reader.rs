@@ -0,0 +1,266 @@
+use crate::error::*;
+
+/// A cursor over the bytes of a module. Every read is bounds checked, multi-byte
+/// values are always little-endian, and LEB128 values are checked against the
+/// maximum length and range the spec allows for their type.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    // Offset of data[0] in the file, for readers over part of a module
+    base: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self::new_at(data, 0)
+    }
+
+    pub fn new_at(data: &'a [u8], base: usize) -> Self {
+        Self { data, position: 0, base }
+    }
+
+    /// Position in the file, not just in this reader's bytes
+    pub fn offset(&self) -> usize {
+        self.base + self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The bytes between an earlier offset and where we are now
+    pub fn bytes_since(&self, offset: usize) -> &'a [u8] {
+        let start = offset.saturating_sub(self.base).min(self.position);
+        &self.data[start..self.position]
+    }
+
+    fn at(&self, offset: usize) -> ErrorLocation {
+        ErrorLocation { offset: Some(offset), ..Default::default() }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], WyrmError> {
+        if len > self.remaining() {
+            return Err(WyrmError::UnexpectedEof(self.at(self.data.len() + self.base)));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], WyrmError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.read_bytes(N)?);
+        Ok(out)
+    }
+
+    /// Splits off the next `len` bytes into their own reader
+    /// A name: its length, then that many bytes of UTF-8
+    pub fn read_name(&mut self) -> Result<&'a str, WyrmError> {
+        let len = self.read_var_u32()? as usize;
+        let start = self.offset();
+        std::str::from_utf8(self.read_bytes(len)?).map_err(|_| WyrmError::InvalidData(self.at(start), "Name isn't valid UTF-8".to_string()))
+    }
+
+    pub fn sub_reader(&mut self, len: usize) -> Result<BinaryReader<'a>, WyrmError> {
+        let base = self.offset();
+        Ok(BinaryReader::new_at(self.read_bytes(len)?, base))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, WyrmError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, WyrmError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, WyrmError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, WyrmError> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, WyrmError> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, WyrmError> {
+        Ok(u128::from_le_bytes(self.read_array()?))
+    }
+
+    // Unsigned LEB128 holding at most `bits` bits. That allows ceil(bits / 7) bytes,
+    // and whatever bits of the last byte don't fit must be zero.
+    fn read_var_unsigned(&mut self, bits: u32) -> Result<u64, WyrmError> {
+        let start = self.offset();
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            result |= ((byte & 0x7f) as u64) << shift;
+
+            if shift + 7 >= bits {
+                if byte & 0x80 != 0 {
+                    return Err(WyrmError::InvalidData(self.at(start), format!("LEB128 integer is longer than u{} allows", bits)));
+                }
+                let used = bits - shift;
+                if used < 7 && (byte & 0x7f) >> used != 0 {
+                    return Err(WyrmError::InvalidData(self.at(start), format!("LEB128 integer is too large for u{}", bits)));
+                }
+                return Ok(result);
+            }
+
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    // Signed LEB128 holding at most `bits` bits. The unused bits of the last byte
+    // must all be copies of the sign bit.
+    fn read_var_signed(&mut self, bits: u32) -> Result<i64, WyrmError> {
+        let start = self.offset();
+        let mut result: i64 = 0;
+        let mut shift = 0;
+        let mut byte;
+        loop {
+            byte = self.read_u8()?;
+            result |= ((byte & 0x7f) as i64) << shift;
+
+            if shift + 7 >= bits {
+                if byte & 0x80 != 0 {
+                    return Err(WyrmError::InvalidData(self.at(start), format!("LEB128 integer is longer than s{} allows", bits)));
+                }
+                let used = bits - shift;
+                if used < 7 {
+                    // The sign bit and everything above it
+                    let high = (byte & 0x7f) >> (used - 1);
+                    if high != 0 && high != 0x7f >> (used - 1) {
+                        return Err(WyrmError::InvalidData(self.at(start), format!("LEB128 integer is too large for s{}", bits)));
+                    }
+                }
+                shift += 7;
+                break;
+            }
+
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        if shift < 64 && byte & 0x40 != 0 {
+            result |= -1i64 << shift;
+        }
+        Ok(result)
+    }
+
+    pub fn read_var_u32(&mut self) -> Result<u32, WyrmError> {
+        Ok(self.read_var_unsigned(32)? as u32)
+    }
+
+    pub fn read_var_u64(&mut self) -> Result<u64, WyrmError> {
+        self.read_var_unsigned(64)
+    }
+
+    pub fn read_var_i32(&mut self) -> Result<i32, WyrmError> {
+        Ok(self.read_var_signed(32)? as i32)
+    }
+
+    pub fn read_var_i64(&mut self) -> Result<i64, WyrmError> {
+        self.read_var_signed(64)
+    }
+
+    /// Block types and heap types are 33 bit so they can hold any u32 type index
+    pub fn read_var_s33(&mut self) -> Result<i64, WyrmError> {
+        self.read_var_signed(33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(bytes: &[u8]) -> BinaryReader<'_> {
+        BinaryReader::new(bytes)
+    }
+
+    #[test]
+    fn reads_little_endian() {
+        let mut r = reader(&[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(r.read_u32().unwrap(), 0x6d736100);
+        assert_eq!(r.read_u32().unwrap(), 1);
+        assert!(r.is_empty());
+        assert!(matches!(r.read_u8(), Err(WyrmError::UnexpectedEof(_))));
+    }
+
+    #[test]
+    fn unsigned_leb128() {
+        assert_eq!(reader(&[0xe5, 0x8e, 0x26]).read_var_u32().unwrap(), 624485);
+        assert_eq!(reader(&[0xff, 0xff, 0xff, 0xff, 0x0f]).read_var_u32().unwrap(), u32::MAX);
+        // Padded with redundant bytes, still within the length limit
+        assert_eq!(reader(&[0x80, 0x80, 0x80, 0x80, 0x00]).read_var_u32().unwrap(), 0);
+        // Too many bytes
+        assert!(reader(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]).read_var_u32().is_err());
+        // Bits past the 32nd
+        assert!(reader(&[0xff, 0xff, 0xff, 0xff, 0x1f]).read_var_u32().is_err());
+        assert_eq!(
+            reader(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).read_var_u64().unwrap(),
+            u64::MAX
+        );
+        assert!(reader(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x03]).read_var_u64().is_err());
+    }
+
+    #[test]
+    fn signed_leb128() {
+        assert_eq!(reader(&[0x7f]).read_var_i32().unwrap(), -1);
+        assert_eq!(reader(&[0xc0, 0xbb, 0x78]).read_var_i64().unwrap(), -123456);
+        assert_eq!(reader(&[0x80, 0x7f]).read_var_i32().unwrap(), -128);
+        assert_eq!(reader(&[0x80, 0x80, 0x80, 0x80, 0x78]).read_var_i32().unwrap(), i32::MIN);
+        assert_eq!(reader(&[0xff, 0xff, 0xff, 0xff, 0x07]).read_var_i32().unwrap(), i32::MAX);
+        // The unused bits must match the sign
+        assert!(reader(&[0xff, 0xff, 0xff, 0xff, 0x0f]).read_var_i32().is_err());
+        assert!(reader(&[0x80, 0x80, 0x80, 0x80, 0x70]).read_var_i32().is_err());
+        assert_eq!(
+            reader(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]).read_var_i64().unwrap(),
+            i64::MIN
+        );
+        assert!(reader(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00]).read_var_i64().is_err());
+    }
+
+    #[test]
+    fn s33_holds_type_indices() {
+        assert_eq!(reader(&[0x40]).read_var_s33().unwrap(), -64);
+        assert_eq!(reader(&[0xff, 0xff, 0xff, 0xff, 0x0f]).read_var_s33().unwrap(), u32::MAX as i64);
+        assert!(reader(&[0xff, 0xff, 0xff, 0xff, 0x1f]).read_var_s33().is_err());
+    }
+
+    #[test]
+    fn sub_readers_keep_file_offsets() {
+        let mut r = reader(&[0x01, 0x02, 0x03, 0x04]);
+        r.read_u8().unwrap();
+        let mut sub = r.sub_reader(2).unwrap();
+        assert_eq!(sub.offset(), 1);
+        sub.read_u8().unwrap();
+        assert_eq!(sub.offset(), 2);
+        assert_eq!(r.offset(), 3);
+        assert!(r.sub_reader(2).is_err());
+    }
+
+    #[test]
+    fn names_are_utf8() {
+        assert_eq!(reader(&[0x03, b'e', b'n', b'v']).read_name().unwrap(), "env");
+        assert_eq!(reader(&[0x02, 0xc3, 0xa9]).read_name().unwrap(), "\u{e9}");
+        let error = reader(&[0x02, 0xc3, 0x28]).read_name().unwrap_err();
+        assert!(matches!(&error, WyrmError::InvalidData(location, _) if location.offset == Some(1)), "{:?}", error);
+        // The length runs past the end
+        assert!(matches!(reader(&[0x05, b'a']).read_name(), Err(WyrmError::UnexpectedEof(_))));
+    }
+}
@@ -0,0 +1,145 @@
+/// The other half of BinaryReader: appends little-endian values and LEB128
+/// integers to a growing buffer, which can then be written out in one go.
+#[derive(Debug, Clone, Default)]
+pub struct BinaryWriter {
+    data: Vec<u8>,
+}
+
+impl BinaryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u128(&mut self, value: u128) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    /// Always the shortest encoding, so values read from padded LEB128 come back smaller
+    pub fn write_var_u64(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.write_u8(byte);
+                return;
+            }
+            self.write_u8(byte | 0x80);
+        }
+    }
+
+    pub fn write_var_u32(&mut self, value: u32) {
+        self.write_var_u64(value as u64);
+    }
+
+    pub fn write_var_i64(&mut self, mut value: i64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            // Done once the rest is all sign bits, and the sign bit of this byte agrees
+            let sign_bit = byte & 0x40 != 0;
+            if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+                self.write_u8(byte);
+                return;
+            }
+            self.write_u8(byte | 0x80);
+        }
+    }
+
+    pub fn write_var_i32(&mut self, value: i32) {
+        self.write_var_i64(value as i64);
+    }
+
+    pub fn write_var_s33(&mut self, value: i64) {
+        self.write_var_i64(value);
+    }
+
+    /// A length-prefixed byte string, which is how names are stored
+    pub fn write_name(&mut self, name: &[u8]) {
+        self.write_var_u32(name.len() as u32);
+        self.write_bytes(name);
+    }
+
+    /// Writes `contents` prefixed with its size, as sections and function bodies are
+    pub fn write_sized(&mut self, contents: &BinaryWriter) {
+        self.write_var_u32(contents.len() as u32);
+        self.write_bytes(contents.bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary_reader::BinaryReader;
+
+    #[test]
+    fn leb128_round_trips() {
+        let unsigned = [0, 1, 63, 64, 127, 128, 624485, u32::MAX as u64, u64::MAX];
+        let signed = [0, -1, 63, 64, -64, -65, -123456, i32::MIN as i64, i32::MAX as i64, i64::MIN, i64::MAX];
+
+        let mut writer = BinaryWriter::new();
+        for value in unsigned {
+            writer.write_var_u64(value);
+        }
+        for value in signed {
+            writer.write_var_i64(value);
+        }
+
+        let mut reader = BinaryReader::new(writer.bytes());
+        for value in unsigned {
+            assert_eq!(reader.read_var_u64().unwrap(), value);
+        }
+        for value in signed {
+            assert_eq!(reader.read_var_i64().unwrap(), value);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn writes_shortest_leb128() {
+        let mut writer = BinaryWriter::new();
+        writer.write_var_u32(624485);
+        writer.write_var_i32(-123456);
+        writer.write_var_s33(-0x40);
+        assert_eq!(writer.bytes(), &[0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0x40]);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{collections::BTreeSet, io::Write};
+
+use crate::error::WyrmError;
+use crate::instr_table::{CALL, CALL_REF, RETURN_CALL, RETURN_CALL_REF};
+use crate::wasm_model::*;
+
+/// Where a call goes. Indirect calls can't be resolved without running the module, so they go to
+/// one node per signature they call with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CallNode {
+    Func(u32),
+    Indirect(u32),
+}
+
+/// Which functions call which, from the call instructions in each body. Tail calls are calls too.
+pub struct CallGraph {
+    pub nodes: Vec<CallNode>,
+    pub calls: Vec<(CallNode, CallNode)>,
+    // Only used for the labels
+    names: WasmNameSection,
+    num_imported_funcs: u32,
+}
+
+fn is_op(info: &InstrInfo, opcode: u64) -> bool {
+    info.prefix == 0 && info.instr == opcode as u32
+}
+
+// What the instruction calls, if it's a call
+fn call_target(seg: &ExprSeg) -> Option<CallNode> {
+    let ExprSeg::Instr(instr) = seg else {
+        return None;
+    };
+    let Some(ExprSeg::Operation(info)) = instr.first() else {
+        return None;
+    };
+    match (instr.get(1), get_edge_case(*info)) {
+        (Some(ExprSeg::Func(func)), _) if is_op(info, CALL) || is_op(info, RETURN_CALL) => Some(CallNode::Func(*func)),
+        (Some(ExprSeg::Int(sig)), SpecialInstr::CallIndirect) => Some(CallNode::Indirect(*sig as u32)),
+        (Some(ExprSeg::Type(sig)), _) if is_op(info, CALL_REF) || is_op(info, RETURN_CALL_REF) => Some(CallNode::Indirect(*sig)),
+        _ => None,
+    }
+}
+
+fn collect_calls(expr: &WasmExpr, calls: &mut BTreeSet<CallNode>) {
+    for seg in expr.expr_string.iter() {
+        match seg {
+            ExprSeg::ControlFlow(_, _, body, ..) => collect_calls(body, calls),
+            seg => calls.extend(call_target(seg)),
+        }
+    }
+}
+
+impl CallGraph {
+    pub fn new(wasm: &WasmFile) -> Self {
+        let mut nodes: BTreeSet<CallNode> = (0..wasm.index_space_len(WasmImportType::Func)).map(CallNode::Func).collect();
+        let mut calls = vec![];
+        for (i, func) in wasm.code_section.functions.iter().enumerate() {
+            let caller = CallNode::Func(wasm.defined_func_idx(i));
+            let mut callees = BTreeSet::new();
+            collect_calls(&func.body, &mut callees);
+            nodes.extend(callees.iter().copied());
+            calls.extend(callees.into_iter().map(|callee| (caller, callee)));
+        }
+        CallGraph {
+            nodes: nodes.into_iter().collect(),
+            calls,
+            names: wasm.name_section.with_unique_ids(),
+            num_imported_funcs: wasm.num_imported(WasmImportType::Func) as u32,
+        }
+    }
+
+    /// Only the functions `root` can reach through calls, and the calls between them
+    pub fn reachable_from(&self, root: u32) -> Self {
+        let mut reached = BTreeSet::from([CallNode::Func(root)]);
+        let mut pending = vec![CallNode::Func(root)];
+        while let Some(caller) = pending.pop() {
+            for (_, callee) in self.calls.iter().filter(|(from, _)| *from == caller) {
+                if reached.insert(*callee) {
+                    pending.push(*callee);
+                }
+            }
+        }
+        CallGraph {
+            nodes: reached.iter().copied().collect(),
+            calls: self.calls.iter().filter(|(caller, _)| reached.contains(caller)).copied().collect(),
+            names: self.names.clone(),
+            num_imported_funcs: self.num_imported_funcs,
+        }
+    }
+
+    pub fn callees(&self, caller: u32) -> impl Iterator<Item = CallNode> + '_ {
+        self.calls.iter().filter(move |(from, _)| *from == CallNode::Func(caller)).map(|(_, callee)| *callee)
+    }
+
+    pub fn callers(&self, callee: CallNode) -> impl Iterator<Item = u32> + '_ {
+        self.calls.iter().filter(move |(_, to)| *to == callee).filter_map(|(from, _)| match from {
+            CallNode::Func(func) => Some(*func),
+            CallNode::Indirect(_) => None,
+        })
+    }
+
+    fn is_imported(&self, node: &CallNode) -> bool {
+        matches!(node, CallNode::Func(func) if *func < self.num_imported_funcs)
+    }
+
+    pub fn render_dot(&self, mut out: &mut dyn Write) -> Result<(), WyrmError> {
+        dot2::render(self, &mut out).map_err(|e| match e {
+            dot2::Error::Io(e) => e.into(),
+            dot2::Error::InvalidId => WyrmError::invalid("Call graph node with an invalid DOT id"),
+        })
+    }
+}
+
+type CallEdge = (CallNode, CallNode);
+
+impl<'a> dot2::Labeller<'a> for CallGraph {
+    type Node = CallNode;
+    type Edge = CallEdge;
+    type Subgraph = ();
+
+    fn graph_id(&'a self) -> dot2::Result<dot2::Id<'a>> {
+        dot2::Id::new("callgraph")
+    }
+
+    fn node_id(&'a self, n: &CallNode) -> dot2::Result<dot2::Id<'a>> {
+        match n {
+            CallNode::Func(func) => dot2::Id::new(format!("func{}", func)),
+            CallNode::Indirect(sig) => dot2::Id::new(format!("indirect{}", sig)),
+        }
+    }
+
+    fn node_label(&'a self, n: &CallNode) -> dot2::Result<dot2::label::Text<'a>> {
+        let label = match n {
+            CallNode::Func(func) => self.names.func_id(*func),
+            CallNode::Indirect(sig) => format!("indirect (type {})", sig),
+        };
+        Ok(dot2::label::Text::label(label))
+    }
+
+    // Imports are boxes, as their bodies aren't in the module
+    fn node_shape(&'a self, n: &CallNode) -> Option<dot2::label::Text<'a>> {
+        self.is_imported(n).then(|| dot2::label::Text::label("box"))
+    }
+
+    fn node_style(&'a self, n: &CallNode) -> dot2::Style {
+        match n {
+            CallNode::Indirect(_) => dot2::Style::Dashed,
+            CallNode::Func(_) => dot2::Style::None,
+        }
+    }
+}
+
+impl<'a> dot2::GraphWalk<'a> for CallGraph {
+    type Node = CallNode;
+    type Edge = CallEdge;
+    type Subgraph = ();
+
+    fn nodes(&'a self) -> dot2::Nodes<'a, CallNode> {
+        (&self.nodes[..]).into()
+    }
+
+    fn edges(&'a self) -> dot2::Edges<'a, CallEdge> {
+        (&self.calls[..]).into()
+    }
+
+    fn source(&'a self, e: &CallEdge) -> CallNode {
+        e.0
+    }
+
+    fn target(&'a self, e: &CallEdge) -> CallNode {
+        e.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    const MODULE: &str = "(module
+      (type $t (func))
+      (import \"env\" \"log\" (func $log))
+      (table 1 funcref)
+      (func $main
+        call $helper
+        block
+          i32.const 0
+          call_indirect (type $t)
+        end)
+      (func $helper
+        call $log
+        return_call $helper)
+      (func $unused
+        call $log))";
+
+    #[test]
+    fn finds_direct_indirect_and_tail_calls() {
+        let graph = CallGraph::new(&parse_wat(MODULE).unwrap());
+        assert_eq!(graph.callees(1).collect::<Vec<_>>(), vec![CallNode::Func(2), CallNode::Indirect(0)]);
+        assert_eq!(graph.callees(2).collect::<Vec<_>>(), vec![CallNode::Func(0), CallNode::Func(2)]);
+        assert_eq!(graph.callers(CallNode::Func(0)).collect::<Vec<_>>(), vec![2, 3]);
+
+        let reachable = graph.reachable_from(1);
+        assert_eq!(reachable.nodes, vec![CallNode::Func(0), CallNode::Func(1), CallNode::Func(2), CallNode::Indirect(0)]);
+    }
+
+    #[test]
+    fn renders_dot() {
+        let graph = CallGraph::new(&parse_wat(MODULE).unwrap());
+        let mut dot = vec![];
+        graph.render_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph callgraph {"));
+        assert!(dot.contains("func0[label=\"$log\"][shape=\"box\"];"), "{}", dot);
+        assert!(dot.contains("func1 -> indirect0"), "{}", dot);
+    }
+}
@@ -0,0 +1,128 @@
+use std::io::Write;
+
+use crate::cfg::{block_name, Cfg, CfgEdge, EdgeKind};
+use crate::error::WyrmError;
+
+// A CFG with lines of notes to show under each block's instructions
+struct CfgDot<'a> {
+    cfg: &'a Cfg,
+    notes: &'a [Vec<String>],
+}
+
+impl Cfg {
+    pub fn render_dot(&self, out: &mut dyn Write) -> Result<(), WyrmError> {
+        self.render_dot_with_notes(&[], out)
+    }
+
+    /// Renders with `notes[block]` under each block, e.g. what an analysis found out about it
+    pub fn render_dot_with_notes(&self, notes: &[Vec<String>], mut out: &mut dyn Write) -> Result<(), WyrmError> {
+        dot2::render(&CfgDot { cfg: self, notes }, &mut out).map_err(|e| match e {
+            dot2::Error::Io(e) => e.into(),
+            dot2::Error::InvalidId => WyrmError::invalid("Control-flow graph node with an invalid DOT id"),
+        })
+    }
+}
+
+impl CfgDot<'_> {
+    // The instructions one to a line, each left-justified, then the notes as comments
+    fn block_label(&self, block: usize) -> String {
+        let mut label = format!("{}\\l", block_name(block));
+        for instr in self.cfg.block_instrs(block) {
+            match instr.offset {
+                Some(offset) => label += &format!("{:#06x}  {}\\l", offset, instr.text),
+                None => label += &format!("{}\\l", instr.text),
+            }
+        }
+        for note in self.notes.get(block).into_iter().flatten() {
+            label += &format!(";; {}\\l", note);
+        }
+        label
+    }
+
+    fn is_code(block: usize) -> bool {
+        block != Cfg::ENTRY && block != Cfg::EXIT
+    }
+}
+
+impl<'a> dot2::Labeller<'a> for CfgDot<'a> {
+    type Node = usize;
+    type Edge = CfgEdge;
+    type Subgraph = ();
+
+    fn graph_id(&'a self) -> dot2::Result<dot2::Id<'a>> {
+        dot2::Id::new("cfg")
+    }
+
+    fn node_id(&'a self, n: &usize) -> dot2::Result<dot2::Id<'a>> {
+        dot2::Id::new(block_name(*n))
+    }
+
+    fn node_label(&'a self, n: &usize) -> dot2::Result<dot2::label::Text<'a>> {
+        let has_notes = self.notes.get(*n).is_some_and(|notes| !notes.is_empty());
+        Ok(match *n {
+            block if Self::is_code(block) || has_notes => dot2::label::Text::EscStr(self.block_label(block).into()),
+            block => dot2::label::Text::label(block_name(block)),
+        })
+    }
+
+    fn node_shape(&'a self, n: &usize) -> Option<dot2::label::Text<'a>> {
+        Self::is_code(*n).then(|| dot2::label::Text::label("box"))
+    }
+
+    // Falling through is the usual case, so only the other edges are labelled
+    fn edge_label(&'a self, e: &CfgEdge) -> dot2::label::Text<'a> {
+        match e.kind {
+            EdgeKind::Fallthrough => dot2::label::Text::label(""),
+            kind => dot2::label::Text::label(kind.to_string()),
+        }
+    }
+
+    fn edge_style(&'a self, e: &CfgEdge) -> dot2::Style {
+        match e.kind {
+            EdgeKind::LoopBack => dot2::Style::Dashed,
+            EdgeKind::Trap => dot2::Style::Dotted,
+            _ => dot2::Style::None,
+        }
+    }
+}
+
+impl<'a> dot2::GraphWalk<'a> for CfgDot<'a> {
+    type Node = usize;
+    type Edge = CfgEdge;
+    type Subgraph = ();
+
+    fn nodes(&'a self) -> dot2::Nodes<'a, usize> {
+        (0..self.cfg.num_blocks()).collect::<Vec<usize>>().into()
+    }
+
+    fn edges(&'a self) -> dot2::Edges<'a, CfgEdge> {
+        (&self.cfg.edges[..]).into()
+    }
+
+    fn source(&'a self, e: &CfgEdge) -> usize {
+        e.from
+    }
+
+    fn target(&'a self, e: &CfgEdge) -> usize {
+        e.to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::Cfg;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn renders_dot() {
+        let wasm = parse_wat("(module (func (param i32) local.get 0 br_if 0 nop))").unwrap();
+        let mut dot = vec![];
+        let notes = vec![vec![], vec![], vec!["idom entry".to_string()]];
+        Cfg::new(&wasm.code_section.functions[0]).render_dot_with_notes(&notes, &mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph cfg {"), "{}", dot);
+        assert!(dot.contains("bb2[label=\"bb2\\llocal.get $var0\\lbr_if 0\\l;; idom entry\\l\"][shape=\"box\"];"), "{}", dot);
+        assert!(dot.contains("bb2 -> bb4[label=\"taken\"];"), "{}", dot);
+        assert!(dot.contains("bb4 -> exit[label=\"return\"];"), "{}", dot);
+    }
+}
@@ -0,0 +1,447 @@
+pub mod draw;
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use crate::wasm_model::*;
+
+/// How control gets from one basic block to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// Straight on into the next block, e.g. past the `end` of a block nothing branches out of
+    Fallthrough,
+    /// A `br` or a `br_if` whose condition holds, or into the `then` arm of an `if`
+    BranchTaken,
+    /// Past a `br_if` whose condition doesn't hold, or into the `else` arm (or past the end) of an `if`
+    BranchNotTaken,
+    /// A `br_table` entry, by its position in the table
+    TableCase(usize),
+    TableDefault,
+    /// A branch back to the start of a loop
+    LoopBack,
+    /// Out of the function to the exit node, by `return`, a tail call or the body's `end`
+    Return,
+    /// Out of the function to the exit node by trapping or throwing
+    Trap,
+}
+
+impl Display for EdgeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgeKind::Fallthrough => write!(f, "fallthrough"),
+            EdgeKind::BranchTaken => write!(f, "taken"),
+            EdgeKind::BranchNotTaken => write!(f, "not taken"),
+            EdgeKind::TableCase(case) => write!(f, "case {}", case),
+            EdgeKind::TableDefault => write!(f, "default"),
+            EdgeKind::LoopBack => write!(f, "loop"),
+            EdgeKind::Return => write!(f, "return"),
+            EdgeKind::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+/// How a block is named in DOT output and descriptions, e.g. `bb3`
+pub fn block_name(block: usize) -> String {
+    match block {
+        Cfg::ENTRY => "entry".to_string(),
+        Cfg::EXIT => "exit".to_string(),
+        block => format!("bb{}", block),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CfgEdge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// An encoded instruction of the body, flattened out of its block
+#[derive(Debug, Clone)]
+pub struct CfgInstr {
+    pub info: InstrInfo,
+    pub immediates: Vec<ExprSeg>,
+    /// How WAT writes it, with the ids a module without names gets
+    pub text: String,
+    /// Where it is in the file, for functions read from a binary
+    pub offset: Option<usize>,
+}
+
+/// A run of instructions that's only entered at its first and only left after its last.
+/// The entry and exit nodes are blocks without instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    /// Indices into Cfg::instrs
+    pub instrs: Range<usize>,
+}
+
+/// The control-flow graph of a function body. Instructions are counted in the order they're
+/// encoded, like WasmFunction::instr_offsets, and a block's header, `else` and `end` are
+/// instructions too. Code that can't be reached still gets blocks, just without predecessors.
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub instrs: Vec<CfgInstr>,
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<CfgEdge>,
+}
+
+impl Cfg {
+    /// Where the function is entered, before its first instruction
+    pub const ENTRY: usize = 0;
+    /// Where every return, trap and throw goes
+    pub const EXIT: usize = 1;
+
+    pub fn new(func: &WasmFunction) -> Self {
+        let mut builder = Builder {
+            cfg: Cfg { instrs: vec![], blocks: vec![BasicBlock { instrs: 0..0 }, BasicBlock { instrs: 0..0 }], edges: vec![] },
+            offsets: &func.instr_offsets,
+            labels: vec![Label { kind: LabelKind::Func, header: Cfg::EXIT, pending: vec![], if_block: None }],
+            current: None,
+            incoming: vec![(Cfg::ENTRY, EdgeKind::Fallthrough)],
+        };
+        builder.walk(&func.body, blank_emitter());
+        builder.cfg
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn out_edges(&self, block: usize) -> impl Iterator<Item = &CfgEdge> + '_ {
+        self.edges.iter().filter(move |edge| edge.from == block)
+    }
+
+    pub fn in_edges(&self, block: usize) -> impl Iterator<Item = &CfgEdge> + '_ {
+        self.edges.iter().filter(move |edge| edge.to == block)
+    }
+
+    /// The blocks control can go to next, each only once
+    pub fn successors(&self, block: usize) -> Vec<usize> {
+        let mut succs: Vec<usize> = self.out_edges(block).map(|edge| edge.to).collect();
+        succs.sort_unstable();
+        succs.dedup();
+        succs
+    }
+
+    pub fn predecessors(&self, block: usize) -> Vec<usize> {
+        let mut preds: Vec<usize> = self.in_edges(block).map(|edge| edge.from).collect();
+        preds.sort_unstable();
+        preds.dedup();
+        preds
+    }
+
+    /// The block an instruction is in
+    pub fn block_of(&self, instr: usize) -> Option<usize> {
+        self.blocks.iter().position(|block| block.instrs.contains(&instr))
+    }
+
+    pub fn block_instrs(&self, block: usize) -> &[CfgInstr] {
+        &self.instrs[self.blocks[block].instrs.clone()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LabelKind {
+    Func,
+    Block,
+    Loop,
+    If,
+}
+
+struct Label {
+    kind: LabelKind,
+    // Where a branch to a loop goes
+    header: usize,
+    // Edges into the block after the `end`, which isn't made until the `end` is reached
+    pending: Vec<(usize, EdgeKind)>,
+    // The block ending with an `if`, until its `else` is reached
+    if_block: Option<usize>,
+}
+
+struct Builder<'a> {
+    cfg: Cfg,
+    offsets: &'a [usize],
+    labels: Vec<Label>,
+    // The block instructions are being added to, None once control has left it
+    current: Option<usize>,
+    // Edges into the next block to be made
+    incoming: Vec<(usize, EdgeKind)>,
+}
+
+impl Builder<'_> {
+    fn edge(&mut self, from: usize, to: usize, kind: EdgeKind) {
+        let edge = CfgEdge { from, to, kind };
+        if !self.cfg.edges.contains(&edge) {
+            self.cfg.edges.push(edge);
+        }
+    }
+
+    fn start_block(&mut self) -> usize {
+        let start = self.cfg.instrs.len();
+        let block = self.cfg.blocks.len();
+        self.cfg.blocks.push(BasicBlock { instrs: start..start });
+        for (from, kind) in std::mem::take(&mut self.incoming) {
+            self.edge(from, block, kind);
+        }
+        self.current = Some(block);
+        block
+    }
+
+    // Adds the instruction to the current block, starting one if control has left the last
+    fn add(&mut self, info: InstrInfo, immediates: &[ExprSeg], text: String) -> usize {
+        let block = match self.current {
+            Some(block) => block,
+            None => self.start_block(),
+        };
+        let offset = self.offsets.get(self.cfg.instrs.len()).copied();
+        self.cfg.instrs.push(CfgInstr { info, immediates: immediates.to_vec(), text, offset });
+        self.cfg.blocks[block].instrs.end = self.cfg.instrs.len();
+        block
+    }
+
+    // Leaves the current block, into whichever block is made next
+    fn cut(&mut self, kind: EdgeKind) {
+        if let Some(block) = self.current.take() {
+            self.incoming.push((block, kind));
+        }
+    }
+
+    fn branch(&mut self, from: usize, depth: usize, kind: EdgeKind) {
+        // Branches past the function's own label aren't valid, and go nowhere
+        let Some(i) = self.labels.len().checked_sub(depth + 1) else {
+            return;
+        };
+        match self.labels[i].kind {
+            LabelKind::Loop => {
+                let header = self.labels[i].header;
+                let kind = if kind == EdgeKind::BranchTaken { EdgeKind::LoopBack } else { kind };
+                self.edge(from, header, kind);
+            }
+            _ => self.labels[i].pending.push((from, kind)),
+        }
+    }
+
+    fn begin_block(&mut self, info: InstrInfo, text: String) {
+        let block = self.add(info, &[], text);
+        let mut label = Label { kind: LabelKind::Block, header: 0, pending: vec![], if_block: None };
+        match info.instr {
+            0x03 => {
+                self.cut(EdgeKind::Fallthrough);
+                label.kind = LabelKind::Loop;
+                label.header = self.start_block();
+            }
+            0x04 => {
+                self.current = None;
+                self.incoming.push((block, EdgeKind::BranchTaken));
+                label.kind = LabelKind::If;
+                label.if_block = Some(block);
+            }
+            _ => {}
+        }
+        self.labels.push(label);
+    }
+
+    // An `else`, or a `catch` or `catch_all`, whose arm nothing branches into since throws go to the exit
+    fn else_arm(&mut self, info: InstrInfo, text: String) {
+        self.add(info, &[], text);
+        let current = self.current.take();
+        let Some(label) = self.labels.last_mut() else {
+            return;
+        };
+        label.pending.extend(current.map(|block| (block, EdgeKind::Fallthrough)));
+        self.incoming.extend(label.if_block.take().map(|block| (block, EdgeKind::BranchNotTaken)));
+    }
+
+    fn end(&mut self, info: InstrInfo, text: String) {
+        let Some(mut label) = self.labels.pop() else {
+            return;
+        };
+        label.pending.extend(label.if_block.take().map(|block| (block, EdgeKind::BranchNotTaken)));
+        // Blocks that are only left by falling through don't need a new block after them
+        if !label.pending.is_empty() {
+            self.cut(EdgeKind::Fallthrough);
+            self.incoming.extend(label.pending);
+            self.start_block();
+        }
+        self.add(info, &[], text);
+        if label.kind == LabelKind::Func {
+            self.cut(EdgeKind::Return);
+            for (from, kind) in std::mem::take(&mut self.incoming) {
+                self.edge(from, Cfg::EXIT, kind);
+            }
+        }
+    }
+
+    fn instr(&mut self, info: InstrInfo, immediates: &[ExprSeg], text: String) {
+        let block = self.add(info, immediates, text);
+        let depth = match immediates.first() {
+            Some(ExprSeg::Int(depth)) => *depth as usize,
+            _ => 0,
+        };
+        match info.name {
+            "br" => {
+                self.branch(block, depth, EdgeKind::BranchTaken);
+                self.current = None;
+            }
+            "br_if" | "br_on_cast" | "br_on_cast_fail" | "br_on_null" | "br_on_non_null" => {
+                self.branch(block, depth, EdgeKind::BranchTaken);
+                self.cut(EdgeKind::BranchNotTaken);
+            }
+            "br_table" => {
+                if let Some(ExprSeg::BrTable(table)) = immediates.first() {
+                    for (case, depth) in table.break_depths.iter().enumerate() {
+                        self.branch(block, *depth as usize, EdgeKind::TableCase(case));
+                    }
+                    self.branch(block, table.default as usize, EdgeKind::TableDefault);
+                }
+                self.current = None;
+            }
+            "return" | "return_call" | "return_call_indirect" | "return_call_ref" => {
+                self.edge(block, Cfg::EXIT, EdgeKind::Return);
+                self.current = None;
+            }
+            "unreachable" | "throw" | "rethrow" | "throw_ref" => {
+                self.edge(block, Cfg::EXIT, EdgeKind::Trap);
+                self.current = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn walk(&mut self, expr: &WasmExpr, state: EmitterState<'_>) {
+        for seg in expr.expr_string.iter() {
+            // An `end`, or the `delegate` that ends a try
+            if let Some(info) = block_end(seg) {
+                self.end(info, seg.emit_wat("".to_string(), state).trim().to_string());
+                return;
+            }
+            match seg {
+                ExprSeg::ControlFlow(info, block_type, body, ..) => {
+                    self.begin_block(*info, format!("{}{}", info.name, state.block_type(*block_type)));
+                    self.walk(body, state.nested());
+                }
+                ExprSeg::Instr(instr) => {
+                    let Some(ExprSeg::Operation(info)) = instr.first() else {
+                        continue;
+                    };
+                    if is_arm(seg) {
+                        self.else_arm(*info, seg.emit_wat("".to_string(), state).trim().to_string());
+                    } else {
+                        let text = seg.emit_wat("".to_string(), state).trim_end().to_string();
+                        self.instr(*info, &instr[1..], text);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    fn cfg_of(wat: &str) -> Cfg {
+        let wasm = parse_wat(wat).unwrap();
+        Cfg::new(&wasm.code_section.functions[0])
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, EdgeKind)> {
+        cfg.edges.iter().map(|edge| (edge.from, edge.to, edge.kind)).collect()
+    }
+
+    #[test]
+    fn splits_ifs_and_loops() {
+        let cfg = cfg_of(
+            "(module
+              (func (param i32) (result i32)
+                local.get 0
+                if (result i32)
+                  i32.const 1
+                else
+                  loop
+                    local.get 0
+                    br_if 0
+                  end
+                  i32.const 2
+                end))",
+        );
+        let ranges: Vec<Range<usize>> = cfg.blocks.iter().map(|block| block.instrs.clone()).collect();
+        // local.get, if | i32.const, else | loop | local.get, br_if | end (loop), i32.const | end (if), end
+        assert_eq!(ranges, vec![0..0, 0..0, 0..2, 2..4, 4..5, 5..7, 7..9, 9..11]);
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 2, EdgeKind::Fallthrough),
+                (2, 3, EdgeKind::BranchTaken),
+                (2, 4, EdgeKind::BranchNotTaken),
+                (4, 5, EdgeKind::Fallthrough),
+                (5, 5, EdgeKind::LoopBack),
+                (5, 6, EdgeKind::BranchNotTaken),
+                (6, 7, EdgeKind::Fallthrough),
+                (3, 7, EdgeKind::Fallthrough),
+                (7, 1, EdgeKind::Return),
+            ]
+        );
+        assert_eq!(cfg.block_of(6), Some(5));
+        assert_eq!(cfg.block_instrs(5)[1].text, "br_if 0");
+    }
+
+    #[test]
+    fn resolves_tables_returns_and_traps() {
+        let cfg = cfg_of(
+            "(module
+              (func (param i32)
+                block
+                  block
+                    local.get 0
+                    br_table 0 1 0
+                  end
+                  return
+                end
+                unreachable
+                nop))",
+        );
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 2, EdgeKind::Fallthrough),
+                (2, 3, EdgeKind::TableCase(0)),
+                (2, 3, EdgeKind::TableDefault),
+                (3, 1, EdgeKind::Return),
+                (2, 4, EdgeKind::TableCase(1)),
+                (4, 1, EdgeKind::Trap),
+                (5, 1, EdgeKind::Return),
+            ]
+        );
+        // The nop and the body's end can't be reached
+        assert_eq!(cfg.blocks[5].instrs, 8..10);
+        assert!(cfg.predecessors(5).is_empty());
+        assert_eq!(cfg.successors(5), vec![Cfg::EXIT]);
+    }
+
+    #[test]
+    fn branches_on_null_references() {
+        let cfg = cfg_of(
+            "(module
+              (func (param funcref)
+                block
+                  local.get 0
+                  br_on_null 0
+                  drop
+                end))",
+        );
+        assert_eq!(cfg.block_instrs(2)[2].text, "br_on_null 0");
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 2, EdgeKind::Fallthrough),
+                (2, 3, EdgeKind::BranchNotTaken),
+                (3, 4, EdgeKind::Fallthrough),
+                (2, 4, EdgeKind::BranchTaken),
+                (4, 1, EdgeKind::Return),
+            ]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::{fmt, fs::File, io::Write};
+
+use wyrm::analysis::dominance::Dominance;
+use wyrm::callgraph::CallGraph;
+use wyrm::cfg::Cfg;
+use wyrm::emitter::{emit, Emitter, FuncFilter};
+use wyrm::error::WyrmError;
+use wyrm::file_reader::wasm_deserialize;
+use wyrm::json_emitter::JsonEmitter;
+use wyrm::json_model::{wasm_from_json_str, wasm_to_json};
+use wyrm::parse_body;
+use wyrm::listing::{with_offsets, ListingEmitter};
+use wyrm::pseudo_emitter::PseudoEmitter;
+use wyrm::stats::{write_func_stats, write_module_stats};
+use wyrm::validate::{validate, validate_func};
+use wyrm::wasm_model::*;
+use wyrm::wat_emitter::WatEmitter;
+use wyrm::wat_parser::parse_wat;
+
+pub const USAGE: &str = "usage: wyrm <command> <file> [options]
+
+Reads a module from a .wasm binary, a .wat text file or a .json model.
+
+commands:
+  wat          the module in the text format
+  listing      every instruction with its offset and encoded bytes
+  pseudo       function bodies as C-like pseudo-code
+  json         the disassembly as JSON
+  dump         the decoded module
+  parse-tree   the Earley parse of a function's body, as DOT (needs --func)
+  cfg          a function's control-flow graph with dominators and loops, as DOT
+               (needs --func)
+  callgraph    which functions call which, as DOT
+  stats        counts of sections, functions and instructions
+  validate     check the module against the spec's validation rules
+
+options:
+  -f, --func <index|name>  only show this function
+  -o, --output <path>      write to this file instead of stdout
+      --folded             (wat) fold instructions into S-expressions
+      --json               (dump) write the versioned JSON model
+  -h, --help               show this
+";
+
+// Bad arguments exit with 2, anything that goes wrong with the module with 1
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Failed(WyrmError),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            CliError::Failed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\nRun `wyrm help` for usage", message),
+            CliError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<WyrmError> for CliError {
+    fn from(e: WyrmError) -> Self {
+        CliError::Failed(e)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        CliError::Failed(e.into())
+    }
+}
+
+fn usage(message: impl Into<String>) -> CliError {
+    CliError::Usage(message.into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Wat,
+    Listing,
+    Pseudo,
+    Json,
+    Dump,
+    ParseTree,
+    Cfg,
+    CallGraph,
+    Stats,
+    Validate,
+    Help,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "wat" => Command::Wat,
+            "listing" => Command::Listing,
+            "pseudo" => Command::Pseudo,
+            "json" => Command::Json,
+            "dump" => Command::Dump,
+            "parse-tree" => Command::ParseTree,
+            "cfg" => Command::Cfg,
+            "callgraph" => Command::CallGraph,
+            "stats" => Command::Stats,
+            "validate" => Command::Validate,
+            "help" | "-h" | "--help" => Command::Help,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Options {
+    pub command: Command,
+    pub input: String,
+    pub func: Option<String>,
+    pub output: Option<String>,
+    pub folded: bool,
+    pub json: bool,
+}
+
+// `args` doesn't include the program's name
+pub fn parse_args(args: &[String]) -> Result<Options, CliError> {
+    let Some(name) = args.first() else {
+        return Err(usage(format!("No command given\n\n{}", USAGE.trim_end())));
+    };
+    let Some(command) = Command::from_name(name) else {
+        return Err(usage(format!("Unknown command {:?}", name)));
+    };
+    let mut options = Options { command, input: String::new(), func: None, output: None, folded: false, json: false };
+    let mut input = None;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().cloned().ok_or_else(|| usage(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "-h" | "--help" => options.command = Command::Help,
+            "-f" | "--func" => options.func = Some(value()?),
+            "-o" | "--output" => options.output = Some(value()?),
+            "--folded" if command == Command::Wat => options.folded = true,
+            "--json" if command == Command::Dump => options.json = true,
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(usage(format!("Unknown option {:?} for {}", flag, name)));
+            }
+            path if input.is_none() => input = Some(path.to_string()),
+            path => return Err(usage(format!("Unexpected argument {:?}", path))),
+        }
+    }
+    if options.command == Command::Help {
+        return Ok(options);
+    }
+    options.input = input.ok_or_else(|| usage("No input file given"))?;
+    Ok(options)
+}
+
+// Text modules and JSON models are told apart from binaries by their extension
+pub fn load(path: &str) -> Result<WasmFile, WyrmError> {
+    if path.ends_with(".wat") {
+        parse_wat(&std::fs::read_to_string(path)?)
+    } else if path.ends_with(".json") {
+        wasm_from_json_str(&std::fs::read_to_string(path)?)
+    } else {
+        wasm_deserialize(File::open(path)?)
+    }
+}
+
+// A function by its index, its name (with or without the `$`) or the name it's exported as
+pub fn select_func(wasm: &WasmFile, spec: &str) -> Result<u32, CliError> {
+    let num_funcs = wasm.index_space_len(WasmImportType::Func);
+    if let Ok(funcidx) = spec.parse::<u32>() {
+        if funcidx >= num_funcs {
+            return Err(usage(format!("There's no function {}, the module has {}", funcidx, num_funcs)));
+        }
+        return Ok(funcidx);
+    }
+    let names = wasm.name_section.with_unique_ids();
+    let id = if spec.starts_with('$') { spec.to_string() } else { format!("${}", spec) };
+    let named = (0..num_funcs).find(|funcidx| names.func_id(*funcidx) == id);
+    let exported = || {
+        wasm.export_section.exports.iter()
+            .find(|export| export.export_kind == 0 && export.export_name == spec)
+            .map(|export| export.export_signature_index)
+    };
+    named.or_else(exported).ok_or_else(|| usage(format!("There's no function named {:?}", spec)))
+}
+
+// The body of a function, which imports don't have
+fn defined_func(wasm: &WasmFile, funcidx: u32) -> Result<&WasmFunction, CliError> {
+    match wasm.index_origin(WasmImportType::Func, funcidx) {
+        Some(WasmIndexOrigin::Defined(i)) => wasm.code_section.functions.get(i).ok_or_else(|| {
+            CliError::Failed(WyrmError::invalid("Function has no body in the code section").in_func(funcidx))
+        }),
+        _ => Err(usage(format!("Function {} is imported, so it has no body", funcidx))),
+    }
+}
+
+fn emit_selected(wasm: &WasmFile, func: Option<u32>, emitter: &mut dyn Emitter, out: &mut dyn Write) -> Result<(), WyrmError> {
+    match func {
+        Some(funcidx) => emit(wasm, &mut FuncFilter::new(funcidx, emitter), out),
+        None => emit(wasm, emitter, out),
+    }
+}
+
+fn render_parse_tree(func: &WasmFunction, funcidx: u32, mut out: &mut dyn Write) -> Result<(), WyrmError> {
+    let Some(sppf) = parse_body(func) else {
+        return Err(WyrmError::invalid("Body doesn't parse with the instruction grammar").in_func(funcidx));
+    };
+    dot2::render(&sppf.to_tree(), &mut out).map_err(|e| match e {
+        dot2::Error::Io(e) => e.into(),
+        dot2::Error::InvalidId => WyrmError::invalid("Parse tree node with an invalid DOT id").in_func(funcidx),
+    })
+}
+
+// Runs the command into `out`
+pub fn run_command(options: &Options, out: &mut dyn Write) -> Result<(), CliError> {
+    if options.command == Command::Help {
+        write!(out, "{}", USAGE)?;
+        return Ok(());
+    }
+    let wasm = load(&options.input)?;
+    let func = options.func.as_deref().map(|spec| select_func(&wasm, spec)).transpose()?;
+    // Only the call graph can start from an import, everything else shows the function's body
+    if let (Some(funcidx), false) = (func, options.command == Command::CallGraph) {
+        defined_func(&wasm, funcidx)?;
+    }
+    let needs_func = || func.ok_or_else(|| usage(format!("{} needs a function, see --func", options.input)));
+
+    match options.command {
+        Command::Wat => emit_selected(&wasm, func, &mut WatEmitter::new(options.folded), out)?,
+        Command::Listing => {
+            let encoded = with_offsets(&wasm)?;
+            emit_selected(encoded.as_ref().unwrap_or(&wasm), func, &mut ListingEmitter::default(), out)?;
+        }
+        Command::Pseudo => emit_selected(&wasm, func, &mut PseudoEmitter::default(), out)?,
+        Command::Json => emit_selected(&wasm, func, &mut JsonEmitter::default(), out)?,
+        Command::Dump if options.json => {
+            let mut json = wasm_to_json(&wasm)?;
+            if let Some(funcidx) = func {
+                json = json["functions"][(funcidx - wasm.defined_func_idx(0)) as usize].take();
+            }
+            writeln!(out, "{}", serde_json::to_string_pretty(&json).map_err(|e| WyrmError::invalid(e.to_string()))?)?;
+        }
+        Command::Dump => match func {
+            Some(funcidx) => writeln!(out, "{:#?}", defined_func(&wasm, funcidx)?)?,
+            None => writeln!(out, "{:#?}", wasm)?,
+        },
+        Command::ParseTree => {
+            let funcidx = needs_func()?;
+            render_parse_tree(defined_func(&wasm, funcidx)?, funcidx, out)?;
+        }
+        Command::Cfg => {
+            let funcidx = needs_func()?;
+            let cfg = Cfg::new(defined_func(&wasm, funcidx)?);
+            let dominance = Dominance::new(&cfg);
+            let notes: Vec<Vec<String>> = (0..cfg.num_blocks()).map(|block| dominance.describe(block)).collect();
+            cfg.render_dot_with_notes(&notes, out)?;
+        }
+        Command::CallGraph => {
+            let graph = CallGraph::new(&wasm);
+            match func {
+                Some(funcidx) => graph.reachable_from(funcidx).render_dot(out)?,
+                None => graph.render_dot(out)?,
+            }
+        }
+        Command::Stats => match func {
+            Some(funcidx) => write_func_stats(&wasm, funcidx, defined_func(&wasm, funcidx)?, out)?,
+            None => write_module_stats(&wasm, out)?,
+        },
+        Command::Validate => {
+            let errors = match func {
+                Some(funcidx) => validate_func(&wasm, funcidx),
+                None => validate(&wasm),
+            };
+            if !errors.is_empty() {
+                let lines: Vec<String> = errors.iter().map(|e| format!("  {}", e)).collect();
+                return Err(WyrmError::invalid(format!("{} validation error{}:\n{}", errors.len(), if errors.len() == 1 { "" } else { "s" }, lines.join("\n"))).into());
+            }
+            writeln!(out, "valid")?;
+        }
+        Command::Help => {}
+    }
+    Ok(())
+}
+
+// Runs the command and writes what it gives to --output or stdout. Nothing is written to a file
+// unless it was asked for, and not at all when the command fails.
+pub fn run(args: &[String]) -> Result<(), CliError> {
+    let options = parse_args(args)?;
+    let mut buffer = vec![];
+    run_command(&options, &mut buffer)?;
+    if !buffer.is_empty() && !buffer.ends_with(b"\n") {
+        buffer.push(b'\n');
+    }
+    match &options.output {
+        Some(path) => std::fs::write(path, &buffer)?,
+        None => std::io::stdout().lock().write_all(&buffer)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        let options = parse_args(&args("wat in.wasm --folded -f $main -o out.wat")).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                command: Command::Wat,
+                input: "in.wasm".to_string(),
+                func: Some("$main".to_string()),
+                output: Some("out.wat".to_string()),
+                folded: true,
+                json: false,
+            }
+        );
+        assert_eq!(parse_args(&args("dump --help")).unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for bad in ["", "frobnicate in.wasm", "listing", "listing in.wasm --folded", "wat in.wasm -o", "stats a.wasm b.wasm"] {
+            let error = parse_args(&args(bad)).unwrap_err();
+            assert_eq!(error.exit_code(), 2, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn selects_functions_by_index_name_and_export() {
+        let wasm = parse_wat(
+            "(module
+              (import \"env\" \"log\" (func $log))
+              (func $main)
+              (func (export \"run\")))",
+        )
+        .unwrap();
+        assert_eq!(select_func(&wasm, "1").unwrap(), 1);
+        assert_eq!(select_func(&wasm, "$main").unwrap(), 1);
+        assert_eq!(select_func(&wasm, "main").unwrap(), 1);
+        assert_eq!(select_func(&wasm, "run").unwrap(), 2);
+        assert!(select_func(&wasm, "3").is_err());
+        assert!(select_func(&wasm, "missing").is_err());
+        assert!(defined_func(&wasm, 0).is_err());
+    }
+}
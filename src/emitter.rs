@@ -0,0 +1,319 @@
+use std::{cell::Cell, io::Write};
+
+use crate::error::WyrmError;
+use crate::stack_types::{infer_func, FuncTypes};
+use crate::wasm_model::*;
+
+/// The module being emitted, and the ids its parts are given. See WasmNameSection::with_unique_ids.
+#[derive(Clone, Copy)]
+pub struct ModuleContext<'a> {
+    pub wasm: &'a WasmFile,
+    pub names: &'a WasmNameSection,
+}
+
+/// A part of the module outside of the function bodies, with its index where it has one.
+/// Imports are given their index in the space of their kind.
+#[derive(Clone, Copy)]
+pub enum Field<'a> {
+    Type(u32, &'a WasmFunctionType),
+    Import(u32, &'a WasmImportHeader),
+    Table(u32, &'a WasmTable),
+    Memory(u32, &'a WasmMemoryStruct),
+    Tag(u32, &'a WasmTag),
+    Global(u32, &'a WasmGlobal),
+    Export(&'a WasmExportHeader),
+    Start(&'a WasmStartSection),
+    Elem(u32, &'a WasmElem),
+    Data(u32, &'a WasmDataSeg),
+}
+
+/// A defined function, with its funcidx
+#[derive(Clone, Copy)]
+pub struct FuncContext<'a> {
+    pub funcidx: u32,
+    pub func: &'a WasmFunction,
+}
+
+/// An instruction, as the walk reaches it
+pub struct InstrSite<'a> {
+    pub info: InstrInfo,
+    /// The instruction's segments, or the whole block for a block's header and end
+    pub seg: &'a ExprSeg,
+    /// How WAT writes it, e.g. `block $label0 (result i32)` or `end $label0`
+    pub text: String,
+    /// How many blocks it's in. A block's header, else and end are at the depth of the block itself.
+    pub depth: usize,
+    /// Where it is in the file and how it's encoded, for functions read from a binary
+    pub offset: Option<usize>,
+    pub bytes: Vec<u8>,
+    /// How many values it pops and pushes, from the body's stack types. None when that can't be
+    /// worked out, e.g. for GC instructions. Blocks count their params, and an `if` its condition too.
+    pub effect: Option<(usize, usize)>,
+}
+
+/// Hooks for each part of a module, called in the order the text format lays them out.
+/// Backends write straight into `out`, and only need the hooks for the parts they show.
+pub trait Emitter {
+    fn begin_module(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    fn field(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _field: Field<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    fn begin_func(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    fn instr(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    fn begin_block(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    // An `else`, or a try's `catch` or `catch_all`
+    fn else_arm(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    // An `end`, or the `delegate` that ends a try
+    fn end_block(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    // `site` is the `end` that finishes the body
+    fn end_func(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+
+    fn end_module(&mut self, _out: &mut dyn Write, _module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        Ok(())
+    }
+}
+
+/// Walks the whole module, calling the emitter's hooks as it goes
+pub fn emit(wasm: &WasmFile, emitter: &mut dyn Emitter, out: &mut dyn Write) -> Result<(), WyrmError> {
+    let names = wasm.name_section.with_unique_ids();
+    let module = ModuleContext { wasm, names: &names };
+    emitter.begin_module(out, module)?;
+
+    for (i, sig) in (0..).zip(wasm.type_section.function_signatures.iter()) {
+        emitter.field(out, module, Field::Type(i, sig))?;
+    }
+
+    // Imports take the first slots of their index space, in order
+    let mut counts = [0u32; 5];
+    for import in wasm.import_section_header.imports.iter() {
+        let count = &mut counts[match import.import_kind {
+            WasmImportType::Func => 0,
+            WasmImportType::Table => 1,
+            WasmImportType::Mem => 2,
+            WasmImportType::Global => 3,
+            WasmImportType::Tag => 4,
+        }];
+        emitter.field(out, module, Field::Import(*count, import))?;
+        *count += 1;
+    }
+
+    for (i, table) in wasm.table_section.tables.iter().enumerate() {
+        emitter.field(out, module, Field::Table(wasm.defined_table_idx(i), table))?;
+    }
+    for (i, memory) in wasm.memory_section.memories.iter().enumerate() {
+        emitter.field(out, module, Field::Memory(wasm.defined_memory_idx(i), memory))?;
+    }
+    for (i, tag) in wasm.tag_section.tags.iter().enumerate() {
+        emitter.field(out, module, Field::Tag(wasm.defined_tag_idx(i), tag))?;
+    }
+    for (i, global) in wasm.global_section.globals.iter().enumerate() {
+        emitter.field(out, module, Field::Global(wasm.defined_global_idx(i), global))?;
+    }
+    for export in wasm.export_section.exports.iter() {
+        emitter.field(out, module, Field::Export(export))?;
+    }
+    if let Some(start) = &wasm.start_section {
+        emitter.field(out, module, Field::Start(start))?;
+    }
+    for (i, elem) in (0..).zip(wasm.elem_section.elems.iter()) {
+        emitter.field(out, module, Field::Elem(i, elem))?;
+    }
+
+    for (i, func) in wasm.code_section.functions.iter().enumerate() {
+        let func = FuncContext { funcidx: wasm.defined_func_idx(i), func };
+        emitter.begin_func(out, module, func)?;
+        let label_count = Cell::new(0);
+        let types = infer_func(wasm, func.funcidx).ok();
+        let mut walk = BodyWalk { module, func, emitter: &mut *emitter, out: &mut *out, types, next_instr: 0 };
+        walk.walk(&func.func.body, function_emitter(&names, func.funcidx, &label_count), 0, None)
+            .map_err(|e| e.in_func(func.funcidx))?;
+    }
+
+    for (i, data) in (0..).zip(wasm.data_section.data_segs.iter()) {
+        emitter.field(out, module, Field::Data(i, data))?;
+    }
+
+    emitter.end_module(out, module)
+}
+
+/// Emits into a string, for backends that only write text
+pub fn emit_to_string(wasm: &WasmFile, emitter: &mut dyn Emitter) -> Result<String, WyrmError> {
+    let mut out: Vec<u8> = vec![];
+    emit(wasm, emitter, &mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+struct BodyWalk<'a, 'w> {
+    module: ModuleContext<'a>,
+    func: FuncContext<'a>,
+    emitter: &'w mut dyn Emitter,
+    out: &'w mut dyn Write,
+    types: Option<FuncTypes>,
+    // Instructions are counted in the order they're encoded, which is the order of instr_offsets
+    next_instr: usize,
+}
+
+impl<'a> BodyWalk<'a, '_> {
+    fn site(&mut self, info: InstrInfo, seg: &'a ExprSeg, text: String, depth: usize) -> InstrSite<'a> {
+        let offsets = &self.func.func.instr_offsets;
+        let offset = offsets.get(self.next_instr).copied();
+        let bytes = match (offset, offsets.first()) {
+            (Some(start), Some(body_start)) => {
+                // Each instruction runs until the next one starts, and the last one to the end of the body
+                let end = offsets.get(self.next_instr + 1).copied().unwrap_or(body_start + self.func.func.raw_body.len());
+                let raw = &self.func.func.raw_body;
+                raw[(start - body_start).min(raw.len())..(end - body_start).min(raw.len())].iter().map(|byte| *byte as u8).collect()
+            }
+            _ => vec![],
+        };
+        let types = self.types.as_ref().and_then(|types| types.instrs.get(self.next_instr));
+        let effect = types.filter(|types| types.exact).map(|types| (types.popped.len(), types.pushed.len()));
+        self.next_instr += 1;
+        InstrSite { info, seg, text, depth, offset, bytes, effect }
+    }
+
+    // `label` is the enclosing block's label, and None for the function's own body
+    fn walk(&mut self, expr: &'a WasmExpr, state: EmitterState<'_>, depth: usize, label: Option<&str>) -> Result<(), WyrmError> {
+        let module = self.module;
+        for seg in expr.expr_string.iter() {
+            if let Some(info) = block_end(seg) {
+                let end_depth = depth.saturating_sub(1);
+                match label {
+                    // A `delegate` is written with its own label, not the try's
+                    Some(_) if matches!(seg, ExprSeg::Instr(_)) => {
+                        let site = self.site(info, seg, seg.emit_wat("".to_string(), state).trim_end().to_string(), end_depth);
+                        self.emitter.end_block(self.out, module, &site)?;
+                    }
+                    Some(label) => {
+                        let site = self.site(info, seg, format!("{} {}", info.name, label), end_depth);
+                        self.emitter.end_block(self.out, module, &site)?;
+                    }
+                    None => {
+                        let site = self.site(info, seg, info.name.to_string(), end_depth);
+                        self.emitter.end_func(self.out, module, self.func, &site)?;
+                    }
+                }
+                return Ok(());
+            }
+            match seg {
+                ExprSeg::ControlFlow(info, block_type, body, _, catches) => {
+                    let block_label = state.label_id();
+                    let text = block_header_wat(info, &block_label, *block_type, catches, state);
+                    let site = self.site(*info, seg, text, depth);
+                    self.emitter.begin_block(self.out, module, &site)?;
+                    self.walk(body, state.nested(), depth + 1, Some(&block_label))?;
+                }
+                ExprSeg::Instr(instr) => {
+                    let Some(ExprSeg::Operation(info)) = instr.first() else {
+                        continue;
+                    };
+                    if is_arm(seg) {
+                        let site = self.site(*info, seg, seg.emit_wat("".to_string(), state).trim_end().to_string(), depth.saturating_sub(1));
+                        self.emitter.else_arm(self.out, module, &site)?;
+                    } else {
+                        let text = seg.emit_wat("".to_string(), state).trim_end().to_string();
+                        let site = self.site(*info, seg, text, depth);
+                        self.emitter.instr(self.out, module, &site)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes on only one function's hooks, and the module's own begin and end, so a backend can show
+/// a single function
+pub struct FuncFilter<'e> {
+    funcidx: u32,
+    inner: &'e mut dyn Emitter,
+    // Whether the walk is in the function being shown
+    active: bool,
+}
+
+impl<'e> FuncFilter<'e> {
+    pub fn new(funcidx: u32, inner: &'e mut dyn Emitter) -> Self {
+        FuncFilter { funcidx, inner, active: false }
+    }
+}
+
+impl Emitter for FuncFilter<'_> {
+    fn begin_module(&mut self, out: &mut dyn Write, module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        self.inner.begin_module(out, module)
+    }
+
+    fn begin_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>) -> Result<(), WyrmError> {
+        self.active = func.funcidx == self.funcidx;
+        if !self.active {
+            return Ok(());
+        }
+        self.inner.begin_func(out, module, func)
+    }
+
+    fn instr(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.active {
+            return Ok(());
+        }
+        self.inner.instr(out, module, site)
+    }
+
+    fn begin_block(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.active {
+            return Ok(());
+        }
+        self.inner.begin_block(out, module, site)
+    }
+
+    fn else_arm(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.active {
+            return Ok(());
+        }
+        self.inner.else_arm(out, module, site)
+    }
+
+    fn end_block(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.active {
+            return Ok(());
+        }
+        self.inner.end_block(out, module, site)
+    }
+
+    fn end_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !std::mem::take(&mut self.active) {
+            return Ok(());
+        }
+        self.inner.end_func(out, module, func, site)
+    }
+
+    fn end_module(&mut self, out: &mut dyn Write, module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        self.inner.end_module(out, module)
+    }
+}
+
+/// Writes one line, indented by `depth` levels of two spaces
+pub fn write_line(out: &mut dyn Write, depth: usize, text: &str) -> Result<(), WyrmError> {
+    writeln!(out, "{}{}", "  ".repeat(depth), text)?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+/// Where in the module something went wrong. Any of these can be unknown,
+/// e.g. the header isn't in a section and most sections aren't in a function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// Byte offset into the file
+    pub offset: Option<usize>,
+    /// Section id
+    pub section: Option<u8>,
+    /// Function index, counting imported functions
+    pub func: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum WyrmError {
+    // The input ended in the middle of a value
+    UnexpectedEof(ErrorLocation),
+    // The file doesn't start with "\0asm"
+    BadMagic(ErrorLocation, u32),
+    // The opcode (or prefix and sub-opcode) isn't in the instruction table
+    UnknownOpcode(ErrorLocation, u8, Option<u32>),
+    // Something the binary format doesn't allow
+    InvalidData(ErrorLocation, String),
+    // Valid WASM that we can't handle yet
+    Unsupported(ErrorLocation, String),
+    Io(ErrorLocation, io::Error),
+}
+
+pub fn section_name(section: u8) -> &'static str {
+    match section {
+        0x00 => "custom",
+        0x01 => "type",
+        0x02 => "import",
+        0x03 => "function",
+        0x04 => "table",
+        0x05 => "memory",
+        0x06 => "global",
+        0x07 => "export",
+        0x08 => "start",
+        0x09 => "element",
+        0x0a => "code",
+        0x0b => "data",
+        0x0c => "data count",
+        0x0d => "tag",
+        _ => "unknown",
+    }
+}
+
+impl WyrmError {
+    pub fn invalid(message: impl Into<String>) -> Self {
+        WyrmError::InvalidData(ErrorLocation::default(), message.into())
+    }
+
+    pub fn unsupported(message: impl Into<String>) -> Self {
+        WyrmError::Unsupported(ErrorLocation::default(), message.into())
+    }
+
+    pub fn location(&self) -> &ErrorLocation {
+        match self {
+            WyrmError::UnexpectedEof(location)
+            | WyrmError::BadMagic(location, _)
+            | WyrmError::UnknownOpcode(location, _, _)
+            | WyrmError::InvalidData(location, _)
+            | WyrmError::Unsupported(location, _)
+            | WyrmError::Io(location, _) => location,
+        }
+    }
+
+    fn location_mut(&mut self) -> &mut ErrorLocation {
+        match self {
+            WyrmError::UnexpectedEof(location)
+            | WyrmError::BadMagic(location, _)
+            | WyrmError::UnknownOpcode(location, _, _)
+            | WyrmError::InvalidData(location, _)
+            | WyrmError::Unsupported(location, _)
+            | WyrmError::Io(location, _) => location,
+        }
+    }
+
+    /// Fills in whatever parts of the location aren't known yet, so errors
+    /// can pick up context as they travel up from where they were made
+    pub fn or_at(mut self, at: ErrorLocation) -> Self {
+        let location = self.location_mut();
+        location.offset = location.offset.or(at.offset);
+        location.section = location.section.or(at.section);
+        location.func = location.func.or(at.func);
+        self
+    }
+
+    pub fn in_section(self, section: u8) -> Self {
+        self.or_at(ErrorLocation { section: Some(section), ..Default::default() })
+    }
+
+    pub fn in_func(self, func: u32) -> Self {
+        self.or_at(ErrorLocation { func: Some(func), ..Default::default() })
+    }
+}
+
+impl Display for ErrorLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {:#x}", offset)?;
+        }
+        if let Some(section) = self.section {
+            write!(f, " in {} section", section_name(section))?;
+        }
+        if let Some(func) = self.func {
+            write!(f, " (function {})", func)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for WyrmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WyrmError::UnexpectedEof(location) => write!(f, "unexpected end of file{}", location),
+            WyrmError::BadMagic(location, magic) => {
+                write!(f, "not a WASM module, magic number was {:#010x}{}", magic, location)
+            }
+            WyrmError::UnknownOpcode(location, opcode, None) => {
+                write!(f, "unknown opcode {:#04x}{}", opcode, location)
+            }
+            WyrmError::UnknownOpcode(location, prefix, Some(opcode)) => {
+                write!(f, "unknown opcode {:#04x} {:#x}{}", prefix, opcode, location)
+            }
+            WyrmError::InvalidData(location, message) => write!(f, "{}{}", message, location),
+            WyrmError::Unsupported(location, message) => write!(f, "unsupported: {}{}", message, location),
+            WyrmError::Io(location, error) => write!(f, "{}{}", error, location),
+        }
+    }
+}
+
+impl Error for WyrmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WyrmError::Io(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WyrmError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            WyrmError::UnexpectedEof(ErrorLocation::default())
+        } else {
+            WyrmError::Io(ErrorLocation::default(), error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_innermost_location() {
+        let error = WyrmError::invalid("Bad thing")
+            .or_at(ErrorLocation { offset: Some(0x20), ..Default::default() })
+            .in_func(3)
+            .or_at(ErrorLocation { offset: Some(0x10), section: Some(0x0a), func: Some(7) });
+        assert_eq!(*error.location(), ErrorLocation { offset: Some(0x20), section: Some(0x0a), func: Some(3) });
+        assert_eq!(error.to_string(), "Bad thing at offset 0x20 in code section (function 3)");
+    }
+
+    #[test]
+    fn maps_short_reads_to_eof() {
+        let error = WyrmError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(error, WyrmError::UnexpectedEof(_)));
+        assert_eq!(error.to_string(), "unexpected end of file");
+    }
+}
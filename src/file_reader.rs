@@ -1,17 +1,15 @@
-use std::{
-    fmt::Debug,
-    io::{Error, Read, ErrorKind},
-    mem,
-};
+use std::io::Read;
 
+use crate::binary_reader::BinaryReader;
+use crate::error::*;
 use crate::wasm_model::*;
 use crate::instr_table::*;
 
 
-fn read_global<T: Read + Debug>(state: &mut WasmDeserializeState<T>) -> Result<WasmGlobal, Error> {
+fn read_global(state: &mut WasmDeserializeState) -> Result<WasmGlobal, WyrmError> {
     
-    let wasm_type = state.read_sized(WasmTypeAnnotation { _type: 0 })?;
-    let mutability = state.read_sized(0)?;
+    let wasm_type = WasmTypeAnnotation { _type: state.read_value_type()? };
+    let mutability = state.read_u8()?;
     let expr = state.read_expr()?.0;
 
     Ok(WasmGlobal {
@@ -21,153 +19,162 @@ fn read_global<T: Read + Debug>(state: &mut WasmDeserializeState<T>) -> Result<W
     })
 }
 
+// Expanded locals, alongside the (type, count) pairs they were declared with
+type LocalDeclarations = (Vec<WasmLocal>, Vec<(u8, usize)>);
+
 #[derive(Debug)]
-struct WasmDeserializeState<T: Read + Debug> {
-    buffer: T,
-    raw_section: Vec<u8>,
-    save_read: bool,
+struct WasmDeserializeState<'a> {
+    reader: BinaryReader<'a>,
+    // Where we are, so errors can say where they happened
+    section: Option<u8>,
+    func: Option<u32>,
+    num_imported_funcs: usize,
 }
 
-impl<T: Read + Debug> WasmDeserializeState<T> {
+impl<'a> WasmDeserializeState<'a> {
 
-    fn start_raw_section(&mut self) {
-        self.raw_section.clear();
-        self.save_read = true;
+    fn new(reader: BinaryReader<'a>) -> Self {
+        Self { reader, section: None, func: None, num_imported_funcs: 0 }
     }
 
-    
-    fn end_raw_section(&mut self) {
-        self.save_read = false;
-    }
-
-    // Read a value of known size
-    fn read_sized<U: Sized + Clone>(&mut self, default: U) -> Result<U, Error> {
-        let mut out: U = default.clone();
-        // UNSAFE: This is used to make sure any data-type with a defined size is read
-        // in the same way. The reasoning for unsafe is that this project is designed to crash on an error
-        // anyway due to its purpose as a cli decompiler back-end.
-        // This may be converted into something that checks for safety later if it is decided
-        // that I want to use the same engine in another, less crash-oblivious program.
-        unsafe {
-            let buffer: &mut [u8] =
-                std::slice::from_raw_parts_mut((&mut out as *mut U).cast(), mem::size_of::<U>());
-            self.buffer.read_exact(buffer)?;
-            if self.save_read {
-                self.raw_section.append(buffer.to_vec().as_mut());
-            }
-        }
-
-        return Ok(out);
+    fn location(&self) -> ErrorLocation {
+        ErrorLocation { offset: Some(self.reader.offset()), section: self.section, func: self.func }
     }
 
-    // Read an int of dynamic size. See arcane_knowledge.md/#unsized_values for more information
-    fn read_dynamic_uint(&mut self, default: usize) -> Result<usize, Error> {
-        let mut out: usize = default.clone();
-        let mut buffer: [u8; 1] = [0];
+    // Attaches the current location to an error made somewhere that doesn't know it
+    fn locate(&self, error: WyrmError) -> WyrmError {
+        error.or_at(self.location())
+    }
 
-        self.buffer.read_exact(&mut buffer)?;
-        
-        if self.save_read {
-            self.raw_section.append(buffer.to_vec().as_mut());
-        }
+    fn invalid(&self, message: impl Into<String>) -> WyrmError {
+        self.locate(WyrmError::invalid(message))
+    }
 
-        out += (buffer[0] & 0x7F) as usize;
-        let mut bits = 7;
-        while buffer[0] & 0x80 != 0 && bits < 64 {
-            self.buffer.read_exact(&mut buffer)?;
-        
-            if self.save_read {
-                self.raw_section.append(buffer.to_vec().as_mut());
-            }
+    fn read_u8(&mut self) -> Result<u8, WyrmError> {
+        self.reader.read_u8().map_err(|e| self.locate(e))
+    }
 
-            out += ((buffer[0] & 0x7F & (!(1 << std::cmp::min(64 - bits, 7)))) as usize) << (7 * (bits / 7));
-            bits += 7;
-        }
+    fn read_u32(&mut self) -> Result<u32, WyrmError> {
+        self.reader.read_u32().map_err(|e| self.locate(e))
+    }
 
-        return Ok(out);
+    // Sizes, counts and indices are all u32 in the binary format
+    fn read_var_u32(&mut self) -> Result<usize, WyrmError> {
+        self.reader.read_var_u32().map(|x| x as usize).map_err(|e| self.locate(e))
     }
 
-    fn read_dynamic_int(&mut self, default: usize) -> Result<i64, Error> {
-        let mut out: usize = default.clone();
-        let mut buffer: [u8; 1] = [0];
+    // An index kept at the width the binary format gives it
+    fn read_index(&mut self) -> Result<u32, WyrmError> {
+        self.reader.read_var_u32().map_err(|e| self.locate(e))
+    }
 
-        self.buffer.read_exact(&mut buffer)?;
-        
-        if self.save_read {
-            self.raw_section.append(buffer.to_vec().as_mut());
-        }
+    fn read_var_i32(&mut self) -> Result<i64, WyrmError> {
+        self.reader.read_var_i32().map(|x| x as i64).map_err(|e| self.locate(e))
+    }
 
-        out += (buffer[0] & 0x7F) as usize;
-        let mut bits = 7;
-        while buffer[0] & 0x80 != 0 && bits < 64 {
-            self.buffer.read_exact(&mut buffer)?;
+    fn read_var_i64(&mut self) -> Result<i64, WyrmError> {
+        self.reader.read_var_i64().map_err(|e| self.locate(e))
+    }
 
-            out += ((buffer[0] & 0x7F & (std::cmp::min(64 - bits, 7))) as usize) << (7 * (bits / 7));
-            bits += 7;
-            
-            if self.save_read {
-                self.raw_section.append(buffer.to_vec().as_mut());
-            }
+    fn read_var_s33(&mut self) -> Result<i64, WyrmError> {
+        self.reader.read_var_s33().map_err(|e| self.locate(e))
+    }
 
+    // Value types are kept as single bytes, so the GC proposal's typed references, which are
+    // followed by a heap type, can't be read
+    fn read_value_type(&mut self) -> Result<u8, WyrmError> {
+        match self.read_u8()? {
+            byte @ (0x63 | 0x64) => Err(self.invalid(format!("Typed reference types ({:#x}) aren't supported", byte))),
+            byte => Ok(byte),
         }
-        
-        if buffer[0] & 0x80 != 0 {
-            return Err(Error::new(ErrorKind::InvalidData, "what"));
-        }
+    }
 
-        if buffer[0] & 0x40 != 0  && bits < 64{
-            return Ok((out | (!0 << bits)) as i64);
-        }
+    // With multi-memory, bit 6 of the alignment says a memory index comes before the offset
+    fn read_memarg(&mut self) -> Result<MemArg, WyrmError> {
+        let flags = self.reader.read_var_u32().map_err(|e| self.locate(e))?;
+        let (align, memory) = match flags {
+            0..=0x3f => (flags, 0),
+            0x40..=0x7f => (flags - 0x40, self.read_index()?),
+            _ => return Err(self.invalid(format!("Bad memarg alignment {:#x}", flags))),
+        };
+        let offset = self.reader.read_var_u64().map_err(|e| self.locate(e))?;
+        Ok(MemArg { align, offset, memory })
+    }
 
-        return Ok(out as i64);
+    // Read a run of raw bytes, such as the payload of a section
+    fn read_bytes(&mut self, num_bytes: usize) -> Result<Vec<u8>, WyrmError> {
+        self.reader.read_bytes(num_bytes).map(|x| x.to_vec()).map_err(|e| self.locate(e))
+    }
 
+    // A state for the next `num_bytes` bytes, such as a custom section's payload
+    fn sub_state(&mut self, num_bytes: usize) -> Result<WasmDeserializeState<'a>, WyrmError> {
+        let reader = self.reader.sub_reader(num_bytes).map_err(|e| self.locate(e))?;
+        Ok(WasmDeserializeState { reader, ..*self })
     }
 
+    fn read_name(&mut self) -> Result<String, WyrmError> {
+        self.reader.read_name().map(str::to_string).map_err(|e| self.locate(e))
+    }
 
-    // Read in a vector of objects of which we know the size
-    fn read_vector<U: Sized + Clone>(
-        &mut self,
-        default: U,
-        num_elements: usize,
-    ) -> Result<Vec<U>, Error> {
-        let mut out = Vec::<U>::new();
+    fn read_value_types(&mut self, num_elements: usize) -> Result<Vec<WasmTypeAnnotation>, WyrmError> {
+        let mut out = Vec::new();
         for _ in 0..num_elements {
-            out.push(self.read_sized(default.clone())?);
+            out.push(WasmTypeAnnotation { _type: self.read_value_type()? });
         }
-        return Ok(out);
+        Ok(out)
     }
 
-    // Read in a vector of objects of which we know the size
+    // Read in a vector of LEB128 indices
     fn read_vector_dynamic(
         &mut self,
         num_elements: usize,
-    ) -> Result<Vec<usize>, Error> {
-        let mut out = Vec::<usize>::new();
+    ) -> Result<Vec<u32>, WyrmError> {
+        let mut out = Vec::<u32>::new();
         for _ in 0..num_elements {
-            out.push(self.read_dynamic_uint(0)?);
+            out.push(self.read_index()?);
         }
-        return Ok(out);
+        Ok(out)
     }
 
-    fn read_expr(&mut self) -> Result<(WasmExpr, Vec<u8>), Error>  {
+    fn read_expr(&mut self) -> Result<(WasmExpr, Vec<u8>), WyrmError>  {
+        let (expr, raw, _) = self.read_expr_with_offsets()?;
+        Ok((expr, raw))
+    }
 
-        self.start_raw_section();
+    // Also gives where each instruction starts in the file, in the order they are read
+    fn read_expr_with_offsets(&mut self) -> Result<(WasmExpr, Vec<u8>, Vec<usize>), WyrmError>  {
+
+        let start = self.reader.offset();
+        let mut offsets: Vec<usize> = vec![];
 
         let mut scope: Vec<Box<WasmExpr>> = vec![];
         let mut last_scope = WasmExpr::new_box();
         let mut expr_box = WasmExpr::new_box();
         let mut level: i32 = 0;
-        let mut control_flow: Vec<InstrInfo> = Vec::new();
-        while let Ok(byte) = self.read_sized::<u8>(0) {
-            let info = INSTRS[byte as usize];
+        let mut control_flow: Vec<(InstrInfo, i64, Vec<CatchClause>)> = Vec::new();
+        loop {
+            let start = self.reader.offset();
+            offsets.push(start);
+            let byte = self.read_u8()?;
+            // Unknown opcodes are reported where the instruction starts, not where reading stopped
+            let at_start = ErrorLocation { offset: Some(start), ..Default::default() };
+            let info = if is_prefix(byte) {
+                let opcode = self.read_var_u32()? as u32;
+                match get_prefixed_instr(byte, opcode) {
+                    Some(info) => info,
+                    None => return Err(self.locate(WyrmError::UnknownOpcode(at_start, byte, Some(opcode)))),
+                }
+            } else {
+                INSTRS[byte as usize]
+            };
             let expr = &mut expr_box.expr_string;
             let mut instr_layout = vec![ExprSeg::Operation(info)];
             let special_case = get_edge_case(info);
             
             if special_case == SpecialInstr::BrTable {
-                let num = self.read_dynamic_uint(0)?;
+                let num = self.read_var_u32()?;
                 let break_depths = self.read_vector_dynamic(num)?;
-                let default = self.read_dynamic_uint(0)?;
+                let default = self.read_index()?;
                 instr_layout.push(ExprSeg::BrTable(BrTableConst {
                     break_depths,
                     default
@@ -177,48 +184,107 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
             }
 
             if special_case == SpecialInstr::CallIndirect {
-                instr_layout.push(ExprSeg::Int(self.read_dynamic_int(0)?));
-                instr_layout.push(ExprSeg::Int(self.read_dynamic_int(0)?));
+                instr_layout.push(ExprSeg::Int(self.read_var_u32()? as i64));
+                instr_layout.push(ExprSeg::Int(self.read_var_u32()? as i64));
                 expr.push(ExprSeg::Instr(instr_layout));
                 continue;
             }
 
-            if info.name == "" {
-                println!("instruction not supported {:#x}", info.instr);
-                todo!()
+            if special_case == SpecialInstr::SelectTyped {
+                let num = self.read_var_u32()?;
+                for _ in 0..num {
+                    instr_layout.push(ExprSeg::Int(self.read_value_type()? as i64));
+                }
+                expr.push(ExprSeg::Instr(instr_layout));
+                continue;
             }
-            
-            if info.takes_align {
-                self.read_sized::<u8>(0)?;
+
+            if special_case == SpecialInstr::BrOnCast {
+                // The flags byte says which of the two heap types are nullable
+                let flags = self.read_u8()?;
+                instr_layout.push(ExprSeg::Int(self.read_var_u32()? as i64));
+                instr_layout.push(ExprSeg::RefType { nullable: flags & 1 != 0, heap: self.read_var_s33()? });
+                instr_layout.push(ExprSeg::RefType { nullable: flags & 2 != 0, heap: self.read_var_s33()? });
+                expr.push(ExprSeg::Instr(instr_layout));
+                continue;
+            }
+
+            if info.name.is_empty() {
+                return Err(self.locate(WyrmError::UnknownOpcode(at_start, byte, None)));
             }
             
             for constant in info.constants {
                 match constant {
                     Prim::F32 => {
-                        instr_layout.push(ExprSeg::Float32(self.read_sized(0.0)?));
+                        instr_layout.push(ExprSeg::Float32(self.reader.read_f32().map_err(|e| self.locate(e))?));
                     }
                     Prim::F64 => {
-                        instr_layout.push(ExprSeg::Float64(self.read_sized(0.0)?));
+                        instr_layout.push(ExprSeg::Float64(self.reader.read_f64().map_err(|e| self.locate(e))?));
                     }
                     Prim::Global => {
-                        let num = self.read_dynamic_uint(0)?;
-                        instr_layout.push(ExprSeg::Local(num));
+                        let num = self.read_index()?;
+                        instr_layout.push(ExprSeg::Global(num));
                     }
                     Prim::Local => {
-                        let num = self.read_dynamic_uint(0)?;
-                        instr_layout.push(ExprSeg::Global(num));
+                        let num = self.read_index()?;
+                        instr_layout.push(ExprSeg::Local(num));
                     }
                     Prim::Func => {
-                        let num = self.read_dynamic_uint(0)?;
+                        let num = self.read_index()?;
                         instr_layout.push(ExprSeg::Func(num));
                     }
+                    Prim::V128 => {
+                        instr_layout.push(ExprSeg::V128(self.reader.read_u128().map_err(|e| self.locate(e))?));
+                    }
+                    Prim::Lane => {
+                        instr_layout.push(ExprSeg::Int(self.read_u8()? as i64));
+                    }
+                    Prim::Table => {
+                        instr_layout.push(ExprSeg::Table(self.read_index()?));
+                    }
+                    Prim::Memory => {
+                        instr_layout.push(ExprSeg::Memory(self.read_index()?));
+                    }
+                    Prim::Type => {
+                        instr_layout.push(ExprSeg::Type(self.read_index()?));
+                    }
+                    Prim::Data => {
+                        instr_layout.push(ExprSeg::Data(self.read_index()?));
+                    }
+                    Prim::Tag => {
+                        instr_layout.push(ExprSeg::Tag(self.read_index()?));
+                    }
+                    Prim::Elem => {
+                        instr_layout.push(ExprSeg::Elem(self.read_index()?));
+                    }
+                    Prim::HeapType => {
+                        instr_layout.push(ExprSeg::HeapType(self.read_var_s33()?));
+                    }
+                    Prim::RefType | Prim::NullRefType => {
+                        let nullable = *constant == Prim::NullRefType;
+                        instr_layout.push(ExprSeg::RefType { nullable, heap: self.read_var_s33()? });
+                    }
+                    Prim::BlockType => {
+                        let block_type = self.read_var_s33()?;
+                        // 0x63 and 0x64 as s33, the typed references
+                        if block_type == -0x1d || block_type == -0x1c {
+                            return Err(self.invalid(format!("Typed reference types ({:#x}) aren't supported", block_type + 0x80)));
+                        }
+                        instr_layout.push(ExprSeg::BlockType(block_type));
+                    }
+                    Prim::MemArg => {
+                        instr_layout.push(ExprSeg::MemArg(self.read_memarg()?));
+                    }
                     Prim::Void => {
                         // void or align
-                        let _num = self.read_sized::<u8>(0)?;
+                        let _num = self.read_u8()?;
+                    }
+                    Prim::I64 => {
+                        instr_layout.push(ExprSeg::Int(self.read_var_i64()?));
                     }
                     // Number
                     _ => {
-                        let num = self.read_dynamic_int(0)?;
+                        let num = self.read_var_i32()?;
                         instr_layout.push(ExprSeg::Int(num));
                     }
                 }
@@ -227,7 +293,8 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
 
             // Control flow is special when it comes to being an "instruction"
             if special_case == SpecialInstr::BeginBlock {
-                control_flow.push(info);
+                let catches = if info.instr == TRY_TABLE as u32 { self.read_catch_clauses()? } else { vec![] };
+                control_flow.push((info, block_type(&instr_layout), catches));
                 level += 1;
                 // Push the scope
                 scope.push(last_scope);
@@ -238,32 +305,55 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
             }
 
             if special_case == SpecialInstr::EndBlock {
+                // A delegate keeps its label, and can only finish a try
+                let is_delegate = instr_layout.len() > 1;
+                if is_delegate && control_flow.last().is_none_or(|(block, ..)| block.instr != TRY as u32) {
+                    return Err(self.invalid("delegate outside of a try"));
+                }
                 // TODO: This is dirty, change later
-                expr.push(ExprSeg::Operation(info));
+                expr.push(if is_delegate { ExprSeg::Instr(instr_layout) } else { ExprSeg::Operation(info) });
                 level -= 1;
                 if level < 0 {
                     break;
                 }
                 // pop the scope
-                let control_flow_context = control_flow.pop().unwrap();
-                last_scope.expr_string.push(ExprSeg::ControlFlow(control_flow_context, expr_box, info));
+                let (Some((control_flow_context, block_type, catches)), Some(outer_scope)) = (control_flow.pop(), scope.pop()) else {
+                    return Err(self.invalid("end without a matching block"));
+                };
+                last_scope.expr_string.push(ExprSeg::ControlFlow(control_flow_context, block_type, expr_box, info, catches));
                 expr_box = last_scope;
-                last_scope = scope.pop().unwrap();
+                last_scope = outer_scope;
                 continue;
             }
             expr.push(ExprSeg::Instr(instr_layout));
         }
-        self.end_raw_section();
-        Ok((*expr_box, self.raw_section.clone()))
+        Ok((*expr_box, self.reader.bytes_since(start).to_vec(), offsets))
+
+    }
 
+    // A try_table's handlers, each a kind, the tag for the kinds that have one, and a label
+    fn read_catch_clauses(&mut self) -> Result<Vec<CatchClause>, WyrmError> {
+        let num_catches = self.read_var_u32()?;
+        let mut catches = Vec::new();
+        for _ in 0..num_catches {
+            let catch = match self.read_u8()? {
+                0x00 => CatchClause::Catch { tag: self.read_index()?, label: self.read_index()? },
+                0x01 => CatchClause::CatchRef { tag: self.read_index()?, label: self.read_index()? },
+                0x02 => CatchClause::CatchAll { label: self.read_index()? },
+                0x03 => CatchClause::CatchAllRef { label: self.read_index()? },
+                kind => return Err(self.invalid(format!("Bad catch clause kind {:#x}", kind))),
+            };
+            catches.push(catch);
+        }
+        Ok(catches)
     }
 
-    fn read_type_section(&mut self) -> Result<WasmTypeSection, Error> {
+    fn read_type_section(&mut self) -> Result<WasmTypeSection, WyrmError> {
     
         // A section that describes the type signature of functions
         let mut type_section: WasmTypeSection = WasmTypeSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_types: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_types: self.read_var_u32()?,
             function_signatures: Vec::new()
         };
     
@@ -275,110 +365,147 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
                 num_results: 0,
                 results: Vec::new(),
             };
-            sig.func = self.read_sized::<u8>(0)?;
-            assert_eq!(sig.func, 0x60, "Function format was incorrect");
+            sig.func = self.read_u8()?;
+            match sig.func {
+                0x60 => {}
+                // rec, sub, sub final, struct and array
+                0x4e | 0x50 | 0x4f | 0x5f | 0x5e => {
+                    return Err(self.invalid(format!("GC type definitions ({:#x}) aren't supported, only function types", sig.func)));
+                }
+                _ => return Err(self.invalid(format!("Expected function type 0x60, found {:#x}", sig.func))),
+            }
     
-            sig.num_params = self.read_dynamic_uint(0)?;
-            sig.params = self.read_vector(WasmTypeAnnotation { _type: 0 }, sig.num_params)?;
+            sig.num_params = self.read_var_u32()?;
+            sig.params = self.read_value_types(sig.num_params)?;
     
-            sig.num_results = self.read_dynamic_uint(0)?;
-            sig.results = self.read_vector(WasmTypeAnnotation { _type: 0 }, sig.num_results)?;
+            sig.num_results = self.read_var_u32()?;
+            sig.results = self.read_value_types(sig.num_results)?;
             type_section.function_signatures.push(sig)
         }
         Ok(type_section)
     }
 
-    fn read_import_section(&mut self) -> Result<WasmImportSection, Error> {
+    fn read_import_section(&mut self) -> Result<WasmImportSection, WyrmError> {
         // A section containing a description of things imported from other sources.
         // Each import header has a name and a signature index
         let mut import_section_header: WasmImportSection = WasmImportSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_imports: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_imports: self.read_var_u32()?,
             imports: Vec::new()
         };
 
         for _ in 0..import_section_header.num_imports {
-            let mut import: WasmImportHeader = WasmImportHeader {
-                mod_name_length: 0,
-                import_module_name: Vec::new(),
-                import_field_len: 0,
-                import_field: Vec::new(),
-                import_kind: WasmImportType::Global,
-                import_type: 0,
+            let import_module_name = self.read_name()?;
+            let import_field = self.read_name()?;
+            let import_kind = num_to_import_type(self.read_u8()?).map_err(|e| self.locate(e))?;
+            let desc = match import_kind {
+                WasmImportType::Func => WasmImportDesc::Func(self.read_index()?),
+                WasmImportType::Table => WasmImportDesc::Table(self.read_table_type()?),
+                WasmImportType::Mem => WasmImportDesc::Mem(self.read_memory_type()?),
+                WasmImportType::Global => {
+                    let wasm_type = WasmTypeAnnotation { _type: self.read_value_type()? };
+                    WasmImportDesc::Global { wasm_type, mutability: self.read_u8()? }
+                }
+                WasmImportType::Tag => WasmImportDesc::Tag(self.read_tag_type()?),
             };
-            import.mod_name_length = self.read_dynamic_uint(0)?;
-            import.import_module_name = self.read_vector(0, import.mod_name_length)?;
-            import.import_field_len = self.read_dynamic_uint(0)?;
-            import.import_field = self.read_vector(0, import.import_field_len)?;
-            import.import_kind = num_to_import_type(self.read_sized(0)?);
-            import.import_type = self.read_sized(0)?;
-            import_section_header.imports.push(import);
+            import_section_header.imports.push(WasmImportHeader {
+                mod_name_length: import_module_name.len(),
+                import_module_name,
+                import_field_len: import_field.len(),
+                import_field,
+                import_kind,
+                desc,
+            });
         }
         Ok(import_section_header)
     }
 
-    fn read_function_section(&mut self) -> Result<WasmFunctionSection, Error> { 
+    // Bit 0 of the flags says there's a maximum, bit 1 that it's shared
+    // and bit 2 that it's 64-bit, in which case the limits are u64
+    fn read_limits(&mut self) -> Result<Limits, WyrmError> {
+        let flags = self.read_u8()?;
+        if flags > 7 {
+            return Err(self.invalid(format!("Invalid limits flags {:#x}", flags)));
+        }
+        let is_64 = flags & 4 != 0;
+        let mut read_limit = || if is_64 {
+            self.reader.read_var_u64().map_err(|e| e.or_at(self.location()))
+        } else {
+            self.reader.read_var_u32().map(|x| x as u64).map_err(|e| e.or_at(self.location()))
+        };
+        let initial = read_limit()?;
+        let max = if flags & 1 != 0 { Some(read_limit()?) } else { None };
+        Ok(Limits { initial, max, shared: flags & 2 != 0, is_64 })
+    }
+
+    fn read_table_type(&mut self) -> Result<WasmTable, WyrmError> {
+        let wasm_type = self.read_value_type()?;
+        let limits = self.read_limits()?;
+        if limits.shared {
+            return Err(self.invalid("Tables can't be shared"));
+        }
+        Ok(WasmTable { wasm_type, limits })
+    }
+
+    fn read_memory_type(&mut self) -> Result<WasmMemoryStruct, WyrmError> {
+        Ok(WasmMemoryStruct { limits: self.read_limits()? })
+    }
+
+    fn read_tag_type(&mut self) -> Result<WasmTag, WyrmError> {
+        let attribute = self.read_u8()?;
+        if attribute != 0 {
+            return Err(self.invalid(format!("Tag had invalid attribute {}", attribute)));
+        }
+        let type_index = self.read_index()?;
+        Ok(WasmTag { attribute, type_index })
+    }
+
+    fn read_function_section(&mut self) -> Result<WasmFunctionSection, WyrmError> { 
         let mut function_section: WasmFunctionSection = WasmFunctionSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_functions: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_functions: self.read_var_u32()?,
             function_signature_indexes: Vec::new()
         };
         for _ in 0..function_section.num_functions {
-            function_section.function_signature_indexes.push(self.read_sized(0)?);
+            function_section.function_signature_indexes.push(self.read_index()?);
         }
         Ok(function_section)
     }
 
-    fn read_table_section(&mut self) -> Result<WasmTableSection, Error> { 
+    fn read_table_section(&mut self) -> Result<WasmTableSection, WyrmError> { 
         
         let mut table_section: WasmTableSection = WasmTableSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_tables: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_tables: self.read_var_u32()?,
             tables: Vec::new()
         };
 
         for _ in 0..table_section.num_tables {
-            let mut table: WasmTable = WasmTable {
-                wasm_type: 0,
-                limits_flags: 0,
-                limits_initial: 0,
-                limits_max: 0,
-            };
-            table.wasm_type = self.read_sized::<u8>(0)?;
-            table.limits_flags = self.read_sized::<u8>(0)?;
-            table.limits_initial = self.read_dynamic_uint(0)?;
-            table.limits_max = self.read_dynamic_uint(0)?;
+            let table = self.read_table_type()?;
             table_section.tables.push(table);
         }
 
         Ok(table_section)
     }
     
-    fn read_memory_section(&mut self) -> Result<WasmMemorySection, Error> { 
+    fn read_memory_section(&mut self) -> Result<WasmMemorySection, WyrmError> { 
         let mut memory_section: WasmMemorySection = WasmMemorySection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_memories: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_memories: self.read_var_u32()?,
             memories: Vec::new()
         };
     
         for _ in 0..memory_section.num_memories {
-            let mut memory: WasmMemoryStruct = WasmMemoryStruct {
-                limits_flags: 0,
-                limits_initial: 0,
-                limits_max: 0,
-            };
-            memory.limits_flags = self.read_sized::<u8>(0)?;
-            memory.limits_initial = self.read_dynamic_uint(0)?;
-            memory.limits_max = self.read_dynamic_uint(0)?;
+            let memory = self.read_memory_type()?;
             memory_section.memories.push(memory);
         }
         Ok(memory_section)
     }
     
-    fn read_global_section(&mut self) -> Result<WasmGlobalSection, Error> {     
+    fn read_global_section(&mut self) -> Result<WasmGlobalSection, WyrmError> {     
         let mut global_section = WasmGlobalSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_globals: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_globals: self.read_var_u32()?,
             globals: Vec::new()
         };
 
@@ -388,123 +515,94 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
         }
         Ok(global_section)
     }
-    fn read_export_section(&mut self) -> Result<WasmExportSection, Error> { 
+    fn read_export_section(&mut self) -> Result<WasmExportSection, WyrmError> { 
         
         let mut export_section: WasmExportSection = WasmExportSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_exports: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_exports: self.read_var_u32()?,
             exports: Vec::new()
         };
 
         for _ in 0..export_section.num_exports {
             let mut export: WasmExportHeader = WasmExportHeader {
                 export_name_len: 0,
-                export_name: Vec::new(),
+                export_name: String::new(),
                 export_kind: 0,
                 export_signature_index: 0,
             };
-            export.export_name_len = self.read_dynamic_uint(0)?;
-            export.export_name = self.read_vector(0, export.export_name_len)?;
-            export.export_kind = self.read_sized(0)?;
-            export.export_signature_index = self.read_sized(0)?;
+            export.export_name = self.read_name()?;
+            export.export_name_len = export.export_name.len();
+            export.export_kind = self.read_u8()?;
+            export.export_signature_index = self.read_index()?;
             export_section.exports.push(export);
         }
         Ok(export_section)
     }
 
-    fn create_elem_expr(ys: Vec<usize>) -> WasmExpr {
-        let expr = ys.iter().flat_map(|y| {
-            vec![
-                ExprSeg::Operation(get_instr("ref.func").unwrap()),
-                ExprSeg::Func(*y as usize),
-                ExprSeg::Operation(get_instr("end").unwrap()),
-            ]
-        }).collect();
-
-        WasmExpr {expr_string: expr}
-    }
-
-    fn read_elem(&mut self) -> Result<WasmElem, Error> {
-        let id = self.read_sized::<u8>(0)? as u32;
-        let _type: WasmRefType;
-        let mut _init: WasmExpr;
-        let _mode: WasmElemMode;
-        match id {
-            0 => {
-                let e = self.read_expr()?.0;
-                let y_size = self.read_dynamic_uint(0)?;
-                let ys = self.read_vector_dynamic(y_size)?;
-                Ok(WasmElem{
-                    mode: WasmElemMode::Active(AcvtiveStruct {table: 0, offset_expr: e}),
-                    _type: WasmRefType::FuncRef,
-                    init: Self::create_elem_expr(ys),
-                })
-            }
-            1 => {
-                let et = self.read_sized::<u8>(0)?;
-                let y_size = self.read_dynamic_uint(0)?;
-                let ys = self.read_vector_dynamic(y_size)?;
-                
-                Ok(WasmElem{
-                    mode: WasmElemMode::Passive,
-                    _type: byte_to_reftype(et)?,
-                    init: Self::create_elem_expr(ys),
-                })
+    // Function indices in the older encodings stand for `ref.func` expressions
+    fn create_elem_exprs(ys: Vec<u32>) -> Vec<WasmExpr> {
+        ys.iter().map(|y| {
+            WasmExpr {
+                expr_string: vec![
+                    ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[REF_FUNC as usize]), ExprSeg::Func(*y)]),
+                    ExprSeg::Operation(INSTRS[END as usize]),
+                ]
             }
+        }).collect()
+    }
+
+    fn read_elem(&mut self) -> Result<WasmElem, WyrmError> {
+        // Bit 0 is set for passive and declarative segments, bit 1 for declarative ones or active
+        // ones with a table index, and bit 2 when the items are expressions instead of funcidxs
+        let flags = self.read_var_u32()? as u32;
+        if flags > 7 {
+            return Err(self.invalid(format!("Elem had invalid flags {}", flags)));
+        }
+
+        let mode = match flags & 3 {
+            0 => WasmElemMode::Active(AcvtiveStruct { table: 0, offset_expr: self.read_expr()?.0 }),
+            1 => WasmElemMode::Passive,
             2 => {
-                let x = self.read_dynamic_uint(0)?;
-                let e = self.read_expr()?.0;
-                let et = self.read_sized::<u8>(0)?;
-                let y_size = self.read_dynamic_uint(0)?;
-                let ys = self.read_vector_dynamic(y_size)?;
-
-                Ok(WasmElem{
-                    mode: WasmElemMode::Active(AcvtiveStruct {table: x as u32, offset_expr: e}),
-                    _type: byte_to_reftype(et)?,
-                    init: Self::create_elem_expr(ys),
-                })
-            }
-            3 => {
-                let et = self.read_sized::<u8>(0)?;
-                let y_size = self.read_dynamic_uint(0)?;
-                let ys = self.read_vector_dynamic(y_size)?;
-
-                Ok(WasmElem{
-                    mode: WasmElemMode::Declarative,
-                    _type: byte_to_reftype(et)?,          
-                    init: Self::create_elem_expr(ys)
-                })               
-            }
-            4 => {
-                todo!()
-            }
-            5 => {
-                _mode = WasmElemMode::Passive;
-                
-                todo!()
-            }
-            6 => {
-                todo!()
-                
+                let table = self.read_var_u32()? as u32;
+                WasmElemMode::Active(AcvtiveStruct { table, offset_expr: self.read_expr()?.0 })
             }
-            7 => {
-                _mode = WasmElemMode::Declarative;
+            _ => WasmElemMode::Declarative,
+        };
 
-                todo!()
+        // Flags 0 and 4 leave the type out. Otherwise funcidx items have an elemkind, where
+        // 0 means funcref, and expression items have a reftype.
+        let _type = if flags & 3 == 0 {
+            WasmRefType::FuncRef
+        } else if flags & 4 == 0 {
+            match self.read_u8()? {
+                0x00 => WasmRefType::FuncRef,
+                kind => return Err(self.invalid(format!("Elem had invalid elemkind {:#x}", kind))),
             }
-            _ => {
-                return Err(Error::new(ErrorKind::InvalidData, "Elem had invalid type"));
+        } else {
+            let et = self.read_u8()?;
+            byte_to_reftype(et).map_err(|e| self.locate(e))?
+        };
+
+        let num_items = self.read_var_u32()?;
+        let init = if flags & 4 == 0 {
+            let ys = self.read_vector_dynamic(num_items)?;
+            Self::create_elem_exprs(ys)
+        } else {
+            let mut exprs = Vec::new();
+            for _ in 0..num_items {
+                exprs.push(self.read_expr()?.0);
             }
-        }
-        
+            exprs
+        };
 
+        Ok(WasmElem { flags, _type, init, mode })
     }
 
-    fn read_elem_section(&mut self) -> Result<WasmElemSection, Error> {
+    fn read_elem_section(&mut self) -> Result<WasmElemSection, WyrmError> {
         
         let mut elem_section: WasmElemSection = WasmElemSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_elems: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_elems: self.read_var_u32()?,
             elems: Vec::new()
         };
 
@@ -514,23 +612,44 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
         Ok(elem_section)
     }
     
-    fn read_data_count_section(&mut self) -> Result<WasmDataCountSection, Error> {
+    fn read_start_section(&mut self) -> Result<WasmStartSection, WyrmError> {
+        Ok(WasmStartSection {
+            section_size: self.read_var_u32()?,
+            func: self.read_index()?,
+        })
+    }
+
+    fn read_tag_section(&mut self) -> Result<WasmTagSection, WyrmError> {
+        let mut tag_section = WasmTagSection {
+            section_size: self.read_var_u32()?,
+            num_tags: self.read_var_u32()?,
+            tags: Vec::new()
+        };
+
+        for _ in 0..tag_section.num_tags {
+            let tag = self.read_tag_type()?;
+            tag_section.tags.push(tag);
+        }
+        Ok(tag_section)
+    }
+
+    fn read_data_count_section(&mut self) -> Result<WasmDataCountSection, WyrmError> {
         Ok(WasmDataCountSection {
-            section_size: self.read_dynamic_uint(0)?,
-            datacount: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            datacount: self.read_var_u32()?,
         })
     }
 
-    fn read_locals(&mut self) -> Result<(Vec<WasmLocal>,  Vec<(u8, usize)>), Error> {
-        let num_decs = self.read_dynamic_uint(0)?;
+    fn read_locals(&mut self) -> Result<LocalDeclarations, WyrmError> {
+        let num_decs = self.read_var_u32()?;
         
         let mut local_types = Vec::<(u8, usize)>::new();
         let mut locals = Vec::<WasmLocal>::new();
         for _ in 0..num_decs {
-            let num_type = self.read_dynamic_uint(0)?;
-            let _type = self.read_sized::<u8>(0)?;
+            let num_type = self.read_var_u32()?;
+            let _type = self.read_value_type()?;
             let mut locals_of_type = 
-                (0.._type).map(
+                (0..num_type).map(
                     |_| WasmLocal { _type: WasmTypeAnnotation { _type } }
                 ).collect();
             local_types.push((_type, num_type));
@@ -540,49 +659,137 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
         Ok((locals, local_types))
     }
 
-    fn read_function(&mut self) -> Result<WasmFunction, Error> {
-        let size = self.read_dynamic_uint(0)?;
-        let (locals, local_types) = self.read_locals()?;
-        let body = self.read_expr()?; 
+    fn read_function(&mut self) -> Result<WasmFunction, WyrmError> {
+        let offset = self.reader.offset();
+        let size = self.read_var_u32()?;
+        // Read within the body's size, so a body can't run on into the next one
+        let mut state = self.sub_state(size)?;
+        let (locals, local_types) = state.read_locals()?;
+        let body = state.read_expr_with_offsets()?;
+        if !state.reader.is_empty() {
+            return Err(state.invalid(format!("{} bytes after the end of the function body", state.reader.remaining())));
+        }
         Ok(WasmFunction{
             size,
             local_types,
             locals,
             body: body.0,
-            raw_body: body.1.into_iter().map(|x| x as u64).collect()
+            raw_body: body.1.into_iter().map(|x| x as u64).collect(),
+            offset,
+            instr_offsets: body.2,
         })        
     }
     
-    fn read_code_section(&mut self) -> Result<WasmCodeSection, Error> {
+    fn read_code_section(&mut self) -> Result<WasmCodeSection, WyrmError> {
         
         let mut code_section: WasmCodeSection = WasmCodeSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_functions: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_functions: self.read_var_u32()?,
             functions: vec![]
         };
 
 
-        for _ in 0..code_section.num_functions {
+        for i in 0..code_section.num_functions {
+            self.func = Some((self.num_imported_funcs + i) as u32);
             code_section.functions.push(self.read_function()?);
         }
+        self.func = None;
         Ok(code_section)
     }
-    fn read_data_section(&mut self) -> Result<WasmDataSection, Error> {
+    fn read_name_map(&mut self) -> Result<WasmNameMap, WyrmError> {
+        let num_names = self.read_var_u32()?;
+        let mut names = WasmNameMap::new();
+        for _ in 0..num_names {
+            let idx = self.read_index()?;
+            names.insert(idx, self.read_name()?);
+        }
+        Ok(names)
+    }
+
+    fn read_indirect_name_map(&mut self) -> Result<WasmIndirectNameMap, WyrmError> {
+        let num_maps = self.read_var_u32()?;
+        let mut maps = WasmIndirectNameMap::new();
+        for _ in 0..num_maps {
+            let idx = self.read_index()?;
+            maps.insert(idx, self.read_name_map()?);
+        }
+        Ok(maps)
+    }
+
+    // The name section is a list of subsections, each with an id and a size.
+    // Subsections we don't know about (such as field names) are skipped over.
+    fn read_name_section(&mut self) -> Result<WasmNameSection, WyrmError> {
+        let mut name_section = WasmNameSection::default();
+        while !self.reader.is_empty() {
+            let subsection_id = self.read_u8()?;
+            let subsection_size = self.read_var_u32()?;
+            let mut subsection = self.sub_state(subsection_size)?;
+            match subsection_id {
+                0x00 => name_section.module_name = Some(subsection.read_name()?),
+                0x01 => name_section.function_names = subsection.read_name_map()?,
+                0x02 => name_section.local_names = subsection.read_indirect_name_map()?,
+                0x03 => name_section.label_names = subsection.read_indirect_name_map()?,
+                0x04 => name_section.type_names = subsection.read_name_map()?,
+                0x05 => name_section.table_names = subsection.read_name_map()?,
+                0x06 => name_section.memory_names = subsection.read_name_map()?,
+                0x07 => name_section.global_names = subsection.read_name_map()?,
+                0x08 => name_section.elem_names = subsection.read_name_map()?,
+                0x09 => name_section.data_names = subsection.read_name_map()?,
+                0x0b => name_section.tag_names = subsection.read_name_map()?,
+                _ => {}
+            }
+        }
+        Ok(name_section)
+    }
+
+    // Custom sections have a name and an opaque payload. Only "name" is understood. They're all
+    // kept with the known section they came after, and since custom sections can't change what a
+    // module means, one with a malformed name, or a name section that can't be read, is kept as
+    // its raw bytes.
+    fn read_custom_section(&mut self, name_section: &mut WasmNameSection, after: u8) -> Result<WasmCustomSection, WyrmError> {
+        let section_size = self.read_var_u32()?;
+        let mut custom = self.sub_state(section_size)?;
+        let mut raw = custom.reader;
+        let raw = raw.read_bytes(raw.remaining())?;
+        let opaque = || WasmCustomSection { name: None, data: raw.to_vec(), after };
+        let Ok(name) = custom.read_name() else {
+            return Ok(opaque());
+        };
+        let mut payload = custom.reader;
+        let data = payload.read_bytes(payload.remaining())?.to_vec();
+        if name == "name" {
+            match custom.read_name_section() {
+                Ok(names) => *name_section = names,
+                Err(_) => return Ok(opaque()),
+            }
+        }
+        Ok(WasmCustomSection { name: Some(name.into_bytes()), data, after })
+    }
+
+    fn read_data_section(&mut self) -> Result<WasmDataSection, WyrmError> {
         
         let mut data_section: WasmDataSection = WasmDataSection {
-            section_size: self.read_dynamic_uint(0)?,
-            num_data_segs: self.read_dynamic_uint(0)?,
+            section_size: self.read_var_u32()?,
+            num_data_segs: self.read_var_u32()?,
             data_segs: vec![]
         };
 
 
         for _ in 0..data_section.num_data_segs {
-            let header_flags = self.read_sized::<u8>(0)?;
-            let expr = self.read_expr()?.0;
-            let data_size = self.read_dynamic_uint(0)?;
-            let header = WasmDataSegHeader  { header_flags, expr, data_size };
+            let header_flags = self.read_var_u32()?;
+            let mode = match header_flags {
+                0 => WasmDataMode::Active { memory: 0, offset_expr: self.read_expr()?.0 },
+                1 => WasmDataMode::Passive,
+                2 => {
+                    let memory = self.read_index()?;
+                    WasmDataMode::Active { memory, offset_expr: self.read_expr()?.0 }
+                }
+                _ => return Err(self.invalid(format!("Data segment had invalid flags {}", header_flags))),
+            };
+            let data_size = self.read_var_u32()?;
+            let header = WasmDataSegHeader { header_flags: header_flags as u8, mode, data_size };
 
-            let data: Vec<u8> = self.read_vector(0, header.data_size)?;
+            let data: Vec<u8> = self.read_bytes(header.data_size)?;
             data_section.data_segs.push(WasmDataSeg {
                 header,
                 data
@@ -591,23 +798,40 @@ impl<T: Read + Debug> WasmDeserializeState<T> {
         Ok(data_section)
     }
 }
-// Reads a WASM file to a WasmFile struct.
-pub fn wasm_deserialize(buffer: impl Read + Debug) -> Result<WasmFile, Error> {
-    let mut state = WasmDeserializeState { buffer, raw_section: Vec::new(), save_read: false };
+// The order the known sections have to come in, each at most once. The tag section goes between
+// memories and globals, and the data count section before the code.
+const SECTION_ORDER: [u8; 13] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x0d, 0x06, 0x07, 0x08, 0x09, 0x0c, 0x0a, 0x0b];
+
+fn section_position(section: u8) -> Option<usize> {
+    SECTION_ORDER.iter().position(|id| *id == section)
+}
+
+/// Reads a WASM file to a WasmFile struct.
+///
+/// The GC proposal is only supported as far as its instructions go: types are kept as function
+/// types and single-byte value types, so a module with `rec`, `sub`, `struct` or `array` types,
+/// or typed references like `(ref null $t)`, is rejected.
+pub fn wasm_deserialize(mut buffer: impl Read) -> Result<WasmFile, WyrmError> {
+    let mut bytes = Vec::new();
+    buffer.read_to_end(&mut bytes)?;
+    wasm_deserialize_bytes(&bytes)
+}
+
+pub fn wasm_deserialize_bytes(bytes: &[u8]) -> Result<WasmFile, WyrmError> {
+    let mut state = WasmDeserializeState::new(BinaryReader::new(bytes));
     let mut wasm_header: WasmHeader = WasmHeader {
         magic_number: 0,
         version: 0,
     };
 
-    // Assert that this is a WASM file by checking it's header
-    wasm_header.magic_number = state.read_sized::<u32>(0)?;
+    // Check that this is a WASM file by checking it's header
+    wasm_header.magic_number = state.read_u32()?;
+    if wasm_header.magic_number != 0x6d736100 {
+        return Err(WyrmError::BadMagic(ErrorLocation { offset: Some(0), ..Default::default() }, wasm_header.magic_number));
+    }
     // TODO: Figure out the difference between versions.
     // Most WASM is version 1 so we focus on that for now.
-    wasm_header.version = state.read_sized::<u32>(0)?;
-    assert_eq!(
-        wasm_header.magic_number, 0x6d736100,
-        "Magic number was incorrect"
-    );
+    wasm_header.version = state.read_u32()?;
 
 
     let mut type_section = WasmTypeSection{
@@ -650,10 +874,13 @@ pub fn wasm_deserialize(buffer: impl Read + Debug) -> Result<WasmFile, Error> {
         num_elems: 0,
         elems: Vec::new(),
     };
-    let mut data_count_section = WasmDataCountSection {
+    let mut start_section = None;
+    let mut tag_section = WasmTagSection {
         section_size: 0,
-        datacount: 0
+        num_tags: 0,
+        tags: Vec::new(),
     };
+    let mut data_count_section = None;
     let mut code_section = WasmCodeSection { 
         section_size: 0, 
         num_functions: 0, 
@@ -664,26 +891,32 @@ pub fn wasm_deserialize(buffer: impl Read + Debug) -> Result<WasmFile, Error> {
         num_data_segs: 0, 
         data_segs: vec![] 
     };
-
-    while let Ok(section_type) = state.read_sized::<u8>(0) {
-        match section_type {
-            0x01 => println!(" = state.read_type_section()?"),
-            0x02 => println!(" = state.read_import_section()?"),
-            0x03 => println!(" = state.read_function_section()?"),
-            0x04 => println!(" = state.read_table_section()?"),
-            0x05 => println!(" = state.read_memory_section()?"),
-            0x06 => println!(" = state.read_global_section()?"),
-            0x07 => println!(" = state.read_export_section()?"),
-            0x09 => println!(" = state.read_elem_section()?"),
-            0x0a => println!(" = state.read_code_section()?"),
-            0x0b => println!(" = state.read_data_section()?"),
-            0x0c => println!(" = state.read_data_count_section()?"),
-            _ => {
-                break
+    let mut name_section = WasmNameSection::default();
+    let mut custom_sections = vec![];
+    // The last known section, which the custom sections after it are kept with
+    let mut last_section = 0;
+
+    while !state.reader.is_empty() {
+        let section_type = state.read_u8()?;
+        log::debug!("Reading section {:#x} at {:#x}", section_type, state.reader.offset() - 1);
+
+        if let (Some(position), Some(last)) = (section_position(section_type), section_position(last_section)) {
+            if position == last {
+                return Err(state.invalid(format!("There's more than one {} section", section_name(section_type))));
+            }
+            if position < last {
+                return Err(state.invalid(format!("The {} section has to come before the {} section", section_name(section_type), section_name(last_section))));
             }
         }
+        state.section = Some(section_type);
+
+        // Every section starts with its size, so peek at it to know where the section should end
+        let mut peek = state.reader;
+        let section_size = peek.read_var_u32().map_err(|e| state.locate(e))? as usize;
+        let section_end = peek.offset() + section_size;
 
         match section_type {
+            0x00 => custom_sections.push(state.read_custom_section(&mut name_section, last_section)?),
             0x01 => type_section = state.read_type_section()?,
             0x02 => import_section_header = state.read_import_section()?,
             0x03 => function_section = state.read_function_section()?,
@@ -691,17 +924,35 @@ pub fn wasm_deserialize(buffer: impl Read + Debug) -> Result<WasmFile, Error> {
             0x05 => memory_section = state.read_memory_section()?,
             0x06 => global_section = state.read_global_section()?,
             0x07 => export_section = state.read_export_section()?,
+            0x08 => start_section = Some(state.read_start_section()?),
             0x09 => elem_section = state.read_elem_section()?,
             0x0a => code_section = state.read_code_section()?,
             0x0b => data_section = state.read_data_section()?,
-            0x0c => data_count_section = state.read_data_count_section()?,
+            0x0c => data_count_section = Some(state.read_data_count_section()?),
+            0x0d => tag_section = state.read_tag_section()?,
             _ => {
-                break
+                state.section = None;
+                return Err(state.invalid(format!("Unknown section id {:#x}", section_type)));
             }
         }
+
+        if state.reader.offset() != section_end {
+            return Err(state.invalid(format!("Section should have ended at offset {:#x}", section_end)));
+        }
+        state.section = None;
+        if (0x01..=0x0d).contains(&section_type) {
+            last_section = section_type;
+        }
+
+        if section_type == 0x02 {
+            state.num_imported_funcs = import_section_header.imports.iter()
+                .filter(|import| import.import_kind == WasmImportType::Func)
+                .count();
+        }
     }
 
-    return Ok(WasmFile {
+
+    Ok(WasmFile {
         wasm_header,
         type_section,
         import_section_header,
@@ -711,8 +962,759 @@ pub fn wasm_deserialize(buffer: impl Read + Debug) -> Result<WasmFile, Error> {
         global_section,
         export_section,
         elem_section, 
+        start_section,
+        tag_section,
         code_section,
         data_section,
         data_count_section,
-    });
+        name_section,
+        custom_sections,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_writer::wasm_serialize_bytes;
+    use crate::wat_emitter::emit_wat;
+
+    fn uleb(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn section(id: u8, count: usize, items: &[u8], out: &mut Vec<u8>) {
+        let mut payload = vec![];
+        uleb(count, &mut payload);
+        payload.extend_from_slice(items);
+        out.push(id);
+        uleb(payload.len(), out);
+        out.extend(payload);
+    }
+
+    // A module with `num_types` types taking 0..num_types i32 params, and `num_funcs`
+    // functions that each call the next one and are all exported
+    fn large_module(num_types: usize, num_funcs: usize) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+
+        let mut types = vec![];
+        for i in 0..num_types {
+            types.push(0x60);
+            uleb(i, &mut types);
+            types.extend(std::iter::repeat_n(0x7f, i));
+            types.push(0);
+        }
+        section(0x01, num_types, &types, &mut module);
+
+        let mut funcs = vec![];
+        for i in 0..num_funcs {
+            uleb(i % num_types, &mut funcs);
+        }
+        section(0x03, num_funcs, &funcs, &mut module);
+
+        let mut exports = vec![];
+        for i in 0..num_funcs {
+            let name = format!("f{}", i);
+            uleb(name.len(), &mut exports);
+            exports.extend(name.as_bytes());
+            exports.push(0x00);
+            uleb(i, &mut exports);
+        }
+        section(0x07, num_funcs, &exports, &mut module);
+
+        let mut code = vec![];
+        for i in 0..num_funcs {
+            // No locals, then `call (i + 1) % num_funcs` without passing any arguments it takes
+            let mut body = vec![0x00, 0x10];
+            uleb((i + 1) % num_funcs, &mut body);
+            body.push(0x0b);
+            uleb(body.len(), &mut code);
+            code.extend(body);
+        }
+        section(0x0a, num_funcs, &code, &mut module);
+        module
+    }
+
+    #[test]
+    fn reads_indices_past_one_byte() {
+        let wasm = wasm_deserialize_bytes(&large_module(300, 2000)).unwrap();
+
+        let sigs = &wasm.function_section.function_signature_indexes;
+        assert_eq!(sigs.len(), 2000);
+        assert_eq!(sigs[299], 299);
+        assert_eq!(sigs[1999], 1999 % 300);
+        assert_eq!(wasm.get_func_sig(1999).unwrap().num_params, 1999 % 300);
+
+        let last_export = wasm.export_section.exports.last().unwrap();
+        assert_eq!(last_export.export_signature_index, 1999);
+        assert_eq!(wasm.export_section.exports[128].export_signature_index, 128);
+
+        let Some(ExprSeg::Instr(call)) = wasm.code_section.functions[1000].body.expr_string.first() else {
+            panic!("expected a call");
+        };
+        assert!(matches!(call[1], ExprSeg::Func(1001)));
+    }
+
+    fn name(name: &str, out: &mut Vec<u8>) {
+        uleb(name.len(), out);
+        out.extend(name.as_bytes());
+    }
+
+    // Two functions of type () -> (), with a name section before the code section
+    fn named_module(func_names: &[(usize, &str)], local_names: &[(usize, &str)]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0, 0], &mut module);
+        section(0x03, 2, &[0, 0], &mut module);
+
+        let mut names = vec![];
+        name("name", &mut names);
+        let mut subsection = vec![];
+        name("demo", &mut subsection);
+        names.push(0x00);
+        uleb(subsection.len(), &mut names);
+        names.extend(subsection);
+        let mut subsection = vec![];
+        uleb(func_names.len(), &mut subsection);
+        for (idx, func_name) in func_names {
+            uleb(*idx, &mut subsection);
+            name(func_name, &mut subsection);
+        }
+        names.push(0x01);
+        uleb(subsection.len(), &mut names);
+        names.extend(subsection);
+        let mut subsection = vec![1, 0];
+        uleb(local_names.len(), &mut subsection);
+        for (idx, local_name) in local_names {
+            uleb(*idx, &mut subsection);
+            name(local_name, &mut subsection);
+        }
+        names.push(0x02);
+        uleb(subsection.len(), &mut names);
+        names.extend(subsection);
+        module.push(0x00);
+        uleb(names.len(), &mut module);
+        module.extend(names);
+
+        // The first function has two i32 locals
+        section(0x0a, 2, &[5, 1, 2, 0x7f, 0x01, 0x0b, 2, 0, 0x0b], &mut module);
+        module
+    }
+
+    #[test]
+    fn reads_the_name_section() {
+        let wasm = wasm_deserialize_bytes(&named_module(&[(0, "main"), (1, "helper")], &[(1, "count")])).unwrap();
+        let names = &wasm.name_section;
+        assert_eq!(names.module_name.as_deref(), Some("demo"));
+        assert_eq!(names.function_names.get(&1).map(String::as_str), Some("helper"));
+        assert_eq!(names.local_names[&0].get(&1).map(String::as_str), Some("count"));
+        // Sections after the name section are still read
+        assert_eq!(wasm.code_section.functions.len(), 2);
+
+        let wat = emit_wat(&wasm).unwrap();
+        assert!(wat.starts_with("(module $demo"), "{}", wat);
+        assert!(wat.contains("(func $main (type 0)\n    (local $var0 i32) (local $count i32)"), "{}", wat);
+        assert!(wat.contains("(func $helper (type 0)"), "{}", wat);
+    }
+
+    #[test]
+    fn gives_clashing_names_unique_ids() {
+        let module = named_module(&[(0, "a b"), (1, "a_b")], &[(0, "var1")]);
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let names = wasm.name_section.with_unique_ids();
+        assert_eq!(names.func_id(0), "$a_b");
+        assert_eq!(names.func_id(1), "$a_b_1");
+        // `$var1` is the id local 1 falls back to
+        assert_eq!(names.local_id(0, 0), "$var1_1");
+        assert_eq!(names.local_id(0, 1), "$var1");
+
+        let wat = emit_wat(&wasm).unwrap();
+        assert!(wat.contains("(func $a_b (type 0)"), "{}", wat);
+        assert!(wat.contains("(func $a_b_1 (type 0)"), "{}", wat);
+    }
+
+    fn import(field: &str, desc: &[u8], out: &mut Vec<u8>) {
+        name("env", out);
+        name(field, out);
+        out.extend(desc);
+    }
+
+    // Imports two functions, then defines one that calls both and exports it
+    fn module_with_imports() -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0, 0], &mut module);
+        let mut imports = vec![];
+        import("f", &[0x00, 0x00], &mut imports);
+        import("g", &[0x00, 0x00], &mut imports);
+        section(0x02, 2, &imports, &mut module);
+        section(0x03, 1, &[0x00], &mut module);
+        let mut exports = vec![];
+        name("main", &mut exports);
+        exports.extend([0x00, 0x02]);
+        section(0x07, 1, &exports, &mut module);
+        section(0x0a, 1, &[0x06, 0x00, 0x10, 0x00, 0x10, 0x01, 0x0b], &mut module);
+        module
+    }
+
+    #[test]
+    fn puts_imports_before_definitions() {
+        let wasm = wasm_deserialize_bytes(&module_with_imports()).unwrap();
+        let funcs = wasm.index_spaces().funcs;
+        assert_eq!(funcs, vec![WasmIndexOrigin::Imported(0), WasmIndexOrigin::Imported(1), WasmIndexOrigin::Defined(0)]);
+        assert_eq!(wasm.defined_func_idx(0), 2);
+
+        let imported = wasm.get_func(1).unwrap();
+        assert!(imported.sig.is_some() && imported.body.is_none());
+        assert!(wasm.get_func(2).unwrap().body.is_some());
+        assert!(wasm.get_func(3).is_none());
+
+        let wat = emit_wat(&wasm).unwrap();
+        assert!(wat.contains("(func $func0 (import \"env\" \"f\")"), "{}", wat);
+        assert!(wat.contains("(export \"main\" (func $func2))"), "{}", wat);
+        assert!(wat.contains("(func $func2"), "{}", wat);
+        assert!(wat.contains("call $func1"), "{}", wat);
+    }
+
+    #[test]
+    fn reads_every_import_kind() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0x01, 0x7f, 0x00], &mut module);
+        let mut imports = vec![];
+        import("f", &[0x00, 0x00], &mut imports);
+        import("t", &[0x01, 0x70, 0x00, 0x01], &mut imports);
+        import("m", &[0x02, 0x01, 0x01, 0x02], &mut imports);
+        import("g", &[0x03, 0x7e, 0x01], &mut imports);
+        import("e", &[0x04, 0x00, 0x00], &mut imports);
+        section(0x02, 5, &imports, &mut module);
+
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let descs: Vec<_> = wasm.import_section_header.imports.iter().map(|import| &import.desc).collect();
+        assert!(matches!(descs[0], WasmImportDesc::Func(0)));
+        assert!(matches!(descs[1], WasmImportDesc::Table(WasmTable { wasm_type: 0x70, limits: Limits { initial: 1, max: None, .. } })));
+        assert!(matches!(descs[2], WasmImportDesc::Mem(WasmMemoryStruct { limits: Limits { initial: 1, max: Some(2), shared: false, is_64: false } })));
+        assert!(matches!(descs[3], WasmImportDesc::Global { wasm_type: WasmTypeAnnotation { _type: 0x7e }, mutability: 1 }));
+        assert!(matches!(descs[4], WasmImportDesc::Tag(WasmTag { attribute: 0, type_index: 0 })));
+
+        // Each import is the first of its own index space
+        let spaces = wasm.index_spaces();
+        for (i, space) in [&spaces.funcs, &spaces.tables, &spaces.memories, &spaces.globals, &spaces.tags].into_iter().enumerate() {
+            assert_eq!(space, &vec![WasmIndexOrigin::Imported(i)]);
+        }
+        assert_eq!(wasm_serialize_bytes(&wasm).unwrap(), module);
+
+        let wat = emit_wat(&wasm).unwrap();
+        let lines: Vec<&str> = wat.lines().map(str::trim).filter(|line| line.contains("(import")).collect();
+        assert_eq!(lines, vec![
+            "(func $func0 (import \"env\" \"f\") (type 0) (param i32))",
+            "(table $table0 (import \"env\" \"t\") 1 funcref)",
+            "(memory $memory0 (import \"env\" \"m\") 1 2)",
+            "(global $global0 (import \"env\" \"g\") (mut i64))",
+            "(tag $tag0 (import \"env\" \"e\") (type 0))",
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_import_kinds() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        let mut imports = vec![];
+        import("x", &[0x05, 0x00], &mut imports);
+        section(0x02, 1, &imports, &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid import kind: 5 at offset 0x12 in import section");
+    }
+
+    #[test]
+    fn rejects_names_that_arent_utf8() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // A memory imported from "env" "\xff"
+        let mut imports = vec![];
+        name("env", &mut imports);
+        imports.extend([0x01, 0xff, 0x02, 0x00, 0x01]);
+        section(0x02, 1, &imports, &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Name isn't valid UTF-8 at offset 0x10 in import section");
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x07, 1, &[0x02, 0xc3, 0x28, 0x00, 0x00], &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Name isn't valid UTF-8 at offset 0xc in export section");
+    }
+
+    #[test]
+    fn reads_limits_by_their_flags() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x04, 1, &[0x70, 0x04, 0x80, 0x80, 0x80, 0x80, 0x80, 0x20], &mut module);
+        let memories = [
+            &[0x00, 0x11][..],
+            &[0x01, 0x11, 0x80, 0x02],
+            &[0x03, 0x11, 0x80, 0x02],
+            &[0x05, 0x01, 0x80, 0x80, 0x80, 0x80, 0x20],
+        ];
+        section(0x05, memories.len(), &memories.concat(), &mut module);
+
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let table = &wasm.table_section.tables[0].limits;
+        assert_eq!((table.initial, table.max, table.shared, table.is_64), (1 << 40, None, false, true));
+        let limits: Vec<_> = wasm.memory_section.memories.iter().map(|memory| &memory.limits).collect();
+        assert_eq!((limits[0].initial, limits[0].max), (17, None));
+        assert_eq!((limits[1].initial, limits[1].max, limits[1].shared), (17, Some(256), false));
+        assert_eq!((limits[2].max, limits[2].shared), (Some(256), true));
+        assert_eq!((limits[3].initial, limits[3].max, limits[3].is_64), (1, Some(1 << 33), true));
+        assert_eq!(wasm_serialize_bytes(&wasm).unwrap(), module);
+
+        let wat = emit_wat(&wasm).unwrap();
+        let lines: Vec<&str> = wat.lines().map(str::trim).filter(|line| line.starts_with("(table") || line.starts_with("(memory")).collect();
+        assert_eq!(lines, vec![
+            "(table $table0 i64 1099511627776 funcref)",
+            "(memory $memory0 17)",
+            "(memory $memory1 17 256)",
+            "(memory $memory2 17 256 shared)",
+            "(memory $memory3 i64 1 8589934592)",
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        let read = |id: u8, items: &[u8]| {
+            let mut module = b"\0asm\x01\0\0\0".to_vec();
+            section(id, 1, items, &mut module);
+            wasm_deserialize_bytes(&module).unwrap_err().to_string()
+        };
+        assert_eq!(read(0x05, &[0x08, 0x01]), "Invalid limits flags 0x8 at offset 0xc in memory section");
+        assert_eq!(read(0x04, &[0x70, 0x03, 0x01, 0x02]), "Tables can't be shared at offset 0xf in table section");
+        // Only 64-bit limits can go past u32
+        assert_eq!(read(0x05, &[0x00, 0x80, 0x80, 0x80, 0x80, 0x10]), "LEB128 integer is too large for u32 at offset 0xc in memory section");
+    }
+
+    #[test]
+    fn index_spaces_follow_edits() {
+        let mut wasm = wasm_deserialize_bytes(&module_with_imports()).unwrap();
+        // Without its second import, the defined function moves down a slot
+        wasm.import_section_header.imports.pop();
+        assert_eq!(wasm.defined_func_idx(0), 1);
+        assert!(wasm.get_func(1).unwrap().body.is_some());
+        assert!(wasm.get_func(2).is_none());
+
+        // A function without a signature or body is still found, just without them
+        wasm.function_section.function_signature_indexes.push(7);
+        let added = wasm.get_func(2).unwrap();
+        assert_eq!(added.origin, WasmIndexOrigin::Defined(1));
+        assert!(added.sig.is_none() && added.body.is_none());
+        assert_eq!(wasm.index_space_len(WasmImportType::Func), 3);
+    }
+
+    #[test]
+    fn counts_imports_of_each_kind_separately() {
+        let imports = [WasmImportType::Global, WasmImportType::Func, WasmImportType::Table, WasmImportType::Mem, WasmImportType::Global];
+        let import_section = WasmImportSection {
+            section_size: 0,
+            num_imports: imports.len(),
+            imports: imports.iter().map(|kind| WasmImportHeader {
+                mod_name_length: 0,
+                import_module_name: String::new(),
+                import_field_len: 0,
+                import_field: String::new(),
+                import_kind: *kind,
+                desc: WasmImportDesc::Func(0),
+            }).collect(),
+        };
+        let function_section = WasmFunctionSection { section_size: 0, num_functions: 2, function_signature_indexes: vec![0, 0] };
+        let table_section = WasmTableSection { section_size: 0, num_tables: 0, tables: vec![] };
+        let memory_section = WasmMemorySection { section_size: 0, num_memories: 0, memories: vec![] };
+        let global_section = WasmGlobalSection { section_size: 0, num_globals: 0, globals: vec![] };
+        let tag_section = WasmTagSection { section_size: 0, num_tags: 0, tags: vec![] };
+        let spaces = WasmIndexSpaces::new(&import_section, &function_section, &table_section, &memory_section, &global_section, &tag_section);
+
+        use WasmIndexOrigin::*;
+        assert_eq!(spaces.funcs, vec![Imported(1), Defined(0), Defined(1)]);
+        assert_eq!(spaces.tables, vec![Imported(2)]);
+        assert_eq!(spaces.memories, vec![Imported(3)]);
+        assert_eq!(spaces.globals, vec![Imported(0), Imported(4)]);
+        assert_eq!(WasmIndexSpaces::num_imported(&spaces.globals), 2);
+    }
+
+    #[test]
+    fn emits_large_modules() {
+        let wasm = wasm_deserialize_bytes(&large_module(130, 1000)).unwrap();
+        let wat = emit_wat(&wasm).unwrap();
+        assert!(wat.contains("(export \"f999\" (func $func999))"));
+        assert!(wat.contains("call $func500"));
+        assert_eq!(wat.lines().filter(|line| line.trim_start().starts_with("(func ")).count(), 1000);
+    }
+
+    // The instructions of a body, each as its name and immediates
+    fn decode(code: &[u8]) -> Vec<(&'static str, Vec<ExprSeg>)> {
+        let mut state = WasmDeserializeState::new(BinaryReader::new(code));
+        let (expr, _) = state.read_expr().unwrap();
+        expr.expr_string.into_iter().map(|seg| match seg {
+            ExprSeg::Operation(info) => (info.name, vec![]),
+            ExprSeg::Instr(mut instr) => match instr.remove(0) {
+                ExprSeg::Operation(info) => (info.name, instr),
+                other => panic!("Instruction without an opcode: {:?}", other),
+            },
+            other => panic!("Unexpected segment {:?}", other),
+        }).collect()
+    }
+
+    #[test]
+    fn reads_typed_function_reference_immediates() {
+        // call_ref 3, return_call_ref 1, br_on_null 0, br_on_non_null 2
+        let instrs = decode(&[0x14, 0x03, 0x15, 0x01, 0xd5, 0x00, 0xd6, 0x02, 0x0b]);
+        assert!(matches!(&instrs[0], ("call_ref", imm) if matches!(imm[..], [ExprSeg::Type(3)])));
+        assert!(matches!(&instrs[1], ("return_call_ref", imm) if matches!(imm[..], [ExprSeg::Type(1)])));
+        assert!(matches!(&instrs[2], ("br_on_null", imm) if matches!(imm[..], [ExprSeg::Int(0)])));
+        assert!(matches!(&instrs[3], ("br_on_non_null", imm) if matches!(imm[..], [ExprSeg::Int(2)])));
+        assert_eq!(instrs[4].0, "end");
+    }
+
+    #[test]
+    fn reads_each_prefix() {
+        let instrs = decode(&[
+            // struct.get 2 1
+            0xfb, 0x02, 0x02, 0x01,
+            // memory.copy 0 1
+            0xfc, 0x0a, 0x00, 0x01,
+            // i8x16.extract_lane_s 15
+            0xfd, 0x15, 0x0f,
+            // i32.atomic.load offset=8 align=4
+            0xfe, 0x10, 0x02, 0x08,
+            0x0b,
+        ]);
+        assert!(matches!(&instrs[0], ("struct.get", imm) if matches!(imm[..], [ExprSeg::Type(2), ExprSeg::Int(1)])));
+        assert!(matches!(&instrs[1], ("memory.copy", imm) if matches!(imm[..], [ExprSeg::Memory(0), ExprSeg::Memory(1)])));
+        assert!(matches!(&instrs[2], ("i8x16.extract_lane_s", imm) if matches!(imm[..], [ExprSeg::Int(15)])));
+        assert!(matches!(&instrs[3], ("i32.atomic.load", imm)
+            if matches!(imm[..], [ExprSeg::MemArg(MemArg { align: 2, offset: 8, memory: 0 })])));
+        assert_eq!(instrs.len(), 5);
+    }
+
+    // Only GC instructions are decoded, not the types they work on
+    #[test]
+    fn rejects_gc_type_definitions() {
+        let read = |id: u8, items: &[u8]| {
+            let mut module = b"\0asm\x01\0\0\0".to_vec();
+            section(id, 1, items, &mut module);
+            wasm_deserialize_bytes(&module).unwrap_err().to_string()
+        };
+        // (type (struct))
+        assert_eq!(read(0x01, &[0x5f, 0x00]), "GC type definitions (0x5f) aren't supported, only function types at offset 0xc in type section");
+        // (type (func (param (ref null 0))))
+        assert_eq!(read(0x01, &[0x60, 0x01, 0x63, 0x00, 0x00]), "Typed reference types (0x63) aren't supported at offset 0xe in type section");
+        // (global (ref func) (ref.func 0))
+        assert_eq!(read(0x06, &[0x64, 0x70, 0x00, 0xd2, 0x00, 0x0b]), "Typed reference types (0x64) aren't supported at offset 0xc in global section");
+
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x02, 0x63, 0x70, 0x0b, 0x0b])).unwrap_err();
+        assert_eq!(error.to_string(), format!("Typed reference types (0x63) aren't supported at offset {:#x} in code section (function 0)", BODY_START + 2));
+    }
+
+    #[test]
+    fn reads_exception_handling_immediates() {
+        let module = module_with_body(&[
+            // try, throw 0, catch 1, rethrow 0, catch_all, end
+            0x06, 0x40, 0x08, 0x00, 0x07, 0x01, 0x09, 0x00, 0x19, 0x0b,
+            // try, nop, delegate 0
+            0x06, 0x40, 0x01, 0x18, 0x00,
+            // try_table (catch 0 0) (catch_all_ref 1), end
+            0x1f, 0x40, 0x02, 0x00, 0x00, 0x00, 0x03, 0x01, 0x0b,
+            0x0b,
+        ]);
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let body = &wasm.code_section.functions[0].body.expr_string;
+        assert!(matches!(&body[1], ExprSeg::ControlFlow(_, _, _, end, catches) if end.name == "delegate" && catches.is_empty()));
+        assert!(matches!(&body[2], ExprSeg::ControlFlow(info, _, _, _, catches) if info.name == "try_table"
+            && matches!(catches[..], [CatchClause::Catch { tag: 0, label: 0 }, CatchClause::CatchAllRef { label: 1 }])));
+
+        let wat = emit_wat(&wasm).unwrap();
+        let start = wat.find("try ").unwrap();
+        let lines: Vec<&str> = wat[start..].lines().map(str::trim).take(13).collect();
+        assert_eq!(lines, vec![
+            "try $label0",
+            "throw $tag0",
+            "catch $tag1",
+            "rethrow 0",
+            "catch_all",
+            "end $label0",
+            "try $label0",
+            "nop",
+            "delegate 0",
+            "try_table $label0 (catch $tag0 0) (catch_all_ref 1)",
+            "end $label0",
+            ")",
+            ")",
+        ]);
+        assert_eq!(wasm_serialize_bytes(&wasm).unwrap(), module);
+
+        // A delegate can only end a try
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x02, 0x40, 0x18, 0x00, 0x0b])).unwrap_err();
+        assert!(error.to_string().starts_with("delegate outside of a try"), "{}", error);
+    }
+
+    fn module_with_data(segs: &[&[u8]]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x05, 1, &[0x00, 0x01], &mut module);
+        section(0x0b, segs.len(), &segs.concat(), &mut module);
+        module
+    }
+
+    #[test]
+    fn reads_every_data_mode() {
+        let module = module_with_data(&[
+            &[0x00, 0x41, 0x08, 0x0b, 0x01, b'a'],
+            &[0x01, 0x01, b'b'],
+            &[0x02, 0x00, 0x41, 0x10, 0x0b, 0x01, b'c'],
+        ]);
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let segs = &wasm.data_section.data_segs;
+        assert!(matches!(segs[0].header.mode, WasmDataMode::Active { memory: 0, .. }));
+        assert!(matches!(segs[1].header.mode, WasmDataMode::Passive));
+        assert!(matches!(segs[2].header.mode, WasmDataMode::Active { memory: 0, .. }));
+        assert_eq!(segs.iter().map(|seg| seg.data.as_slice()).collect::<Vec<_>>(), vec![b"a", b"b", b"c"]);
+        // The explicit memory index is kept, so the segment is written back the same way
+        assert_eq!(wasm_serialize_bytes(&wasm).unwrap(), module);
+
+        let wat = emit_wat(&wasm).unwrap();
+        let lines: Vec<&str> = wat.lines().map(str::trim).filter(|line| line.starts_with("(data")).collect();
+        assert_eq!(lines, vec![
+            "(data (i32.const 8) \"a\")",
+            "(data \"b\")",
+            "(data (i32.const 16) \"c\")",
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_data_flags() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x0b, 1, &[0x03], &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Data segment had invalid flags 3 at offset 0xc in data section");
+    }
+
+    // One function of type [] -> [] with the given body, after its local declarations
+    fn module_with_body(body: &[u8]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0x00, 0x00], &mut module);
+        section(0x03, 1, &[0x00], &mut module);
+        let mut code = vec![];
+        uleb(body.len() + 1, &mut code);
+        code.push(0x00);
+        code.extend_from_slice(body);
+        section(0x0a, 1, &code, &mut module);
+        module
+    }
+
+    // Where the only body starts: past the header, the type and function sections, and the
+    // code section's id, size, count, body size and local declarations
+    const BODY_START: usize = 8 + 6 + 4 + 5;
+
+    #[test]
+    fn rejects_bad_magic() {
+        let error = wasm_deserialize_bytes(b"\0asn\x01\0\0\0").unwrap_err();
+        assert!(matches!(error, WyrmError::BadMagic(_, 0x6e736100)), "{:?}", error);
+        assert_eq!(error.location().offset, Some(0));
+    }
+
+    #[test]
+    fn reports_truncated_imports() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // The import's field name says it's 5 bytes long, but the section ends after 2
+        section(0x02, 1, &[0x03, b'e', b'n', b'v', 0x05, b'f', b'n'], &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert!(matches!(error, WyrmError::UnexpectedEof(_)), "{:?}", error);
+        // Reported where the section runs out
+        assert_eq!(*error.location(), ErrorLocation { offset: Some(module.len()), section: Some(0x02), func: None });
+        assert_eq!(error.to_string(), "unexpected end of file at offset 0x12 in import section");
+    }
+
+    #[test]
+    fn rejects_unknown_and_misplaced_sections() {
+        let read = |sections: &[(u8, &[u8])]| {
+            let mut module = b"\0asm\x01\0\0\0".to_vec();
+            for (id, items) in sections {
+                section(*id, 1, items, &mut module);
+            }
+            wasm_deserialize_bytes(&module).map(|_| ()).map_err(|e| e.to_string())
+        };
+        let memory: &[u8] = &[0x00, 0x01];
+        let tag: &[u8] = &[0x00, 0x00];
+        assert_eq!(read(&[(0x0e, &[])]), Err("Unknown section id 0xe at offset 0x9".to_string()));
+        assert_eq!(read(&[(0x05, memory), (0x05, memory)]), Err("There's more than one memory section at offset 0xe".to_string()));
+        assert_eq!(read(&[(0x06, &[0x7f, 0x00, 0x41, 0x00, 0x0b]), (0x05, memory)]), Err("The memory section has to come before the global section at offset 0x11".to_string()));
+        // Custom sections can go anywhere, and tags come before globals despite their id
+        assert_eq!(read(&[(0x05, memory), (0x00, &[0x00]), (0x0d, tag), (0x06, &[0x7f, 0x00, 0x41, 0x00, 0x0b])]), Ok(()));
+    }
+
+    #[test]
+    fn keeps_malformed_custom_sections() {
+        let custom = |payload: &[u8], out: &mut Vec<u8>| {
+            out.push(0x00);
+            uleb(payload.len(), out);
+            out.extend_from_slice(payload);
+        };
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0, 0], &mut module);
+        // A name longer than the section, and one that isn't UTF-8
+        custom(&[0x05, b'a', b'b'], &mut module);
+        custom(&[0x02, 0xff, 0xfe, 0x01], &mut module);
+        section(0x03, 1, &[0], &mut module);
+        // A name section whose function names subsection runs past its end
+        let mut names = vec![];
+        name("name", &mut names);
+        names.extend([0x01, 0x05, 0x01, 0x00]);
+        custom(&names, &mut module);
+        section(0x0a, 1, &[2, 0, 0x0b], &mut module);
+
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let customs: Vec<_> = wasm.custom_sections.iter().map(|custom| (custom.name.as_deref(), custom.data.len(), custom.after)).collect();
+        assert_eq!(customs, vec![(None, 3, 0x01), (None, 4, 0x01), (None, names.len(), 0x03)]);
+        assert!(wasm.name_section.function_names.is_empty());
+        assert_eq!(wasm_serialize_bytes(&wasm).unwrap(), module);
+    }
+
+    #[test]
+    fn reports_unknown_opcodes_where_they_start() {
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x01, 0x27, 0x0b])).unwrap_err();
+        assert!(matches!(error, WyrmError::UnknownOpcode(_, 0x27, None)), "{:?}", error);
+        assert_eq!(*error.location(), ErrorLocation { offset: Some(BODY_START + 1), section: Some(0x0a), func: Some(0) });
+
+        let error = wasm_deserialize_bytes(&module_with_body(&[0xfc, 0x7f, 0x0b])).unwrap_err();
+        assert!(matches!(error, WyrmError::UnknownOpcode(_, 0xfc, Some(0x7f))), "{:?}", error);
+        assert_eq!(error.location().offset, Some(BODY_START));
+        assert_eq!(error.to_string(), format!("unknown opcode 0xfc 0x7f at offset {:#x} in code section (function 0)", BODY_START));
+    }
+
+    #[test]
+    fn reports_bodies_cut_short() {
+        // i32.const without its immediate, and no `end`
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x41])).unwrap_err();
+        assert!(matches!(error, WyrmError::UnexpectedEof(_)), "{:?}", error);
+        assert_eq!(error.location().section, Some(0x0a));
+        assert_eq!(error.location().func, Some(0));
+    }
+
+    #[test]
+    fn rejects_bytes_after_the_final_end() {
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x0b, 0x01, 0x01])).unwrap_err();
+        assert!(matches!(error, WyrmError::InvalidData(..)), "{:?}", error);
+        assert_eq!(*error.location(), ErrorLocation { offset: Some(BODY_START + 1), section: Some(0x0a), func: Some(0) });
+        assert_eq!(error.to_string(), format!("2 bytes after the end of the function body at offset {:#x} in code section (function 0)", BODY_START + 1));
+    }
+
+    #[test]
+    fn keeps_bodies_within_their_size() {
+        let mut module = module_with_body(&[0x01, 0x0b]);
+        // Claim one less byte for the body, so its `end` is past it
+        let body_size = BODY_START - 2;
+        module[body_size] -= 1;
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert!(matches!(error, WyrmError::UnexpectedEof(_)), "{:?}", error);
+        assert_eq!(error.location().offset, Some(BODY_START + 1));
+    }
+
+    #[test]
+    fn reads_memargs() {
+        let code = [
+            // i32.load align=4 offset=16
+            0x28, 0x02, 0x10,
+            // i64.load8_u align=2 offset=2^32, which needs a 64-bit offset
+            0x31, 0x01, 0x80, 0x80, 0x80, 0x80, 0x10,
+            // i32.store to memory 1, with bit 6 of the alignment set
+            0x36, 0x42, 0x01, 0x04,
+            0x0b,
+        ];
+        let instrs = decode(&code);
+        let memargs: Vec<MemArg> = instrs.iter().filter_map(|(_, imm)| match imm[..] {
+            [ExprSeg::MemArg(memarg)] => Some(memarg),
+            _ => None,
+        }).collect();
+        assert_eq!(memargs, vec![
+            MemArg { align: 2, offset: 16, memory: 0 },
+            MemArg { align: 1, offset: 1 << 32, memory: 0 },
+            MemArg { align: 2, offset: 4, memory: 1 },
+        ]);
+        assert_eq!(memargs[1].alignment(), 2);
+
+        let wat = emit_wat(&wasm_deserialize_bytes(&module_with_body(&code)).unwrap()).unwrap();
+        assert!(wat.contains("i32.load offset=16\n"), "{}", wat);
+        assert!(wat.contains("i64.load8_u offset=4294967296 align=2\n"), "{}", wat);
+        assert!(wat.contains("i32.store $memory1 offset=4\n"), "{}", wat);
+    }
+
+    #[test]
+    fn rejects_memarg_flags_past_bit_6() {
+        let error = wasm_deserialize_bytes(&module_with_body(&[0x41, 0x00, 0x28, 0x80, 0x01, 0x00, 0x0b])).unwrap_err();
+        assert!(matches!(error, WyrmError::InvalidData(..)), "{:?}", error);
+        assert_eq!(error.to_string(), format!("Bad memarg alignment 0x80 at offset {:#x} in code section (function 0)", BODY_START + 5));
+    }
+
+    // One function, one funcref table and an element segment in each of the eight encodings
+    fn module_with_elems() -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x01, 1, &[0x60, 0x00, 0x00], &mut module);
+        section(0x03, 1, &[0x00], &mut module);
+        section(0x04, 1, &[0x70, 0x00, 0x01], &mut module);
+        let offset = [0x41, 0x00, 0x0b];
+        let ref_func = [0xd2, 0x00, 0x0b];
+        let elems = [
+            [&[0x00][..], &offset, &[0x01, 0x00]].concat(),
+            vec![0x01, 0x00, 0x01, 0x00],
+            [&[0x02, 0x00][..], &offset, &[0x00, 0x01, 0x00]].concat(),
+            vec![0x03, 0x00, 0x01, 0x00],
+            [&[0x04][..], &offset, &[0x01], &ref_func].concat(),
+            vec![0x05, 0x70, 0x01, 0xd0, 0x70, 0x0b],
+            [&[0x06, 0x00][..], &offset, &[0x70, 0x01], &ref_func].concat(),
+            [&[0x07, 0x70, 0x01][..], &ref_func].concat(),
+        ];
+        section(0x09, elems.len(), &elems.concat(), &mut module);
+        section(0x0a, 1, &[0x02, 0x00, 0x0b], &mut module);
+        module
+    }
+
+    #[test]
+    fn reads_every_elem_encoding() {
+        let wasm = wasm_deserialize_bytes(&module_with_elems()).unwrap();
+        let elems = &wasm.elem_section.elems;
+        assert_eq!(elems.iter().map(|elem| elem.flags).collect::<Vec<_>>(), (0..8).collect::<Vec<_>>());
+        for elem in elems {
+            let expected = match elem.flags & 3 {
+                0 | 2 => matches!(elem.mode, WasmElemMode::Active(AcvtiveStruct { table: 0, .. })),
+                1 => matches!(elem.mode, WasmElemMode::Passive),
+                _ => matches!(elem.mode, WasmElemMode::Declarative),
+            };
+            assert!(expected, "flags {} gave {:?}", elem.flags, elem.mode);
+            assert!(matches!(elem._type, WasmRefType::FuncRef));
+            assert_eq!(elem.init.len(), 1);
+        }
+
+        let wat = emit_wat(&wasm).unwrap();
+        let lines: Vec<&str> = wat.lines().map(str::trim).filter(|line| line.starts_with("(elem")).collect();
+        assert_eq!(lines, vec![
+            "(elem $elem0 (offset (i32.const 0)) func $func0)",
+            "(elem $elem1 func $func0)",
+            "(elem $elem2 (table $table0) (offset (i32.const 0)) func $func0)",
+            "(elem $elem3 declare func $func0)",
+            "(elem $elem4 (offset (i32.const 0)) funcref (item (ref.func $func0)))",
+            "(elem $elem5 funcref (item (ref.null func)))",
+            "(elem $elem6 (table $table0) (offset (i32.const 0)) funcref (item (ref.func $func0)))",
+            "(elem $elem7 declare funcref (item (ref.func $func0)))",
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_elem_flags() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x09, 1, &[0x08], &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Elem had invalid flags 8 at offset 0xc in element section");
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        section(0x09, 1, &[0x01, 0x01, 0x00], &mut module);
+        let error = wasm_deserialize_bytes(&module).unwrap_err();
+        assert_eq!(error.to_string(), "Elem had invalid elemkind 0x1 at offset 0xd in element section");
+    }
 }
@@ -0,0 +1,630 @@
+use std::io::Write;
+
+use crate::binary_writer::BinaryWriter;
+use crate::error::*;
+use crate::wasm_model::*;
+use crate::instr_table::TRY_TABLE;
+
+
+fn write_opcode(out: &mut BinaryWriter, info: &InstrInfo) {
+    if info.prefix != 0 {
+        out.write_u8(info.prefix);
+        out.write_var_u32(info.instr);
+    } else {
+        out.write_u8(info.instr as u8);
+    }
+}
+
+fn write_memarg(out: &mut BinaryWriter, memarg: &MemArg) {
+    if memarg.memory != 0 {
+        out.write_var_u32(memarg.align | 0x40);
+        out.write_var_u32(memarg.memory);
+    } else {
+        out.write_var_u32(memarg.align);
+    }
+    out.write_var_u64(memarg.offset);
+}
+
+fn bad_immediate(info: &InstrInfo) -> WyrmError {
+    WyrmError::invalid(format!("Immediates of {} don't match its signature", info.name))
+}
+
+// An instruction and its immediates, in the layout read_expr gives them
+fn write_instr(out: &mut BinaryWriter, instr: &[ExprSeg]) -> Result<(), WyrmError> {
+    let Some(ExprSeg::Operation(info)) = instr.first() else {
+        return Err(WyrmError::invalid("Instruction didn't start with an opcode"));
+    };
+    write_opcode(out, info);
+    let mut immediates = instr[1..].iter();
+
+    match get_edge_case(*info) {
+        SpecialInstr::BrTable => {
+            let Some(ExprSeg::BrTable(table)) = immediates.next() else {
+                return Err(bad_immediate(info));
+            };
+            out.write_var_u32(table.break_depths.len() as u32);
+            for depth in table.break_depths.iter() {
+                out.write_var_u32(*depth);
+            }
+            out.write_var_u32(table.default);
+            return Ok(());
+        }
+        SpecialInstr::CallIndirect => {
+            // Type index, then table index
+            for _ in 0..2 {
+                let Some(ExprSeg::Int(idx)) = immediates.next() else {
+                    return Err(bad_immediate(info));
+                };
+                out.write_var_u32(*idx as u32);
+            }
+            return Ok(());
+        }
+        SpecialInstr::SelectTyped => {
+            out.write_var_u32(immediates.len() as u32);
+            for seg in immediates {
+                let ExprSeg::Int(value_type) = seg else {
+                    return Err(bad_immediate(info));
+                };
+                out.write_u8(*value_type as u8);
+            }
+            return Ok(());
+        }
+        SpecialInstr::BrOnCast => {
+            let (Some(ExprSeg::Int(label)), Some(ExprSeg::RefType { nullable: null1, heap: heap1 }), Some(ExprSeg::RefType { nullable: null2, heap: heap2 })) =
+                (immediates.next(), immediates.next(), immediates.next()) else {
+                return Err(bad_immediate(info));
+            };
+            out.write_u8(*null1 as u8 | (*null2 as u8) << 1);
+            out.write_var_u32(*label as u32);
+            out.write_var_s33(*heap1);
+            out.write_var_s33(*heap2);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    for constant in info.constants {
+        // Void immediates are reserved bytes that aren't kept around
+        if *constant == Prim::Void {
+            out.write_u8(0);
+            continue;
+        }
+        let Some(seg) = immediates.next() else {
+            return Err(bad_immediate(info));
+        };
+        match (constant, seg) {
+            (Prim::F32, ExprSeg::Float32(f)) => out.write_f32(*f),
+            (Prim::F64, ExprSeg::Float64(f)) => out.write_f64(*f),
+            (Prim::V128, ExprSeg::V128(v)) => out.write_u128(*v),
+            (Prim::Lane, ExprSeg::Int(lane)) => out.write_u8(*lane as u8),
+            (Prim::I64, ExprSeg::Int(i)) => out.write_var_i64(*i),
+            (Prim::MemArg, ExprSeg::MemArg(memarg)) => write_memarg(out, memarg),
+            (Prim::BlockType, ExprSeg::BlockType(block_type)) => out.write_var_s33(*block_type),
+            (Prim::HeapType, ExprSeg::HeapType(heap)) => out.write_var_s33(*heap),
+            (Prim::RefType | Prim::NullRefType, ExprSeg::RefType { heap, .. }) => out.write_var_s33(*heap),
+            (_, ExprSeg::Global(idx) | ExprSeg::Local(idx) | ExprSeg::Func(idx) | ExprSeg::Table(idx)
+                | ExprSeg::Memory(idx) | ExprSeg::Type(idx) | ExprSeg::Data(idx) | ExprSeg::Elem(idx) | ExprSeg::Tag(idx)) => {
+                out.write_var_u32(*idx);
+            }
+            // Everything else was read as a signed 32 bit number
+            (_, ExprSeg::Int(i)) => out.write_var_i64(*i),
+            _ => return Err(bad_immediate(info)),
+        }
+    }
+    Ok(())
+}
+
+/// Writes an expression, including its final `end`
+pub fn write_expr(out: &mut BinaryWriter, expr: &WasmExpr) -> Result<(), WyrmError> {
+    for seg in expr.expr_string.iter() {
+        match seg {
+            ExprSeg::Operation(info) => write_opcode(out, info),
+            ExprSeg::Instr(instr) => write_instr(out, instr)?,
+            // The body already ends with the block's `end`
+            ExprSeg::ControlFlow(info, block_type, body, _, catches) => {
+                write_opcode(out, info);
+                out.write_var_s33(*block_type);
+                if info.prefix == 0 && info.instr == TRY_TABLE as u32 {
+                    out.write_var_u32(catches.len() as u32);
+                    for catch in catches {
+                        out.write_u8(catch.opcode());
+                        if let Some(tag) = catch.tag() {
+                            out.write_var_u32(tag);
+                        }
+                        out.write_var_u32(catch.label());
+                    }
+                }
+                write_expr(out, body)?;
+            }
+            ExprSeg::Idiom(_) => return Err(WyrmError::unsupported("Writing idioms back out")),
+            _ => return Err(WyrmError::invalid("Immediate found outside of an instruction")),
+        }
+    }
+    Ok(())
+}
+
+fn write_limits(out: &mut BinaryWriter, limits: &Limits) {
+    out.write_u8(limits.flags());
+    let mut write_limit = |limit: u64| if limits.is_64 {
+        out.write_var_u64(limit);
+    } else {
+        out.write_var_u32(limit as u32);
+    };
+    write_limit(limits.initial);
+    if let Some(max) = limits.max {
+        write_limit(max);
+    }
+}
+
+fn write_table_type(out: &mut BinaryWriter, table: &WasmTable) {
+    out.write_u8(table.wasm_type);
+    write_limits(out, &table.limits);
+}
+
+fn write_tag_type(out: &mut BinaryWriter, tag: &WasmTag) {
+    out.write_u8(tag.attribute);
+    out.write_var_u32(tag.type_index);
+}
+
+fn write_section(out: &mut BinaryWriter, id: u8, contents: &BinaryWriter) {
+    out.write_u8(id);
+    out.write_sized(contents);
+}
+
+// Writes a section holding a vector, leaving it out if the vector is empty
+fn write_vec_section<T>(
+    out: &mut BinaryWriter,
+    id: u8,
+    items: &[T],
+    mut write_item: impl FnMut(&mut BinaryWriter, usize, &T) -> Result<(), WyrmError>,
+) -> Result<(), WyrmError> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let mut section = BinaryWriter::new();
+    section.write_var_u32(items.len() as u32);
+    for (i, item) in items.iter().enumerate() {
+        write_item(&mut section, i, item).map_err(|e| e.in_section(id))?;
+    }
+    write_section(out, id, &section);
+    Ok(())
+}
+
+fn write_name_map(out: &mut BinaryWriter, names: &WasmNameMap) {
+    out.write_var_u32(names.len() as u32);
+    for (idx, name) in names.iter() {
+        out.write_var_u32(*idx);
+        out.write_name(name.as_bytes());
+    }
+}
+
+fn write_indirect_name_map(out: &mut BinaryWriter, names: &WasmIndirectNameMap) {
+    out.write_var_u32(names.len() as u32);
+    for (idx, map) in names.iter() {
+        out.write_var_u32(*idx);
+        write_name_map(out, map);
+    }
+}
+
+fn write_name_section(out: &mut BinaryWriter, names: &WasmNameSection) {
+    let contents = |write: &dyn Fn(&mut BinaryWriter)| {
+        let mut contents = BinaryWriter::new();
+        write(&mut contents);
+        contents
+    };
+    let name_map = |map: &WasmNameMap| (!map.is_empty()).then(|| contents(&|out| write_name_map(out, map)));
+    let indirect_name_map = |map: &WasmIndirectNameMap| {
+        (!map.is_empty()).then(|| contents(&|out| write_indirect_name_map(out, map)))
+    };
+
+    // Subsections have to be in order of their ids
+    let subsections = [
+        (0x00, names.module_name.as_ref().map(|name| contents(&|out| out.write_name(name.as_bytes())))),
+        (0x01, name_map(&names.function_names)),
+        (0x02, indirect_name_map(&names.local_names)),
+        (0x03, indirect_name_map(&names.label_names)),
+        (0x04, name_map(&names.type_names)),
+        (0x05, name_map(&names.table_names)),
+        (0x06, name_map(&names.memory_names)),
+        (0x07, name_map(&names.global_names)),
+        (0x08, name_map(&names.elem_names)),
+        (0x09, name_map(&names.data_names)),
+        (0x0b, name_map(&names.tag_names)),
+    ];
+    if subsections.iter().all(|(_, subsection)| subsection.is_none()) {
+        return;
+    }
+
+    let mut section = BinaryWriter::new();
+    section.write_name(b"name");
+    for (id, subsection) in subsections.iter() {
+        if let Some(subsection) = subsection {
+            section.write_u8(*id);
+            section.write_sized(subsection);
+        }
+    }
+    write_section(out, 0x00, &section);
+}
+
+fn write_elem(out: &mut BinaryWriter, elem: &WasmElem) -> Result<(), WyrmError> {
+    let exprs = elem.flags & 4 != 0;
+    // The mode bits come from the mode, in case it was changed since reading
+    let mode_flags = match &elem.mode {
+        WasmElemMode::Active(active) if active.table == 0 && elem.flags & 2 == 0 && matches!(elem._type, WasmRefType::FuncRef) => 0,
+        WasmElemMode::Active(_) => 2,
+        WasmElemMode::Passive => 1,
+        WasmElemMode::Declarative => 3,
+    };
+    out.write_var_u32(mode_flags | if exprs { 4 } else { 0 });
+
+    if let WasmElemMode::Active(active) = &elem.mode {
+        if mode_flags == 2 {
+            out.write_var_u32(active.table);
+        }
+        write_expr(out, &active.offset_expr)?;
+    }
+
+    if mode_flags != 0 {
+        if exprs {
+            out.write_u8(reftype_to_byte(&elem._type));
+        } else {
+            // elemkind 0 is funcref
+            out.write_u8(0x00);
+        }
+    }
+
+    out.write_var_u32(elem.init.len() as u32);
+    for item in elem.init.iter() {
+        if exprs {
+            write_expr(out, item)?;
+            continue;
+        }
+        let Some(ExprSeg::Instr(instr)) = item.expr_string.first() else {
+            return Err(WyrmError::invalid("Elem item isn't a ref.func"));
+        };
+        let Some(ExprSeg::Func(idx)) = instr.get(1) else {
+            return Err(WyrmError::invalid("Elem item isn't a ref.func"));
+        };
+        out.write_var_u32(*idx);
+    }
+    Ok(())
+}
+
+fn write_data(out: &mut BinaryWriter, data: &WasmDataSeg) -> Result<(), WyrmError> {
+    match &data.header.mode {
+        WasmDataMode::Passive => out.write_var_u32(1),
+        WasmDataMode::Active { memory, offset_expr } => {
+            if *memory != 0 || data.header.header_flags == 2 {
+                out.write_var_u32(2);
+                out.write_var_u32(*memory);
+            } else {
+                out.write_var_u32(0);
+            }
+            write_expr(out, offset_expr)?;
+        }
+    }
+    out.write_name(&data.data);
+    Ok(())
+}
+
+/// A function that wasn't read from a binary, with its size and raw body filled in by encoding it
+pub fn encode_function(local_types: Vec<(u8, usize)>, body: WasmExpr) -> Result<WasmFunction, WyrmError> {
+    let mut encoded = BinaryWriter::new();
+    encoded.write_var_u32(local_types.len() as u32);
+    for (_type, count) in local_types.iter() {
+        encoded.write_var_u32(*count as u32);
+        encoded.write_u8(*_type);
+    }
+    let mut raw_body = BinaryWriter::new();
+    write_expr(&mut raw_body, &body)?;
+
+    let locals = local_types.iter()
+        .flat_map(|(_type, count)| (0..*count).map(|_| WasmLocal { _type: WasmTypeAnnotation { _type: *_type } }))
+        .collect();
+    Ok(WasmFunction {
+        size: encoded.len() + raw_body.len(),
+        local_types,
+        locals,
+        body,
+        raw_body: raw_body.bytes().iter().map(|b| *b as u64).collect(),
+        offset: 0,
+        instr_offsets: vec![],
+    })
+}
+
+fn write_function(out: &mut BinaryWriter, func: &WasmFunction) -> Result<(), WyrmError> {
+    let mut body = BinaryWriter::new();
+    body.write_var_u32(func.local_types.len() as u32);
+    for (_type, count) in func.local_types.iter() {
+        body.write_var_u32(*count as u32);
+        body.write_u8(*_type);
+    }
+    write_expr(&mut body, &func.body)?;
+    out.write_sized(&body);
+    Ok(())
+}
+
+/// Encodes a WasmFile, in the order the spec requires its sections to be in.
+/// Sizes and counts are recomputed, so the model can be edited before writing.
+// The custom sections that came after the known section `after`, with the names where the
+// name section was
+fn write_custom_sections(out: &mut BinaryWriter, wasm: &WasmFile, after: u8) {
+    for custom in wasm.custom_sections.iter().filter(|custom| custom.after == after) {
+        if custom.name.as_deref() == Some(b"name") {
+            write_name_section(out, &wasm.name_section);
+            continue;
+        }
+        let mut section = BinaryWriter::new();
+        if let Some(name) = &custom.name {
+            section.write_name(name);
+        }
+        section.write_bytes(&custom.data);
+        write_section(out, 0x00, &section);
+    }
+}
+
+pub fn wasm_serialize_bytes(wasm: &WasmFile) -> Result<Vec<u8>, WyrmError> {
+    let mut out = BinaryWriter::new();
+    out.write_u32(wasm.wasm_header.magic_number);
+    out.write_u32(wasm.wasm_header.version);
+    write_custom_sections(&mut out, wasm, 0);
+
+    write_vec_section(&mut out, 0x01, &wasm.type_section.function_signatures, |out, _, sig| {
+        out.write_u8(sig.func);
+        out.write_var_u32(sig.params.len() as u32);
+        sig.params.iter().for_each(|param| out.write_u8(param._type));
+        out.write_var_u32(sig.results.len() as u32);
+        sig.results.iter().for_each(|result| out.write_u8(result._type));
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x01);
+
+    write_vec_section(&mut out, 0x02, &wasm.import_section_header.imports, |out, _, import| {
+        out.write_name(import.import_module_name.as_bytes());
+        out.write_name(import.import_field.as_bytes());
+        match &import.desc {
+            WasmImportDesc::Func(sig) => {
+                out.write_u8(0x00);
+                out.write_var_u32(*sig);
+            }
+            WasmImportDesc::Table(table) => {
+                out.write_u8(0x01);
+                write_table_type(out, table);
+            }
+            WasmImportDesc::Mem(memory) => {
+                out.write_u8(0x02);
+                write_limits(out, &memory.limits);
+            }
+            WasmImportDesc::Global { wasm_type, mutability } => {
+                out.write_u8(0x03);
+                out.write_u8(wasm_type._type);
+                out.write_u8(*mutability);
+            }
+            WasmImportDesc::Tag(tag) => {
+                out.write_u8(0x04);
+                write_tag_type(out, tag);
+            }
+        }
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x02);
+
+    write_vec_section(&mut out, 0x03, &wasm.function_section.function_signature_indexes, |out, _, sig| {
+        out.write_var_u32(*sig);
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x03);
+
+    write_vec_section(&mut out, 0x04, &wasm.table_section.tables, |out, _, table| {
+        write_table_type(out, table);
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x04);
+
+    write_vec_section(&mut out, 0x05, &wasm.memory_section.memories, |out, _, memory| {
+        write_limits(out, &memory.limits);
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x05);
+
+    write_vec_section(&mut out, 0x0d, &wasm.tag_section.tags, |out, _, tag| {
+        write_tag_type(out, tag);
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x0d);
+
+    write_vec_section(&mut out, 0x06, &wasm.global_section.globals, |out, _, global| {
+        out.write_u8(global.wasm_type._type);
+        out.write_u8(global.mutability);
+        write_expr(out, &global.expr)
+    })?;
+    write_custom_sections(&mut out, wasm, 0x06);
+
+    write_vec_section(&mut out, 0x07, &wasm.export_section.exports, |out, _, export| {
+        out.write_name(export.export_name.as_bytes());
+        out.write_u8(export.export_kind);
+        out.write_var_u32(export.export_signature_index);
+        Ok(())
+    })?;
+    write_custom_sections(&mut out, wasm, 0x07);
+
+    if let Some(start) = &wasm.start_section {
+        let mut section = BinaryWriter::new();
+        section.write_var_u32(start.func);
+        write_section(&mut out, 0x08, &section);
+    }
+    write_custom_sections(&mut out, wasm, 0x08);
+
+    write_vec_section(&mut out, 0x09, &wasm.elem_section.elems, |out, _, elem| write_elem(out, elem))?;
+    write_custom_sections(&mut out, wasm, 0x09);
+
+    // Only modules that came with a data count get one back, it's optional otherwise
+    if wasm.data_count_section.is_some() {
+        let mut section = BinaryWriter::new();
+        section.write_var_u32(wasm.data_section.data_segs.len() as u32);
+        write_section(&mut out, 0x0c, &section);
+    }
+    write_custom_sections(&mut out, wasm, 0x0c);
+
+    write_vec_section(&mut out, 0x0a, &wasm.code_section.functions, |out, i, func| {
+        write_function(out, func).map_err(|e| e.in_func(wasm.defined_func_idx(i)))
+    })?;
+    write_custom_sections(&mut out, wasm, 0x0a);
+
+    write_vec_section(&mut out, 0x0b, &wasm.data_section.data_segs, |out, _, data| write_data(out, data))?;
+    write_custom_sections(&mut out, wasm, 0x0b);
+
+    // Modules that weren't read from a binary have their names last
+    if !wasm.custom_sections.iter().any(|custom| custom.name.as_deref() == Some(b"name")) {
+        write_name_section(&mut out, &wasm.name_section);
+    }
+
+    Ok(out.into_bytes())
+}
+
+pub fn wasm_serialize(wasm: &WasmFile, mut out: impl Write) -> Result<(), WyrmError> {
+    out.write_all(&wasm_serialize_bytes(wasm)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::wasm_deserialize_bytes;
+
+    fn section(out: &mut BinaryWriter, id: u8, build: impl FnOnce(&mut BinaryWriter)) {
+        let mut contents = BinaryWriter::new();
+        build(&mut contents);
+        write_section(out, id, &contents);
+    }
+
+    // A module in canonical form (shortest LEB128s, every section in order) touching
+    // each section and most kinds of immediates, with a custom section after the types
+    fn sample_module() -> Vec<u8> {
+        let mut module = BinaryWriter::new();
+        module.write_bytes(b"\0asm\x01\0\0\0");
+
+        section(&mut module, 0x01, |out| {
+            out.write_var_u32(2);
+            out.write_bytes(&[0x60, 0x01, 0x7f, 0x01, 0x7f]);
+            out.write_bytes(&[0x60, 0x00, 0x00]);
+        });
+        section(&mut module, 0x00, |out| {
+            out.write_name(b"producers");
+            out.write_bytes(&[0x01, 0x02, 0x03]);
+        });
+        section(&mut module, 0x02, |out| {
+            out.write_var_u32(2);
+            out.write_name(b"env");
+            out.write_name(b"log");
+            out.write_bytes(&[0x00, 0x00]);
+            out.write_name(b"env");
+            out.write_name(b"mem");
+            out.write_bytes(&[0x02, 0x03, 0x11, 0x80, 0x02]);
+        });
+        section(&mut module, 0x03, |out| out.write_bytes(&[0x02, 0x00, 0x01]));
+        section(&mut module, 0x04, |out| out.write_bytes(&[0x01, 0x70, 0x00, 0x02]));
+        section(&mut module, 0x05, |out| out.write_bytes(&[0x01, 0x00, 0x01]));
+        section(&mut module, 0x0d, |out| out.write_bytes(&[0x01, 0x00, 0x01]));
+        section(&mut module, 0x06, |out| out.write_bytes(&[0x01, 0x7f, 0x01, 0x41, 0x7b, 0x0b]));
+        section(&mut module, 0x07, |out| {
+            out.write_var_u32(1);
+            out.write_name(b"run");
+            out.write_bytes(&[0x00, 0x01]);
+        });
+        section(&mut module, 0x08, |out| out.write_var_u32(2));
+        section(&mut module, 0x09, |out| {
+            out.write_var_u32(2);
+            out.write_bytes(&[0x00, 0x41, 0x00, 0x0b, 0x01, 0x01]);
+            out.write_bytes(&[0x05, 0x70, 0x02, 0xd0, 0x70, 0x0b, 0xd2, 0x02, 0x0b]);
+        });
+        section(&mut module, 0x0c, |out| out.write_var_u32(2));
+        section(&mut module, 0x0a, |out| {
+            out.write_var_u32(2);
+            let mut body = BinaryWriter::new();
+            // One i64 local
+            body.write_bytes(&[0x01, 0x01, 0x7e]);
+            // block (result i32) / loop / if / else / br_table
+            body.write_bytes(&[0x02, 0x7f, 0x03, 0x40, 0x20, 0x00, 0x04, 0x40, 0x0c, 0x01, 0x05]);
+            body.write_bytes(&[0x20, 0x00, 0x0e, 0x02, 0x00, 0x01, 0x00, 0x0b, 0x0b, 0x41, 0x01, 0x0b, 0x1a]);
+            // i64.const, f32.const, f64.const
+            body.write_u8(0x42);
+            body.write_var_i64(-1234567890123);
+            body.write_bytes(&[0x21, 0x01, 0x43]);
+            body.write_f32(1.5);
+            body.write_bytes(&[0x1a, 0x44]);
+            body.write_f64(-2.25);
+            body.write_u8(0x1a);
+            // i32.load offset=70000, i32.store8 align=1
+            body.write_bytes(&[0x41, 0x00, 0x28, 0x02, 0xf0, 0xa2, 0x04, 0x1a]);
+            body.write_bytes(&[0x41, 0x00, 0x41, 0x07, 0x3a, 0x00, 0x00]);
+            // call_indirect, call
+            body.write_bytes(&[0x41, 0x00, 0x41, 0x00, 0x11, 0x00, 0x00, 0x10, 0x00, 0x1a]);
+            // v128.const, i8x16.shuffle, drop
+            body.write_bytes(&[0xfd, 0x0c]);
+            body.write_u128(0x0f0e0d0c0b0a09080706050403020100);
+            body.write_bytes(&[0xfd, 0x0c]);
+            body.write_u128(0);
+            body.write_bytes(&[0xfd, 0x0d]);
+            body.write_bytes(&[15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
+            body.write_u8(0x1a);
+            // memory.init 1, data.drop 1, atomic.fence, ref.null func
+            body.write_bytes(&[0x41, 0x00, 0x41, 0x00, 0x41, 0x00, 0xfc, 0x08, 0x01, 0x00]);
+            body.write_bytes(&[0xfc, 0x09, 0x01, 0xfe, 0x03, 0x00, 0xd0, 0x70, 0x1a]);
+            body.write_bytes(&[0x41, 0x2a, 0x0b]);
+            out.write_sized(&body);
+            out.write_sized(&{
+                let mut body = BinaryWriter::new();
+                body.write_bytes(&[0x00, 0x0b]);
+                body
+            });
+        });
+        section(&mut module, 0x0b, |out| {
+            out.write_var_u32(2);
+            out.write_bytes(&[0x00, 0x41, 0x10, 0x0b]);
+            out.write_name(b"hi");
+            out.write_var_u32(1);
+            out.write_name(&[0, 1, 2]);
+        });
+        section(&mut module, 0x00, |out| {
+            out.write_name(b"name");
+            section(out, 0x01, |out| {
+                out.write_var_u32(1);
+                out.write_var_u32(1);
+                out.write_name(b"run");
+            });
+            section(out, 0x02, |out| {
+                out.write_var_u32(1);
+                out.write_var_u32(1);
+                out.write_var_u32(1);
+                out.write_var_u32(0);
+                out.write_name(b"x");
+            });
+        });
+        module.into_bytes()
+    }
+
+    #[test]
+    fn round_trips_modules() {
+        let module = sample_module();
+        let wasm = wasm_deserialize_bytes(&module).unwrap();
+        let customs: Vec<_> = wasm.custom_sections.iter().map(|custom| (custom.name.as_deref(), custom.after)).collect();
+        assert_eq!(customs, vec![(Some(&b"producers"[..]), 0x01), (Some(&b"name"[..]), 0x0b)]);
+        let written = wasm_serialize_bytes(&wasm).unwrap();
+        assert_eq!(written, module);
+    }
+
+    #[test]
+    fn writes_edited_modules() {
+        let mut wasm = wasm_deserialize_bytes(&sample_module()).unwrap();
+        wasm.export_section.exports[0].export_name = "renamed".to_string();
+        wasm.data_section.data_segs[1].data = vec![0; 300];
+
+        let mut written = vec![];
+        wasm_serialize(&wasm, &mut written).unwrap();
+        let reread = wasm_deserialize_bytes(&written).unwrap();
+        assert_eq!(reread.export_section.exports[0].export_name, "renamed");
+        assert_eq!(reread.data_section.data_segs[1].data.len(), 300);
+        assert_eq!(wasm_serialize_bytes(&reread).unwrap(), written);
+    }
+}
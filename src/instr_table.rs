@@ -1,13 +1,12 @@
 use crate::wasm_model::*;
-use crate::parser::prs;
  
 
 include!(concat!(env!("OUT_DIR"), "/instr_table.rs"));
 
 
-// how 2 constexpr???
+/// how 2 constexpr???
 pub fn get_instr(name: &str) -> Option<InstrInfo> {
-    INSTRS.iter().find(|x| {x.name == name}).map(|x| {*x})
+    INSTRS.iter().chain(PREFIXED_INSTRS.iter()).find(|x| {x.name == name}).copied()
 }
 
 pub fn get_instr_from_op(opcode: u64) -> Option<InstrInfo> {
@@ -24,4 +23,21 @@ pub fn get_special_sim(sym: u64) -> Option<String> {
     } else {
         None
     }
-}
\ No newline at end of file
+}
+
+pub const GC_PREFIX: u8 = 0xfb;
+pub const MISC_PREFIX: u8 = 0xfc;
+pub const SIMD_PREFIX: u8 = 0xfd;
+pub const ATOMIC_PREFIX: u8 = 0xfe;
+
+pub fn is_prefix(byte: u8) -> bool {
+    matches!(byte, GC_PREFIX | MISC_PREFIX | SIMD_PREFIX | ATOMIC_PREFIX)
+}
+
+/// Prefixed instructions are looked up by their prefix byte and LEB128 sub-opcode
+pub fn get_prefixed_instr(prefix: u8, opcode: u32) -> Option<InstrInfo> {
+    PREFIXED_INSTRS
+        .binary_search_by_key(&(prefix, opcode), |x| (x.prefix, x.instr))
+        .ok()
+        .map(|i| PREFIXED_INSTRS[i])
+}
@@ -0,0 +1,201 @@
+use std::io::Write;
+
+use serde_json::{json, Value};
+
+use crate::emitter::*;
+use crate::error::WyrmError;
+use crate::json_model::*;
+use crate::wasm_model::*;
+
+/// A JSON string, with quotes, backslashes and control characters escaped
+pub fn json_string(s: &str) -> String {
+    let mut json = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            '\n' => json += "\\n",
+            '\r' => json += "\\r",
+            '\t' => json += "\\t",
+            c if (c as u32) < 0x20 => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+    json + "\""
+}
+
+/// Writes the module as one JSON document, e.g. for scripts that want the disassembly:
+///
+/// ```text
+/// {"module": "$id" or null, "items": [
+///   {"kind": "type", "index": 0, "entry": {"params": [], "results": []}},
+///   {"kind": "func", "index": 1, "name": "$f", "offset": 36, "instructions": [
+///     {"kind": "instr", "offset": 38, "depth": 0, "bytes": "2000", "op": "local.get", "prefix": 0,
+///      "opcode": 32, "imm": [{"local": 0}], "text": "local.get $var0"},
+///     ...]},
+///   ...]}
+/// ```
+///
+/// Fields outside of the code section have the same entry json_model writes for them, and
+/// exports and the start function have no index. Each instruction's kind is one of "instr",
+/// "block", "else" (a `catch` or `catch_all` too), "end" (a `delegate` too) and "end_func", and
+/// its immediates are json_model's too. A block's are its type, and a try_table also has its
+/// "catches". Offset and bytes are null and "" for functions that weren't read from a binary.
+#[derive(Default)]
+pub struct JsonEmitter {
+    // Whether anything has been written to the list being written, so commas go between entries
+    wrote_item: bool,
+    wrote_instr: bool,
+}
+
+impl JsonEmitter {
+    fn item(&mut self, out: &mut dyn Write, json: &str) -> Result<(), WyrmError> {
+        let separator = if self.wrote_item { ",\n" } else { "\n" };
+        self.wrote_item = true;
+        write!(out, "{}  {}", separator, json)?;
+        Ok(())
+    }
+
+    fn site(&mut self, out: &mut dyn Write, kind: &str, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        let separator = if self.wrote_instr { ",\n" } else { "\n" };
+        self.wrote_instr = true;
+        let offset = site.offset.map(|offset| offset.to_string()).unwrap_or("null".to_string());
+        let bytes: String = site.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        // A block's header gets the whole block, and its end only the `end` or `delegate`
+        let (imm, catches): (Vec<Value>, &[CatchClause]) = match site.seg {
+            ExprSeg::Instr(layout) => (layout[1..].iter().map(immediate_json).collect::<Result<_, _>>()?, &[]),
+            ExprSeg::ControlFlow(_, block_type, _, _, catches) => (vec![json!({ "block_type": block_type })], catches),
+            _ => (vec![], &[]),
+        };
+        let catches = if catches.is_empty() {
+            "".to_string()
+        } else {
+            format!(", \"catches\": {}", Value::Array(catches.iter().map(catch_json).collect()))
+        };
+        write!(
+            out,
+            "{}    {{\"kind\": {}, \"offset\": {}, \"depth\": {}, \"bytes\": {}, \"op\": {}, \"prefix\": {}, \"opcode\": {}, \"imm\": {}{}, \"text\": {}}}",
+            separator,
+            json_string(kind),
+            offset,
+            site.depth,
+            json_string(&bytes),
+            json_string(site.info.name),
+            site.info.prefix,
+            site.info.instr,
+            Value::Array(imm),
+            catches,
+            json_string(&site.text)
+        )?;
+        Ok(())
+    }
+}
+
+impl Emitter for JsonEmitter {
+    fn begin_module(&mut self, out: &mut dyn Write, module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        let id = module.names.module_id().map(|id| json_string(&id)).unwrap_or("null".to_string());
+        write!(out, "{{\"module\": {}, \"items\": [", id)?;
+        Ok(())
+    }
+
+    fn field(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, field: Field<'_>) -> Result<(), WyrmError> {
+        let (kind, index, entry) = match field {
+            Field::Type(i, sig) => ("type", Some(i), type_json(sig)),
+            Field::Import(i, import) => ("import", Some(i), import_json(import)),
+            Field::Table(i, table) => ("table", Some(i), table_json(table)),
+            Field::Memory(i, memory) => ("memory", Some(i), memory_json(memory)),
+            Field::Tag(i, tag) => ("tag", Some(i), tag_json(tag)),
+            Field::Global(i, global) => ("global", Some(i), global_json(global)?),
+            Field::Export(export) => ("export", None, export_json(export)),
+            Field::Start(start) => ("start", None, json!(start.func)),
+            Field::Elem(i, elem) => ("elem", Some(i), elem_json(elem)?),
+            Field::Data(i, data) => ("data", Some(i), data_json(data)?),
+        };
+        let index = index.map(|i| format!(", \"index\": {}", i)).unwrap_or_default();
+        self.item(out, &format!("{{\"kind\": {}{}, \"entry\": {}}}", json_string(kind), index, entry))
+    }
+
+    fn begin_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>) -> Result<(), WyrmError> {
+        let offset = if func.func.instr_offsets.is_empty() { "null".to_string() } else { func.func.offset.to_string() };
+        self.item(out, &format!(
+            "{{\"kind\": \"func\", \"index\": {}, \"name\": {}, \"offset\": {}, \"instructions\": [",
+            func.funcidx,
+            json_string(&module.names.func_id(func.funcidx)),
+            offset
+        ))?;
+        self.wrote_instr = false;
+        Ok(())
+    }
+
+    fn instr(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.site(out, "instr", site)
+    }
+
+    fn begin_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.site(out, "block", site)
+    }
+
+    fn else_arm(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.site(out, "else", site)
+    }
+
+    fn end_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.site(out, "end", site)
+    }
+
+    fn end_func(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.site(out, "end_func", site)?;
+        write!(out, "]}}")?;
+        Ok(())
+    }
+
+    fn end_module(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        writeln!(out, "\n]}}")?;
+        Ok(())
+    }
+}
+
+pub fn emit_json(wasm: &WasmFile) -> Result<String, WyrmError> {
+    emit_to_string(wasm, &mut JsonEmitter::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}é"), "\"a\\\"b\\\\c\\n\\u0001é\"");
+    }
+
+    #[test]
+    fn lists_fields_and_instructions() {
+        let wasm = parse_wat(
+            "(module $m
+              (func $f (export \"f\") (param $a i32) (result i32)
+                local.get $a
+                block (result i32)
+                  i32.const 1
+                end
+                i32.add))",
+        )
+        .unwrap();
+        let json = emit_json(&wasm).unwrap();
+        assert_eq!(
+            json,
+            r#"{"module": "$m", "items": [
+  {"kind": "type", "index": 0, "entry": {"params":["i32"],"results":["i32"]}},
+  {"kind": "export", "entry": {"index":0,"kind":"func","name":"f"}},
+  {"kind": "func", "index": 0, "name": "$f", "offset": null, "instructions": [
+    {"kind": "instr", "offset": null, "depth": 0, "bytes": "", "op": "local.get", "prefix": 0, "opcode": 32, "imm": [{"local":0}], "text": "local.get $a"},
+    {"kind": "block", "offset": null, "depth": 0, "bytes": "", "op": "block", "prefix": 0, "opcode": 2, "imm": [{"block_type":-1}], "text": "block $label0 (result i32)"},
+    {"kind": "instr", "offset": null, "depth": 1, "bytes": "", "op": "i32.const", "prefix": 0, "opcode": 65, "imm": [{"int":1}], "text": "i32.const 1"},
+    {"kind": "end", "offset": null, "depth": 0, "bytes": "", "op": "end", "prefix": 0, "opcode": 11, "imm": [], "text": "end $label0"},
+    {"kind": "instr", "offset": null, "depth": 0, "bytes": "", "op": "i32.add", "prefix": 0, "opcode": 106, "imm": [], "text": "i32.add"},
+    {"kind": "end_func", "offset": null, "depth": 0, "bytes": "", "op": "end", "prefix": 0, "opcode": 11, "imm": [], "text": "end"}]}
+]}
+"#
+        );
+    }
+}
@@ -0,0 +1,796 @@
+//! The whole module as JSON, for tools that want to read or build modules without a wasm parser.
+//! Unlike json_emitter, which is the disassembly, this keeps everything needed to write the module
+//! back out. The document looks like:
+//!
+//! ```text
+//! {"format": "wyrm-module", "version": 1,
+//!  "header": {"magic": 1836278016, "version": 1},
+//!  "types": [{"params": ["i32"], "results": []}],
+//!  "imports": [{"module": "env", "name": "f", "kind": "func", "type": 0}],
+//!  "functions": [{"index": 1, "type": 0, "locals": [{"count": 1, "type": "i64"}], "body": [
+//!    {"op": "local.get", "prefix": 0, "opcode": 32, "imm": [{"local": 0}]},
+//!    {"op": "block", "prefix": 0, "opcode": 2, "block_type": -64, "body": [...]}]}],
+//!  "tables", "memories", "tags", "globals", "exports", "start", "elems", "data_count", "data", "names",
+//!  "custom_sections": [{"name": "producers", "after": 11, "bytes": "0102"}]}
+//! ```
+//!
+//! Value types are their WAT names where they have one and their type byte otherwise. Names of
+//! imports and exports are strings, and so are custom section names when they're UTF-8, which
+//! are {"hex": "..."} when they aren't. A custom section whose name couldn't be read has a null
+//! name, and all of its bytes in "bytes".
+//! Expressions leave out the `end` that finishes them, and `else` is an instruction of its own, as
+//! are `catch` and `catch_all`. A try finished by `delegate` gives its label as "delegate", and a
+//! try_table's handlers are its "catches", e.g. {"kind": "catch", "tag": 0, "label": 1}.
+//! Immediates are objects with one key saying what they are, e.g. {"int": -1}, {"func": 3},
+//! {"memarg": {"align": 2, "offset": 0, "memory": 0}}. Floats are given as their bits in hex,
+//! so NaN payloads survive.
+
+use serde_json::{json, Map, Value};
+
+use crate::error::WyrmError;
+use crate::file_writer::encode_function;
+use crate::instr_table::{get_prefixed_instr, DELEGATE, END, INSTRS};
+use crate::wasm_model::*;
+use crate::wat_emitter::type_to_str;
+
+pub const JSON_FORMAT: &str = "wyrm-module";
+/// Bumped whenever the schema changes in a way older readers can't follow
+pub const JSON_VERSION: u64 = 1;
+
+const IMPORT_KINDS: [&str; 5] = ["func", "table", "memory", "global", "tag"];
+
+fn value_type_json(_type: u8) -> Value {
+    match type_to_str(WasmTypeAnnotation { _type }) {
+        name if name.is_empty() => json!(_type),
+        name => json!(name),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn bytes_json(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => json!(s),
+        Err(_) => json!({ "hex": hex(bytes) }),
+    }
+}
+
+fn limits_json(limits: &Limits) -> Value {
+    json!({ "initial": limits.initial, "max": limits.max, "shared": limits.shared, "is_64": limits.is_64 })
+}
+
+pub fn immediate_json(seg: &ExprSeg) -> Result<Value, WyrmError> {
+    Ok(match seg {
+        ExprSeg::Int(n) => json!({ "int": n }),
+        ExprSeg::Float32(f) => json!({ "f32": format!("{:#010x}", f.to_bits()) }),
+        ExprSeg::Float64(f) => json!({ "f64": format!("{:#018x}", f.to_bits()) }),
+        ExprSeg::V128(v) => json!({ "v128": format!("{:#034x}", v) }),
+        ExprSeg::Local(i) => json!({ "local": i }),
+        ExprSeg::Global(i) => json!({ "global": i }),
+        ExprSeg::Func(i) => json!({ "func": i }),
+        ExprSeg::Table(i) => json!({ "table": i }),
+        ExprSeg::Memory(i) => json!({ "memory": i }),
+        ExprSeg::Type(i) => json!({ "type": i }),
+        ExprSeg::Data(i) => json!({ "data": i }),
+        ExprSeg::Elem(i) => json!({ "elem": i }),
+        ExprSeg::Tag(i) => json!({ "tag": i }),
+        ExprSeg::HeapType(heap) => json!({ "heap_type": heap }),
+        ExprSeg::RefType { nullable, heap } => json!({ "ref_type": { "nullable": nullable, "heap": heap } }),
+        ExprSeg::MemArg(memarg) => {
+            json!({ "memarg": { "align": memarg.align, "offset": memarg.offset, "memory": memarg.memory } })
+        }
+        ExprSeg::BlockType(block_type) => json!({ "block_type": block_type }),
+        ExprSeg::BrTable(table) => json!({ "br_table": { "depths": table.break_depths, "default": table.default } }),
+        seg => return Err(WyrmError::unsupported(format!("Can't write {:?} as an immediate", seg))),
+    })
+}
+
+fn op_json(info: &InstrInfo) -> Map<String, Value> {
+    let mut instr = Map::new();
+    instr.insert("op".to_string(), json!(info.name));
+    instr.insert("prefix".to_string(), json!(info.prefix));
+    instr.insert("opcode".to_string(), json!(info.instr));
+    instr
+}
+
+fn instr_json(seg: &ExprSeg) -> Result<Value, WyrmError> {
+    match seg {
+        ExprSeg::Instr(layout) => {
+            let Some(ExprSeg::Operation(info)) = layout.first() else {
+                return Err(WyrmError::invalid("Instruction without an operation"));
+            };
+            let mut instr = op_json(info);
+            let imm = layout[1..].iter().map(immediate_json).collect::<Result<Vec<_>, _>>()?;
+            instr.insert("imm".to_string(), Value::Array(imm));
+            Ok(Value::Object(instr))
+        }
+        ExprSeg::ControlFlow(info, block_type, body, _, catches) => {
+            let mut instr = op_json(info);
+            instr.insert("block_type".to_string(), json!(block_type));
+            if !catches.is_empty() {
+                instr.insert("catches".to_string(), catches.iter().map(catch_json).collect());
+            }
+            instr.insert("body".to_string(), expr_json(body)?);
+            if let Some(ExprSeg::Instr(delegate)) = body.expr_string.last() {
+                if let Some(ExprSeg::Int(label)) = delegate.get(1) {
+                    instr.insert("delegate".to_string(), json!(label));
+                }
+            }
+            Ok(Value::Object(instr))
+        }
+        seg => Err(WyrmError::unsupported(format!("Can't write {:?} as an instruction", seg))),
+    }
+}
+
+pub fn catch_json(catch: &CatchClause) -> Value {
+    let mut clause = Map::new();
+    clause.insert("kind".to_string(), json!(catch.name()));
+    if let Some(tag) = catch.tag() {
+        clause.insert("tag".to_string(), json!(tag));
+    }
+    clause.insert("label".to_string(), json!(catch.label()));
+    Value::Object(clause)
+}
+
+// The instructions of an expression, without the `end` it finishes with
+pub fn expr_json(expr: &WasmExpr) -> Result<Value, WyrmError> {
+    let instrs = expr.expr_string.iter().filter(|seg| !is_block_end(seg)).map(instr_json).collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::Array(instrs))
+}
+
+fn name_map_json(names: &WasmNameMap) -> Value {
+    Value::Object(names.iter().map(|(i, name)| (i.to_string(), json!(name))).collect())
+}
+
+fn indirect_name_map_json(names: &WasmIndirectNameMap) -> Value {
+    Value::Object(names.iter().map(|(i, inner)| (i.to_string(), name_map_json(inner))).collect())
+}
+
+fn names_json(names: &WasmNameSection) -> Value {
+    json!({
+        "module": names.module_name,
+        "functions": name_map_json(&names.function_names),
+        "locals": indirect_name_map_json(&names.local_names),
+        "labels": indirect_name_map_json(&names.label_names),
+        "types": name_map_json(&names.type_names),
+        "tables": name_map_json(&names.table_names),
+        "memories": name_map_json(&names.memory_names),
+        "globals": name_map_json(&names.global_names),
+        "elems": name_map_json(&names.elem_names),
+        "data": name_map_json(&names.data_names),
+        "tags": name_map_json(&names.tag_names),
+    })
+}
+
+pub fn import_json(import: &WasmImportHeader) -> Value {
+    let mut json = json!({
+        "module": import.import_module_name,
+        "name": import.import_field,
+    });
+    let desc = match &import.desc {
+        WasmImportDesc::Func(sig) => json!({ "kind": "func", "type": sig }),
+        WasmImportDesc::Table(table) => {
+            json!({ "kind": "table", "type": value_type_json(table.wasm_type), "limits": limits_json(&table.limits) })
+        }
+        WasmImportDesc::Mem(memory) => json!({ "kind": "memory", "limits": limits_json(&memory.limits) }),
+        WasmImportDesc::Global { wasm_type, mutability } => {
+            json!({ "kind": "global", "type": value_type_json(wasm_type._type), "mutable": *mutability != 0 })
+        }
+        WasmImportDesc::Tag(tag) => json!({ "kind": "tag", "attribute": tag.attribute, "type": tag.type_index }),
+    };
+    if let (Value::Object(json), Value::Object(desc)) = (&mut json, desc) {
+        json.extend(desc);
+    }
+    json
+}
+
+pub fn elem_json(elem: &WasmElem) -> Result<Value, WyrmError> {
+    let mode = match &elem.mode {
+        WasmElemMode::Passive => json!({ "kind": "passive" }),
+        WasmElemMode::Declarative => json!({ "kind": "declarative" }),
+        WasmElemMode::Active(active) => {
+            json!({ "kind": "active", "table": active.table, "offset": expr_json(&active.offset_expr)? })
+        }
+    };
+    Ok(json!({
+        "flags": elem.flags,
+        "type": value_type_json(reftype_to_byte(&elem._type)),
+        "mode": mode,
+        "items": elem.init.iter().map(expr_json).collect::<Result<Vec<_>, _>>()?,
+    }))
+}
+
+pub fn data_json(data: &WasmDataSeg) -> Result<Value, WyrmError> {
+    let mode = match &data.header.mode {
+        WasmDataMode::Passive => json!({ "kind": "passive" }),
+        WasmDataMode::Active { memory, offset_expr } => {
+            json!({ "kind": "active", "memory": memory, "offset": expr_json(offset_expr)? })
+        }
+    };
+    Ok(json!({ "flags": data.header.header_flags, "mode": mode, "bytes": hex(&data.data) }))
+}
+
+pub fn type_json(sig: &WasmFunctionType) -> Value {
+    json!({
+        "params": sig.params.iter().map(|t| value_type_json(t._type)).collect::<Vec<_>>(),
+        "results": sig.results.iter().map(|t| value_type_json(t._type)).collect::<Vec<_>>(),
+    })
+}
+
+pub fn table_json(table: &WasmTable) -> Value {
+    json!({ "type": value_type_json(table.wasm_type), "limits": limits_json(&table.limits) })
+}
+
+pub fn memory_json(memory: &WasmMemoryStruct) -> Value {
+    json!({ "limits": limits_json(&memory.limits) })
+}
+
+pub fn tag_json(tag: &WasmTag) -> Value {
+    json!({ "attribute": tag.attribute, "type": tag.type_index })
+}
+
+pub fn global_json(global: &WasmGlobal) -> Result<Value, WyrmError> {
+    Ok(json!({
+        "type": value_type_json(global.wasm_type._type),
+        "mutable": global.mutability != 0,
+        "init": expr_json(&global.expr)?,
+    }))
+}
+
+pub fn export_json(export: &WasmExportHeader) -> Value {
+    let kind = IMPORT_KINDS.get(export.export_kind as usize).map(|kind| json!(kind)).unwrap_or(json!(export.export_kind));
+    json!({ "name": export.export_name, "kind": kind, "index": export.export_signature_index })
+}
+
+pub fn wasm_to_json(wasm: &WasmFile) -> Result<Value, WyrmError> {
+    let types: Vec<Value> = wasm.type_section.function_signatures.iter().map(type_json).collect();
+
+    let mut functions = vec![];
+    for (i, func) in wasm.code_section.functions.iter().enumerate() {
+        let funcidx = wasm.defined_func_idx(i);
+        let Some(sig) = wasm.function_section.function_signature_indexes.get(i) else {
+            return Err(WyrmError::invalid("Function without a type").in_section(0x03).in_func(funcidx));
+        };
+        let locals: Vec<Value> = func.local_types.iter()
+            .map(|(_type, count)| json!({ "count": count, "type": value_type_json(*_type) }))
+            .collect();
+        let body = expr_json(&func.body).map_err(|e| e.in_func(funcidx))?;
+        functions.push(json!({ "index": funcidx, "type": sig, "locals": locals, "body": body }));
+    }
+
+    let globals = wasm.global_section.globals.iter().map(global_json).collect::<Result<Vec<_>, _>>()?;
+    let exports: Vec<Value> = wasm.export_section.exports.iter().map(export_json).collect();
+    let data_count = wasm.data_count_section.as_ref().map(|section| section.datacount);
+
+    Ok(json!({
+        "format": JSON_FORMAT,
+        "version": JSON_VERSION,
+        "header": { "magic": wasm.wasm_header.magic_number, "version": wasm.wasm_header.version },
+        "types": types,
+        "imports": wasm.import_section_header.imports.iter().map(import_json).collect::<Vec<_>>(),
+        "functions": functions,
+        "tables": wasm.table_section.tables.iter().map(table_json).collect::<Vec<_>>(),
+        "memories": wasm.memory_section.memories.iter().map(memory_json).collect::<Vec<_>>(),
+        "tags": wasm.tag_section.tags.iter().map(tag_json).collect::<Vec<_>>(),
+        "globals": globals,
+        "exports": exports,
+        "start": wasm.start_section.as_ref().map(|start| start.func),
+        "elems": wasm.elem_section.elems.iter().map(elem_json).collect::<Result<Vec<_>, _>>()?,
+        "data_count": data_count,
+        "data": wasm.data_section.data_segs.iter().map(data_json).collect::<Result<Vec<_>, _>>()?,
+        "names": names_json(&wasm.name_section),
+        "custom_sections": wasm.custom_sections.iter()
+            .map(|custom| json!({ "name": custom.name.as_deref().map(bytes_json), "after": custom.after, "bytes": hex(&custom.data) }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+pub fn wasm_to_json_string(wasm: &WasmFile) -> Result<String, WyrmError> {
+    serde_json::to_string_pretty(&wasm_to_json(wasm)?).map_err(|e| WyrmError::invalid(e.to_string()))
+}
+
+// Reading the JSON back. Every missing or mistyped field is an error naming the field.
+
+fn field<'a>(value: &'a Value, key: &str) -> Result<&'a Value, WyrmError> {
+    value.get(key).ok_or_else(|| WyrmError::invalid(format!("JSON: missing \"{}\"", key)))
+}
+
+fn expected(key: &str, what: &str) -> WyrmError {
+    WyrmError::invalid(format!("JSON: expected \"{}\" to be {}", key, what))
+}
+
+fn u64_field(value: &Value, key: &str) -> Result<u64, WyrmError> {
+    field(value, key)?.as_u64().ok_or_else(|| expected(key, "an unsigned integer"))
+}
+
+fn usize_field(value: &Value, key: &str) -> Result<usize, WyrmError> {
+    Ok(u64_field(value, key)? as usize)
+}
+
+// Indices are u32 in the model, as in the binary format
+fn as_index(value: &Value) -> Option<u32> {
+    value.as_u64().and_then(|n| u32::try_from(n).ok())
+}
+
+fn index_field(value: &Value, key: &str) -> Result<u32, WyrmError> {
+    as_index(field(value, key)?).ok_or_else(|| expected(key, "an index"))
+}
+
+fn i64_field(value: &Value, key: &str) -> Result<i64, WyrmError> {
+    field(value, key)?.as_i64().ok_or_else(|| expected(key, "an integer"))
+}
+
+fn bool_field(value: &Value, key: &str) -> Result<bool, WyrmError> {
+    field(value, key)?.as_bool().ok_or_else(|| expected(key, "a boolean"))
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> Result<&'a str, WyrmError> {
+    field(value, key)?.as_str().ok_or_else(|| expected(key, "a string"))
+}
+
+fn array_field<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>, WyrmError> {
+    field(value, key)?.as_array().ok_or_else(|| expected(key, "an array"))
+}
+
+fn hex_bytes(hex: &str) -> Result<Vec<u8>, WyrmError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(WyrmError::invalid(format!("JSON: odd number of hex digits in {:?}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| WyrmError::invalid(format!("JSON: invalid hex {:?}", hex)))
+}
+
+// Parses the `0x...` bits floats and v128s are written with
+fn hex_bits(value: &Value, key: &str) -> Result<u128, WyrmError> {
+    let bits = value.as_str().and_then(|s| s.strip_prefix("0x")).ok_or_else(|| expected(key, "a hex string"))?;
+    u128::from_str_radix(bits, 16).map_err(|_| expected(key, "a hex string"))
+}
+
+fn bytes_field(value: &Value, key: &str) -> Result<Vec<u8>, WyrmError> {
+    match field(value, key)? {
+        Value::String(s) => Ok(s.as_bytes().to_vec()),
+        Value::Object(_) => hex_bytes(str_field(field(value, key)?, "hex")?),
+        _ => Err(expected(key, "a string or {\"hex\": ...}")),
+    }
+}
+
+// Names used to be written as hex when they weren't UTF-8, which they now always are
+fn name_field(value: &Value, key: &str) -> Result<String, WyrmError> {
+    String::from_utf8(bytes_field(value, key)?).map_err(|_| expected(key, "a UTF-8 name"))
+}
+
+fn value_type_field(value: &Value, key: &str) -> Result<u8, WyrmError> {
+    match field(value, key)? {
+        Value::Number(n) => n.as_u64().and_then(|n| u8::try_from(n).ok()).ok_or_else(|| expected(key, "a type byte")),
+        Value::String(name) => (0x40..=0x7f)
+            .find(|byte| type_to_str(WasmTypeAnnotation { _type: *byte }) == *name)
+            .ok_or_else(|| WyrmError::invalid(format!("JSON: unknown value type {:?}", name))),
+        _ => Err(expected(key, "a value type")),
+    }
+}
+
+fn value_types(values: &[Value], key: &str) -> Result<Vec<WasmTypeAnnotation>, WyrmError> {
+    values.iter().map(|value| Ok(WasmTypeAnnotation { _type: value_type_field(&json!({ key: value }), key)? })).collect()
+}
+
+fn limits_field(value: &Value) -> Result<Limits, WyrmError> {
+    let limits = field(value, "limits")?;
+    let max = match field(limits, "max")? {
+        Value::Null => None,
+        _ => Some(u64_field(limits, "max")?),
+    };
+    Ok(Limits { initial: u64_field(limits, "initial")?, max, shared: bool_field(limits, "shared")?, is_64: bool_field(limits, "is_64")? })
+}
+
+fn immediate_from_json(value: &Value) -> Result<ExprSeg, WyrmError> {
+    let Some((key, imm)) = value.as_object().filter(|imm| imm.len() == 1).and_then(|imm| imm.iter().next()) else {
+        return Err(WyrmError::invalid(format!("JSON: expected an immediate with one key, found {}", value)));
+    };
+    let index = || as_index(imm).ok_or_else(|| expected(key, "an index"));
+    Ok(match key.as_str() {
+        "int" => ExprSeg::Int(imm.as_i64().ok_or_else(|| expected(key, "an integer"))?),
+        "f32" => ExprSeg::Float32(f32::from_bits(hex_bits(imm, key)? as u32)),
+        "f64" => ExprSeg::Float64(f64::from_bits(hex_bits(imm, key)? as u64)),
+        "v128" => ExprSeg::V128(hex_bits(imm, key)?),
+        "local" => ExprSeg::Local(index()?),
+        "global" => ExprSeg::Global(index()?),
+        "func" => ExprSeg::Func(index()?),
+        "table" => ExprSeg::Table(index()?),
+        "memory" => ExprSeg::Memory(index()?),
+        "type" => ExprSeg::Type(index()?),
+        "data" => ExprSeg::Data(index()?),
+        "elem" => ExprSeg::Elem(index()?),
+        "tag" => ExprSeg::Tag(index()?),
+        "heap_type" => ExprSeg::HeapType(imm.as_i64().ok_or_else(|| expected(key, "an integer"))?),
+        "ref_type" => ExprSeg::RefType { nullable: bool_field(imm, "nullable")?, heap: i64_field(imm, "heap")? },
+        "memarg" => ExprSeg::MemArg(MemArg {
+            align: u64_field(imm, "align")? as u32,
+            offset: u64_field(imm, "offset")?,
+            memory: index_field(imm, "memory")?,
+        }),
+        "block_type" => ExprSeg::BlockType(imm.as_i64().ok_or_else(|| expected(key, "an integer"))?),
+        "br_table" => ExprSeg::BrTable(BrTableConst {
+            break_depths: array_field(imm, "depths")?.iter()
+                .map(|depth| as_index(depth).ok_or_else(|| expected("depths", "a list of indices")))
+                .collect::<Result<_, _>>()?,
+            default: index_field(imm, "default")?,
+        }),
+        _ => return Err(WyrmError::invalid(format!("JSON: unknown immediate {:?}", key))),
+    })
+}
+
+fn instr_from_json(value: &Value) -> Result<ExprSeg, WyrmError> {
+    let (op, prefix, opcode) = (str_field(value, "op")?, u64_field(value, "prefix")?, u64_field(value, "opcode")?);
+    let info = match prefix {
+        0 => INSTRS.get(opcode as usize).copied(),
+        prefix => get_prefixed_instr(prefix as u8, opcode as u32),
+    };
+    // The name has to agree with the opcode, so a hand-edited op can't quietly mean something else
+    let Some(info) = info.filter(|info| !info.name.is_empty() && info.name == op) else {
+        return Err(WyrmError::invalid(format!("JSON: {:?} isn't the instruction {:#x} {:#x}", op, prefix, opcode)));
+    };
+    if value.get("body").is_some() {
+        let mut body = expr_from_json(field(value, "body")?, "body")?;
+        let catches = match value.get("catches") {
+            Some(_) => array_field(value, "catches")?.iter().map(catch_from_json).collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        let mut end = INSTRS[END as usize];
+        if value.get("delegate").is_some() {
+            end = INSTRS[DELEGATE as usize];
+            let delegate = ExprSeg::Instr(vec![ExprSeg::Operation(end), ExprSeg::Int(i64_field(value, "delegate")?)]);
+            *body.expr_string.last_mut().expect("expr_from_json adds an end") = delegate;
+        }
+        return Ok(ExprSeg::ControlFlow(info, i64_field(value, "block_type")?, Box::new(body), end, catches));
+    }
+    let mut layout = vec![ExprSeg::Operation(info)];
+    for imm in array_field(value, "imm")? {
+        layout.push(immediate_from_json(imm)?);
+    }
+    Ok(ExprSeg::Instr(layout))
+}
+
+fn catch_from_json(value: &Value) -> Result<CatchClause, WyrmError> {
+    let label = index_field(value, "label")?;
+    let tag = || index_field(value, "tag");
+    Ok(match str_field(value, "kind")? {
+        "catch" => CatchClause::Catch { tag: tag()?, label },
+        "catch_ref" => CatchClause::CatchRef { tag: tag()?, label },
+        "catch_all" => CatchClause::CatchAll { label },
+        "catch_all_ref" => CatchClause::CatchAllRef { label },
+        kind => return Err(WyrmError::invalid(format!("JSON: unknown catch kind {:?}", kind))),
+    })
+}
+
+// An expression, with the `end` the JSON leaves out put back
+fn expr_from_json(value: &Value, key: &str) -> Result<WasmExpr, WyrmError> {
+    let instrs = value.as_array().ok_or_else(|| expected(key, "a list of instructions"))?;
+    let mut expr_string = instrs.iter().map(instr_from_json).collect::<Result<Vec<_>, _>>()?;
+    expr_string.push(ExprSeg::Operation(INSTRS[END as usize]));
+    Ok(WasmExpr { expr_string })
+}
+
+fn name_map_from_json(value: &Value, key: &str) -> Result<WasmNameMap, WyrmError> {
+    let Some(names) = value.as_object() else {
+        return Err(expected(key, "an object"));
+    };
+    names.iter()
+        .map(|(i, name)| {
+            let i = i.parse::<u32>().map_err(|_| expected(key, "keyed by index"))?;
+            Ok((i, name.as_str().ok_or_else(|| expected(key, "a map to strings"))?.to_string()))
+        })
+        .collect()
+}
+
+fn indirect_name_map_from_json(value: &Value, key: &str) -> Result<WasmIndirectNameMap, WyrmError> {
+    let Some(maps) = value.as_object() else {
+        return Err(expected(key, "an object"));
+    };
+    maps.iter()
+        .map(|(i, inner)| Ok((i.parse::<u32>().map_err(|_| expected(key, "keyed by index"))?, name_map_from_json(inner, key)?)))
+        .collect()
+}
+
+fn names_from_json(value: &Value) -> Result<WasmNameSection, WyrmError> {
+    let names = field(value, "names")?;
+    let map = |key: &str| name_map_from_json(field(names, key)?, key);
+    let indirect = |key: &str| indirect_name_map_from_json(field(names, key)?, key);
+    Ok(WasmNameSection {
+        module_name: field(names, "module")?.as_str().map(|name| name.to_string()),
+        function_names: map("functions")?,
+        local_names: indirect("locals")?,
+        label_names: indirect("labels")?,
+        type_names: map("types")?,
+        table_names: map("tables")?,
+        memory_names: map("memories")?,
+        global_names: map("globals")?,
+        elem_names: map("elems")?,
+        data_names: map("data")?,
+        tag_names: map("tags")?,
+    })
+}
+
+fn import_from_json(value: &Value) -> Result<WasmImportHeader, WyrmError> {
+    let kind = str_field(value, "kind")?;
+    let (import_kind, desc) = match kind {
+        "func" => (WasmImportType::Func, WasmImportDesc::Func(index_field(value, "type")?)),
+        "table" => (WasmImportType::Table, WasmImportDesc::Table(WasmTable { wasm_type: value_type_field(value, "type")?, limits: limits_field(value)? })),
+        "memory" => (WasmImportType::Mem, WasmImportDesc::Mem(WasmMemoryStruct { limits: limits_field(value)? })),
+        "global" => (WasmImportType::Global, WasmImportDesc::Global {
+            wasm_type: WasmTypeAnnotation { _type: value_type_field(value, "type")? },
+            mutability: bool_field(value, "mutable")? as u8,
+        }),
+        "tag" => (WasmImportType::Tag, WasmImportDesc::Tag(WasmTag {
+            attribute: u64_field(value, "attribute")? as u8,
+            type_index: index_field(value, "type")?,
+        })),
+        _ => return Err(WyrmError::invalid(format!("JSON: unknown import kind {:?}", kind))),
+    };
+    let (module, name) = (name_field(value, "module")?, name_field(value, "name")?);
+    Ok(WasmImportHeader {
+        mod_name_length: module.len(),
+        import_module_name: module,
+        import_field_len: name.len(),
+        import_field: name,
+        import_kind,
+        desc,
+    })
+}
+
+fn elem_from_json(value: &Value) -> Result<WasmElem, WyrmError> {
+    let mode = field(value, "mode")?;
+    let kind = str_field(mode, "kind")?;
+    let mode = match kind {
+        "passive" => WasmElemMode::Passive,
+        "declarative" => WasmElemMode::Declarative,
+        "active" => WasmElemMode::Active(AcvtiveStruct {
+            table: index_field(mode, "table")?,
+            offset_expr: expr_from_json(field(mode, "offset")?, "offset")?,
+        }),
+        _ => return Err(WyrmError::invalid(format!("JSON: unknown elem mode {:?}", kind))),
+    };
+    Ok(WasmElem {
+        flags: u64_field(value, "flags")? as u32,
+        _type: byte_to_reftype(value_type_field(value, "type")?)?,
+        init: array_field(value, "items")?.iter().map(|item| expr_from_json(item, "items")).collect::<Result<_, _>>()?,
+        mode,
+    })
+}
+
+fn data_from_json(value: &Value) -> Result<WasmDataSeg, WyrmError> {
+    let mode = field(value, "mode")?;
+    let kind = str_field(mode, "kind")?;
+    let mode = match kind {
+        "passive" => WasmDataMode::Passive,
+        "active" => WasmDataMode::Active {
+            memory: index_field(mode, "memory")?,
+            offset_expr: expr_from_json(field(mode, "offset")?, "offset")?,
+        },
+        _ => return Err(WyrmError::invalid(format!("JSON: unknown data mode {:?}", kind))),
+    };
+    let data = hex_bytes(str_field(value, "bytes")?)?;
+    Ok(WasmDataSeg {
+        header: WasmDataSegHeader { header_flags: u64_field(value, "flags")? as u8, mode, data_size: data.len() },
+        data,
+    })
+}
+
+/// Builds a module from the JSON wasm_to_json writes. Section sizes are left at 0, as the writer
+/// works them out again.
+pub fn wasm_from_json(value: &Value) -> Result<WasmFile, WyrmError> {
+    let format = str_field(value, "format")?;
+    if format != JSON_FORMAT {
+        return Err(WyrmError::invalid(format!("JSON: expected format {:?}, found {:?}", JSON_FORMAT, format)));
+    }
+    let version = u64_field(value, "version")?;
+    if version != JSON_VERSION {
+        return Err(WyrmError::unsupported(format!("JSON: version {} isn't supported, only version {} is", version, JSON_VERSION)));
+    }
+
+    let header = field(value, "header")?;
+    let types = array_field(value, "types")?.iter()
+        .map(|sig| {
+            let (params, results) = (value_types(array_field(sig, "params")?, "params")?, value_types(array_field(sig, "results")?, "results")?);
+            Ok(WasmFunctionType { func: 0x60, num_params: params.len(), params, num_results: results.len(), results })
+        })
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let imports = array_field(value, "imports")?.iter().map(import_from_json).collect::<Result<Vec<_>, _>>()?;
+
+    let mut function_signature_indexes = vec![];
+    let mut functions = vec![];
+    for func in array_field(value, "functions")? {
+        function_signature_indexes.push(index_field(func, "type")?);
+        let local_types = array_field(func, "locals")?.iter()
+            .map(|local| Ok((value_type_field(local, "type")?, usize_field(local, "count")?)))
+            .collect::<Result<Vec<_>, WyrmError>>()?;
+        functions.push(encode_function(local_types, expr_from_json(field(func, "body")?, "body")?)?);
+    }
+
+    let tables = array_field(value, "tables")?.iter()
+        .map(|table| Ok(WasmTable { wasm_type: value_type_field(table, "type")?, limits: limits_field(table)? }))
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let memories = array_field(value, "memories")?.iter()
+        .map(|memory| Ok(WasmMemoryStruct { limits: limits_field(memory)? }))
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let tags = array_field(value, "tags")?.iter()
+        .map(|tag| Ok(WasmTag { attribute: u64_field(tag, "attribute")? as u8, type_index: index_field(tag, "type")? }))
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let globals = array_field(value, "globals")?.iter()
+        .map(|global| Ok(WasmGlobal {
+            wasm_type: WasmTypeAnnotation { _type: value_type_field(global, "type")? },
+            mutability: bool_field(global, "mutable")? as u8,
+            expr: expr_from_json(field(global, "init")?, "init")?,
+        }))
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let exports = array_field(value, "exports")?.iter()
+        .map(|export| {
+            let export_kind = match field(export, "kind")? {
+                Value::String(kind) => IMPORT_KINDS.iter().position(|k| k == kind)
+                    .ok_or_else(|| WyrmError::invalid(format!("JSON: unknown export kind {:?}", kind)))? as u8,
+                _ => u64_field(export, "kind")? as u8,
+            };
+            let name = name_field(export, "name")?;
+            Ok(WasmExportHeader {
+                export_name_len: name.len(),
+                export_name: name,
+                export_kind,
+                export_signature_index: index_field(export, "index")?,
+            })
+        })
+        .collect::<Result<Vec<_>, WyrmError>>()?;
+    let start = match field(value, "start")? {
+        Value::Null => None,
+        _ => Some(WasmStartSection { section_size: 0, func: index_field(value, "start")? }),
+    };
+    let elems = array_field(value, "elems")?.iter().map(elem_from_json).collect::<Result<Vec<_>, _>>()?;
+    let data_segs = array_field(value, "data")?.iter().map(data_from_json).collect::<Result<Vec<_>, _>>()?;
+
+    let data_count_section = match field(value, "data_count")? {
+        Value::Null => None,
+        _ => Some(WasmDataCountSection { section_size: 0, datacount: usize_field(value, "data_count")? }),
+    };
+
+    let import_section_header = WasmImportSection { section_size: 0, num_imports: imports.len(), imports };
+    let function_section = WasmFunctionSection {
+        section_size: 0,
+        num_functions: function_signature_indexes.len(),
+        function_signature_indexes,
+    };
+    let table_section = WasmTableSection { section_size: 0, num_tables: tables.len(), tables };
+    let memory_section = WasmMemorySection { section_size: 0, num_memories: memories.len(), memories };
+    let global_section = WasmGlobalSection { section_size: 0, num_globals: globals.len(), globals };
+    let tag_section = WasmTagSection { section_size: 0, num_tags: tags.len(), tags };
+    // Left out by documents written before custom sections were kept
+    let custom_sections = match value.get("custom_sections") {
+        None => vec![],
+        Some(_) => array_field(value, "custom_sections")?.iter()
+            .map(|custom| Ok(WasmCustomSection {
+                name: match field(custom, "name")? {
+                    Value::Null => None,
+                    _ => Some(bytes_field(custom, "name")?),
+                },
+                data: hex_bytes(str_field(custom, "bytes")?)?,
+                after: u64_field(custom, "after")? as u8,
+            }))
+            .collect::<Result<Vec<_>, WyrmError>>()?,
+    };
+
+    Ok(WasmFile {
+        wasm_header: WasmHeader { magic_number: u64_field(header, "magic")? as u32, version: u64_field(header, "version")? as u32 },
+        type_section: WasmTypeSection { section_size: 0, num_types: types.len(), function_signatures: types },
+        import_section_header,
+        function_section,
+        table_section,
+        memory_section,
+        global_section,
+        export_section: WasmExportSection { section_size: 0, num_exports: exports.len(), exports },
+        elem_section: WasmElemSection { section_size: 0, num_elems: elems.len(), elems },
+        start_section: start,
+        tag_section,
+        code_section: WasmCodeSection { section_size: 0, num_functions: functions.len(), functions },
+        data_count_section,
+        data_section: WasmDataSection { section_size: 0, num_data_segs: data_segs.len(), data_segs },
+        name_section: names_from_json(value)?,
+        custom_sections,
+    })
+}
+
+pub fn wasm_from_json_str(text: &str) -> Result<WasmFile, WyrmError> {
+    let value: Value = serde_json::from_str(text).map_err(|e| WyrmError::invalid(format!("JSON: {}", e)))?;
+    wasm_from_json(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::wasm_deserialize_bytes;
+    use crate::file_writer::wasm_serialize_bytes;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn golden_corpus_round_trips() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let mut paths: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let wasm = wasm_deserialize_bytes(&std::fs::read(&path).unwrap()).unwrap();
+            let json = wasm_to_json_string(&wasm).unwrap();
+            let read_back = wasm_from_json_str(&json).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            assert_eq!(wasm_serialize_bytes(&read_back).unwrap(), wasm_serialize_bytes(&wasm).unwrap(), "{}", path.display());
+            assert_eq!(wasm_to_json_string(&read_back).unwrap(), json, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn writes_instructions_and_immediates() {
+        let wasm = parse_wat(
+            "(module
+              (memory 1)
+              (func (param i32) (result f32)
+                local.get 0
+                f32.load offset=4
+                block
+                  nop
+                end))",
+        )
+        .unwrap();
+        let json = wasm_to_json(&wasm).unwrap();
+        assert_eq!(json["version"], JSON_VERSION);
+        assert_eq!(
+            json["functions"][0]["body"],
+            json!([
+                { "op": "local.get", "prefix": 0, "opcode": 0x20, "imm": [{ "local": 0 }] },
+                { "op": "f32.load", "prefix": 0, "opcode": 0x2a, "imm": [{ "memarg": { "align": 2, "offset": 4, "memory": 0 } }] },
+                { "op": "block", "prefix": 0, "opcode": 2, "block_type": -64, "body": [
+                    { "op": "nop", "prefix": 0, "opcode": 1, "imm": [] },
+                ] },
+            ])
+        );
+    }
+
+    #[test]
+    fn round_trips_exception_handling() {
+        let wasm = parse_wat(
+            "(module
+              (tag (param i32))
+              (func
+                try
+                  try
+                    nop
+                  delegate 0
+                catch 0
+                  drop
+                end
+                try_table (catch_all 0)
+                end))",
+        )
+        .unwrap();
+        let json = wasm_to_json(&wasm).unwrap();
+        let body = &json["functions"][0]["body"];
+        assert_eq!(body[0]["body"][0]["delegate"], 0);
+        assert_eq!(body[1]["catches"], json!([{ "kind": "catch_all", "label": 0 }]));
+        let read_back = wasm_from_json(&json).unwrap();
+        assert_eq!(wasm_serialize_bytes(&read_back).unwrap(), wasm_serialize_bytes(&wasm).unwrap());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_mismatched_ops() {
+        let wasm = parse_wat("(module (func nop))").unwrap();
+        let mut json = wasm_to_json(&wasm).unwrap();
+        json["version"] = json!(JSON_VERSION + 1);
+        assert!(matches!(wasm_from_json(&json), Err(WyrmError::Unsupported(..))));
+
+        json["version"] = json!(JSON_VERSION);
+        json["functions"][0]["body"][0]["op"] = json!("drop");
+        assert!(matches!(wasm_from_json(&json), Err(WyrmError::InvalidData(..))));
+    }
+}
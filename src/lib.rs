@@ -0,0 +1,148 @@
+//! wyrm reads, writes and analyses WebAssembly modules.
+//!
+//! Modules are loaded into a [`WasmFile`], the decoded model in [`wasm_model`], from the binary
+//! format ([`load_bytes`], [`load_reader`]), the text format ([`load_wat`]) or the JSON model
+//! ([`json_model`]). From there they can be written back out ([`to_bytes`]), shown through one
+//! of the emitter backends ([`emitter::Emitter`], with [`emit_wat`], [`emit_listing`] and the
+//! rest as shortcuts), checked with [`validate()`], or analysed, e.g. with [`callgraph`],
+//! [`cfg`](mod@cfg) or the Earley parser in [`parser::prs`].
+//!
+//! ```
+//! let wasm = wyrm::load_wat("(module (func (export \"f\") (result i32) i32.const 1))").unwrap();
+//! let bytes = wyrm::to_bytes(&wasm).unwrap();
+//! let read_back = wyrm::load_bytes(&bytes).unwrap();
+//! assert!(wyrm::emit_wat(&read_back).unwrap().contains("i32.const 1"));
+//! ```
+
+use std::io::Read;
+
+/// Analyses over control-flow graphs
+pub mod analysis;
+/// Bounds-checked reading of bytes, integers and LEB128 values
+pub mod binary_reader;
+/// Writing bytes, integers and LEB128 values
+pub mod binary_writer;
+/// Which functions call which
+pub mod callgraph;
+/// Control-flow graphs of function bodies, and DOT output for them
+pub mod cfg;
+/// The walk over a module that every output backend hooks into
+pub mod emitter;
+/// The error type everything returns
+pub mod error;
+/// Reading the binary format
+pub mod file_reader;
+/// Writing the binary format
+pub mod file_writer;
+/// Every instruction's opcode, name, operand types and immediates
+pub mod instr_table;
+/// The disassembly as JSON
+pub mod json_emitter;
+/// The whole module as versioned JSON, which can be read back
+pub mod json_model;
+/// An objdump-style listing with offsets and encoded bytes
+pub mod listing;
+/// A generic Earley parser, and DOT output for its parse trees
+pub mod parser;
+/// Function bodies as C-like pseudo-code
+pub mod pseudo_emitter;
+/// Operand-stack types and heights at every instruction
+pub mod stack_types;
+/// Counts of sections, functions and instructions
+pub mod stats;
+/// Checking a module against the spec's validation rules
+pub mod validate;
+/// The decoded module
+pub mod wasm_model;
+/// The grammar of encoded instructions, for the Earley parser
+pub mod wasm_parser;
+/// The text format
+pub mod wat_emitter;
+/// Reading the text format
+pub mod wat_parser;
+
+pub use error::WyrmError;
+pub use json_emitter::emit_json;
+pub use listing::emit_listing;
+pub use pseudo_emitter::emit_pseudo;
+pub use validate::validate;
+pub use wasm_model::{WasmExpr, WasmFile, WasmFunction};
+pub use wat_emitter::{emit_folded_wat, emit_wat};
+
+use parser::prs::{earley_parser, EarleySppf};
+
+/// Reads a module in the binary format.
+pub fn load_bytes(bytes: &[u8]) -> Result<WasmFile, WyrmError> {
+    file_reader::wasm_deserialize_bytes(bytes)
+}
+
+/// Reads a module in the binary format from anything readable, e.g. a file.
+pub fn load_reader(reader: impl Read) -> Result<WasmFile, WyrmError> {
+    file_reader::wasm_deserialize(reader)
+}
+
+/// Reads a module in the text format.
+pub fn load_wat(text: &str) -> Result<WasmFile, WyrmError> {
+    wat_parser::parse_wat(text)
+}
+
+/// Encodes a module in the binary format. Section sizes are worked out again, so a module that
+/// was changed or built by hand can be written as it is.
+pub fn to_bytes(wasm: &WasmFile) -> Result<Vec<u8>, WyrmError> {
+    file_writer::wasm_serialize_bytes(wasm)
+}
+
+/// Runs the Earley parser over a function's encoded body with the instruction grammar in
+/// [`wasm_parser`]. Gives `None` when the body doesn't parse.
+pub fn parse_body(func: &WasmFunction) -> Option<EarleySppf<'static, u64>> {
+    earley_parser(func.raw_body.clone(), &wasm_parser::PARSER_GRAMMAR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &str = r#"(module
+      (memory 1)
+      (func $add (export "add") (param i32 i32) (result i32)
+        local.get 0
+        local.get 1
+        i32.add))"#;
+
+    #[test]
+    fn loads_from_bytes_readers_and_text() {
+        let bytes = to_bytes(&load_wat(MODULE).unwrap()).unwrap();
+        let from_bytes = load_bytes(&bytes).unwrap();
+        let from_reader = load_reader(&bytes[..]).unwrap();
+        assert_eq!(to_bytes(&from_bytes).unwrap(), bytes);
+        assert_eq!(to_bytes(&from_reader).unwrap(), bytes);
+        assert_eq!(from_bytes.code_section.functions.len(), 1);
+        assert_eq!(from_bytes.export_section.exports[0].export_name, "add");
+
+        assert!(matches!(load_bytes(b"\0wat"), Err(WyrmError::BadMagic(..))));
+        assert!(load_wat("(module (func (call $missing)))").is_err());
+    }
+
+    #[test]
+    fn emits_with_every_backend() {
+        let wasm = load_bytes(&to_bytes(&load_wat(MODULE).unwrap()).unwrap()).unwrap();
+        assert!(emit_wat(&wasm).unwrap().contains("i32.add"));
+        assert!(emit_folded_wat(&wasm).unwrap().contains("(i32.add (local.get $var0) (local.get $var1))"));
+        assert!(emit_listing(&wasm).unwrap().contains("6a"));
+        assert!(emit_pseudo(&wasm).unwrap().contains('+'));
+        let json: serde_json::Value = serde_json::from_str(&emit_json(&wasm).unwrap()).unwrap();
+        assert!(json.is_object());
+    }
+
+    #[test]
+    fn validates_and_parses_bodies() {
+        let wasm = load_bytes(&to_bytes(&load_wat(MODULE).unwrap()).unwrap()).unwrap();
+        assert!(validate(&wasm).is_empty());
+        assert!(parse_body(&wasm.code_section.functions[0]).is_some());
+
+        let invalid = load_wat("(module (func (result i32) i64.const 1))").unwrap();
+        let errors = validate(&invalid);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_eq!(errors[0].location.func, Some(0));
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::Write;
+
+use crate::emitter::*;
+use crate::error::WyrmError;
+use crate::file_reader::wasm_deserialize_bytes;
+use crate::file_writer::wasm_serialize_bytes;
+use crate::wasm_model::*;
+
+// Encoded bytes shown per line, longer instructions carry on over the lines after
+const BYTES_PER_LINE: usize = 8;
+
+/// A disassembly like `wasm-objdump -d` gives. Every instruction is listed with its file offset,
+/// its offset from the start of its function's entry (the function's size), its encoded bytes
+/// and its text. Functions that weren't read from a binary have no offsets or bytes to show,
+/// see emit_listing.
+#[derive(Default)]
+pub struct ListingEmitter {
+    // Where the current function's entry starts
+    func_offset: usize,
+}
+
+impl ListingEmitter {
+    fn write_site(&self, out: &mut dyn Write, site: &InstrSite<'_>, text: &str) -> Result<(), WyrmError> {
+        let text = format!("{}{}", "  ".repeat(site.depth), text);
+        let Some(offset) = site.offset else {
+            writeln!(out, " {:6} {:5}  {:<23} | {}", "", "", "", text)?;
+            return Ok(());
+        };
+        for (j, chunk) in site.bytes.chunks(BYTES_PER_LINE).enumerate() {
+            let at = offset + j * BYTES_PER_LINE;
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text = if j == 0 { text.as_str() } else { "" };
+            let line = format!(" {:06x} +{:04x}: {:<23} | {}", at, at - self.func_offset, hex.join(" "), text);
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl Emitter for ListingEmitter {
+    fn begin_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>) -> Result<(), WyrmError> {
+        self.func_offset = func.func.offset;
+        writeln!(out, "{:06x} func[{}] <{}>:", func.func.offset, func.funcidx, module.names.func_id(func.funcidx))?;
+        Ok(())
+    }
+
+    fn instr(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.write_site(out, site, &site.text)
+    }
+
+    fn begin_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.write_site(out, site, &site.text)
+    }
+
+    fn else_arm(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.write_site(out, site, &site.text)
+    }
+
+    // Like objdump, ends are written without their label
+    fn end_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.write_site(out, site, site.info.name)
+    }
+
+    fn end_func(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.write_site(out, site, site.info.name)
+    }
+}
+
+/// The module read back from its encoding, when it wasn't read from a binary, so the offsets
+/// are the ones the written file would have
+pub fn with_offsets(wasm: &WasmFile) -> Result<Option<WasmFile>, WyrmError> {
+    if wasm.code_section.functions.iter().any(|func| func.instr_offsets.is_empty()) {
+        return Ok(Some(wasm_deserialize_bytes(&wasm_serialize_bytes(wasm)?)?));
+    }
+    Ok(None)
+}
+
+pub fn emit_listing(wasm: &WasmFile) -> Result<String, WyrmError> {
+    match with_offsets(wasm)? {
+        Some(encoded) => emit_to_string(&encoded, &mut ListingEmitter::default()),
+        None => emit_to_string(wasm, &mut ListingEmitter::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn lists_offsets_and_encoded_bytes() {
+        let bytes = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00,
+            // The i32.const is padded to five bytes, which the listing keeps
+            0x0a, 0x0f, 0x01, 0x0d, 0x00,
+            0x41, 0x80, 0x80, 0x80, 0x80, 0x00, 0x1a, 0x02, 0x40, 0x01, 0x0b, 0x0b,
+        ];
+        let listing = emit_listing(&wasm_deserialize_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(
+            listing,
+            "000015 func[0] <$func0>:
+ 000017 +0002: 41 80 80 80 80 00       | i32.const 0
+ 00001d +0008: 1a                      | drop
+ 00001e +0009: 02 40                   | block $label0
+ 000020 +000b: 01                      |   nop
+ 000021 +000c: 0b                      | end
+ 000022 +000d: 0b                      | end
+"
+        );
+    }
+
+    #[test]
+    fn lists_modules_read_from_text() {
+        let wasm = parse_wat(
+            "(module
+              (func (result v128)
+                v128.const i32x4 1 2 3 4))",
+        )
+        .unwrap();
+        let listing = emit_listing(&wasm).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "000016 func[0] <$func0>:");
+        assert_eq!(lines[1], " 000018 +0002: fd 0c 01 00 00 00 02 00 | v128.const i32x4 0x00000001 0x00000002 0x00000003 0x00000004");
+        assert_eq!(lines[2], " 000020 +000a: 00 00 03 00 00 00 04 00 |");
+        assert_eq!(lines[3], " 000028 +0012: 00 00                   |");
+        assert_eq!(lines[4], " 00002a +0014: 0b                      | end");
+    }
+}
@@ -1,8 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 
-use log::debug;
 
-use crate::parser::prs::{earley_state_id, earley_state_repr, Derivation, EarleySppf, EarleyState, EarleyTree, GrammarTrait};
+use crate::parser::prs::{earley_state_id, earley_state_repr, Derivation, EarleyTree, GrammarTrait};
 
 type Dot2Node<'a, T> = Derivation<'a, T>;
 type Dot2Edge<'a, T> = (Dot2Node<'a, T>, Dot2Node<'a, T>);
@@ -37,7 +36,7 @@ impl<'a, T: GrammarTrait + 'static> dot2::Labeller<'a> for Edges<'a, T> {
             Derivation::CompletedFrom { 
                 state
             } => {
-                Ok(dot2::label::Text::label(format!("{}", earley_state_repr(state))))
+                Ok(dot2::label::Text::label(earley_state_repr(state)))
             },
             Derivation::ScannedFrom { 
                 symbol, idx
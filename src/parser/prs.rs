@@ -1,14 +1,13 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
-use log::debug;
 
 pub trait GrammarTrait: Hash + PartialEq + Eq + Clone + Debug {
     fn start_sym() -> Self;
     
     
-    fn to_node_rep(&self, parent_sym: Option<Self>) -> String {
-        format!("{:?}", self).to_string()
+    fn to_node_rep(&self, _parent_sym: Option<Self>) -> String {
+        format!("{:?}", self)
     }
 }
 
@@ -26,7 +25,7 @@ pub struct Grammar<T: GrammarTrait + 'static> {
 impl<T: GrammarTrait + 'static> Grammar<T> {
     pub fn get_rules(&self, lhs: T) -> Vec<Rule<T>> {
         let mut found = Vec::<Rule<T>>::new();
-        for rule in self.rules.clone() {
+        for rule in self.rules {
             if rule.left_hand == lhs {
                 found.push(rule.clone());
             }
@@ -35,12 +34,12 @@ impl<T: GrammarTrait + 'static> Grammar<T> {
     }
 
     pub const fn new(rules: &'static [Rule<T>]) -> Self {
-        Self { rules: rules }
+        Self { rules }
     }
 
     pub fn is_nonterm(&self, elem: &T) -> bool {
         // This is annoyingly slow, but becuase I want grammars to be constant its needed.
-        for rule in self.rules.clone() {
+        for rule in self.rules {
             if rule.left_hand == *elem {
                 return true;
             }
@@ -81,7 +80,7 @@ pub struct EarleyState<'a, T: GrammarTrait + 'static> {
 
 impl<T: GrammarTrait + 'static> PartialEq for EarleyState<'_, T> {
     fn eq(&self, other: &Self) -> bool {
-        self.from == other.from && self.to == other.to && self.origin == other.origin && self.idx == other.idx && self.end == self.end
+        self.from == other.from && self.to == other.to && self.origin == other.origin && self.idx == other.idx && self.end == other.end
     }
 }
 impl<T: GrammarTrait + 'static> Eq for EarleyState<'_, T> {}
@@ -107,15 +106,14 @@ impl<'a, T: GrammarTrait + 'static> EarleyState<'a, T> {
     // Step forward this state with the statements that completed it
     // as a sub node
     fn next(&self, completed: Self, end: usize) -> (Self, Children<'a, T>) {
-        let mut new: Vec::<PackedNode<T>> = Vec::new();
-        new.push(PackedNode {
+        let new: Vec::<PackedNode<T>> = vec![PackedNode {
             left_child: Derivation::CompletedFrom { state: self.clone() },
             right_child: Some(Derivation::CompletedFrom { state: completed.clone() })
-        });
+        }];
 
         (Self {
             from: self.from.clone(),
-            to: self.to.clone(),
+            to: self.to,
             origin: self.origin,
             end,
             idx: self.idx + 1,
@@ -141,7 +139,7 @@ impl<'a, T: GrammarTrait + 'static> EarleyState<'a, T> {
 
         (Self {
             from: self.from.clone(),
-            to: self.to.clone(),
+            to: self.to,
             origin: self.origin,
             end: idx,
             idx: self.idx + 1,
@@ -153,22 +151,20 @@ impl<'a, T: GrammarTrait + 'static> EarleyState<'a, T> {
         self.to[self.idx].clone()
     }
 
-    // Traverses the parse tree, breadth first, for an ambiguity
-    // Returns some if there is an ambiguity (the first node with 2 packed nodes), 
-    // None otherwise
+    /// Traverses the parse tree, breadth first, for an ambiguity
+    /// Returns some if there is an ambiguity (the first node with 2 packed nodes), 
+    /// None otherwise
     pub fn find_ambiguity(&self, states: &States<'a, T>) ->  Option<EarleyState<'a, T>> {        
         let mut queue = VecDeque::<EarleyState<T>>::new();
         queue.push_back(self.clone());
 
         while let Some(item) = queue.pop_front() {
-            let Some(packed_nodes) = states.get(&item) else {
-                return None;
-            };
+            let packed_nodes = states.get(&item)?;
 
             if packed_nodes.len() > 1 {
                 return Some(item);
             }
-            if packed_nodes.len() == 0 {
+            if packed_nodes.is_empty() {
                 continue;
             }
 
@@ -185,7 +181,7 @@ impl<'a, T: GrammarTrait + 'static> EarleyState<'a, T> {
             };
         }
 
-        return None;
+        None
     }
 }
 
@@ -205,7 +201,7 @@ pub fn earley_state_repr<T: GrammarTrait + 'static>(state: &EarleyState<T>) -> S
     for i in 0..state.idx {
         repr += format!("{} ", state.to[i].to_node_rep(None)).as_str();
     }
-    repr += format!("*").as_str();  
+    repr += "*";
     for i in state.idx..state.to.len() {
         repr += format!(" {}", state.to[i].to_node_rep(None)).as_str();
     }
@@ -213,8 +209,8 @@ pub fn earley_state_repr<T: GrammarTrait + 'static>(state: &EarleyState<T>) -> S
     repr
 }
 
-pub fn print_earley_states<T: GrammarTrait + 'static, F>(states: &States<T>, _grammar: &Grammar<T>, end: usize, output: F)
-    where F: Fn(String) -> () {
+pub fn print_earley_states<T: GrammarTrait + 'static, F>(states: &States<T>, _grammar: &Grammar<T>, _end: usize, output: F)
+    where F: Fn(String) {
 
     for (i, state_pair) in states.clone().iter().enumerate() {
         let mut repr = format!("{}:\t", i);
@@ -222,7 +218,7 @@ pub fn print_earley_states<T: GrammarTrait + 'static, F>(states: &States<T>, _gr
         let packed_nodes = state_pair.1;
         repr += earley_state_repr(state).as_str();
 
-        if packed_nodes.len() > 0 {
+        if !packed_nodes.is_empty() {
             repr += "\t packed_nodes: ";
             repr += packed_nodes.iter().map(|node| {
                 let mut str = "".to_string();
@@ -275,21 +271,19 @@ pub struct EarleyTree<'a, T: GrammarTrait + 'static> {
 
 
 fn find_state<'a, T: GrammarTrait + 'static>(
-    state: &EarleyState<'a, T>, states: & Vec<States<'a, T>>
+    state: &EarleyState<'a, T>, states: &[States<'a, T>]
 ) -> Option<Children<'a, T>> {
     for step in states {
-        let maybe_state = step.get(state);
-        if maybe_state.is_some() {
-            return Some(maybe_state.unwrap().clone());
+        if let Some(found) = step.get(state) {
+            return Some(found.clone());
         }
     }
 
     None
 }
 
-fn create_sppf<'a, T: GrammarTrait + 'static>(start_state: &EarleyState<'a, T>, states: &Vec<States<'a, T>>) -> EarleySppf<'a, T> {
+fn create_sppf<'a, T: GrammarTrait + 'static>(start_state: &EarleyState<'a, T>, states: &[States<'a, T>]) -> EarleySppf<'a, T> {
     let mut new_states  = States::<'a, T>::new();
-    let mut full_set  = States::<'a, T>::new();
 
     let mut queue = VecDeque::<EarleyState<T>>::new();
     queue.push_back(start_state.clone());
@@ -299,20 +293,12 @@ fn create_sppf<'a, T: GrammarTrait + 'static>(start_state: &EarleyState<'a, T>,
     while let Some(item) = queue.pop_front() {
         let item_packed_nodes = find_state(&item, states).unwrap();
         for p in &item_packed_nodes {
-            match &p.left_child {
-                Derivation::CompletedFrom { state } => {
-                    queue.push_back(state.clone());
-                },
-                _ => ()
+            if let Derivation::CompletedFrom { state } = &p.left_child {
+                queue.push_back(state.clone());
             }
          
-            if let Some(right) = &p.right_child {
-                match right {
-                    Derivation::CompletedFrom { state } => {
-                        queue.push_back(state.clone());
-                    },
-                    _ => ()
-                }                    
+            if let Some(Derivation::CompletedFrom { state }) = &p.right_child {
+                queue.push_back(state.clone());
             }
         }
         new_states.insert(item, item_packed_nodes);
@@ -339,7 +325,7 @@ impl<'a, T: GrammarTrait + 'static> EarleySppf<'a, T> {
             let new_node = self.states.get(&item)
                 .expect("Root of SPPF not found in states");
             
-            if new_node.len() == 0 {
+            if new_node.is_empty() {
                 continue;
             }
 
@@ -348,13 +334,11 @@ impl<'a, T: GrammarTrait + 'static> EarleySppf<'a, T> {
             
 
             match &packed_node.left_child {
-                Derivation::CompletedFrom {state: next_state } => {
-                    if next_state.idx != 0 {
-                        queue.push_back(next_state.clone());
-                        tree_states.push((deriv.clone(), Derivation::CompletedFrom {
-                            state: next_state.clone() 
-                        }));
-                    }
+                Derivation::CompletedFrom {state: next_state } if next_state.idx != 0 => {
+                    queue.push_back(next_state.clone());
+                    tree_states.push((deriv.clone(), Derivation::CompletedFrom {
+                        state: next_state.clone() 
+                    }));
                 },
                 Derivation::ScannedFrom {symbol, idx } => {
                     tree_states.push((deriv.clone(), Derivation::ScannedFrom {
@@ -401,7 +385,6 @@ pub fn earley_parser<'a, T: GrammarTrait + 'static>(sentence: Vec<T>, grammar: &
     // The initial rules, from which all rules will be derived
     for rule in grammar.get_rules(T::start_sym()) {
         for value in rule.right_hand {
-            let len = states[0].len();
             states[0].insert(EarleyState::<'_, T> {
                 from: rule.left_hand.clone(),
                 to: value,
@@ -432,10 +415,9 @@ pub fn earley_parser<'a, T: GrammarTrait + 'static>(sentence: Vec<T>, grammar: &
                     // Predictor
                     // Check what rules could potentially complete the statement in this step,
                     // then add them to this step 
-                    for rule in grammar.rules.clone() {
+                    for rule in grammar.rules {
                         if symbol == rule.left_hand {
-                            for possibility in rule.right_hand.clone() {
-                                let len = states[i].len();
+                            for possibility in rule.right_hand {
                                 let new_state = EarleyState::<'_, T> {
                                     from: symbol.clone(),
                                     to: possibility,
@@ -445,9 +427,9 @@ pub fn earley_parser<'a, T: GrammarTrait + 'static>(sentence: Vec<T>, grammar: &
                                 };
 
                                 // This seems slow, look at for speed in the future
-                                if !states[i].contains_key(&new_state) {
-                                    queue.push_back(new_state.clone());
-                                    states[i].insert(new_state, vec![]);
+                                if let Entry::Vacant(entry) = states[i].entry(new_state.clone()) {
+                                    queue.push_back(new_state);
+                                    entry.insert(vec![]);
                                 }
                             }
                         }
@@ -460,7 +442,6 @@ pub fn earley_parser<'a, T: GrammarTrait + 'static>(sentence: Vec<T>, grammar: &
                     }
                 
                     if sentence[i] == item.elem() {
-                        let len = states[i + 1].len();
                         let (next, mut children) = item.next_scanned(sentence[i].clone(), i + 1);
 
                         if let Some(other)  = states[i + 1].get(&next) {
@@ -501,20 +482,22 @@ pub fn earley_parser<'a, T: GrammarTrait + 'static>(sentence: Vec<T>, grammar: &
     // println!("{:?}", states[sentence.len()]);
     let end_state = EarleyState::<'_, T> {
         from: T::start_sym(),
-        to: &start_rule,
+        to: start_rule,
         origin: 0,
         end: sentence.len(),
         idx: start_rule.len(),
     };
     states[sentence.len()].get(&end_state)
-        .map(|x| create_sppf(&end_state, &states))
+        .map(|_| create_sppf(&end_state, &states))
 }
 
+// For grammars over a user's own symbol enum, where the rules name its variants
+#[macro_export]
 macro_rules! user_rule {
     ($t:ident, $lhs:ident, $($rhs:expr),+) => {
         {
             use $t::*;
-            prs::Rule::<$t> {
+            $crate::parser::prs::Rule::<$t> {
                 left_hand: $lhs,
                 right_hand: &[$($rhs),+]
             }
@@ -522,26 +505,26 @@ macro_rules! user_rule {
     };
 }
 
+#[macro_export]
 macro_rules! rule {
     ($t:ident, $lhs:ident, $($rhs:expr),+) => {
-        prs::Rule::<$t> {
+        $crate::parser::prs::Rule::<$t> {
             left_hand: $lhs,
             right_hand: &[$($rhs),+]
         }
     };
 }
 
+#[macro_export]
 macro_rules! term_rule {
     ($t:ident, $lhs:ident, $rhs:ident) => {
-        prs::Rule::<$t> {
+        $crate::parser::prs::Rule::<$t> {
             left_hand: $lhs,
             right_hand: &$rhs
         }
     };
 }
-pub(crate) use user_rule;
-pub(crate) use rule;
-pub(crate) use term_rule;
+pub use crate::{rule, term_rule, user_rule};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,258 @@
+use std::io::Write;
+
+use crate::emitter::*;
+use crate::error::WyrmError;
+use crate::instr_table::{BR_IF, IF, RETURN};
+use crate::wasm_model::*;
+use crate::wat_emitter::type_to_str;
+
+// A value on the stack, as an expression
+struct Value {
+    text: String,
+    // Binary operations are put in parentheses when they're an operand
+    binary: bool,
+}
+
+impl Value {
+    fn operand(&self) -> String {
+        if self.binary {
+            format!("({})", self.text)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+/// Writes function bodies as C-like pseudo-code, e.g. `$a = $a + 1;`. Operands are nested the same
+/// way the folded WAT nests them, so nothing is reordered; values that don't get nested into
+/// anything are written as `push`, and operands that were pushed earlier than that as `pop()`.
+/// Only functions are written, the rest of the module is left to the other backends.
+#[derive(Default)]
+pub struct PseudoEmitter {
+    // Values pushed in each open block and not taken yet, innermost block last
+    frames: Vec<Vec<Value>>,
+    // How many values the current function returns
+    num_results: usize,
+}
+
+// The C operator for a binary instruction, going by what follows the type, e.g. `i32.add`
+fn binary_operator(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "add" => "+",
+        "sub" => "-",
+        "mul" => "*",
+        "div" | "div_s" | "div_u" => "/",
+        "rem_s" | "rem_u" => "%",
+        "and" => "&",
+        "or" => "|",
+        "xor" => "^",
+        "shl" => "<<",
+        "shr_s" | "shr_u" => ">>",
+        "eq" => "==",
+        "ne" => "!=",
+        "lt" | "lt_s" | "lt_u" => "<",
+        "gt" | "gt_s" | "gt_u" => ">",
+        "le" | "le_s" | "le_u" => "<=",
+        "ge" | "ge_s" | "ge_u" => ">=",
+        _ => return None,
+    })
+}
+
+fn is_op(info: &InstrInfo, opcode: u64) -> bool {
+    info.prefix == 0 && info.instr == opcode as u32
+}
+
+impl PseudoEmitter {
+    fn frame(&mut self) -> Result<&mut Vec<Value>, WyrmError> {
+        self.frames.last_mut().ok_or_else(|| WyrmError::invalid("Instruction outside of a function body"))
+    }
+
+    // The top `count` values, oldest first, with `pop()` for any that were pushed before this block
+    fn take(&mut self, count: usize) -> Result<Vec<Value>, WyrmError> {
+        let frame = self.frame()?;
+        let available = frame.split_off(frame.len().saturating_sub(count));
+        let missing = (available.len()..count).map(|_| Value { text: "pop()".to_string(), binary: false });
+        Ok(missing.chain(available).collect())
+    }
+
+    // Writes out the values nothing has taken, before a statement that has to come after them
+    fn flush(&mut self, out: &mut dyn Write, depth: usize) -> Result<(), WyrmError> {
+        for value in std::mem::take(self.frame()?) {
+            write_line(out, depth, &format!("push {};", value.text))?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, out: &mut dyn Write, depth: usize, text: &str) -> Result<(), WyrmError> {
+        self.flush(out, depth)?;
+        write_line(out, depth, text)
+    }
+}
+
+impl Emitter for PseudoEmitter {
+    fn begin_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>) -> Result<(), WyrmError> {
+        let (wasm, names, funcidx) = (module.wasm, module.names, func.funcidx);
+        let Some(sig) = wasm.get_func_sig(funcidx) else {
+            return Err(WyrmError::invalid("Function refers to a missing type").in_section(0x03).in_func(funcidx));
+        };
+        let params: Vec<String> = (0..).zip(sig.params.iter())
+            .map(|(i, param)| format!("{}: {}", names.local_id(funcidx, i), type_to_str(*param)))
+            .collect();
+        let results: Vec<String> = sig.results.iter().map(|result| type_to_str(*result)).collect();
+        let results = if results.is_empty() { "".to_string() } else { format!(" -> {}", results.join(", ")) };
+        writeln!(out, "func {}({}){} {{", names.func_id(funcidx), params.join(", "), results)?;
+        for (i, local) in (sig.params.len() as u32..).zip(func.func.locals.iter()) {
+            write_line(out, 1, &format!("var {}: {};", names.local_id(funcidx, i), type_to_str(local._type)))?;
+        }
+        self.frames = vec![vec![]];
+        self.num_results = sig.results.len();
+        Ok(())
+    }
+
+    fn instr(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        let depth = site.depth + 1;
+        let name = site.info.name;
+        let (op, immediates) = site.text.split_once(' ').unwrap_or((site.text.as_str(), ""));
+        let kind = name.split_once('.').map(|(_, kind)| kind).unwrap_or(name);
+
+        // Branches and returns take values the instruction table doesn't list
+        if is_op(&site.info, BR_IF) {
+            let condition = self.take(1)?.remove(0);
+            return self.statement(out, depth, &format!("if ({}) br {};", condition.text, immediates));
+        }
+        if is_op(&site.info, RETURN) {
+            let values: Vec<String> = self.take(self.num_results)?.into_iter().map(|value| value.text).collect();
+            return self.statement(out, depth, format!("return {};", values.join(", ")).replace(" ;", ";").as_str());
+        }
+
+        let Some((pops, pushes)) = site.effect else {
+            return self.statement(out, depth, &format!("{};", site.text));
+        };
+        let args = self.take(pops)?;
+        let value = match (name, args.as_slice()) {
+            ("local.get" | "global.get", []) => Value { text: immediates.to_string(), binary: false },
+            (_, []) if kind == "const" => Value { text: immediates.to_string(), binary: false },
+            ("local.set" | "global.set", [value]) => {
+                return self.statement(out, depth, &format!("{} = {};", immediates, value.text));
+            }
+            ("local.tee", [value]) => Value { text: format!("{} = {}", immediates, value.text), binary: true },
+            ("drop", [value]) => return self.statement(out, depth, &format!("{};", value.text)),
+            (_, [value]) if kind == "eqz" => Value { text: format!("{} == 0", value.operand()), binary: true },
+            (_, [lhs, rhs]) if immediates.is_empty() && binary_operator(kind).is_some() => Value {
+                text: format!("{} {} {}", lhs.operand(), binary_operator(kind).unwrap_or_default(), rhs.operand()),
+                binary: true,
+            },
+            _ => {
+                let args: Vec<String> = args.iter().map(|arg| arg.text.clone()).collect();
+                let callee = match site.seg {
+                    ExprSeg::Instr(instr) if matches!(instr.get(1), Some(ExprSeg::Func(_))) && !name.starts_with("ref.") => {
+                        let call = format!("{}({})", immediates, args.join(", "));
+                        if name.starts_with("return_") { format!("return {}", call) } else { call }
+                    }
+                    _ if immediates.is_empty() => format!("{}({})", op, args.join(", ")),
+                    _ => format!("{}[{}]({})", op, immediates, args.join(", ")),
+                };
+                Value { text: callee, binary: false }
+            }
+        };
+        match pushes {
+            0 => self.statement(out, depth, &format!("{};", value.text)),
+            1 => {
+                self.frame()?.push(value);
+                Ok(())
+            }
+            _ => self.statement(out, depth, &format!("push {};", value.text)),
+        }
+    }
+
+    fn begin_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        let depth = site.depth + 1;
+        let header = if is_op(&site.info, IF) {
+            let condition = self.take(1)?.remove(0);
+            let (_, label) = site.text.split_once(' ').unwrap_or_default();
+            format!("if ({}) {} {{", condition.text, label)
+        } else {
+            format!("{} {{", site.text)
+        };
+        self.statement(out, depth, &header)?;
+        self.frames.push(vec![]);
+        Ok(())
+    }
+
+    fn else_arm(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.flush(out, site.depth + 2)?;
+        write_line(out, site.depth + 1, &format!("}} {} {{", site.text))
+    }
+
+    fn end_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        self.flush(out, site.depth + 2)?;
+        self.frames.pop();
+        write_line(out, site.depth + 1, "}")
+    }
+
+    fn end_func(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        // Whatever is left is what the function returns
+        let values: Vec<String> = std::mem::take(self.frame()?).into_iter().map(|value| value.text).collect();
+        if !values.is_empty() {
+            write_line(out, 1, &format!("return {};", values.join(", ")))?;
+        }
+        self.frames.clear();
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+pub fn emit_pseudo(wasm: &WasmFile) -> Result<String, WyrmError> {
+    emit_to_string(wasm, &mut PseudoEmitter::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn writes_expressions_and_blocks() {
+        let wasm = parse_wat(
+            "(module
+              (func $add (param $a i32) (param $b i32) (result i32)
+                local.get $a
+                local.get $b
+                i32.add)
+              (func $f (param $a i32) (result i32)
+                (local $t i32)
+                local.get $a
+                i32.const 1
+                i32.add
+                local.tee $t
+                i32.eqz
+                if (result i32)
+                  local.get $t
+                  i32.const 2
+                  call $add
+                else
+                  i32.const 0
+                end
+                i32.const 4
+                i32.load offset=8
+                i32.mul))",
+        )
+        .unwrap();
+        assert_eq!(
+            emit_pseudo(&wasm).unwrap(),
+            "func $add($a: i32, $b: i32) -> i32 {
+  return $a + $b;
+}
+func $f($a: i32) -> i32 {
+  var $t: i32;
+  if (($t = $a + 1) == 0) $label0 (result i32) {
+    push $add($t, 2);
+  } else {
+    push 0;
+  }
+  return pop() * i32.load[offset=8](4);
+}
+"
+        );
+    }
+}
@@ -0,0 +1,1021 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::error::WyrmError;
+use crate::wasm_model::*;
+use crate::wat_emitter::type_to_str;
+
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+const V128: u8 = 0x7b;
+const FUNCREF: u8 = 0x70;
+const EXNREF: u8 = 0x69;
+
+/// A value on the operand stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackValue {
+    /// A value type, as the byte it's encoded as, e.g. 0x7f for i32
+    Type(u8),
+    /// Could be any type. Popped from the stack of unreachable code, or made by an instruction whose
+    /// type depends on things the model doesn't keep, like GC type definitions.
+    Unknown,
+}
+
+fn is_ref(byte: u8) -> bool {
+    (0x63..=0x74).contains(&byte)
+}
+
+impl StackValue {
+    /// Whether a value of this type can be used where `expected` is. Subtyping between reference
+    /// types isn't modelled, so any reference matches any other.
+    pub fn matches(self, expected: StackValue) -> bool {
+        match (self, expected) {
+            (StackValue::Type(a), StackValue::Type(b)) => a == b || (is_ref(a) && is_ref(b)),
+            _ => true,
+        }
+    }
+}
+
+impl Display for StackValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StackValue::Type(byte) => match type_to_str(WasmTypeAnnotation { _type: *byte }) {
+                name if name.is_empty() => write!(f, "{:#04x}", byte),
+                name => write!(f, "{}", name),
+            },
+            StackValue::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+fn types_to_str(types: &[StackValue]) -> String {
+    format!("[{}]", types.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(" "))
+}
+
+/// The operand stack around one encoded instruction
+#[derive(Debug, Clone)]
+pub struct InstrTypes {
+    pub info: InstrInfo,
+    /// Where it is in the file, for functions read from a binary
+    pub offset: Option<usize>,
+    /// How many blocks it's in, counted the same way as InstrSite::depth
+    pub depth: usize,
+    /// The whole stack before and after it, bottom first
+    pub before: Vec<StackValue>,
+    pub after: Vec<StackValue>,
+    /// What it takes off the stack and puts on, bottom first. A block's header pops its params and
+    /// pushes them again inside the block, and an `end` does the same with its results.
+    pub popped: Vec<StackValue>,
+    pub pushed: Vec<StackValue>,
+    /// False after an `unreachable`, branch, return or throw, until the block ends
+    pub reachable: bool,
+    /// False when how many values it pops or pushes is a guess
+    pub exact: bool,
+}
+
+/// An instruction that doesn't fit the stack it's given
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackError {
+    /// The instruction, as an index into FuncTypes::instrs
+    pub instr: usize,
+    pub offset: Option<usize>,
+    pub message: String,
+    pub expected: Vec<StackValue>,
+    pub actual: Vec<StackValue>,
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "instruction {}", self.instr)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at {:#x}", offset)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if !self.expected.is_empty() || !self.actual.is_empty() {
+            write!(f, ", expected {} but found {}", types_to_str(&self.expected), types_to_str(&self.actual))?;
+        }
+        Ok(())
+    }
+}
+
+/// The stack types of a whole body
+#[derive(Debug, Clone, Default)]
+pub struct FuncTypes {
+    /// One for every encoded instruction, in the order they're encoded (headers, `else`s and `end`s
+    /// included), so they line up with WasmFunction::instr_offsets
+    pub instrs: Vec<InstrTypes>,
+    pub errors: Vec<StackError>,
+    /// The most values the stack holds at once
+    pub max_height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If,
+    Else,
+    Try,
+    Catch,
+}
+
+struct Frame {
+    kind: FrameKind,
+    params: Vec<StackValue>,
+    results: Vec<StackValue>,
+    // Stack height when the block was entered, after its params were taken
+    height: usize,
+    // Past a branch, where anything can be popped
+    unreachable: bool,
+}
+
+impl Frame {
+    // What a branch to this frame carries
+    fn label_types(&self) -> &[StackValue] {
+        match self.kind {
+            FrameKind::Loop => &self.params,
+            _ => &self.results,
+        }
+    }
+}
+
+fn to_values(types: &[WasmTypeAnnotation]) -> Vec<StackValue> {
+    types.iter().map(|t| StackValue::Type(t._type)).collect()
+}
+
+fn prim_value(prim: Prim) -> StackValue {
+    match prim {
+        Prim::I32 => StackValue::Type(I32),
+        Prim::I64 => StackValue::Type(I64),
+        Prim::F32 => StackValue::Type(F32),
+        Prim::F64 => StackValue::Type(F64),
+        Prim::V128 => StackValue::Type(V128),
+        Prim::Func => StackValue::Type(FUNCREF),
+        _ => StackValue::Unknown,
+    }
+}
+
+fn address_type(is_64: bool) -> StackValue {
+    StackValue::Type(if is_64 { I64 } else { I32 })
+}
+
+// A copy's length has to fit in both its tables or memories
+fn narrower_address(a: StackValue, b: StackValue) -> StackValue {
+    address_type(a == StackValue::Type(I64) && b == StackValue::Type(I64))
+}
+
+struct Typer<'a> {
+    wasm: &'a WasmFile,
+    locals: &'a [u8],
+    offsets: &'a [usize],
+    vals: Vec<StackValue>,
+    frames: Vec<Frame>,
+    // The instruction being typed
+    popped: Vec<StackValue>,
+    pushed: Vec<StackValue>,
+    exact: bool,
+    underflowed: bool,
+    out: FuncTypes,
+}
+
+impl<'a> Typer<'a> {
+    fn error(&mut self, message: impl Into<String>, expected: Vec<StackValue>, actual: Vec<StackValue>) {
+        let instr = self.out.instrs.len();
+        self.out.errors.push(StackError { instr, offset: self.offsets.get(instr).copied(), message: message.into(), expected, actual });
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("the function's frame is never popped")
+    }
+
+    fn pop(&mut self, expected: StackValue) -> StackValue {
+        let frame = self.frame();
+        let actual = if self.vals.len() > frame.height {
+            self.vals.pop().unwrap_or(StackValue::Unknown)
+        } else if frame.unreachable {
+            StackValue::Unknown
+        } else {
+            // One error is enough for an instruction that runs out of operands
+            if !self.underflowed {
+                self.underflowed = true;
+                self.error("Not enough operands on the stack", vec![expected], vec![]);
+            }
+            StackValue::Unknown
+        };
+        if !actual.matches(expected) {
+            self.error("Operand has the wrong type", vec![expected], vec![actual]);
+        }
+        let value = if actual == StackValue::Unknown { expected } else { actual };
+        self.popped.insert(0, value);
+        value
+    }
+
+    fn pop_all(&mut self, expected: &[StackValue]) -> Vec<StackValue> {
+        let mut values: Vec<StackValue> = expected.iter().rev().map(|t| self.pop(*t)).collect();
+        values.reverse();
+        values
+    }
+
+    fn push(&mut self, value: StackValue) {
+        self.pushed.push(value);
+        self.vals.push(value);
+    }
+
+    fn push_all(&mut self, values: &[StackValue]) {
+        for value in values {
+            self.push(*value);
+        }
+    }
+
+    // Anything after this is never run, so the stack can be popped past the block's start
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().expect("the function's frame is never popped");
+        self.vals.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    // For instructions whose operands aren't known, drops the block's stack so nothing after them
+    // is reported against a guess
+    fn unknown_effect(&mut self) {
+        self.exact = false;
+        self.set_unreachable();
+        self.push(StackValue::Unknown);
+    }
+
+    fn label(&mut self, depth: usize) -> Option<Vec<StackValue>> {
+        match self.frames.len().checked_sub(depth + 1) {
+            Some(i) => Some(self.frames[i].label_types().to_vec()),
+            None => {
+                self.error(format!("Branch to label {} but only {} blocks are open", depth, self.frames.len()), vec![], vec![]);
+                None
+            }
+        }
+    }
+
+    fn block_sig(&mut self, block_type: i64) -> (Vec<StackValue>, Vec<StackValue>) {
+        match block_type {
+            -0x40 => (vec![], vec![]),
+            t if t < 0 => (vec![], vec![StackValue::Type((t + 0x80) as u8)]),
+            t => match self.wasm.type_section.function_signatures.get(t as usize) {
+                Some(sig) => (to_values(&sig.params), to_values(&sig.results)),
+                None => {
+                    self.error(format!("Block uses missing type {}", t), vec![], vec![]);
+                    (vec![], vec![])
+                }
+            },
+        }
+    }
+
+    fn local(&mut self, idx: u32) -> StackValue {
+        match self.locals.get(idx as usize) {
+            Some(t) => StackValue::Type(*t),
+            None => {
+                self.error(format!("No local {}", idx), vec![], vec![]);
+                StackValue::Unknown
+            }
+        }
+    }
+
+    fn global(&mut self, idx: u32) -> StackValue {
+        match self.wasm.get_global(idx) {
+            Some(WasmIndexEntry::Defined(global)) => StackValue::Type(global.wasm_type._type),
+            Some(WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Global { wasm_type, .. }, .. })) => StackValue::Type(wasm_type._type),
+            _ => {
+                self.error(format!("No global {}", idx), vec![], vec![]);
+                StackValue::Unknown
+            }
+        }
+    }
+
+    // The element type and address type of a table
+    fn table(&mut self, idx: u32) -> (StackValue, StackValue) {
+        let table = match self.wasm.get_table(idx) {
+            Some(WasmIndexEntry::Defined(table)) => Some(table),
+            Some(WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Table(table), .. })) => Some(table),
+            _ => None,
+        };
+        match table {
+            Some(table) => (StackValue::Type(table.wasm_type), address_type(table.limits.is_64)),
+            None => {
+                self.error(format!("No table {}", idx), vec![], vec![]);
+                (StackValue::Unknown, StackValue::Type(I32))
+            }
+        }
+    }
+
+    fn memory_address(&mut self, idx: u32) -> StackValue {
+        let memory = match self.wasm.get_memory(idx) {
+            Some(WasmIndexEntry::Defined(memory)) => Some(memory),
+            Some(WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Mem(memory), .. })) => Some(memory),
+            _ => None,
+        };
+        match memory {
+            Some(memory) => address_type(memory.limits.is_64),
+            None => {
+                self.error(format!("No memory {}", idx), vec![], vec![]);
+                StackValue::Type(I32)
+            }
+        }
+    }
+
+    fn call(&mut self, sig: Option<&WasmFunctionType>, what: String, tail: bool) {
+        let Some(sig) = sig else {
+            self.error(format!("No signature for {}", what), vec![], vec![]);
+            self.unknown_effect();
+            return;
+        };
+        let (params, results) = (to_values(&sig.params), to_values(&sig.results));
+        self.pop_all(&params);
+        if tail {
+            let returns = self.frames[0].results.clone();
+            if returns.len() != results.len() || results.iter().zip(returns.iter()).any(|(a, b)| !a.matches(*b)) {
+                self.error("Tail call returns the wrong types", returns, results);
+            }
+            self.set_unreachable();
+        } else {
+            self.push_all(&results);
+        }
+    }
+
+    fn begin(&mut self) -> (Vec<StackValue>, bool) {
+        self.popped.clear();
+        self.pushed.clear();
+        self.exact = true;
+        self.underflowed = false;
+        (self.vals.clone(), !self.frame().unreachable)
+    }
+
+    fn finish(&mut self, info: InstrInfo, depth: usize, (before, reachable): (Vec<StackValue>, bool)) {
+        let offset = self.offsets.get(self.out.instrs.len()).copied();
+        self.out.max_height = self.out.max_height.max(self.vals.len()).max(before.len());
+        self.out.instrs.push(InstrTypes {
+            info,
+            offset,
+            depth,
+            before,
+            after: self.vals.clone(),
+            popped: std::mem::take(&mut self.popped),
+            pushed: std::mem::take(&mut self.pushed),
+            reachable,
+            exact: self.exact,
+        });
+    }
+
+    // A try_table's handlers branch to labels outside it, with the tag's params and, for the
+    // `_ref` kinds, the exception
+    fn check_catches(&mut self, catches: &[CatchClause]) {
+        for catch in catches {
+            let mut values = match catch.tag() {
+                Some(tag) => self.tag_params(tag),
+                None => vec![],
+            };
+            if matches!(catch, CatchClause::CatchRef { .. } | CatchClause::CatchAllRef { .. }) {
+                values.push(StackValue::Type(EXNREF));
+            }
+            if let Some(types) = self.label(catch.label() as usize) {
+                if types.len() != values.len() || !values.iter().zip(&types).all(|(value, t)| value.matches(*t)) {
+                    self.error(format!("{} clause doesn't give what label {} takes", catch.name(), catch.label()), types, values);
+                }
+            }
+        }
+    }
+
+    fn begin_block(&mut self, info: InstrInfo, block_type: i64, catches: &[CatchClause]) {
+        self.check_catches(catches);
+        let (params, results) = self.block_sig(block_type);
+        let kind = match info.instr {
+            0x03 => FrameKind::Loop,
+            0x04 => {
+                self.pop(StackValue::Type(I32));
+                FrameKind::If
+            }
+            0x06 => FrameKind::Try,
+            _ => FrameKind::Block,
+        };
+        let params = self.pop_all(&params);
+        let height = self.vals.len();
+        self.frames.push(Frame { kind, params: params.clone(), results, height, unreachable: false });
+        self.push_all(&params);
+    }
+
+    // Checks the results are all that's left in the block
+    fn close_frame(&mut self) {
+        let results = self.frame().results.clone();
+        self.pop_all(&results);
+        let frame = self.frame();
+        if self.vals.len() > frame.height {
+            let left = self.vals[frame.height..].to_vec();
+            self.error("Values left on the stack at the end of the block", vec![], left);
+        }
+        self.vals.truncate(self.frame().height);
+    }
+
+    fn else_arm(&mut self) {
+        if self.frame().kind != FrameKind::If {
+            self.error("`else` outside of an `if`", vec![], vec![]);
+            return;
+        }
+        self.close_frame();
+        // The popped results are what the first arm gives, not an effect of `else`
+        self.popped.clear();
+        let frame = self.frames.last_mut().expect("the function's frame is never popped");
+        frame.kind = FrameKind::Else;
+        frame.unreachable = false;
+        let params = frame.params.clone();
+        self.push_all(&params);
+    }
+
+    // A `catch` starts with the tag's params on the stack, a `catch_all` with nothing
+    fn catch_arm(&mut self, tag: Option<u32>) {
+        if !matches!(self.frame().kind, FrameKind::Try | FrameKind::Catch) {
+            self.error("`catch` outside of a `try`", vec![], vec![]);
+            return;
+        }
+        self.close_frame();
+        self.popped.clear();
+        let frame = self.frames.last_mut().expect("the function's frame is never popped");
+        frame.kind = FrameKind::Catch;
+        frame.unreachable = false;
+        if let Some(tag) = tag {
+            let params = self.tag_params(tag);
+            self.push_all(&params);
+        }
+    }
+
+    fn tag_params(&mut self, tag: u32) -> Vec<StackValue> {
+        match self.wasm.get_tag_sig(tag) {
+            Some(sig) => to_values(&sig.params),
+            None => {
+                self.error(format!("No tag {}", tag), vec![], vec![]);
+                self.exact = false;
+                vec![]
+            }
+        }
+    }
+
+    fn end(&mut self) {
+        self.close_frame();
+        // The function's results stay on the stack, as what it returns
+        if self.frames.len() == 1 {
+            let results = self.frames[0].results.clone();
+            self.push_all(&results);
+            return;
+        }
+        let frame = self.frames.pop().expect("checked above");
+        if frame.kind == FrameKind::If && frame.params != frame.results {
+            self.error("`if` without an `else` has to give back its params", frame.results.clone(), frame.params.clone());
+        }
+        self.push_all(&frame.results);
+    }
+
+    fn instr(&mut self, info: InstrInfo, instr: &[ExprSeg]) {
+        let imm = instr.get(1);
+        match (info.name, imm) {
+            ("unreachable", _) => self.set_unreachable(),
+            ("rethrow", Some(ExprSeg::Int(depth))) => {
+                let depth = *depth as usize;
+                match self.frames.len().checked_sub(depth + 1) {
+                    Some(i) if self.frames[i].kind != FrameKind::Catch => {
+                        self.error(format!("rethrow label {} isn't a catch", depth), vec![], vec![]);
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.label(depth);
+                    }
+                }
+                self.set_unreachable();
+            }
+            ("throw_ref", _) => {
+                self.pop(StackValue::Unknown);
+                self.set_unreachable();
+            }
+            ("throw", Some(ExprSeg::Tag(tag))) => {
+                let params = self.tag_params(*tag);
+                self.pop_all(&params);
+                self.set_unreachable();
+            }
+            ("else", _) => self.else_arm(),
+            ("catch", Some(ExprSeg::Tag(tag))) => self.catch_arm(Some(*tag)),
+            ("catch_all", _) => self.catch_arm(None),
+            ("br", Some(ExprSeg::Int(depth))) => {
+                if let Some(types) = self.label(*depth as usize) {
+                    self.pop_all(&types);
+                }
+                self.set_unreachable();
+            }
+            ("br_if", Some(ExprSeg::Int(depth))) => {
+                self.pop(StackValue::Type(I32));
+                if let Some(types) = self.label(*depth as usize) {
+                    let values = self.pop_all(&types);
+                    self.push_all(&values);
+                }
+            }
+            ("br_on_null", Some(ExprSeg::Int(depth))) => {
+                let reference = self.pop(StackValue::Unknown);
+                if let Some(types) = self.label(*depth as usize) {
+                    let values = self.pop_all(&types);
+                    self.push_all(&values);
+                }
+                self.push(reference);
+            }
+            // The label takes the non-null reference on top of its other values
+            ("br_on_non_null", Some(ExprSeg::Int(depth))) => {
+                self.pop(StackValue::Unknown);
+                if let Some(types) = self.label(*depth as usize) {
+                    match types.split_last() {
+                        Some((_, rest)) => {
+                            let values = self.pop_all(rest);
+                            self.push_all(&values);
+                        }
+                        None => self.error(format!("br_on_non_null target {} takes no reference", depth), vec![], vec![]),
+                    }
+                }
+            }
+            ("br_table", Some(ExprSeg::BrTable(table))) => {
+                self.pop(StackValue::Type(I32));
+                if let Some(types) = self.label(table.default as usize) {
+                    // Each target is checked against the operands on their own, so after an
+                    // `unreachable` they can take different types
+                    for depth in table.break_depths.iter() {
+                        match self.label(*depth as usize) {
+                            Some(target) if target.len() != types.len() => {
+                                self.error(format!("br_table target {} takes a different number of values than the default", depth), types.clone(), target);
+                            }
+                            Some(target) => {
+                                let snapshot = (self.vals.clone(), self.popped.clone(), self.pushed.clone());
+                                self.pop_all(&target);
+                                (self.vals, self.popped, self.pushed) = snapshot;
+                            }
+                            None => {}
+                        }
+                    }
+                    self.pop_all(&types);
+                }
+                self.set_unreachable();
+            }
+            ("return", _) => {
+                let results = self.frames[0].results.clone();
+                self.pop_all(&results);
+                self.set_unreachable();
+            }
+            ("call" | "return_call", Some(ExprSeg::Func(func))) => {
+                self.call(self.wasm.get_func_sig(*func), format!("function {}", func), info.name == "return_call");
+            }
+            ("call_indirect" | "return_call_indirect", Some(ExprSeg::Int(sig))) => {
+                let table = match instr.get(2) {
+                    Some(ExprSeg::Int(table)) => *table as u32,
+                    _ => 0,
+                };
+                let (_, address) = self.table(table);
+                self.pop(address);
+                self.call(self.wasm.type_section.function_signatures.get(*sig as usize), format!("type {}", sig), info.name == "return_call_indirect");
+            }
+            ("call_ref" | "return_call_ref", Some(ExprSeg::Type(sig))) => {
+                self.pop(StackValue::Unknown);
+                self.call(self.wasm.type_section.function_signatures.get(*sig as usize), format!("type {}", sig), info.name == "return_call_ref");
+            }
+            ("drop", _) => {
+                self.pop(StackValue::Unknown);
+            }
+            ("select", _) => {
+                self.pop(StackValue::Type(I32));
+                let second = self.pop(StackValue::Unknown);
+                let first = self.pop(second);
+                self.push(if second == StackValue::Unknown { first } else { second });
+            }
+            ("select_1", Some(ExprSeg::Int(t))) => {
+                let t = StackValue::Type(*t as u8);
+                self.pop(StackValue::Type(I32));
+                self.pop_all(&[t, t]);
+                self.push(t);
+            }
+            ("local.get", Some(ExprSeg::Local(idx))) => {
+                let t = self.local(*idx);
+                self.push(t);
+            }
+            ("local.set", Some(ExprSeg::Local(idx))) => {
+                let t = self.local(*idx);
+                self.pop(t);
+            }
+            ("local.tee", Some(ExprSeg::Local(idx))) => {
+                let t = self.local(*idx);
+                self.pop(t);
+                self.push(t);
+            }
+            ("global.get", Some(ExprSeg::Global(idx))) => {
+                let t = self.global(*idx);
+                self.push(t);
+            }
+            ("global.set", Some(ExprSeg::Global(idx))) => {
+                let t = self.global(*idx);
+                self.pop(t);
+            }
+            ("table.get", Some(ExprSeg::Table(idx))) => {
+                let (elem, address) = self.table(*idx);
+                self.pop(address);
+                self.push(elem);
+            }
+            ("table.set", Some(ExprSeg::Table(idx))) => {
+                let (elem, address) = self.table(*idx);
+                self.pop_all(&[address, elem]);
+            }
+            ("table.size", Some(ExprSeg::Table(idx))) => {
+                let (_, address) = self.table(*idx);
+                self.push(address);
+            }
+            ("table.grow", Some(ExprSeg::Table(idx))) => {
+                let (elem, address) = self.table(*idx);
+                self.pop_all(&[elem, address]);
+                self.push(address);
+            }
+            ("table.fill", Some(ExprSeg::Table(idx))) => {
+                let (elem, address) = self.table(*idx);
+                self.pop_all(&[address, elem, address]);
+            }
+            // The segment's offset and length are always i32
+            ("table.init", Some(ExprSeg::Elem(_))) => {
+                let table = match instr.get(2) {
+                    Some(ExprSeg::Table(table)) => *table,
+                    _ => 0,
+                };
+                let (_, address) = self.table(table);
+                self.pop_all(&[address, StackValue::Type(I32), StackValue::Type(I32)]);
+            }
+            ("table.copy", Some(ExprSeg::Table(dst))) => {
+                let src = match instr.get(2) {
+                    Some(ExprSeg::Table(src)) => *src,
+                    _ => 0,
+                };
+                let (_, dst) = self.table(*dst);
+                let (_, src) = self.table(src);
+                self.pop_all(&[dst, src, narrower_address(dst, src)]);
+            }
+            ("ref.null", Some(ExprSeg::HeapType(heap))) => {
+                // Abstract heap types share their byte with the nullable reference to them
+                let t = if *heap < 0 { StackValue::Type((heap + 0x80) as u8) } else { StackValue::Unknown };
+                self.push(t);
+            }
+            ("ref.is_null", _) => {
+                self.pop(StackValue::Unknown);
+                self.push(StackValue::Type(I32));
+            }
+            ("ref.as_non_null", _) => {
+                let t = self.pop(StackValue::Unknown);
+                self.push(t);
+            }
+            ("ref.func", _) => self.push(StackValue::Type(FUNCREF)),
+            ("memory.size", Some(ExprSeg::Memory(idx))) => {
+                let address = self.memory_address(*idx);
+                self.push(address);
+            }
+            ("memory.grow", Some(ExprSeg::Memory(idx))) => {
+                let address = self.memory_address(*idx);
+                self.pop(address);
+                self.push(address);
+            }
+            ("memory.fill", Some(ExprSeg::Memory(idx))) => {
+                let address = self.memory_address(*idx);
+                self.pop_all(&[address, StackValue::Type(I32), address]);
+            }
+            ("memory.init", Some(ExprSeg::Data(_))) => {
+                let memory = match instr.get(2) {
+                    Some(ExprSeg::Memory(memory)) => *memory,
+                    _ => 0,
+                };
+                let address = self.memory_address(memory);
+                self.pop_all(&[address, StackValue::Type(I32), StackValue::Type(I32)]);
+            }
+            ("memory.copy", Some(ExprSeg::Memory(dst))) => {
+                let src = match instr.get(2) {
+                    Some(ExprSeg::Memory(src)) => *src,
+                    _ => 0,
+                };
+                let dst = self.memory_address(*dst);
+                let src = self.memory_address(src);
+                self.pop_all(&[dst, src, narrower_address(dst, src)]);
+            }
+            _ => self.table_effect(info, imm),
+        }
+    }
+
+    // Goes by the instruction table, with the address of a memory access widened for 64-bit memories
+    fn table_effect(&mut self, info: InstrInfo, imm: Option<&ExprSeg>) {
+        let out_types: &[Prim] = if info.out_types == [Prim::Void] { &[] } else { info.out_types };
+        let arity_unknown = matches!(info.name, "struct.new" | "array.new_fixed") || matches!(get_edge_case(info), SpecialInstr::BrOnCast);
+        if arity_unknown || info.in_types.contains(&Prim::Void) || out_types.contains(&Prim::Void) {
+            self.unknown_effect();
+            return;
+        }
+        let mut in_types: Vec<StackValue> = info.in_types.iter().map(|prim| prim_value(*prim)).collect();
+        if let (Some(ExprSeg::MemArg(memarg)), Some(first)) = (imm, in_types.first_mut()) {
+            *first = self.memory_address(memarg.memory);
+        }
+        self.pop_all(&in_types);
+        for prim in out_types {
+            self.push(prim_value(*prim));
+        }
+    }
+
+    fn walk(&mut self, expr: &WasmExpr, depth: usize) {
+        for seg in expr.expr_string.iter() {
+            // An `end`, or the `delegate` that ends a try
+            if let Some(info) = block_end(seg) {
+                let state = self.begin();
+                self.end();
+                self.finish(info, depth.saturating_sub(1), state);
+                return;
+            }
+            match seg {
+                ExprSeg::ControlFlow(info, block_type, body, _, catches) => {
+                    let state = self.begin();
+                    self.begin_block(*info, *block_type, catches);
+                    self.finish(*info, depth, state);
+                    self.walk(body, depth + 1);
+                }
+                ExprSeg::Instr(instr) => {
+                    let Some(ExprSeg::Operation(info)) = instr.first() else {
+                        continue;
+                    };
+                    let state = self.begin();
+                    self.instr(*info, instr);
+                    let depth = if is_arm(seg) { depth.saturating_sub(1) } else { depth };
+                    self.finish(*info, depth, state);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Types an expression that runs with the given locals and has to leave `results` on the stack,
+/// such as a constant expression. `offsets` are its instructions' file offsets, if known.
+pub fn infer_expr(wasm: &WasmFile, locals: &[u8], results: &[u8], expr: &WasmExpr, offsets: &[usize]) -> FuncTypes {
+    let results = results.iter().map(|t| StackValue::Type(*t)).collect();
+    let mut typer = Typer {
+        wasm,
+        locals,
+        offsets,
+        vals: vec![],
+        frames: vec![Frame { kind: FrameKind::Func, params: vec![], results, height: 0, unreachable: false }],
+        popped: vec![],
+        pushed: vec![],
+        exact: true,
+        underflowed: false,
+        out: FuncTypes::default(),
+    };
+    typer.walk(expr, 0);
+    typer.out
+}
+
+/// Types the body of a defined function, going by its signature and locals
+pub fn infer_func(wasm: &WasmFile, funcidx: u32) -> Result<FuncTypes, WyrmError> {
+    let entry = wasm.get_func(funcidx).ok_or_else(|| WyrmError::invalid(format!("No function {}", funcidx)))?;
+    let func = entry.body.ok_or_else(|| WyrmError::invalid("Imported functions have no body to type").in_func(funcidx))?;
+    let sig = entry.sig.ok_or_else(|| WyrmError::invalid("Function has no signature").in_func(funcidx))?;
+    let locals: Vec<u8> = sig.params.iter().map(|t| t._type).chain(func.locals.iter().map(|local| local._type._type)).collect();
+    let results: Vec<u8> = sig.results.iter().map(|t| t._type).collect();
+    Ok(infer_expr(wasm, &locals, &results, &func.body, &func.instr_offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    const I32_VAL: StackValue = StackValue::Type(I32);
+
+    fn heights(types: &FuncTypes) -> Vec<(&'static str, usize, usize)> {
+        types.instrs.iter().map(|instr| (instr.info.name, instr.before.len(), instr.after.len())).collect()
+    }
+
+    #[test]
+    fn tracks_heights_and_types() {
+        let wasm = parse_wat(
+            "(module
+              (func (param i32 i64) (result f64)
+                local.get 0
+                i32.const 1
+                i32.add
+                drop
+                local.get 1
+                f64.convert_i64_s))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        assert_eq!(
+            heights(&types),
+            vec![("local.get", 0, 1), ("i32.const", 1, 2), ("i32.add", 2, 1), ("drop", 1, 0), ("local.get", 0, 1), ("f64.convert_i64_s", 1, 1), ("end", 1, 1)]
+        );
+        assert_eq!(types.instrs[2].popped, vec![I32_VAL, I32_VAL]);
+        assert_eq!(types.instrs[5].after, vec![StackValue::Type(F64)]);
+        assert_eq!(types.max_height, 2);
+    }
+
+    #[test]
+    fn handles_block_params_and_unreachable_code() {
+        let wasm = parse_wat(
+            "(module
+              (type $pair (func (param i32 i32) (result i32)))
+              (func (result i32)
+                i32.const 1
+                i32.const 2
+                block (type $pair)
+                  i32.add
+                end
+                block (result i32)
+                  i32.const 3
+                  br 0
+                  i32.add
+                end
+                drop))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        // The block takes both constants as its params
+        assert_eq!((types.instrs[2].popped.len(), types.instrs[2].pushed.len()), (2, 2));
+        assert_eq!(types.instrs[4].after, vec![I32_VAL]);
+        // `br 0` carries the block's result, and the stack is polymorphic after it
+        assert_eq!(types.instrs[7].popped, vec![I32_VAL]);
+        assert!(types.instrs[7].reachable);
+        assert!(!types.instrs[8].reachable);
+        assert_eq!(types.instrs[8].popped, vec![I32_VAL, I32_VAL]);
+        assert_eq!(types.instrs[9].after, vec![I32_VAL, I32_VAL]);
+    }
+
+    #[test]
+    fn uses_call_signatures() {
+        let wasm = parse_wat(
+            "(module
+              (type $t (func (param f32) (result i64)))
+              (import \"env\" \"f\" (func $f (param i32 i32) (result f32)))
+              (table 1 funcref)
+              (func (result i64)
+                i32.const 1
+                i32.const 2
+                call $f
+                i32.const 0
+                call_indirect (type $t)))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 1).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        assert_eq!((types.instrs[2].popped.len(), types.instrs[2].pushed.len()), (2, 1));
+        assert_eq!(types.instrs[4].popped, vec![StackValue::Type(F32), I32_VAL]);
+        assert_eq!(types.instrs[4].pushed, vec![StackValue::Type(I64)]);
+    }
+
+    #[test]
+    fn types_function_references() {
+        let wasm = parse_wat(
+            "(module
+              (type $t (func (param i32) (result i64)))
+              (func (param funcref) (result i64)
+                i32.const 7
+                local.get 0
+                call_ref $t)
+              (func (param funcref)
+                block (result funcref)
+                  local.get 0
+                  br_on_non_null 0
+                  ref.null func
+                end
+                drop))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        assert_eq!(types.instrs[2].popped, vec![I32_VAL, StackValue::Type(FUNCREF)]);
+        assert_eq!(types.instrs[2].pushed, vec![StackValue::Type(I64)]);
+
+        let types = infer_func(&wasm, 1).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        // The reference only stays on the stack when it's taken to the label
+        assert_eq!(heights(&types)[2], ("br_on_non_null", 1, 0));
+    }
+
+    #[test]
+    fn types_throws_and_catches_by_their_tags() {
+        let wasm = parse_wat(
+            "(module
+              (tag $e (param i32 i64))
+              (func (result i64) (local i64)
+                try (result i64)
+                  i32.const 1
+                  i64.const 2
+                  throw $e
+                catch $e
+                  local.set 0
+                  drop
+                  local.get 0
+                catch_all
+                  i64.const 0
+                end)
+              (func
+                throw 3))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+        assert_eq!(types.instrs[3].popped, vec![I32_VAL, StackValue::Type(I64)]);
+        assert!(types.instrs[3].exact);
+        // A catch starts with the tag's params, at the depth of the try
+        assert_eq!(heights(&types)[4], ("catch", 0, 2));
+        assert_eq!(types.instrs[4].depth, 0);
+        assert_eq!(heights(&types)[8], ("catch_all", 1, 0));
+
+        let types = infer_func(&wasm, 1).unwrap();
+        assert_eq!(types.errors.iter().map(|e| e.message.as_str()).collect::<Vec<_>>(), vec!["No tag 3"]);
+    }
+
+    #[test]
+    fn checks_br_table_targets_against_the_operands() {
+        let wasm = parse_wat(
+            "(module
+              (func (param i32) (result i32)
+                block (result i32)
+                  block (result f32)
+                    i32.const 1
+                    local.get 0
+                    br_table 0 1
+                  end
+                  drop
+                  i32.const 2
+                end))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert_eq!(types.errors.len(), 1, "{:?}", types.errors);
+        let error = &types.errors[0];
+        assert_eq!(error.message, "Operand has the wrong type");
+        assert_eq!((error.expected.clone(), error.actual.clone()), (vec![StackValue::Type(F32)], vec![I32_VAL]));
+        assert_eq!(types.instrs[4].popped, vec![I32_VAL, I32_VAL]);
+
+        // With nothing on the stack, the targets don't have to agree
+        let wasm = parse_wat(
+            "(module
+              (func (param i32) (result i32)
+                (block (result i32)
+                  (block (result i64)
+                    unreachable
+                    (br_table 0 1 (local.get 0)))
+                  drop
+                  i32.const 0)))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+    }
+
+    #[test]
+    fn uses_the_address_type_of_each_memory_and_table() {
+        let wasm = parse_wat(
+            "(module
+              (memory $wide i64 1)
+              (memory $narrow 1)
+              (table $wide_table i64 1 funcref)
+              (table $narrow_table 1 funcref)
+              (data $d \"hi\")
+              (elem $e func)
+              (func
+                i64.const 0 i32.const 0 i64.const 1 memory.fill $wide
+                i64.const 0 i32.const 0 i32.const 1 memory.init $wide $d
+                i64.const 0 i64.const 0 i64.const 1 memory.copy $wide $wide
+                i64.const 0 i32.const 0 i32.const 1 memory.copy $wide $narrow
+                i32.const 0 i64.const 0 i32.const 1 memory.copy $narrow $wide
+                i64.const 0 i32.const 0 i32.const 0 table.init $wide_table $e
+                i64.const 0 i32.const 0 i32.const 1 table.copy $wide_table $narrow_table)
+              (func
+                i64.const 0 i32.const 0 i64.const 1 memory.copy $wide $narrow))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert!(types.errors.is_empty(), "{:?}", types.errors);
+
+        // The length is only 64-bit when both sides are
+        let types = infer_func(&wasm, 1).unwrap();
+        assert_eq!(types.errors.len(), 1, "{:?}", types.errors);
+        assert_eq!((types.errors[0].expected.clone(), types.errors[0].actual.clone()), (vec![I32_VAL], vec![StackValue::Type(I64)]));
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let wasm = parse_wat(
+            "(module
+              (func (result i32)
+                f32.const 1
+                i32.const 2
+                i32.add))",
+        )
+        .unwrap();
+        let types = infer_func(&wasm, 0).unwrap();
+        assert_eq!(types.errors.len(), 1, "{:?}", types.errors);
+        let error = &types.errors[0];
+        assert_eq!(error.instr, 2);
+        assert_eq!((error.expected.clone(), error.actual.clone()), (vec![I32_VAL], vec![StackValue::Type(F32)]));
+        assert_eq!(error.to_string(), "instruction 2: Operand has the wrong type, expected [i32] but found [f32]");
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::error::WyrmError;
+use crate::wasm_model::*;
+
+// How many of the most used instructions are listed
+const TOP_INSTRS: usize = 10;
+
+/// What a body or set of bodies is made of. Every encoded instruction is counted, including the
+/// `else` and `end`s.
+#[derive(Debug, Default)]
+pub struct InstrStats {
+    pub total: usize,
+    pub by_name: BTreeMap<&'static str, usize>,
+    /// How deeply blocks are nested, 0 for a body without blocks
+    pub max_depth: usize,
+}
+
+impl InstrStats {
+    pub fn add_expr(&mut self, expr: &WasmExpr) {
+        self.add_expr_at(expr, 0);
+    }
+
+    fn add_expr_at(&mut self, expr: &WasmExpr, depth: usize) {
+        self.max_depth = self.max_depth.max(depth);
+        for seg in expr.expr_string.iter() {
+            match seg {
+                ExprSeg::Operation(info) => self.add(info),
+                ExprSeg::ControlFlow(info, _, body, ..) => {
+                    self.add(info);
+                    self.add_expr_at(body, depth + 1);
+                }
+                ExprSeg::Instr(instr) => {
+                    if let Some(ExprSeg::Operation(info)) = instr.first() {
+                        self.add(info);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn add(&mut self, info: &InstrInfo) {
+        self.total += 1;
+        *self.by_name.entry(info.name).or_default() += 1;
+    }
+
+    /// The most used instructions, most used first
+    pub fn most_used(&self, count: usize) -> Vec<(&'static str, usize)> {
+        let mut by_count: Vec<(&'static str, usize)> = self.by_name.iter().map(|(name, n)| (*name, *n)).collect();
+        by_count.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        by_count.truncate(count);
+        by_count
+    }
+
+    fn write(&self, out: &mut dyn Write) -> Result<(), WyrmError> {
+        writeln!(out, "instructions: {}", self.total)?;
+        writeln!(out, "max block depth: {}", self.max_depth)?;
+        let most_used = self.most_used(TOP_INSTRS);
+        if !most_used.is_empty() {
+            writeln!(out, "most used:")?;
+            let width = most_used.iter().map(|(name, _)| name.len()).max().unwrap_or_default();
+            for (name, count) in most_used {
+                writeln!(out, "  {:<width$}  {}", name, count, width = width)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn write_module_stats(wasm: &WasmFile, out: &mut dyn Write) -> Result<(), WyrmError> {
+    let spaces = wasm.index_spaces();
+    let imported = |space: &Vec<WasmIndexOrigin>| space.iter().filter(|origin| matches!(origin, WasmIndexOrigin::Imported(_))).count();
+    writeln!(out, "types: {}", wasm.type_section.function_signatures.len())?;
+    writeln!(out, "imports: {}", wasm.import_section_header.imports.len())?;
+    for (kind, space) in [
+        ("functions", &spaces.funcs),
+        ("tables", &spaces.tables),
+        ("memories", &spaces.memories),
+        ("globals", &spaces.globals),
+        ("tags", &spaces.tags),
+    ] {
+        writeln!(out, "{}: {} ({} imported)", kind, space.len(), imported(space))?;
+    }
+    writeln!(out, "exports: {}", wasm.export_section.exports.len())?;
+    writeln!(out, "elems: {}", wasm.elem_section.elems.len())?;
+    writeln!(out, "data: {} ({} bytes)", wasm.data_section.data_segs.len(), wasm.data_section.data_segs.iter().map(|data| data.data.len()).sum::<usize>())?;
+    writeln!(out, "code bytes: {}", wasm.code_section.functions.iter().map(|func| func.raw_body.len()).sum::<usize>())?;
+
+    let mut instrs = InstrStats::default();
+    for func in wasm.code_section.functions.iter() {
+        instrs.add_expr(&func.body);
+    }
+    instrs.write(out)
+}
+
+pub fn write_func_stats(wasm: &WasmFile, funcidx: u32, func: &WasmFunction, out: &mut dyn Write) -> Result<(), WyrmError> {
+    let names = wasm.name_section.with_unique_ids();
+    writeln!(out, "function: {} {}", funcidx, names.func_id(funcidx))?;
+    if let Some(sig) = wasm.get_func_sig(funcidx) {
+        writeln!(out, "params: {}", sig.params.len())?;
+        writeln!(out, "results: {}", sig.results.len())?;
+    }
+    writeln!(out, "locals: {}", func.locals.len())?;
+    writeln!(out, "code bytes: {}", func.raw_body.len())?;
+    let mut instrs = InstrStats::default();
+    instrs.add_expr(&func.body);
+    instrs.write(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+
+    #[test]
+    fn counts_instructions_and_depth() {
+        let wasm = parse_wat(
+            "(module
+              (func (param i32)
+                local.get 0
+                if
+                  block
+                    local.get 0
+                    drop
+                  end
+                else
+                  nop
+                end))",
+        )
+        .unwrap();
+        let mut stats = InstrStats::default();
+        stats.add_expr(&wasm.code_section.functions[0].body);
+        // Two local.gets, if, block, drop, else, nop and three ends
+        assert_eq!(stats.total, 10);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.most_used(2), vec![("end", 3), ("local.get", 2)]);
+    }
+}
@@ -0,0 +1,693 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::{self, Display, Formatter},
+};
+
+use crate::error::{ErrorLocation, WyrmError};
+use crate::stack_types::{infer_expr, infer_func, StackValue, StackError};
+use crate::wasm_model::*;
+
+const TYPE_SECTION: u8 = 0x01;
+const IMPORT_SECTION: u8 = 0x02;
+const FUNCTION_SECTION: u8 = 0x03;
+const TABLE_SECTION: u8 = 0x04;
+const MEMORY_SECTION: u8 = 0x05;
+const GLOBAL_SECTION: u8 = 0x06;
+const EXPORT_SECTION: u8 = 0x07;
+const START_SECTION: u8 = 0x08;
+const ELEM_SECTION: u8 = 0x09;
+const DATA_COUNT_SECTION: u8 = 0x0c;
+const CODE_SECTION: u8 = 0x0a;
+const DATA_SECTION: u8 = 0x0b;
+const TAG_SECTION: u8 = 0x0d;
+
+// Pages of 64KiB a memory can have
+const MAX_PAGES_32: u64 = 1 << 16;
+const MAX_PAGES_64: u64 = 1 << 48;
+
+/// Something the module does that the spec's validation rules don't allow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub location: ErrorLocation,
+    /// The instruction, counted in the order they're encoded, for errors in a function body
+    pub instr: Option<usize>,
+    pub message: String,
+    /// The types an instruction needed and the ones it got, for typing errors
+    pub expected: Vec<StackValue>,
+    pub actual: Vec<StackValue>,
+}
+
+fn types_to_str(types: &[StackValue]) -> String {
+    format!("[{}]", types.iter().map(|t| t.to_string()).collect::<Vec<String>>().join(" "))
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.expected.is_empty() || !self.actual.is_empty() {
+            write!(f, ", expected {} but found {}", types_to_str(&self.expected), types_to_str(&self.actual))?;
+        }
+        if let Some(instr) = self.instr {
+            write!(f, " at instruction {}", instr)?;
+        }
+        write!(f, "{}", self.location)
+    }
+}
+
+impl From<ValidationError> for WyrmError {
+    fn from(e: ValidationError) -> Self {
+        let location = e.location;
+        let message = ValidationError { location: ErrorLocation::default(), ..e }.to_string();
+        WyrmError::InvalidData(location, message)
+    }
+}
+
+fn in_section(section: u8) -> ErrorLocation {
+    ErrorLocation { section: Some(section), ..Default::default() }
+}
+
+// Calls `visit` with every encoded instruction's index, info and immediates, in encoding order
+fn for_each_instr(expr: &WasmExpr, next: &mut usize, visit: &mut dyn FnMut(usize, &InstrInfo, &[ExprSeg])) {
+    for seg in expr.expr_string.iter() {
+        match seg {
+            ExprSeg::Operation(info) if is_block_end(seg) => {
+                visit(*next, info, &[]);
+                *next += 1;
+                return;
+            }
+            ExprSeg::ControlFlow(info, _, body, ..) => {
+                visit(*next, info, &[]);
+                *next += 1;
+                for_each_instr(body, next, visit);
+            }
+            ExprSeg::Instr(instr) => {
+                if let Some(ExprSeg::Operation(info)) = instr.first() {
+                    visit(*next, info, &instr[1..]);
+                    *next += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_const_instr(info: &InstrInfo) -> bool {
+    info.name.ends_with(".const")
+        || matches!(
+            info.name,
+            "end"
+                | "ref.null"
+                | "ref.func"
+                | "global.get"
+                | "i32.add"
+                | "i32.sub"
+                | "i32.mul"
+                | "i64.add"
+                | "i64.sub"
+                | "i64.mul"
+                | "struct.new"
+                | "struct.new_default"
+                | "array.new"
+                | "array.new_default"
+                | "array.new_fixed"
+                | "ref.i31"
+                | "any.convert_extern"
+                | "extern.convert_any"
+        )
+}
+
+// How many lanes a lane index picks from. A shuffle picks bytes from both of its operands.
+fn lane_count(name: &str) -> u32 {
+    if name == "i8x16.shuffle" {
+        return 32;
+    }
+    let lane_bits = name.strip_prefix("v128.load").or_else(|| name.strip_prefix("v128.store")).and_then(|rest| rest.strip_suffix("_lane"));
+    if let Some(bits) = lane_bits.and_then(|bits| bits.parse::<u32>().ok()) {
+        return 128 / bits;
+    }
+    // The shape, e.g. i16x8
+    let shape = name.split('.').next().unwrap_or_default();
+    shape.split_once('x').and_then(|(_, lanes)| lanes.parse().ok()).unwrap_or(u32::MAX)
+}
+
+// Whether an index is past the end of an index space or section with `len` entries
+fn is_missing(idx: u32, len: usize) -> bool {
+    idx as usize >= len
+}
+
+fn global_type(wasm: &WasmFile, idx: u32) -> Option<(u8, bool)> {
+    match wasm.get_global(idx)? {
+        WasmIndexEntry::Defined(global) => Some((global.wasm_type._type, global.mutability != 0)),
+        WasmIndexEntry::Imported(import) => match import.desc {
+            WasmImportDesc::Global { wasm_type, mutability } => Some((wasm_type._type, mutability != 0)),
+            _ => None,
+        },
+    }
+}
+
+fn table_of(wasm: &WasmFile, idx: u32) -> Option<&WasmTable> {
+    match wasm.get_table(idx)? {
+        WasmIndexEntry::Defined(table) => Some(table),
+        WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Table(table), .. }) => Some(table),
+        _ => None,
+    }
+}
+
+fn memory_of(wasm: &WasmFile, idx: u32) -> Option<&WasmMemoryStruct> {
+    match wasm.get_memory(idx)? {
+        WasmIndexEntry::Defined(memory) => Some(memory),
+        WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Mem(memory), .. }) => Some(memory),
+        _ => None,
+    }
+}
+
+fn address_byte(is_64: bool) -> u8 {
+    if is_64 {
+        0x7e
+    } else {
+        0x7f
+    }
+}
+
+struct Validator<'a> {
+    wasm: &'a WasmFile,
+    // Functions that ref.func can refer to in a body
+    declared_funcs: BTreeSet<u32>,
+    errors: Vec<ValidationError>,
+}
+
+impl Validator<'_> {
+    fn error(&mut self, location: ErrorLocation, message: impl Into<String>) {
+        self.errors.push(ValidationError { location, instr: None, message: message.into(), expected: vec![], actual: vec![] });
+    }
+
+    fn check_type(&mut self, location: ErrorLocation, sig: u32, what: &str) {
+        if is_missing(sig, self.wasm.type_section.function_signatures.len()) {
+            self.error(location, format!("{} uses missing type {}", what, sig));
+        }
+    }
+
+    fn check_limits(&mut self, location: ErrorLocation, limits: &Limits, what: &str, max_size: u64) {
+        if limits.initial > max_size {
+            self.error(location, format!("{} starts at {}, more than the {} allowed", what, limits.initial, max_size));
+        }
+        match limits.max {
+            Some(max) if max > max_size => self.error(location, format!("{} can grow to {}, more than the {} allowed", what, max, max_size)),
+            Some(max) if max < limits.initial => self.error(location, format!("{}'s maximum size {} is below its initial size {}", what, max, limits.initial)),
+            None if limits.shared => self.error(location, format!("{} is shared, so it needs a maximum size", what)),
+            _ => {}
+        }
+    }
+
+    fn check_table(&mut self, location: ErrorLocation, table: &WasmTable, what: &str) {
+        let max_size = if table.limits.is_64 { u64::MAX } else { u32::MAX as u64 };
+        self.check_limits(location, &table.limits, what, max_size);
+    }
+
+    fn check_memory(&mut self, location: ErrorLocation, memory: &WasmMemoryStruct, what: &str) {
+        let max_size = if memory.limits.is_64 { MAX_PAGES_64 } else { MAX_PAGES_32 };
+        self.check_limits(location, &memory.limits, what, max_size);
+    }
+
+    // `globals` is how many globals the expression can read: the imported ones and those defined
+    // before it, for a global's own initializer
+    fn check_const_expr(&mut self, location: ErrorLocation, expr: &WasmExpr, expected: u8, globals: u32, what: &str) {
+        let wasm = self.wasm;
+        let num_funcs = wasm.index_space_len(WasmImportType::Func);
+        let mut problems = vec![];
+        for_each_instr(expr, &mut 0, &mut |_, info, immediates| {
+            if !is_const_instr(info) {
+                problems.push(format!("{} isn't constant, it uses {}", what, info.name));
+            }
+            if let Some(ExprSeg::Func(idx)) = immediates.first().filter(|_| info.name == "ref.func") {
+                if *idx >= num_funcs {
+                    problems.push(format!("{} refers to missing function {}", what, idx));
+                }
+            }
+            if let (true, Some(ExprSeg::Global(idx))) = (info.name == "global.get", immediates.first()) {
+                match global_type(wasm, *idx) {
+                    _ if *idx >= globals => problems.push(format!("{} reads global {}, which isn't defined before it", what, idx)),
+                    Some((_, true)) => problems.push(format!("{} reads global {}, which is mutable", what, idx)),
+                    _ => {}
+                }
+            }
+        });
+        for problem in problems {
+            self.error(location, problem);
+        }
+        for e in infer_expr(wasm, &[], &[expected], expr, &[]).errors {
+            self.errors.push(ValidationError {
+                location,
+                instr: None,
+                message: format!("{}: {}", what, e.message),
+                expected: e.expected,
+                actual: e.actual,
+            });
+        }
+    }
+
+    fn check_imports(&mut self) {
+        let wasm = self.wasm;
+        let location = in_section(IMPORT_SECTION);
+        for (i, import) in wasm.import_section_header.imports.iter().enumerate() {
+            let what = format!("Import {}", i);
+            match &import.desc {
+                WasmImportDesc::Func(sig) => self.check_type(location, *sig, &what),
+                WasmImportDesc::Table(table) => self.check_table(location, table, &what),
+                WasmImportDesc::Mem(memory) => self.check_memory(location, memory, &what),
+                WasmImportDesc::Tag(tag) => self.check_tag(location, tag, &what),
+                WasmImportDesc::Global { .. } => {}
+            }
+        }
+    }
+
+    fn check_tag(&mut self, location: ErrorLocation, tag: &WasmTag, what: &str) {
+        match self.wasm.type_section.function_signatures.get(tag.type_index as usize) {
+            None => self.check_type(location, tag.type_index, what),
+            Some(sig) if !sig.results.is_empty() => self.error(location, format!("{}'s type {} has results, which tags can't", what, tag.type_index)),
+            _ => {}
+        }
+    }
+
+    fn check_definitions(&mut self) {
+        let wasm = self.wasm;
+        for (i, sig) in wasm.function_section.function_signature_indexes.iter().enumerate() {
+            let funcidx = wasm.defined_func_idx(i);
+            let location = ErrorLocation { func: Some(funcidx), ..in_section(FUNCTION_SECTION) };
+            self.check_type(location, *sig, &format!("Function {}", funcidx));
+        }
+        let (num_funcs, num_bodies) = (wasm.function_section.function_signature_indexes.len(), wasm.code_section.functions.len());
+        if num_funcs != num_bodies {
+            self.error(in_section(CODE_SECTION), format!("{} functions are declared but {} bodies are given", num_funcs, num_bodies));
+        }
+        for (i, table) in wasm.table_section.tables.iter().enumerate() {
+            self.check_table(in_section(TABLE_SECTION), table, &format!("Table {}", wasm.defined_table_idx(i)));
+        }
+        for (i, memory) in wasm.memory_section.memories.iter().enumerate() {
+            self.check_memory(in_section(MEMORY_SECTION), memory, &format!("Memory {}", wasm.defined_memory_idx(i)));
+        }
+        for (i, tag) in wasm.tag_section.tags.iter().enumerate() {
+            self.check_tag(in_section(TAG_SECTION), tag, &format!("Tag {}", wasm.defined_tag_idx(i)));
+        }
+        for (i, global) in wasm.global_section.globals.iter().enumerate() {
+            let globalidx = wasm.defined_global_idx(i);
+            self.check_const_expr(in_section(GLOBAL_SECTION), &global.expr, global.wasm_type._type, globalidx, &format!("Global {}", globalidx));
+        }
+    }
+
+    fn check_exports(&mut self) {
+        let wasm = self.wasm;
+        let location = in_section(EXPORT_SECTION);
+        let mut names = HashSet::new();
+        for export in wasm.export_section.exports.iter() {
+            let name = export.export_name.clone();
+            if !names.insert(name.clone()) {
+                self.error(location, format!("Export name {:?} is used more than once", name));
+            }
+            let spaces = wasm.index_spaces();
+            let (kind, space) = match export.export_kind {
+                0x00 => ("function", &spaces.funcs),
+                0x01 => ("table", &spaces.tables),
+                0x02 => ("memory", &spaces.memories),
+                0x03 => ("global", &spaces.globals),
+                0x04 => ("tag", &spaces.tags),
+                kind => {
+                    self.error(location, format!("Export {:?} has unknown kind {}", name, kind));
+                    continue;
+                }
+            };
+            let idx = export.export_signature_index;
+            if is_missing(idx, space.len()) {
+                self.error(location, format!("Export {:?} refers to missing {} {}", name, kind, idx));
+            }
+        }
+
+        let Some(start) = &wasm.start_section else {
+            return;
+        };
+        match wasm.get_func_sig(start.func) {
+            None => self.error(in_section(START_SECTION), format!("Start function {} doesn't exist", start.func)),
+            Some(sig) if !sig.params.is_empty() || !sig.results.is_empty() => {
+                self.error(in_section(START_SECTION), format!("Start function {} has to take and return nothing", start.func));
+            }
+            _ => {}
+        }
+    }
+
+    fn check_segments(&mut self) {
+        let wasm = self.wasm;
+        let num_globals = wasm.index_space_len(WasmImportType::Global);
+        for (i, elem) in wasm.elem_section.elems.iter().enumerate() {
+            let location = in_section(ELEM_SECTION);
+            if let WasmElemMode::Active(active) = &elem.mode {
+                match table_of(wasm, active.table) {
+                    Some(table) => {
+                        let ty = reftype_to_byte(&elem._type);
+                        if table.wasm_type != ty {
+                            let (ty, table_ty) = (StackValue::Type(ty), StackValue::Type(table.wasm_type));
+                            self.error(location, format!("Element segment {} holds {} but table {} holds {}", i, ty, active.table, table_ty));
+                        }
+                        let address = address_byte(table.limits.is_64);
+                        self.check_const_expr(location, &active.offset_expr, address, num_globals, &format!("Element segment {}'s offset", i));
+                    }
+                    None => self.error(location, format!("Element segment {} is for missing table {}", i, active.table)),
+                }
+            }
+            for item in elem.init.iter() {
+                self.check_const_expr(location, item, reftype_to_byte(&elem._type), num_globals, &format!("Element segment {}'s item", i));
+            }
+        }
+        let num_data_segs = wasm.data_section.data_segs.len();
+        if let Some(data_count) = wasm.data_count_section.as_ref().filter(|data_count| data_count.datacount != num_data_segs) {
+            self.error(in_section(DATA_COUNT_SECTION), format!("The data count is {} but there are {} data segments", data_count.datacount, num_data_segs));
+        }
+        for (i, data) in wasm.data_section.data_segs.iter().enumerate() {
+            let location = in_section(DATA_SECTION);
+            if let WasmDataMode::Active { memory, offset_expr } = &data.header.mode {
+                match memory_of(wasm, *memory) {
+                    Some(mem) => {
+                        let address = address_byte(mem.limits.is_64);
+                        self.check_const_expr(location, offset_expr, address, num_globals, &format!("Data segment {}'s offset", i));
+                    }
+                    None => self.error(location, format!("Data segment {} is for missing memory {}", i, memory)),
+                }
+            }
+        }
+    }
+
+    // Functions are declared for ref.func by being exported, or used in a global or element segment
+    fn declare_funcs(&mut self) {
+        let wasm = self.wasm;
+        let mut declared = BTreeSet::new();
+        let mut collect = |expr: &WasmExpr| {
+            for_each_instr(expr, &mut 0, &mut |_, info, immediates| {
+                if let (true, Some(ExprSeg::Func(func))) = (info.name == "ref.func", immediates.first()) {
+                    declared.insert(*func);
+                }
+            })
+        };
+        wasm.global_section.globals.iter().for_each(|global| collect(&global.expr));
+        wasm.elem_section.elems.iter().flat_map(|elem| elem.init.iter()).for_each(&mut collect);
+        declared.extend(wasm.export_section.exports.iter().filter(|export| export.export_kind == 0).map(|export| export.export_signature_index));
+        self.declared_funcs = declared;
+    }
+
+    // What the stack typing doesn't see: indices it has no reason to look up, alignments, offsets,
+    // lane indices, writes to immutable globals and data segment uses without a data count section
+    fn check_immediates(&self, func: &WasmFunction) -> Vec<(usize, String)> {
+        let wasm = self.wasm;
+        let has_data_count = wasm.data_count_section.is_some();
+        let spaces = wasm.index_spaces();
+        let mut problems = vec![];
+        for_each_instr(&func.body, &mut 0, &mut |instr, info, immediates| {
+            for imm in immediates {
+                let problem = match imm {
+                    ExprSeg::Func(idx) if is_missing(*idx, spaces.funcs.len()) => Some(format!("No function {}", idx)),
+                    ExprSeg::Func(idx) if info.name == "ref.func" && !self.declared_funcs.contains(idx) => {
+                        Some(format!("Function {} is used by ref.func without being declared in an element segment, export or global", idx))
+                    }
+                    ExprSeg::Table(idx) if is_missing(*idx, spaces.tables.len()) => Some(format!("No table {}", idx)),
+                    ExprSeg::Memory(idx) if is_missing(*idx, spaces.memories.len()) => Some(format!("No memory {}", idx)),
+                    ExprSeg::MemArg(memarg) if is_missing(memarg.memory, spaces.memories.len()) => Some(format!("No memory {}", memarg.memory)),
+                    // Atomic accesses have to be aligned exactly
+                    ExprSeg::MemArg(memarg) if info.prefix == 0xfe && info.natural_align.is_some_and(|natural| memarg.align != natural as u32) => {
+                        Some(format!("Alignment {} isn't the natural alignment {}", memarg.alignment(), 1u64 << info.natural_align.unwrap_or_default()))
+                    }
+                    ExprSeg::MemArg(memarg) if info.natural_align.is_some_and(|natural| memarg.align > natural as u32) => {
+                        Some(format!("Alignment {} is more than the natural alignment {}", memarg.alignment(), 1u64 << info.natural_align.unwrap_or_default()))
+                    }
+                    ExprSeg::MemArg(memarg) if memarg.offset > u32::MAX as u64 && memory_of(wasm, memarg.memory).is_some_and(|memory| !memory.limits.is_64) => {
+                        Some(format!("Offset {} is too big for 32-bit memory {}", memarg.offset, memarg.memory))
+                    }
+                    ExprSeg::Int(lane) if info.constants.contains(&Prim::Lane) && *lane >= lane_count(info.name) as i64 => {
+                        Some(format!("Lane {} is out of range, {} only has {}", lane, info.name, lane_count(info.name)))
+                    }
+                    ExprSeg::Data(idx) if is_missing(*idx, wasm.data_section.data_segs.len()) => Some(format!("No data segment {}", idx)),
+                    ExprSeg::Data(_) if !has_data_count => Some(format!("{} needs a data count section", info.name)),
+                    ExprSeg::Elem(idx) if is_missing(*idx, wasm.elem_section.elems.len()) => Some(format!("No element segment {}", idx)),
+                    ExprSeg::Type(idx) if is_missing(*idx, wasm.type_section.function_signatures.len()) => Some(format!("No type {}", idx)),
+                    ExprSeg::Tag(idx) if is_missing(*idx, spaces.tags.len()) => Some(format!("No tag {}", idx)),
+                    ExprSeg::Global(idx) if info.name == "global.set" && matches!(global_type(wasm, *idx), Some((_, false))) => {
+                        Some(format!("Global {} is immutable", idx))
+                    }
+                    _ => None,
+                };
+                problems.extend(problem.map(|problem| (instr, problem)));
+            }
+        });
+        problems
+    }
+
+    fn check_func(&mut self, funcidx: u32, func: &WasmFunction) {
+        let location = |instr: usize| ErrorLocation { offset: func.instr_offsets.get(instr).copied(), section: Some(CODE_SECTION), func: Some(funcidx) };
+        let types = match infer_func(self.wasm, funcidx) {
+            Ok(types) => types,
+            Err(e) => {
+                self.error(ErrorLocation { func: Some(funcidx), ..in_section(CODE_SECTION) }, e.to_string());
+                return;
+            }
+        };
+        let mut errors: Vec<ValidationError> = types
+            .errors
+            .into_iter()
+            .map(|StackError { instr, message, expected, actual, .. }| ValidationError { location: location(instr), instr: Some(instr), message, expected, actual })
+            .collect();
+        for (instr, message) in self.check_immediates(func) {
+            // The stack typing already reports the indices it looks up
+            if !errors.iter().any(|e| e.instr == Some(instr) && e.message == message) {
+                errors.push(ValidationError { location: location(instr), instr: Some(instr), message, expected: vec![], actual: vec![] });
+            }
+        }
+        errors.sort_by_key(|e| e.instr);
+        self.errors.extend(errors);
+    }
+}
+
+/// Checks one function's body: the types on its operand stack, its branches' label depths and the
+/// indices, alignments, offsets and lanes its instructions use
+pub fn validate_func(wasm: &WasmFile, funcidx: u32) -> Vec<ValidationError> {
+    let mut validator = Validator { wasm, declared_funcs: BTreeSet::new(), errors: vec![] };
+    validator.declare_funcs();
+    if let Some(func) = wasm.get_func(funcidx).and_then(|entry| entry.body) {
+        validator.check_func(funcidx, func);
+    }
+    validator.errors
+}
+
+/// Checks the module against the validation rules of the core spec, and gives every error found.
+/// An empty list means the module is valid, as far as the model can tell: subtyping between
+/// reference types isn't checked, so any reference is taken where another is expected.
+pub fn validate(wasm: &WasmFile) -> Vec<ValidationError> {
+    let mut validator = Validator { wasm, declared_funcs: BTreeSet::new(), errors: vec![] };
+    validator.declare_funcs();
+    for (i, sig) in wasm.type_section.function_signatures.iter().enumerate() {
+        if sig.func != 0x60 {
+            validator.error(in_section(TYPE_SECTION), format!("Type {} isn't a function type", i));
+        }
+    }
+    validator.check_imports();
+    validator.check_definitions();
+    validator.check_exports();
+    validator.check_segments();
+    for (i, func) in wasm.code_section.functions.iter().enumerate() {
+        validator.check_func(wasm.defined_func_idx(i), func);
+    }
+    validator.errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wat_parser::parse_wat;
+    use crate::{load_bytes, to_bytes};
+
+    fn messages(errors: &[ValidationError]) -> Vec<String> {
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_the_golden_corpus() {
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "wasm") {
+                let wasm = load_bytes(&std::fs::read(&path).unwrap()).unwrap();
+                assert_eq!(messages(&validate(&wasm)), Vec::<String>::new(), "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn reports_body_errors_with_offsets() {
+        let wasm = parse_wat(
+            "(module
+              (global $g i32 (i32.const 0))
+              (func
+                f32.const 1
+                global.set $g
+                block
+                  br 2
+                end))",
+        )
+        .unwrap();
+        // Read back from the binary so the instructions have offsets
+        let wasm = load_bytes(&to_bytes(&wasm).unwrap()).unwrap();
+        let errors = validate(&wasm);
+        assert_eq!(
+            messages(&errors),
+            vec![
+                "Operand has the wrong type, expected [i32] but found [f32] at instruction 1 at offset 0x24 in code section (function 0)",
+                "Global 0 is immutable at instruction 1 at offset 0x24 in code section (function 0)",
+                "Branch to label 2 but only 2 blocks are open at instruction 3 at offset 0x28 in code section (function 0)",
+            ]
+        );
+        assert_eq!(errors[0].location.func, Some(0));
+        assert_eq!(validate_func(&wasm, 0), errors);
+    }
+
+    #[test]
+    fn reports_module_errors() {
+        let wasm = parse_wat(
+            "(module
+              (memory 2 1)
+              (global $m (mut i32) (i32.const 0))
+              (global i32 (global.get $m))
+              (global i64 (i32.const 1))
+              (func $f (param i32))
+              (start $f)
+              (export \"a\" (func $f))
+              (export \"a\" (memory 0)))",
+        )
+        .unwrap();
+        assert_eq!(
+            messages(&validate(&wasm)),
+            vec![
+                "Memory 0's maximum size 1 is below its initial size 2 in memory section",
+                "Global 1 reads global 0, which is mutable in global section",
+                "Global 2: Operand has the wrong type, expected [i64] but found [i32] in global section",
+                "Export name \"a\" is used more than once in export section",
+                "Start function 0 has to take and return nothing in start section",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_br_table_targets_data_count_and_elem_types() {
+        let wasm = parse_wat(
+            "(module
+              (func (param i32) (result i32)
+                block (result i32)
+                  block (result i64)
+                    i32.const 0
+                    local.get 0
+                    br_table 0 1
+                  end
+                  drop
+                  i32.const 1
+                end))",
+        )
+        .unwrap();
+        assert_eq!(
+            messages(&validate(&wasm)),
+            vec!["Operand has the wrong type, expected [i64] but found [i32] at instruction 4 in code section (function 0)"]
+        );
+
+        let mut wasm = parse_wat(
+            "(module
+              (memory 1)
+              (data \"hi\")
+              (func
+                i32.const 0
+                i32.const 0
+                i32.const 2
+                memory.init 0
+                data.drop 0))",
+        )
+        .unwrap();
+        assert_eq!(messages(&validate(&wasm)), Vec::<String>::new());
+        wasm.data_count_section = None;
+        assert_eq!(
+            messages(&validate(&wasm)),
+            vec![
+                "memory.init needs a data count section at instruction 3 in code section (function 0)",
+                "data.drop needs a data count section at instruction 4 in code section (function 0)",
+            ]
+        );
+
+        let wasm = parse_wat(
+            "(module
+              (table 1 externref)
+              (func $f)
+              (elem (i32.const 0) func $f))",
+        )
+        .unwrap();
+        assert_eq!(messages(&validate(&wasm)), vec!["Element segment 0 holds funcref but table 0 holds externref in element section"]);
+    }
+
+    #[test]
+    fn checks_lanes_alignments_offsets_and_handlers() {
+        let wasm = parse_wat(
+            "(module
+              (memory 1 1 shared)
+              (memory $wide i64 1)
+              (tag $t (param i32))
+              (func (param v128) (result i32)
+                i32.const 0
+                i32.atomic.load align=4
+                drop
+                i32.const 0
+                i32.atomic.load align=2
+                drop
+                i32.const 0
+                i32.load offset=4294967296
+                drop
+                i64.const 0
+                i32.load $wide offset=4294967296
+                drop
+                local.get 0
+                i8x16.extract_lane_s 16
+                drop
+                local.get 0
+                i64x2.extract_lane 1
+                drop
+                block
+                  try_table (catch $t 0) (catch_all 0)
+                  end
+                end
+                try
+                catch_all
+                  rethrow 0
+                end
+                block
+                  rethrow 0
+                end
+                local.get 0
+                i32x4.extract_lane 3))",
+        )
+        .unwrap();
+        assert_eq!(
+            messages(&validate(&wasm)),
+            vec![
+                "Alignment 2 isn't the natural alignment 4 at instruction 4 in code section (function 0)",
+                "Offset 4294967296 is too big for 32-bit memory 0 at instruction 7 in code section (function 0)",
+                "Lane 16 is out of range, i8x16.extract_lane_s only has 16 at instruction 13 in code section (function 0)",
+                "catch clause doesn't give what label 0 takes, expected [] but found [i32] at instruction 19 in code section (function 0)",
+                "rethrow label 0 isn't a catch at instruction 27 in code section (function 0)",
+            ]
+        );
+
+        let mut wasm = parse_wat(
+            "(module
+              (global funcref (ref.func 3))
+              (data \"hi\"))",
+        )
+        .unwrap();
+        wasm.data_count_section = Some(WasmDataCountSection { section_size: 0, datacount: 2 });
+        assert_eq!(
+            messages(&validate(&wasm)),
+            vec![
+                "Global 0 refers to missing function 3 in global section",
+                "The data count is 2 but there are 1 data segments in data count section",
+            ]
+        );
+    }
+}
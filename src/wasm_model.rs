@@ -1,13 +1,15 @@
 use core::fmt;
 use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashSet},
     fmt::{Debug, Display, Formatter},
-    io::{Error, ErrorKind},
-    slice::Iter,
 };
 
 use crate::{
+    error::WyrmError,
     instr_table::{self, *},
-    parser::prs
+    parser::prs,
+    wat_emitter::type_to_str,
 };
 
 pub trait TypeTrait {}
@@ -23,12 +25,116 @@ pub enum Prim {
     Global,
     Generic,
     Func,
+    V128,
+    // A single byte lane index
+    Lane,
+    Table,
+    Memory,
+    Type,
+    Data,
+    Elem,
+    HeapType,
+    // Heap types written as (ref ...) and (ref null ...)
+    RefType,
+    NullRefType,
+    // Alignment, offset and memory index of a load or store
+    MemArg,
+    // The type of a block, loop or if
+    BlockType,
+    Tag,
+}
+
+/// The immediate of every load, store and atomic access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemArg {
+    /// log2 of the alignment in bytes, as it is written in the file
+    pub align: u32,
+    pub offset: u64,
+    pub memory: u32,
+}
+
+impl MemArg {
+    /// Alignment in bytes
+    pub fn alignment(&self) -> u64 {
+        1u64.checked_shl(self.align).unwrap_or(0)
+    }
+
+    /// WAT leaves out the memory index if it's 0, the offset if it's 0 and the alignment if it's
+    /// natural. Without knowing the instruction, the alignment is always written.
+    pub fn emit_wat(&self, natural_align: Option<u8>, state: EmitterState<'_>) -> String {
+        let mut parts = vec![];
+        if self.memory != 0 {
+            parts.push(state.memory_id(self.memory));
+        }
+        if self.offset != 0 {
+            parts.push(format!("offset={}", self.offset));
+        }
+        if natural_align.map(|natural| natural as u32) != Some(self.align) {
+            parts.push(format!("align={}", self.alignment()));
+        }
+        parts.join(" ")
+    }
+}
+
+/// One of a try_table's handlers, which branches to its label when a matching exception is caught
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatchClause {
+    Catch { tag: u32, label: u32 },
+    /// Also passes on the exception as an exnref
+    CatchRef { tag: u32, label: u32 },
+    CatchAll { label: u32 },
+    CatchAllRef { label: u32 },
+}
+
+impl CatchClause {
+    /// The byte each kind of clause is encoded with
+    pub fn opcode(&self) -> u8 {
+        match self {
+            CatchClause::Catch { .. } => 0x00,
+            CatchClause::CatchRef { .. } => 0x01,
+            CatchClause::CatchAll { .. } => 0x02,
+            CatchClause::CatchAllRef { .. } => 0x03,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CatchClause::Catch { .. } => "catch",
+            CatchClause::CatchRef { .. } => "catch_ref",
+            CatchClause::CatchAll { .. } => "catch_all",
+            CatchClause::CatchAllRef { .. } => "catch_all_ref",
+        }
+    }
+
+    pub fn tag(&self) -> Option<u32> {
+        match self {
+            CatchClause::Catch { tag, .. } | CatchClause::CatchRef { tag, .. } => Some(*tag),
+            CatchClause::CatchAll { .. } | CatchClause::CatchAllRef { .. } => None,
+        }
+    }
+
+    /// The label's depth, counted from outside the try_table
+    pub fn label(&self) -> u32 {
+        match self {
+            CatchClause::Catch { label, .. }
+            | CatchClause::CatchRef { label, .. }
+            | CatchClause::CatchAll { label }
+            | CatchClause::CatchAllRef { label } => *label,
+        }
+    }
+
+    pub fn emit_wat(&self, state: EmitterState<'_>) -> String {
+        match self.tag() {
+            Some(tag) => format!("({} {} {})", self.name(), state.tag_id(tag), self.label()),
+            None => format!("({} {})", self.name(), self.label()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct BrTableConst {
-    pub break_depths: Vec<usize>,
-    pub default: usize,
+    pub break_depths: Vec<u32>,
+    pub default: u32,
 }
 
 /*
@@ -51,21 +157,21 @@ pub struct WasmIdiomPattern {
 }
 
 impl WasmIdiomPattern {
-    fn independent_expr(expr: WasmExpr, idiom: WasmIdiom) -> Self {
-        let expr = expr.parse_string().expect("Error parsing Idiom pattern");
-        Self {
+    fn independent_expr(expr: WasmExpr, idiom: WasmIdiom) -> Result<Self, WyrmError> {
+        let expr = expr.parse_string()?;
+        Ok(Self {
             pattern: vec![IdiomGrammar::StrictExpr(expr)],
             idiom,
-        }
+        })
     }
 
-    // An example of an idiom, will research more later
-    pub fn double() -> Self {
+    /// An example of an idiom, will research more later
+    pub fn double() -> Result<Self, WyrmError> {
         Self::independent_expr(
             new_expr(vec![
-                get_op_seg("i32.const"),
+                get_op_seg("i32.const")?,
                 ExprSeg::Int(1),
-                get_op_seg("i32.shl"),
+                get_op_seg("i32.shl")?,
             ]),
             WasmIdiom::Double,
         )
@@ -80,14 +186,30 @@ pub enum WasmIdiom {
 #[derive(Debug, Clone)]
 pub enum ExprSeg {
     Operation(InstrInfo),
-    ControlFlow(InstrInfo, Box<WasmExpr>, InstrInfo),
+    // The block's instruction, its type, its body (ending with `end`, or `delegate` for a `try`), the
+    // `end` and, for a try_table, its catch clauses
+    ControlFlow(InstrInfo, i64, Box<WasmExpr>, InstrInfo, Vec<CatchClause>),
     // Raw bits of an int, signage and other things are figured out later (all ints are stored in the same manner)
     Int(i64),
     Float32(f32),
     Float64(f64),
-    Local(usize),
-    Global(usize),
-    Func(usize),
+    Local(u32),
+    Global(u32),
+    Func(u32),
+    V128(u128),
+    Table(u32),
+    Memory(u32),
+    Type(u32),
+    Data(u32),
+    Elem(u32),
+    Tag(u32),
+    // Negative values are the abstract heap types, anything else is a type index
+    HeapType(i64),
+    RefType { nullable: bool, heap: i64 },
+    MemArg(MemArg),
+    // 0x40 (read as -64) for no result, other negative values for a single value type,
+    // anything else is a type index
+    BlockType(i64),
     BrTable(BrTableConst),
     Instr(Vec<ExprSeg>),
     // TODO: Parse for idioms
@@ -95,47 +217,83 @@ pub enum ExprSeg {
 }
 
 impl ExprSeg {
-    pub fn emit_wat(&self, mut wat: String, state: EmitterState) -> String {
+    pub fn emit_wat(&self, mut wat: String, state: EmitterState<'_>) -> String {
         match self {
             ExprSeg::Operation(info) => {
-                wat += format!("{:}", info.name).as_str();
+                wat += info.name;
             }
             ExprSeg::Int(i) => {
                 wat += format!("{:}", i).as_str();
             }
             ExprSeg::Float32(f) => {
-                wat += format!("{:}", f).as_str();
+                wat += f32_to_wat(*f).as_str();
             }
             ExprSeg::Float64(f) => {
-                wat += format!("{:}", f).as_str();
+                wat += f64_to_wat(*f).as_str();
             }
             ExprSeg::Global(idx) => {
-                wat += format!("$global{:}", idx).as_str();
+                wat += state.global_id(*idx).as_str();
             }
             ExprSeg::Local(idx) => {
-                wat += format!("$var{:}", idx).as_str();
+                wat += state.local_id(*idx).as_str();
             }
             ExprSeg::Func(idx) => {
-                wat += format!("$func{:}", idx).as_str();
+                wat += state.func_id(*idx).as_str();
             }
-            ExprSeg::BrTable(_table_const) => {}
-            ExprSeg::ControlFlow(info, expr, end_info) => {
-                // Add extra characters for indentation
-                wat += &format!("{:} $label{}\n  ", info.name, state.label);
-
-                let (_, new_emit) = expr.emit_block_wat(EmitterState {
-                    start_segment: 0,
-                    label: state.label + 1,
-                });
-
-                wat += new_emit.replace("\n", "\n  ").as_str();
-                wat += format!("\n{:} $label{}\n", end_info.name, state.label).as_str();
+            ExprSeg::V128(v) => {
+                let lanes: Vec<String> = (0..4).map(|i| format!("{:#010x}", (v >> (32 * i)) as u32)).collect();
+                wat += format!("i32x4 {}", lanes.join(" ")).as_str();
+            }
+            // Table and memory 0 are implied when the index is left out
+            ExprSeg::Table(0) | ExprSeg::Memory(0) => {}
+            ExprSeg::Table(idx) => {
+                wat += state.table_id(*idx).as_str();
+            }
+            ExprSeg::Memory(idx) => {
+                wat += state.memory_id(*idx).as_str();
+            }
+            ExprSeg::Type(idx) => {
+                wat += state.type_id(*idx).as_str();
+            }
+            ExprSeg::Data(idx) => {
+                wat += state.data_id(*idx).as_str();
+            }
+            ExprSeg::Elem(idx) => {
+                wat += state.elem_id(*idx).as_str();
+            }
+            ExprSeg::Tag(idx) => {
+                wat += state.tag_id(*idx).as_str();
+            }
+            ExprSeg::HeapType(heap) => {
+                wat += state.heap_type(*heap).as_str();
+            }
+            ExprSeg::RefType { nullable, heap } => {
+                let null = if *nullable { "null " } else { "" };
+                wat += format!("(ref {}{})", null, state.heap_type(*heap)).as_str();
+            }
+            ExprSeg::MemArg(memarg) => {
+                wat += memarg.emit_wat(None, state).as_str();
+            }
+            ExprSeg::BrTable(table) => {
+                let depths: Vec<String> = table.break_depths.iter().chain([&table.default]).map(u32::to_string).collect();
+                wat += depths.join(" ").as_str();
+            }
+            ExprSeg::ControlFlow(info, block_type, expr, end_info, catches) => {
+                wat += emit_control_flow_wat(info, *block_type, expr, end_info, catches, state).as_str();
             }
             ExprSeg::Instr(instr_expr) => {
-                for seg in instr_expr {
-                    wat = seg.emit_wat(wat, state) + " ";
+                let Some(ExprSeg::Operation(info)) = instr_expr.first() else {
+                    return wat;
+                };
+                // Both kinds of select are written as `select`
+                wat += if get_edge_case(*info) == SpecialInstr::SelectTyped { "select" } else { info.name };
+                for immediate in immediates_wat(info, &instr_expr[1..], state) {
+                    if !immediate.is_empty() {
+                        wat += " ";
+                        wat += immediate.as_str();
+                    }
                 }
-                wat = wat + "\n";
+                wat += "\n";
             }
             _ => {}
         }
@@ -143,22 +301,286 @@ impl ExprSeg {
     }
 }
 
+// Display already gives the shortest decimal that reads back as the same value, and
+// infinity as `inf`. NaNs are written as `nan`, with their payload unless it's the canonical one.
+fn nan_to_wat(negative: bool, payload: u64, canonical: u64) -> String {
+    let sign = if negative { "-" } else { "" };
+    if payload == canonical {
+        format!("{}nan", sign)
+    } else {
+        format!("{}nan:{:#x}", sign, payload)
+    }
+}
+
+pub fn f32_to_wat(f: f32) -> String {
+    if f.is_nan() {
+        nan_to_wat(f.is_sign_negative(), (f.to_bits() & 0x7f_ffff) as u64, 1 << 22)
+    } else {
+        f.to_string()
+    }
+}
+
+pub fn f64_to_wat(f: f64) -> String {
+    if f.is_nan() {
+        nan_to_wat(f.is_sign_negative(), f.to_bits() & 0xf_ffff_ffff_ffff, 1 << 51)
+    } else {
+        f.to_string()
+    }
+}
+
+/// A block's first line, e.g. `try_table $label0 (result i32) (catch $tag0 0)`
+pub fn block_header_wat(info: &InstrInfo, label: &str, block_type: i64, catches: &[CatchClause], state: EmitterState<'_>) -> String {
+    let mut wat = format!("{} {}{}", info.name, label, state.block_type(block_type));
+    for catch in catches {
+        wat += " ";
+        wat += catch.emit_wat(state).as_str();
+    }
+    wat
+}
+
+// A block with its body indented, apart from an if's `else` and a try's `catch`es, which line up
+// with the block's instruction
+fn emit_control_flow_wat(info: &InstrInfo, block_type: i64, expr: &WasmExpr, end_info: &InstrInfo, catches: &[CatchClause], state: EmitterState<'_>) -> String {
+    let label = state.label_id();
+    let mut wat = block_header_wat(info, &label, block_type, catches, state) + "\n";
+    for seg in expr.expr_string.iter() {
+        match seg {
+            // `delegate` takes a label outside of the try instead of repeating its own
+            _ if is_block_end(seg) && end_info.instr == DELEGATE as u32 => return wat + &seg.emit_wat("".to_string(), state),
+            _ if is_block_end(seg) => break,
+            _ if is_arm(seg) => wat += &seg.emit_wat("".to_string(), state),
+            _ => {
+                for line in seg.emit_wat("".to_string(), state.nested()).lines() {
+                    wat += &format!("  {}\n", line);
+                }
+            }
+        }
+    }
+    wat + &format!("{} {}\n", end_info.name, label)
+}
+
+/// The `end` that finishes a body, or the `delegate` that finishes a try's
+pub fn block_end(seg: &ExprSeg) -> Option<InstrInfo> {
+    let info = match seg {
+        ExprSeg::Operation(info) => *info,
+        ExprSeg::Instr(instr) => match instr.first() {
+            Some(ExprSeg::Operation(info)) => *info,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    (get_edge_case(info) == SpecialInstr::EndBlock).then_some(info)
+}
+
+pub fn is_block_end(seg: &ExprSeg) -> bool {
+    block_end(seg).is_some()
+}
+
+fn is_op_seg(seg: &ExprSeg, opcodes: &[u64]) -> bool {
+    matches!(seg, ExprSeg::Instr(instr) if matches!(instr.first(), Some(ExprSeg::Operation(op)) if op.prefix == 0 && opcodes.contains(&(op.instr as u64))))
+}
+
+pub fn is_else(seg: &ExprSeg) -> bool {
+    is_op_seg(seg, &[ELSE])
+}
+
+/// An `else`, `catch` or `catch_all`, which starts another arm of the block it's in
+pub fn is_arm(seg: &ExprSeg) -> bool {
+    is_op_seg(seg, &[ELSE, CATCH, CATCH_ALL])
+}
+
+/// How many values an instruction pops and pushes, going by the instruction table.
+/// Calls and the like depend on a signature, so they aren't known.
+pub fn stack_effect(info: &InstrInfo) -> Option<(usize, usize)> {
+    if info.in_types.contains(&Prim::Void) || info.out_types.contains(&Prim::Void) {
+        return None;
+    }
+    Some((info.in_types.len(), info.out_types.len()))
+}
+
+// The immediates of an instruction in the order WAT writes them, which isn't always the binary order
+fn immediates_wat(info: &InstrInfo, immediates: &[ExprSeg], state: EmitterState<'_>) -> Vec<String> {
+    let emit = |seg: &ExprSeg| match seg {
+        ExprSeg::MemArg(memarg) => memarg.emit_wat(info.natural_align, state),
+        _ => seg.emit_wat("".to_string(), state),
+    };
+    match immediates {
+        // Value types are kept as the bytes they are encoded as
+        _ if get_edge_case(*info) == SpecialInstr::SelectTyped => {
+            let types = immediates.iter().filter_map(|seg| match seg {
+                ExprSeg::Int(t) => Some(type_to_str(WasmTypeAnnotation { _type: *t as u8 })),
+                _ => None,
+            });
+            vec![format!("(result {})", types.collect::<Vec<String>>().join(" "))]
+        }
+        // The type is written as a type use, after the table
+        [ExprSeg::Int(sig), ExprSeg::Int(table)] if get_edge_case(*info) == SpecialInstr::CallIndirect => {
+            let table = if *table != 0 { state.table_id(*table as u32) } else { "".to_string() };
+            vec![table, format!("(type {})", state.type_id(*sig as u32))]
+        }
+        // memory.init and table.init give the memory or table first
+        [segment @ (ExprSeg::Data(_) | ExprSeg::Elem(_)), target @ (ExprSeg::Memory(_) | ExprSeg::Table(_))] => {
+            vec![emit(target), emit(segment)]
+        }
+        // memory.copy and table.copy can leave out both indices, but not just one
+        [ExprSeg::Memory(dst), ExprSeg::Memory(src)] if *dst != 0 || *src != 0 => {
+            vec![state.memory_id(*dst), state.memory_id(*src)]
+        }
+        [ExprSeg::Table(dst), ExprSeg::Table(src)] if *dst != 0 || *src != 0 => {
+            vec![state.table_id(*dst), state.table_id(*src)]
+        }
+        _ => immediates.iter().map(emit).collect(),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WasmExpr {
     pub expr_string: Vec<ExprSeg>,
 }
 
 #[derive(Clone, Copy)]
-pub struct EmitterState {
+pub struct EmitterState<'a> {
     start_segment: usize,
     label: usize,
+    // Debug names from the name section, if the module has one
+    names: Option<&'a WasmNameSection>,
+    // The function being emitted, so locals and labels can be named
+    func: u32,
+    // Labels are named by the order their blocks appear in, not their depth
+    label_count: Option<&'a Cell<usize>>,
 }
 
-pub fn blank_emitter() -> EmitterState {
-    return EmitterState {
+pub fn blank_emitter() -> EmitterState<'static> {
+    EmitterState {
         start_segment: 0,
         label: 0,
-    };
+        names: None,
+        func: 0,
+        label_count: None,
+    }
+}
+
+pub fn named_emitter(names: &WasmNameSection) -> EmitterState<'_> {
+    EmitterState {
+        names: Some(names),
+        ..blank_emitter()
+    }
+}
+
+pub fn function_emitter<'a>(names: &'a WasmNameSection, func: u32, label_count: &'a Cell<usize>) -> EmitterState<'a> {
+    EmitterState {
+        func,
+        label_count: Some(label_count),
+        ..named_emitter(names)
+    }
+}
+
+impl EmitterState<'_> {
+    pub fn nested(&self) -> Self {
+        Self {
+            start_segment: 0,
+            label: self.label + 1,
+            ..*self
+        }
+    }
+
+    /// Takes the next label, so this should be called once per block, in order
+    pub fn label_id(&self) -> String {
+        let label_idx = self.label_count.map(|count| count.replace(count.get() + 1));
+        match (self.names, label_idx) {
+            (Some(names), Some(label_idx)) => names.label_id(self.func, label_idx as u32, self.label),
+            _ => format!("$label{}", self.label),
+        }
+    }
+
+    fn local_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.local_id(self.func, idx),
+            None => format!("$var{}", idx),
+        }
+    }
+
+    fn global_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.global_id(idx),
+            None => format!("$global{}", idx),
+        }
+    }
+
+    fn func_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.func_id(idx),
+            None => format!("$func{}", idx),
+        }
+    }
+
+    fn table_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.table_id(idx),
+            None => format!("$table{}", idx),
+        }
+    }
+
+    fn memory_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.memory_id(idx),
+            None => format!("$memory{}", idx),
+        }
+    }
+
+    fn elem_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.elem_id(idx),
+            None => format!("$elem{}", idx),
+        }
+    }
+
+    fn tag_id(&self, idx: u32) -> String {
+        match self.names {
+            Some(names) => names.tag_id(idx),
+            None => format!("$tag{}", idx),
+        }
+    }
+
+    // Types and data segments fall back to their plain index
+    fn type_id(&self, idx: u32) -> String {
+        self.names.and_then(|names| names.type_id(idx)).unwrap_or(idx.to_string())
+    }
+
+    fn data_id(&self, idx: u32) -> String {
+        self.names.and_then(|names| names.data_id(idx)).unwrap_or(idx.to_string())
+    }
+
+    /// Blocks with no result leave their type out
+    pub fn block_type(&self, block_type: i64) -> String {
+        match block_type {
+            -0x40 => "".to_string(),
+            value_type if value_type < 0 => {
+                format!(" (result {})", type_to_str(WasmTypeAnnotation { _type: (value_type + 0x80) as u8 }))
+            }
+            sig => format!(" (type {})", self.type_id(sig as u32)),
+        }
+    }
+
+    fn heap_type(&self, heap: i64) -> String {
+        // Abstract heap types are single negative s33 bytes, e.g. 0x70 reads as -0x10
+        match heap {
+            -0x0c => "noexn".to_string(),
+            -0x0d => "nofunc".to_string(),
+            -0x0e => "noextern".to_string(),
+            -0x0f => "none".to_string(),
+            -0x10 => "func".to_string(),
+            -0x11 => "extern".to_string(),
+            -0x12 => "any".to_string(),
+            -0x13 => "eq".to_string(),
+            -0x14 => "i31".to_string(),
+            -0x15 => "struct".to_string(),
+            -0x16 => "array".to_string(),
+            -0x17 => "exn".to_string(),
+            idx if idx >= 0 => self.type_id(idx as u32),
+            _ => format!("{}", heap),
+        }
+    }
 }
 
 impl WasmExpr {
@@ -168,27 +590,23 @@ impl WasmExpr {
         })
     }
 
-    fn parse_error() -> Error {
-        Error::new(
-            ErrorKind::InvalidData,
-            "WasmExpr::parse_string: Error while parsing, likely data was misordered",
-        )
+    fn parse_error() -> WyrmError {
+        WyrmError::invalid("WasmExpr::parse_string: Error while parsing, likely data was misordered")
     }
 
-    // This really needs to be cleaned up
-    pub fn parse_string(&self) -> Result<WasmExpr, Error> {
+    /// This really needs to be cleaned up
+    pub fn parse_string(&self) -> Result<WasmExpr, WyrmError> {
         let mut scope: Vec<Box<WasmExpr>> = vec![];
         let mut last_scope = WasmExpr::new_box();
         let mut expr_box = WasmExpr::new_box();
         let mut level: i32 = 0;
-        let mut control_flow: Vec<InstrInfo> = Vec::new();
+        let mut control_flow: Vec<(InstrInfo, i64)> = Vec::new();
         let mut iter = self.expr_string.iter();
         while let Some(seg) = iter.next() {
             let info = match seg {
                 ExprSeg::Operation(info) => info,
                 _ => {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
+                    return Err(WyrmError::invalid(
                         "WasmExpr::parse_string: Expected instruction, didn't find one",
                     ));
                 }
@@ -199,27 +617,26 @@ impl WasmExpr {
             let special_case = get_edge_case(*info);
 
             if special_case == SpecialInstr::BrTable {
-                // Likely to crash, oh well
-                let br_table = iter.next().unwrap();
+                let br_table = iter.next().ok_or_else(Self::parse_error)?;
                 instr_layout.push(br_table.clone());
                 expr.push(ExprSeg::Instr(instr_layout));
                 continue;
             }
 
             if special_case == SpecialInstr::CallIndirect {
-                instr_layout.push(iter.next().unwrap().clone());
-                instr_layout.push(iter.next().unwrap().clone());
+                instr_layout.push(iter.next().ok_or_else(Self::parse_error)?.clone());
+                instr_layout.push(iter.next().ok_or_else(Self::parse_error)?.clone());
                 expr.push(ExprSeg::Instr(instr_layout));
                 continue;
             }
 
             for _ in info.constants {
-                instr_layout.push(iter.next().unwrap().clone());
+                instr_layout.push(iter.next().ok_or_else(Self::parse_error)?.clone());
             }
 
             // Control flow is special when it comes to being an "instruction"
             if special_case == SpecialInstr::BeginBlock {
-                control_flow.push(*info);
+                control_flow.push((*info, block_type(&instr_layout)));
                 level += 1;
                 // Push the scope
                 scope.push(last_scope);
@@ -237,14 +654,18 @@ impl WasmExpr {
                     break;
                 }
                 // pop the scope
-                let control_flow_context = control_flow.pop().unwrap();
+                let (Some((control_flow_context, block_type)), Some(outer_scope)) = (control_flow.pop(), scope.pop()) else {
+                    return Err(Self::parse_error());
+                };
                 last_scope.expr_string.push(ExprSeg::ControlFlow(
                     control_flow_context,
+                    block_type,
                     expr_box,
                     *info,
+                    vec![],
                 ));
                 expr_box = last_scope;
-                last_scope = scope.pop().unwrap();
+                last_scope = outer_scope;
                 continue;
             }
             expr.push(ExprSeg::Instr(instr_layout));
@@ -253,7 +674,7 @@ impl WasmExpr {
         Ok(*expr_box)
     }
 
-    pub fn emit_block_wat(&self, state: EmitterState) -> (usize, String) {
+    pub fn emit_block_wat(&self, state: EmitterState<'_>) -> (usize, String) {
         let mut wat = "".to_string();
         let mut emit_until = 0;
 
@@ -271,30 +692,19 @@ impl WasmExpr {
                         return (state.start_segment + i, wat);
                     }
 
-                    wat += format!("{:}", info.name).as_str();
+                    wat += info.name;
 
                     // Figure out how many expression segments come after this one
                     emit_until = if special_case == SpecialInstr::CallIndirect {
                         2
-                    } else if special_case == SpecialInstr::BeginBlock {
-                        1
-                    } else if info.constants.len() != 0 {
+                    } else if special_case == SpecialInstr::BeginBlock || !info.constants.is_empty() {
                         1
                     } else {
                         0
                     }
                 }
-                ExprSeg::ControlFlow(info, expr, end_info) => {
-                    // Add extra characters for indentation
-                    wat += &format!("{} $label{}\n  ", info.name, state.label);
-
-                    let (_, new_emit) = expr.emit_block_wat(EmitterState {
-                        start_segment: 0,
-                        label: state.label + 1,
-                    });
-
-                    wat += new_emit.replace("\n", "\n  ").trim_end();
-                    wat += format!("\n{:} $label{}\n", end_info.name, state.label).as_str();
+                ExprSeg::ControlFlow(info, block_type, expr, end_info, catches) => {
+                    wat += emit_control_flow_wat(info, *block_type, expr, end_info, catches, state).as_str();
                 }
                 _ => {
                     wat = seg.emit_wat(wat, state);
@@ -306,38 +716,44 @@ impl WasmExpr {
                 emit_until -= 1;
             }
         }
-        (self.expr_string.len() - 1, wat)
+        (self.expr_string.len().saturating_sub(1), wat)
     }
 
     pub fn emit_expression_wat(&self) -> String {
-        let mut wat = "".to_string();
-        let mut i = 0;
-        while i < self.expr_string.len() - 1 {
-            wat += "(";
-            let new_emit: String;
-            (i, new_emit) = self.emit_block_wat(EmitterState {
-                start_segment: i,
-                label: 0,
-            });
-            i += 1;
-            wat += new_emit.trim_end();
-            wat += ") ";
-        }
-        // Remove extraneous space
-        wat.pop();
+        self.emit_expression_wat_with(blank_emitter())
+    }
 
-        wat
+    /// Emits a constant expression, using whatever names the state knows about. Each instruction
+    /// is folded on its own, e.g. `(global.get $g) (i32.const 4) (i32.add)`.
+    pub fn emit_expression_wat_with(&self, state: EmitterState<'_>) -> String {
+        let instrs: Vec<String> = self
+            .expr_string
+            .iter()
+            .take_while(|seg| !matches!(seg, ExprSeg::Operation(info) if get_edge_case(*info) == SpecialInstr::EndBlock))
+            .map(|seg| format!("({})", seg.emit_wat(String::new(), state).trim_end()))
+            .collect();
+        instrs.join(" ")
     }
 }
 
 impl Display for WasmExpr {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.write_str(&self.emit_expression_wat().as_str())
+        f.write_str(self.emit_expression_wat().as_str())
     }
 }
 
-fn get_op_seg(name: &str) -> ExprSeg {
-    ExprSeg::Operation(get_instr(name).unwrap())
+/// The type a block instruction was read with, or no result if it doesn't have one
+pub fn block_type(instr_layout: &[ExprSeg]) -> i64 {
+    match instr_layout.get(1) {
+        Some(ExprSeg::BlockType(block_type)) => *block_type,
+        _ => -0x40,
+    }
+}
+
+fn get_op_seg(name: &str) -> Result<ExprSeg, WyrmError> {
+    get_instr(name)
+        .map(ExprSeg::Operation)
+        .ok_or_else(|| WyrmError::invalid(format!("No instruction named {}", name)))
 }
 
 fn new_expr(expr_string: Vec<ExprSeg>) -> WasmExpr {
@@ -362,6 +778,19 @@ pub fn type_values(t: Prim) -> (i32, String) {
         Prim::Global => (6, "global".to_string()),
         Prim::Func => (7, "funcidx".to_string()),
         Prim::Generic => (8, "generic".to_string()),
+        Prim::V128 => (9, "v128".to_string()),
+        Prim::Lane => (10, "laneidx".to_string()),
+        Prim::Table => (11, "tableidx".to_string()),
+        Prim::Memory => (12, "memidx".to_string()),
+        Prim::Type => (13, "typeidx".to_string()),
+        Prim::Data => (14, "dataidx".to_string()),
+        Prim::Elem => (15, "elemidx".to_string()),
+        Prim::HeapType => (16, "heaptype".to_string()),
+        Prim::RefType => (17, "reftype".to_string()),
+        Prim::NullRefType => (18, "nullreftype".to_string()),
+        Prim::MemArg => (19, "memarg".to_string()),
+        Prim::BlockType => (20, "blocktype".to_string()),
+        Prim::Tag => (21, "tagidx".to_string()),
     }
 }
 
@@ -371,7 +800,7 @@ pub struct WasmHeader {
     pub version: u32,
 }
 
-// Section containing function types?
+/// Section containing function types?
 #[derive(Debug)]
 pub struct WasmTypeSection {
     pub section_size: usize,
@@ -379,7 +808,7 @@ pub struct WasmTypeSection {
     pub function_signatures: Vec<WasmFunctionType>,
 }
 
-// Type field for function signatures
+/// Type field for function signatures
 #[derive(Debug, Clone, Copy)]
 pub struct WasmTypeAnnotation {
     pub _type: u8,
@@ -394,19 +823,19 @@ pub enum WasmTypedData {
     F64(f64),
 }
 
-// Section containing the signature of a function
+/// Section containing the signature of a function
 #[derive(Debug)]
 pub struct WasmFunctionType {
     pub func: u8,
     pub num_params: usize,
-    // of size num_params
+    /// of size num_params
     pub params: Vec<WasmTypeAnnotation>,
     pub num_results: usize,
-    // of size num_results
+    /// of size num_results
     pub results: Vec<WasmTypeAnnotation>,
 }
 
-// Metadata about import section
+/// Metadata about import section
 #[derive(Debug)]
 pub struct WasmImportSection {
     pub section_size: usize,
@@ -414,42 +843,55 @@ pub struct WasmImportSection {
     pub imports: Vec<WasmImportHeader>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WasmImportType {
     Func,
     Table,
     Mem,
     Global,
+    Tag,
 }
 
-pub fn num_to_import_type(num: u8) -> WasmImportType {
+pub fn num_to_import_type(num: u8) -> Result<WasmImportType, WyrmError> {
     match num {
-        0x00 => WasmImportType::Func,
-        0x01 => WasmImportType::Table,
-        0x02 => WasmImportType::Mem,
-        0x03 => WasmImportType::Global,
-        _ => WasmImportType::Global,
+        0x00 => Ok(WasmImportType::Func),
+        0x01 => Ok(WasmImportType::Table),
+        0x02 => Ok(WasmImportType::Mem),
+        0x03 => Ok(WasmImportType::Global),
+        0x04 => Ok(WasmImportType::Tag),
+        _ => Err(WyrmError::invalid(format!("Invalid import kind: {:?}", num))),
     }
 }
 
-// Section describing imports
+/// What an import is, which depends on its kind
+#[derive(Debug)]
+pub enum WasmImportDesc {
+    // Index of the function's type
+    Func(u32),
+    Table(WasmTable),
+    Mem(WasmMemoryStruct),
+    Global { wasm_type: WasmTypeAnnotation, mutability: u8 },
+    Tag(WasmTag),
+}
+
+/// Section describing imports
 #[derive(Debug)]
 pub struct WasmImportHeader {
     pub mod_name_length: usize,
-    // of size mod_name_length
-    pub import_module_name: Vec<u8>,
+    /// of size mod_name_length
+    pub import_module_name: String,
     pub import_field_len: usize,
-    // of size emscripten_memcp_len
-    pub import_field: Vec<u8>,
+    /// of size emscripten_memcp_len
+    pub import_field: String,
     pub import_kind: WasmImportType,
-    pub import_type: u8,
+    pub desc: WasmImportDesc,
 }
 
 #[derive(Debug)]
 pub struct WasmFunctionSection {
     pub section_size: usize,
     pub num_functions: usize,
-    pub function_signature_indexes: Vec<u8>,
+    pub function_signature_indexes: Vec<u32>,
 }
 
 #[derive(Debug)]
@@ -458,12 +900,28 @@ pub struct WasmTableSection {
     pub num_tables: usize,
     pub tables: Vec<WasmTable>,
 }
+/// Sizes of a table (in elements) or memory (in pages)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub initial: u64,
+    pub max: Option<u64>,
+    /// Shared between threads, only for memories
+    pub shared: bool,
+    /// Addressed with i64 instead of i32, so the limits are u64 as well
+    pub is_64: bool,
+}
+
+impl Limits {
+    /// The flags byte these limits are encoded with
+    pub fn flags(&self) -> u8 {
+        (self.max.is_some() as u8) | (self.shared as u8) << 1 | (self.is_64 as u8) << 2
+    }
+}
+
 #[derive(Debug)]
 pub struct WasmTable {
     pub wasm_type: u8,
-    pub limits_flags: u8,
-    pub limits_initial: usize,
-    pub limits_max: usize,
+    pub limits: Limits,
 }
 
 #[derive(Debug)]
@@ -475,9 +933,7 @@ pub struct WasmMemorySection {
 
 #[derive(Debug)]
 pub struct WasmMemoryStruct {
-    pub limits_flags: u8,
-    pub limits_initial: usize,
-    pub limits_max: usize,
+    pub limits: Limits,
 }
 #[derive(Debug, Clone)]
 pub struct WasmGlobal {
@@ -506,26 +962,30 @@ pub enum WasmRefType {
     ExternRef,
 }
 
-pub fn byte_to_reftype(byte: u8) -> Result<WasmRefType, Error> {
+pub fn byte_to_reftype(byte: u8) -> Result<WasmRefType, WyrmError> {
     match byte {
         0x70 => Ok(WasmRefType::FuncRef),
         0x6F => Ok(WasmRefType::ExternRef),
 
-        _ => Err(Error::new(
-            ErrorKind::InvalidData,
-            format!("Invalid RefType byte: {:?}", byte),
-        )),
+        _ => Err(WyrmError::invalid(format!("Invalid RefType byte: {:?}", byte))),
+    }
+}
+
+pub fn reftype_to_byte(reftype: &WasmRefType) -> u8 {
+    match reftype {
+        WasmRefType::FuncRef => 0x70,
+        WasmRefType::ExternRef => 0x6F,
     }
 }
 
-// Section describing imports
+/// Section describing imports
 #[derive(Debug)]
 pub struct WasmExportHeader {
-    // of size emscripten_memcp_len
+    /// of size emscripten_memcp_len
     pub export_name_len: usize,
-    pub export_name: Vec<u8>,
+    pub export_name: String,
     pub export_kind: u8,
-    pub export_signature_index: u8,
+    pub export_signature_index: u32,
 }
 
 #[derive(Debug)]
@@ -543,8 +1003,11 @@ pub enum WasmElemMode {
 
 #[derive(Debug)]
 pub struct WasmElem {
+    /// Which of the eight encodings the segment was read from
+    pub flags: u32,
     pub _type: WasmRefType,
-    pub init: WasmExpr,
+    /// One constant expression per item
+    pub init: Vec<WasmExpr>,
     pub mode: WasmElemMode,
 }
 
@@ -569,10 +1032,19 @@ pub struct WasmDataSection {
     pub data_segs: Vec<WasmDataSeg>,
 }
 
+#[derive(Debug)]
+pub enum WasmDataMode {
+    // Only copied into memory by memory.init
+    Passive,
+    // Copied into a memory at instantiation, at the offset the expression gives
+    Active { memory: u32, offset_expr: WasmExpr },
+}
+
 #[derive(Debug)]
 pub struct WasmDataSegHeader {
+    /// 0 is active in memory 0, 1 is passive, 2 is active with an explicit memory index
     pub header_flags: u8,
-    pub expr: WasmExpr,
+    pub mode: WasmDataMode,
     pub data_size: usize,
 }
 
@@ -582,6 +1054,42 @@ pub struct WasmDataSeg {
     pub data: Vec<u8>,
 }
 
+/// The function called once the module is instantiated
+#[derive(Debug)]
+pub struct WasmStartSection {
+    pub section_size: usize,
+    pub func: u32,
+}
+
+/// Exception tags, whose type gives the values thrown with them
+#[derive(Debug)]
+pub struct WasmTag {
+    /// Always 0, for exceptions
+    pub attribute: u8,
+    pub type_index: u32,
+}
+
+#[derive(Debug)]
+pub struct WasmTagSection {
+    pub section_size: usize,
+    pub num_tags: usize,
+    pub tags: Vec<WasmTag>,
+}
+
+/// A custom section as it was read, kept so it's written back in the same place
+#[derive(Debug, Clone)]
+pub struct WasmCustomSection {
+    /// None if the name couldn't be read, or it's a name section that couldn't be, in which case
+    /// `data` is the whole section and it's written back unchanged
+    pub name: Option<Vec<u8>>,
+    /// The payload after the name
+    pub data: Vec<u8>,
+    /// The id of the last known section before it, or 0 if it came before all of them
+    pub after: u8,
+}
+
+/// How many data segments there are, given before the code so memory.init and data.drop can be
+/// checked in one pass. Modules only have one when they need it.
 #[derive(Debug)]
 pub struct WasmDataCountSection {
     pub section_size: usize,
@@ -598,7 +1106,12 @@ pub struct WasmFunction {
     pub local_types: Vec<(u8, usize)>,
     pub locals: Vec<WasmLocal>,
     pub body: WasmExpr,
-    pub raw_body: Vec<u64>
+    pub raw_body: Vec<u64>,
+    /// Where the function's entry in the code section starts, at its size
+    pub offset: usize,
+    /// Where each instruction of the body starts, in the order they are encoded.
+    /// Both are file offsets, and only known for functions read from a binary.
+    pub instr_offsets: Vec<usize>,
 }
 
 impl Debug for WasmFunction {
@@ -619,6 +1132,145 @@ impl Debug for WasmFunction {
     }
 }
 
+/// Maps an index in one of the index spaces to its debug name
+pub type WasmNameMap = BTreeMap<u32, String>;
+/// Maps an index to a second name map, e.g. function -> local -> name
+pub type WasmIndirectNameMap = BTreeMap<u32, WasmNameMap>;
+
+/// The custom "name" section. Every part of it is optional, and names are only
+/// for display, so nothing else in the module depends on them.
+#[derive(Debug, Default, Clone)]
+pub struct WasmNameSection {
+    pub module_name: Option<String>,
+    pub function_names: WasmNameMap,
+    pub local_names: WasmIndirectNameMap,
+    pub label_names: WasmIndirectNameMap,
+    pub type_names: WasmNameMap,
+    pub table_names: WasmNameMap,
+    pub memory_names: WasmNameMap,
+    pub global_names: WasmNameMap,
+    pub elem_names: WasmNameMap,
+    pub data_names: WasmNameMap,
+    pub tag_names: WasmNameMap,
+}
+
+/// Turns a debug name into a WAT identifier. Names are arbitrary UTF-8, but ids
+/// can only hold printable ASCII that isn't a delimiter, so anything else becomes '_'
+pub fn name_to_id(name: &str) -> String {
+    let id: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && !"\"(),;[]{}".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("${}", id)
+}
+
+impl WasmNameSection {
+    fn id_or(name: Option<&String>, fallback: String) -> String {
+        match name {
+            Some(name) if !name.is_empty() => name_to_id(name),
+            _ => fallback,
+        }
+    }
+
+    pub fn module_id(&self) -> Option<String> {
+        self.module_name.as_ref().filter(|name| !name.is_empty()).map(|name| name_to_id(name))
+    }
+
+    pub fn func_id(&self, func: u32) -> String {
+        Self::id_or(self.function_names.get(&func), format!("$func{}", func))
+    }
+
+    pub fn local_id(&self, func: u32, local: u32) -> String {
+        let name = self.local_names.get(&func).and_then(|locals| locals.get(&local));
+        Self::id_or(name, format!("$var{}", local))
+    }
+
+    /// Falls back to the depth-based label, as label indices are only meaningful with names
+    pub fn label_id(&self, func: u32, label: u32, depth: usize) -> String {
+        let name = self.label_names.get(&func).and_then(|labels| labels.get(&label));
+        Self::id_or(name, format!("$label{}", depth))
+    }
+
+    pub fn table_id(&self, table: u32) -> String {
+        Self::id_or(self.table_names.get(&table), format!("$table{}", table))
+    }
+
+    pub fn memory_id(&self, memory: u32) -> String {
+        Self::id_or(self.memory_names.get(&memory), format!("$memory{}", memory))
+    }
+
+    pub fn global_id(&self, global: u32) -> String {
+        Self::id_or(self.global_names.get(&global), format!("$global{}", global))
+    }
+
+    pub fn elem_id(&self, elem: u32) -> String {
+        Self::id_or(self.elem_names.get(&elem), format!("$elem{}", elem))
+    }
+
+    pub fn tag_id(&self, tag: u32) -> String {
+        Self::id_or(self.tag_names.get(&tag), format!("$tag{}", tag))
+    }
+
+    /// Types and data segments aren't given ids unless they have a name
+    pub fn type_id(&self, sig: u32) -> Option<String> {
+        self.type_names.get(&sig).filter(|name| !name.is_empty()).map(|name| name_to_id(name))
+    }
+
+    pub fn data_id(&self, data: u32) -> Option<String> {
+        self.data_names.get(&data).filter(|name| !name.is_empty()).map(|name| name_to_id(name))
+    }
+
+    // Makes every name's id different from every other id in its index space. Names can
+    // repeat, become the same id once they're cleaned up, or look like the id another
+    // index falls back to, and WAT doesn't allow any of those, so clashes get a `_N` suffix.
+    fn unique_names(names: &WasmNameMap, fallback: Option<&str>) -> WasmNameMap {
+        let mut seen = HashSet::new();
+        let is_other_fallback = |idx: u32, id: &str| {
+            let Some(fallback) = fallback else {
+                return false;
+            };
+            let number = id.strip_prefix('$').and_then(|id| id.strip_prefix(fallback)).and_then(|n| n.parse::<u32>().ok());
+            number.is_some_and(|n| n != idx && id == format!("${}{}", fallback, n))
+        };
+        let mut unique = WasmNameMap::new();
+        for (idx, name) in names.iter().filter(|(_, name)| !name.is_empty()) {
+            let base = name_to_id(name);
+            let mut id = base.clone();
+            let mut suffix = 1;
+            while is_other_fallback(*idx, &id) || seen.contains(&id) {
+                id = format!("{}_{}", base, suffix);
+                suffix += 1;
+            }
+            seen.insert(id.clone());
+            unique.insert(*idx, id[1..].to_string());
+        }
+        unique
+    }
+
+    /// The names as ids that can be used in WAT, with clashes renamed. Labels are left alone, as they can shadow each other.
+    pub fn with_unique_ids(&self) -> WasmNameSection {
+        WasmNameSection {
+            module_name: self.module_name.clone(),
+            function_names: Self::unique_names(&self.function_names, Some("func")),
+            local_names: self.local_names.iter().map(|(func, locals)| (*func, Self::unique_names(locals, Some("var")))).collect(),
+            label_names: self.label_names.clone(),
+            type_names: Self::unique_names(&self.type_names, None),
+            table_names: Self::unique_names(&self.table_names, Some("table")),
+            memory_names: Self::unique_names(&self.memory_names, Some("memory")),
+            global_names: Self::unique_names(&self.global_names, Some("global")),
+            elem_names: Self::unique_names(&self.elem_names, Some("elem")),
+            data_names: Self::unique_names(&self.data_names, None),
+            tag_names: Self::unique_names(&self.tag_names, Some("tag")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WasmFile {
     pub wasm_header: WasmHeader,
@@ -630,25 +1282,106 @@ pub struct WasmFile {
     pub global_section: WasmGlobalSection,
     pub export_section: WasmExportSection,
     pub elem_section: WasmElemSection,
+    pub start_section: Option<WasmStartSection>,
+    pub tag_section: WasmTagSection,
     pub code_section: WasmCodeSection,
     pub data_section: WasmDataSection,
-    pub data_count_section: WasmDataCountSection,
+    pub data_count_section: Option<WasmDataCountSection>,
+    pub name_section: WasmNameSection,
+    /// In the order they were read. A "name" section among them only marks where the names go,
+    /// as they're written from name_section.
+    pub custom_sections: Vec<WasmCustomSection>,
+}
+
+/// Where an entry in one of the module's index spaces comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasmIndexOrigin {
+    // Index into the import section, which counts imports of every kind
+    Imported(usize),
+    // Index into the section that defines it (function, table, memory, global or tag)
+    Defined(usize),
+}
+
+/// The function, table, memory, global and tag index spaces. Imports of each kind
+/// come first, followed by the module's own definitions, so an index taken from
+/// an instruction or export only means something once it is resolved through here.
+#[derive(Debug, Default, Clone)]
+pub struct WasmIndexSpaces {
+    pub funcs: Vec<WasmIndexOrigin>,
+    pub tables: Vec<WasmIndexOrigin>,
+    pub memories: Vec<WasmIndexOrigin>,
+    pub globals: Vec<WasmIndexOrigin>,
+    pub tags: Vec<WasmIndexOrigin>,
+}
+
+impl WasmIndexSpaces {
+    pub fn new(
+        imports: &WasmImportSection,
+        function_section: &WasmFunctionSection,
+        table_section: &WasmTableSection,
+        memory_section: &WasmMemorySection,
+        global_section: &WasmGlobalSection,
+        tag_section: &WasmTagSection,
+    ) -> Self {
+        let mut spaces = Self::default();
+        for (i, import) in imports.imports.iter().enumerate() {
+            let space = match import.import_kind {
+                WasmImportType::Func => &mut spaces.funcs,
+                WasmImportType::Table => &mut spaces.tables,
+                WasmImportType::Mem => &mut spaces.memories,
+                WasmImportType::Global => &mut spaces.globals,
+                WasmImportType::Tag => &mut spaces.tags,
+            };
+            space.push(WasmIndexOrigin::Imported(i));
+        }
+
+        let defined = |count: usize| (0..count).map(WasmIndexOrigin::Defined);
+        spaces.funcs.extend(defined(function_section.function_signature_indexes.len()));
+        spaces.tables.extend(defined(table_section.tables.len()));
+        spaces.memories.extend(defined(memory_section.memories.len()));
+        spaces.globals.extend(defined(global_section.globals.len()));
+        spaces.tags.extend(defined(tag_section.tags.len()));
+        spaces
+    }
+
+    /// Number of imports in a space, which is also the index of its first definition
+    pub fn num_imported(space: &[WasmIndexOrigin]) -> usize {
+        space.iter().take_while(|origin| matches!(origin, WasmIndexOrigin::Imported(_))).count()
+    }
+}
+
+/// A resolved entry in an index space: either an import or a local definition
+#[derive(Debug, Clone, Copy)]
+pub enum WasmIndexEntry<'a, T> {
+    Imported(&'a WasmImportHeader),
+    Defined(&'a T),
+}
+
+/// A resolved function. Imported functions have no body, and a malformed module
+/// may be missing a signature or body for a defined one.
+#[derive(Debug, Clone, Copy)]
+pub struct WasmFuncEntry<'a> {
+    pub origin: WasmIndexOrigin,
+    pub sig: Option<&'a WasmFunctionType>,
+    pub body: Option<&'a WasmFunction>,
 }
 
 #[derive(Clone, Copy)]
 pub struct InstrInfo {
-    // The opcode
-    pub instr: u8,
-    // The identifier
+    /// 0xFB, 0xFC, 0xFD or 0xFE for prefixed instructions, 0 for the core set
+    pub prefix: u8,
+    /// The opcode, or the sub-opcode that follows the prefix
+    pub instr: u32,
+    /// The identifier
     pub name: &'static str,
-    // What we take (non-immediates, from the stack)
+    /// What we take (non-immediates, from the stack)
     pub in_types: &'static [Prim],
-    // What we output (to the stack)
+    /// What we output (to the stack)
     pub out_types: &'static [Prim],
-    // What we take from the file
+    /// What we take from the file
     pub constants: &'static [Prim],
-    // Does an align byte follow?
-    pub takes_align: bool,
+    /// log2 of the access width, for instructions that take a MemArg
+    pub natural_align: Option<u8>,
 }
 
 impl Debug for InstrInfo {
@@ -660,7 +1393,7 @@ impl Debug for InstrInfo {
             .collect::<Vec<String>>()
             .join(", ");
 
-        let rettype = if self.out_types.len() == 0 {
+        let rettype = if self.out_types.is_empty() {
             "".to_string()
         } else {
             format!(" -> {:}", out_string)
@@ -673,7 +1406,7 @@ impl Debug for InstrInfo {
             .collect::<Vec<String>>()
             .join(", ");
 
-        let arg = if self.in_types.len() == 0 {
+        let arg = if self.in_types.is_empty() {
             "".to_string()
         } else {
             arg_string
@@ -686,16 +1419,22 @@ impl Debug for InstrInfo {
             .collect::<Vec<String>>()
             .join(", ");
 
-        let constant = if self.constants.len() != 0 {
+        let constant = if !self.constants.is_empty() {
             format!("[{}]", constant)
         } else {
             constant
         };
 
+        let opcode = if self.prefix != 0 {
+            format!("{:#x} {:#x}", self.prefix, self.instr)
+        } else {
+            format!("{:#x}", self.instr)
+        };
+
         write!(
             f,
-            "{:#x}: {}{}({}){}",
-            self.instr, self.name, constant, arg, rettype
+            "{}: {}{}({}){}",
+            opcode, self.name, constant, arg, rettype
         )
     }
 }
@@ -707,53 +1446,23 @@ pub enum SpecialInstr {
     BeginBlock,
     EndBlock,
     CallIndirect,
+    BrOnCast,
+    // `select` with the type of its operands written out
+    SelectTyped,
 }
 
 pub fn get_edge_case(info: InstrInfo) -> SpecialInstr {
-    match info.instr {
-        0x0e => SpecialInstr::BrTable,
-        0x02 | 0x03 | 0x04 => SpecialInstr::BeginBlock,
-        0x0b => SpecialInstr::EndBlock,
-        0x11 => SpecialInstr::CallIndirect,
+    match (info.prefix, info.instr) {
+        (0, 0x0e) => SpecialInstr::BrTable,
+        (0, 0x02..=0x04 | 0x06 | 0x1f) => SpecialInstr::BeginBlock,
+        (0, 0x0b | 0x18) => SpecialInstr::EndBlock,
+        (0, 0x11 | 0x13) => SpecialInstr::CallIndirect,
+        (GC_PREFIX, 0x18..=0x19) => SpecialInstr::BrOnCast,
+        (0, 0x1c) => SpecialInstr::SelectTyped,
         _ => SpecialInstr::None,
     }
 }
 
-pub fn calc_dyn_size(mut i: i64) -> usize {
-    let mut count = 1;
-    if i == i64::MIN {
-        i = i + 1;
-    }
-    i = i.abs();
-    while i != 0 {
-        i >>= 7;
-        count += 1;
-    }
-    count
-}
-
-pub fn calculate_body_len(expr: &WasmExpr) -> usize {
-    let mut total = 0;
-    for seg in expr.expr_string.clone() {
-        total += match seg {
-            ExprSeg::Operation(_) => 1,
-            ExprSeg::Int(i) => calc_dyn_size(i),
-            ExprSeg::Float32(_) => 4,
-            ExprSeg::Float64(_) => 8,
-            ExprSeg::BrTable(tab) => {
-                calc_dyn_size(tab.default as i64)
-                    + tab
-                        .break_depths
-                        .iter()
-                        .fold(0, |acc: usize, i| acc + calc_dyn_size(*i as i64))
-            }
-            ExprSeg::ControlFlow(_, block, _) => calculate_body_len(block.as_ref()),
-            _ => 0,
-        }
-    }
-    total
-}
-
 impl prs::GrammarTrait for u64 {
     fn start_sym() -> Self {
         START
@@ -774,16 +1483,156 @@ impl prs::GrammarTrait for u64 {
             }
         }
         
-        return self.to_string();
+        self.to_string()
     }
 }
 
 impl WasmFile {
-    pub fn get_import_sig(&self, import: &WasmImportHeader) -> &WasmFunctionType {
-        &self.type_section.function_signatures[import.import_type as usize]
+    pub fn get_import_sig(&self, import: &WasmImportHeader) -> Result<&WasmFunctionType, WyrmError> {
+        let WasmImportDesc::Func(sig) = import.desc else {
+            return Err(WyrmError::invalid("Import is not a function").in_section(0x02));
+        };
+        self.type_section.function_signatures.get(sig as usize).ok_or_else(|| {
+            WyrmError::invalid(format!("Import refers to missing type {}", sig)).in_section(0x02)
+        })
+    }
+
+    /// The index spaces as the sections are now. They aren't kept on the file, so they
+    /// can't go stale when imports or definitions are edited.
+    pub fn index_spaces(&self) -> WasmIndexSpaces {
+        WasmIndexSpaces::new(
+            &self.import_section_header,
+            &self.function_section,
+            &self.table_section,
+            &self.memory_section,
+            &self.global_section,
+            &self.tag_section,
+        )
+    }
+
+    /// Number of imports of one kind, which is also the index of its first definition
+    pub fn num_imported(&self, kind: WasmImportType) -> usize {
+        self.import_section_header.imports.iter().filter(|import| import.import_kind == kind).count()
+    }
+
+    fn num_defined(&self, kind: WasmImportType) -> usize {
+        match kind {
+            WasmImportType::Func => self.function_section.function_signature_indexes.len(),
+            WasmImportType::Table => self.table_section.tables.len(),
+            WasmImportType::Mem => self.memory_section.memories.len(),
+            WasmImportType::Global => self.global_section.globals.len(),
+            WasmImportType::Tag => self.tag_section.tags.len(),
+        }
+    }
+
+    /// Number of entries in one of the index spaces, imported and defined
+    pub fn index_space_len(&self, kind: WasmImportType) -> u32 {
+        (self.num_imported(kind) + self.num_defined(kind)) as u32
+    }
+
+    /// Where an index comes from, found from the sections without building the whole space
+    pub fn index_origin(&self, kind: WasmImportType, idx: u32) -> Option<WasmIndexOrigin> {
+        let mut num_imported = 0;
+        for (i, import) in self.import_section_header.imports.iter().enumerate() {
+            if import.import_kind != kind {
+                continue;
+            }
+            if num_imported == idx {
+                return Some(WasmIndexOrigin::Imported(i));
+            }
+            num_imported += 1;
+        }
+        let defined = (idx - num_imported) as usize;
+        (defined < self.num_defined(kind)).then_some(WasmIndexOrigin::Defined(defined))
+    }
+
+    /// The funcidx of the defined function at `defined` in the code section
+    pub fn defined_func_idx(&self, defined: usize) -> u32 {
+        (self.num_imported(WasmImportType::Func) + defined) as u32
+    }
+
+    pub fn defined_table_idx(&self, defined: usize) -> u32 {
+        (self.num_imported(WasmImportType::Table) + defined) as u32
+    }
+
+    pub fn defined_memory_idx(&self, defined: usize) -> u32 {
+        (self.num_imported(WasmImportType::Mem) + defined) as u32
     }
-    pub fn get_func_sig(&self, func: usize) -> &WasmFunctionType {
-        let func_sig_idx = self.function_section.function_signature_indexes[func] as usize;
-        &self.type_section.function_signatures[func_sig_idx]
+
+    pub fn defined_global_idx(&self, defined: usize) -> u32 {
+        (self.num_imported(WasmImportType::Global) + defined) as u32
+    }
+
+    pub fn defined_tag_idx(&self, defined: usize) -> u32 {
+        (self.num_imported(WasmImportType::Tag) + defined) as u32
+    }
+
+    pub fn get_func(&self, funcidx: u32) -> Option<WasmFuncEntry<'_>> {
+        let origin = self.index_origin(WasmImportType::Func, funcidx)?;
+        Some(match origin {
+            WasmIndexOrigin::Imported(i) => {
+                let import = self.import_section_header.imports.get(i)?;
+                WasmFuncEntry {
+                    origin,
+                    sig: self.get_import_sig(import).ok(),
+                    body: None,
+                }
+            }
+            WasmIndexOrigin::Defined(i) => {
+                let sig_idx = self.function_section.function_signature_indexes.get(i);
+                WasmFuncEntry {
+                    origin,
+                    sig: sig_idx.and_then(|sig| self.type_section.function_signatures.get(*sig as usize)),
+                    body: self.code_section.functions.get(i),
+                }
+            }
+        })
+    }
+
+    pub fn get_func_sig(&self, funcidx: u32) -> Option<&WasmFunctionType> {
+        self.get_func(funcidx)?.sig
+    }
+
+    /// The index of a function's type, rather than the type itself
+    pub fn func_sig_index(&self, funcidx: u32) -> Option<u32> {
+        match self.index_origin(WasmImportType::Func, funcidx)? {
+            WasmIndexOrigin::Imported(i) => match self.import_section_header.imports.get(i)?.desc {
+                WasmImportDesc::Func(sig) => Some(sig),
+                _ => None,
+            },
+            WasmIndexOrigin::Defined(i) => self.function_section.function_signature_indexes.get(i).copied(),
+        }
+    }
+
+    fn resolve<'a, T>(&'a self, kind: WasmImportType, idx: u32, defined: &'a [T]) -> Option<WasmIndexEntry<'a, T>> {
+        match self.index_origin(kind, idx)? {
+            WasmIndexOrigin::Imported(i) => self.import_section_header.imports.get(i).map(WasmIndexEntry::Imported),
+            WasmIndexOrigin::Defined(i) => defined.get(i).map(WasmIndexEntry::Defined),
+        }
+    }
+
+    pub fn get_table(&self, tableidx: u32) -> Option<WasmIndexEntry<'_, WasmTable>> {
+        self.resolve(WasmImportType::Table, tableidx, &self.table_section.tables)
+    }
+
+    pub fn get_memory(&self, memidx: u32) -> Option<WasmIndexEntry<'_, WasmMemoryStruct>> {
+        self.resolve(WasmImportType::Mem, memidx, &self.memory_section.memories)
+    }
+
+    pub fn get_tag(&self, tagidx: u32) -> Option<&WasmTag> {
+        match self.resolve(WasmImportType::Tag, tagidx, &self.tag_section.tags)? {
+            WasmIndexEntry::Imported(WasmImportHeader { desc: WasmImportDesc::Tag(tag), .. }) => Some(tag),
+            WasmIndexEntry::Imported(_) => None,
+            WasmIndexEntry::Defined(tag) => Some(tag),
+        }
+    }
+
+    /// The type whose params are the values a tag's exceptions carry
+    pub fn get_tag_sig(&self, tagidx: u32) -> Option<&WasmFunctionType> {
+        self.type_section.function_signatures.get(self.get_tag(tagidx)?.type_index as usize)
+    }
+
+    pub fn get_global(&self, globalidx: u32) -> Option<WasmIndexEntry<'_, WasmGlobal>> {
+        self.resolve(WasmImportType::Global, globalidx, &self.global_section.globals)
     }
 }
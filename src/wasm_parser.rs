@@ -1,6 +1,5 @@
 // An Earley parser for wasm specifically
 use crate::{
-    wasm_model::*,
     instr_table::*,
     parser::prs
 };
@@ -10,7 +9,7 @@ pub const PARSER_GRAMMAR: prs::Grammar<u64> = prs::Grammar::<u64>::new(&[
     prs::rule!(u64, START, &[STMTS]),
     prs::rule!(u64, STMTS, &[STMT], &[STMT, STMTS]),
     prs::rule!(u64, STMT, &[ADD_I64_OP], &[INSTR]),
-    prs::term_rule!(u64, INSTR, all_symbols),
+    prs::term_rule!(u64, INSTR, ALL_SYMBOLS),
     prs::rule!(u64, TERM_VOID, &[BYTE]),
     prs::rule!(u64, TERM_I32, &[LEB128]),
     prs::rule!(u64, TERM_I64, &[LEB128]),
@@ -20,10 +19,18 @@ pub const PARSER_GRAMMAR: prs::Grammar<u64> = prs::Grammar::<u64>::new(&[
     prs::rule!(u64, TERM_GLOBAL, &[LEB128]),
     prs::rule!(u64, TERM_GENERIC, &[LEB128]),
     prs::rule!(u64, TERM_FUNC, &[LEB128]),
+    prs::rule!(u64, TERM_TABLE, &[LEB128]),
+    prs::rule!(u64, TERM_MEMORY, &[LEB128]),
+    prs::rule!(u64, TERM_HEAPTYPE, &[LEB128]),
+    prs::rule!(u64, TERM_BLOCKTYPE, &[LEB128]),
+    prs::rule!(u64, TERM_TYPE, &[LEB128]),
+    prs::rule!(u64, TERM_TAG, &[LEB128]),
+    // Alignment, then the memory index when multi-memory sets bit 6, then the offset
+    prs::rule!(u64, TERM_MEMARG, &[LEB128, LEB128], &[LEB128, LEB128, LEB128]),
     prs::rule!(u64, QWORD, &[BYTE, BYTE, BYTE, BYTE, BYTE, BYTE, BYTE, BYTE]),
     prs::rule!(u64, DWORD, &[BYTE, BYTE, BYTE, BYTE]),
-    prs::term_rule!(u64, BYTE, bytes),
+    prs::term_rule!(u64, BYTE, BYTES),
     prs::rule!(u64, LEB128, &[HIGH_BYTE, LEB128], &[LOW_BYTE]),
-    prs::term_rule!(u64, LOW_BYTE, lower_bytes),
-    prs::term_rule!(u64, HIGH_BYTE, upper_bytes),
+    prs::term_rule!(u64, LOW_BYTE, LOWER_BYTES),
+    prs::term_rule!(u64, HIGH_BYTE, UPPER_BYTES),
 ]);
@@ -1,194 +1,528 @@
-
-use crate::wasm_model::*;
-use std::str::from_utf8;
+use crate::{emitter::*, error::WyrmError, instr_table::{DELEGATE, IF, TRY}, wasm_model::*};
+use std::io::Write;
 
 pub fn type_to_str(wasm_type: WasmTypeAnnotation) -> String {
-    match wasm_type {
-        // f64
-        WasmTypeAnnotation { _type: 0x7c } => "f64",
-        // f32
-        WasmTypeAnnotation { _type: 0x7d } => "f32",
-        // i64
-        WasmTypeAnnotation { _type: 0x7e } => "i64",
-        // i32
-        WasmTypeAnnotation { _type: 0x7f } => "i32",
-        // funcref
-        WasmTypeAnnotation { _type: 0x70 } => "funcref",
+    match wasm_type._type {
+        0x7f => "i32",
+        0x7e => "i64",
+        0x7d => "f32",
+        0x7c => "f64",
+        0x7b => "v128",
+        // Nullable references to abstract heap types have their own shorthands
+        0x70 => "funcref",
+        0x6f => "externref",
+        0x6e => "anyref",
+        0x6d => "eqref",
+        0x6c => "i31ref",
+        0x6b => "structref",
+        0x6a => "arrayref",
+        0x69 => "exnref",
+        0x71 => "nullref",
+        0x72 => "nullexternref",
+        0x73 => "nullfuncref",
+        0x74 => "nullexnref",
         _ => ""
     }.to_string()
-} 
+}
+
+fn types_to_str(types: &[WasmTypeAnnotation]) -> String {
+    types.iter().map(|x| type_to_str(*x)).collect::<Vec<String>>().join(" ")
+}
 
-pub fn vec_to_string(vec: Vec<u8>) -> String {
-    let mut str = "".to_string();
-    for u in vec.clone() {
-        str = str + (u as char).to_string().as_str();
+/// WAT strings are quoted, with anything that isn't printable ASCII written as a \hh escape
+pub fn bytes_to_wat_string(bytes: &[u8]) -> String {
+    let mut str = "\"".to_string();
+    for b in bytes {
+        match *b {
+            b'"' => str += "\\\"",
+            b'\\' => str += "\\\\",
+            0x20..=0x7e => str.push(*b as char),
+            _ => str += &format!("\\{:02x}", b),
+        }
     }
-    str
+    str + "\""
 }
 
-pub fn indent(s: String, indent: u32) -> String {
-    let indentation = (0..indent).fold("".to_string(), |acc, _| ("  ".to_string() + acc.as_str()));
-    let indented = indentation.clone() + &s.replace("\n", &("\n".to_string() + &indentation));
-    indented.strip_suffix(&indentation).unwrap_or(&indented).to_string()
+/// e.g. `17 256 shared`, or `i64 1` for a 64-bit memory with no maximum
+pub fn limits_to_wat(limits: &Limits) -> String {
+    let mut wat = if limits.is_64 { "i64 ".to_string() } else { "".to_string() };
+    wat += &limits.initial.to_string();
+    if let Some(max) = limits.max {
+        wat += &format!(" {}", max);
+    }
+    if limits.shared {
+        wat += " shared";
+    }
+    wat
+}
+
+// Joins the parts of a field that aren't empty, e.g. a signature with no params or results
+// A data segment's offset can leave out `offset` when it's a single instruction
+fn data_offset_to_wat(offset_expr: &WasmExpr, state: EmitterState<'_>) -> String {
+    let wat = offset_expr.emit_expression_wat_with(state);
+    if offset_expr.expr_string.len() > 2 { format!("(offset {})", wat) } else { wat }
 }
 
+fn join_parts(parts: &[&str]) -> String {
+    parts.iter().filter(|part| !part.is_empty()).copied().collect::<Vec<&str>>().join(" ")
+}
+
+// Every method takes the names to use for ids, which are the module's names with any
+// that would clash renamed. See WasmNameSection::with_unique_ids.
 impl WasmFile {
 
 
 pub fn sig_to_import_wat(&self, f: &WasmFunctionType) -> String {
-    let mut wat: String = "".to_string();
-    if f.num_params != 0 {
-        wat += "(param ";
-        wat += f.params.clone().iter().map(|x| type_to_str(*x)).collect::<Vec<String>>().join(" ").as_str();
-        wat += ")";
-        if f.num_results != 0 {
-            wat += " ";
-        }
-    }
+    let params = if f.num_params != 0 { format!("(param {})", types_to_str(&f.params)) } else { "".to_string() };
+    let results = if f.num_results != 0 { format!("(result {})", types_to_str(&f.results)) } else { "".to_string() };
+    join_parts(&[&params, &results])
+}
 
-    if f.num_results != 0 {
-        wat += "(result ";
-        wat += f.results.clone().iter().map(|x| type_to_str(*x)).collect::<Vec<String>>().join(" ").as_str();
-        wat += ")";
-    }
-    wat
+pub fn sig_to_wat(&self, names: &WasmNameSection, func: u32, f: &WasmFunctionType) -> (usize, String) {
+    let params = (0..).zip(f.params.iter())
+        .map(|(i, x)| format!("(param {} {})", names.local_id(func, i), type_to_str(*x)))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let results = if f.num_results != 0 { format!("(result {})", types_to_str(&f.results)) } else { "".to_string() };
+    (f.params.len(), join_parts(&[&params, &results]))
 }
 
-pub fn sig_to_wat(&self, f: &WasmFunctionType) -> (usize, String) {
-    let mut wat: String = "".to_string();
-    if f.num_params != 0 {
-        wat += f.params.clone().iter().enumerate()
-                               .map(|(i, x)| format!("(param $var{} {})", i, type_to_str(*x))).collect::<Vec<String>>().join(" ").as_str();
-        if f.num_results != 0 {
-            wat += " ";
-        }
-    }
+/// The type is always given explicitly, as leaving it out picks the first type that matches
+pub fn type_use_to_wat(&self, names: &WasmNameSection, sig: u32) -> String {
+    format!("(type {})", names.type_id(sig).unwrap_or(sig.to_string()))
+}
 
-    if f.num_results != 0 {
-        wat += "(result ";
-        wat += f.results.clone().iter().map(|x| type_to_str(*x)).collect::<Vec<String>>().join(" ").as_str();
-        wat += ")";
-    }
-    (f.params.len(), wat)
+pub fn type_to_wat(&self, names: &WasmNameSection, i: u32, f: &WasmFunctionType) -> String {
+    let id = names.type_id(i).unwrap_or_default();
+    let func = join_parts(&["func", &self.sig_to_import_wat(f)]);
+    format!("({})\n", join_parts(&["type", &id, &format!("({})", func)]))
 }
 
-pub fn export_to_wat(&self, export: &WasmExportHeader) -> String{
-    // TODO: Need to find list of these export kinds
-    if export.export_kind == 0 {
-        return format!("(export \"{}\" (func $func{}))\n", from_utf8(export.export_name.as_slice()).unwrap(), export.export_signature_index);
-    }
-    if export.export_kind == 2 {
-        return format!("(export \"{}\" (memory $memory{}))\n", from_utf8(export.export_name.as_slice()).unwrap(), export.export_signature_index);
-    }
-    return "".to_string();
+pub fn export_to_wat(&self, names: &WasmNameSection, export: &WasmExportHeader) -> String {
+    let idx = export.export_signature_index;
+    let (kind, id) = match export.export_kind {
+        0 => ("func", names.func_id(idx)),
+        1 => ("table", names.table_id(idx)),
+        2 => ("memory", names.memory_id(idx)),
+        3 => ("global", names.global_id(idx)),
+        4 => ("tag", names.tag_id(idx)),
+        _ => return "".to_string(),
+    };
+    format!("(export {} ({} {}))\n", bytes_to_wat_string(export.export_name.as_bytes()), kind, id)
+}
+
+pub fn table_type_to_wat(&self, table: &WasmTable) -> String {
+    format!("{} {}", limits_to_wat(&table.limits), type_to_str(WasmTypeAnnotation { _type: table.wasm_type}))
 }
 
-pub fn table_to_wat(&self, i: usize, table: &WasmTable) -> String {
-    format!("(table $table{} {} {} {})\n", i, table.limits_initial, table.limits_max, type_to_str(WasmTypeAnnotation { _type: table.wasm_type}))
+pub fn table_to_wat(&self, names: &WasmNameSection, i: u32, table: &WasmTable) -> String {
+    format!("(table {} {})\n", names.table_id(i), self.table_type_to_wat(table))
 }
 
-pub fn elem_to_wat(&self, i: usize, elem: &WasmElem) -> String {
+pub fn elem_to_wat(&self, names: &WasmNameSection, i: u32, elem: &WasmElem) -> String {
     let reftype = match elem._type {
         WasmRefType::FuncRef => "funcref",
         WasmRefType::ExternRef => "externref",
     };
 
-    match &elem.mode {
-        WasmElemMode::Passive => {
-            format!("(elem $elem{:} {:} {:})\n", i, reftype, elem.init)
-        },
+    let state = named_emitter(names);
+    let id = names.elem_id(i);
+    let mode = match &elem.mode {
+        WasmElemMode::Passive => "".to_string(),
+        // Without bit 1 the table is implicitly table 0, and leaving it out keeps that encoding
+        WasmElemMode::Active(active_struct) if elem.flags & 2 == 0 => {
+            format!(" (offset {})", active_struct.offset_expr.emit_expression_wat_with(state))
+        }
         WasmElemMode::Active(active_struct) => {
-            format!("(elem $elem{:} {:} {:} {:})\n", i, active_struct.offset_expr, reftype, elem.init)
+            format!(" (table {}) (offset {})",
+                names.table_id(active_struct.table),
+                active_struct.offset_expr.emit_expression_wat_with(state))
         },
-        WasmElemMode::Declarative => {
-            format!("(elem $elem{:} {:} {:})\n", i, reftype, elem.init)
-        }
-    }
+        WasmElemMode::Declarative => " declare".to_string(),
+    };
+
+    // Segments encoded as function indices keep the `func` shorthand, everything else lists its expressions
+    let items = if elem.flags & 4 == 0 {
+        let funcs = elem.init.iter().filter_map(|item| match item.expr_string.first() {
+            Some(ExprSeg::Instr(instr)) => match instr.get(1) {
+                Some(ExprSeg::Func(idx)) => Some(" ".to_string() + &names.func_id(*idx)),
+                _ => None,
+            },
+            _ => None,
+        });
+        "func".to_string() + &funcs.collect::<String>()
+    } else {
+        let exprs = elem.init.iter().map(|item| format!(" (item {})", item.emit_expression_wat_with(state)));
+        reftype.to_string() + &exprs.collect::<String>()
+    };
+
+    format!("(elem {}{} {})\n", id, mode, items)
 }
 
-pub fn func_to_wat(&self, i: usize, func: &WasmFunction) -> String {
-    let mut wat: String = "".to_string(); 
-    wat += &format!("(func $func{:}", i);
-    let (num_params, t) = self.sig_to_wat(self.get_func_sig(i));
-    wat += &t;
-    wat += "\n";
-    if func.locals.len() > 0 {
-        wat += &indent(func.locals.iter().enumerate()
-                        .map(|(i, x)| format!("(local $var{} {})", i + num_params, &type_to_str(x._type)))
-                        .reduce(|acc, s| (acc + " " + &s).to_string())
-                        .unwrap_or("".to_string()).to_string() + "\n", 1);
+/// The function's first line and its locals, which its body follows. `i` is the funcidx,
+/// which counts imported functions.
+pub fn func_header_to_wat(&self, names: &WasmNameSection, i: u32, func: &WasmFunction) -> Result<String, WyrmError> {
+    let (Some(sig_idx), Some(sig)) = (self.func_sig_index(i), self.get_func_sig(i)) else {
+        return Err(WyrmError::invalid("Function refers to a missing type").in_section(0x03).in_func(i));
+    };
+    let (num_params, t) = self.sig_to_wat(names, i, sig);
+    let num_params = num_params as u32;
+    let mut wat = format!("({}\n", join_parts(&["func", &names.func_id(i), &self.type_use_to_wat(names, sig_idx), &t]));
+    if !func.locals.is_empty() {
+        let locals: Vec<String> = (num_params..).zip(func.locals.iter())
+            .map(|(j, x)| format!("(local {} {})", names.local_id(i, j), type_to_str(x._type)))
+            .collect();
+        wat += &format!("  {}\n", locals.join(" "));
     }
-    wat += &indent(format!("{:}", func.body.emit_block_wat(blank_emitter()).1), 1);
-    wat += ")\n";
-    wat
+    Ok(wat)
 }
 
-pub fn memory_to_wat(&self, i: usize, memory: &WasmMemoryStruct) -> String {
-    // TODO: Figure out what the second value is
-    format!("(memory $memory{} {} {})\n", i, memory.limits_initial, memory.limits_flags)
+/// `i` is the import's index in the space of its kind
+pub fn import_to_wat(&self, names: &WasmNameSection, i: u32, import: &WasmImportHeader) -> Result<String, WyrmError> {
+    let desc = match &import.desc {
+        WasmImportDesc::Func(sig) => {
+            join_parts(&[&self.type_use_to_wat(names, *sig), &self.sig_to_import_wat(self.get_import_sig(import)?)])
+        }
+        WasmImportDesc::Table(table) => self.table_type_to_wat(table),
+        WasmImportDesc::Mem(memory) => self.memory_type_to_wat(memory),
+        WasmImportDesc::Global { wasm_type, mutability } => self.global_type_to_wat(*wasm_type, *mutability),
+        WasmImportDesc::Tag(tag) => self.tag_type_to_wat(names, tag),
+    };
+    let (type_str, id) = match import.import_kind {
+        WasmImportType::Func => ("func", names.func_id(i)),
+        WasmImportType::Table => ("table", names.table_id(i)),
+        WasmImportType::Mem => ("memory", names.memory_id(i)),
+        WasmImportType::Global => ("global", names.global_id(i)),
+        WasmImportType::Tag => ("tag", names.tag_id(i)),
+    };
+    Ok(format!("({} {} (import {} {}) {})\n",
+        type_str,
+        id,
+        bytes_to_wat_string(import.import_module_name.as_bytes()),
+        bytes_to_wat_string(import.import_field.as_bytes()),
+        desc
+    ))
+}
+
+pub fn memory_type_to_wat(&self, memory: &WasmMemoryStruct) -> String {
+    limits_to_wat(&memory.limits)
 }
-pub fn global_to_wat(&self, i: usize, global: &WasmGlobal) -> String {
-    format!("(global $global{} ({} {}) ({}))\n", i, if global.mutability != 0 {
-        "mut"
+
+pub fn memory_to_wat(&self, names: &WasmNameSection, i: u32, memory: &WasmMemoryStruct) -> String {
+    format!("(memory {} {})\n", names.memory_id(i), self.memory_type_to_wat(memory))
+}
+
+pub fn global_type_to_wat(&self, wasm_type: WasmTypeAnnotation, mutability: u8) -> String {
+    if mutability != 0 {
+        format!("(mut {})", type_to_str(wasm_type))
     } else {
-        "immut"
-    }, type_to_str(global.wasm_type), global.expr)
+        type_to_str(wasm_type)
+    }
+}
+
+pub fn global_to_wat(&self, names: &WasmNameSection, i: u32, global: &WasmGlobal) -> String {
+    format!("(global {} {} {})\n",
+        names.global_id(i),
+        self.global_type_to_wat(global.wasm_type, global.mutability),
+        global.expr.emit_expression_wat_with(named_emitter(names)))
+}
+
+pub fn data_to_wat(&self, names: &WasmNameSection, i: u32, data: &WasmDataSeg) -> String {
+    let id = names.data_id(i).map(|id| id + " ").unwrap_or_default();
+    let mode = match &data.header.mode {
+        WasmDataMode::Passive => "".to_string(),
+        WasmDataMode::Active { memory: 0, offset_expr } => {
+            format!("{} ", data_offset_to_wat(offset_expr, named_emitter(names)))
+        }
+        WasmDataMode::Active { memory, offset_expr } => {
+            format!("(memory {}) (offset {}) ",
+                names.memory_id(*memory),
+                offset_expr.emit_expression_wat_with(named_emitter(names)))
+        }
+    };
+    format!("(data {}{}{})\n", id, mode, bytes_to_wat_string(&data.data))
+}
+
+pub fn start_to_wat(&self, names: &WasmNameSection, start: &WasmStartSection) -> String {
+    format!("(start {})\n", names.func_id(start.func))
 }
 
-pub fn data_to_wat(&self, data: &WasmDataSeg) -> String {
-    format!("(data {:} {:})\n", data.header.expr, from_utf8(data.data.as_slice()).unwrap_or("<PARSE ERROR>"))
+pub fn tag_type_to_wat(&self, names: &WasmNameSection, tag: &WasmTag) -> String {
+    self.type_use_to_wat(names, tag.type_index)
 }
 
+pub fn tag_to_wat(&self, names: &WasmNameSection, i: u32, tag: &WasmTag) -> String {
+    format!("(tag {} {})\n", names.tag_id(i), self.tag_type_to_wat(names, tag))
+}
+
+}
+
+/// Writes the module as WAT. Function bodies are either flat, or folded so each instruction has
+/// the operands it pops nested inside it, e.g. `(i32.add (local.get $a) (i32.const 1))`.
+#[derive(Default)]
+pub struct WatEmitter {
+    folded: bool,
+    // The blocks being folded, innermost last
+    frames: Vec<FoldFrame>,
+}
+
+// Only values pushed right before an instruction are nested into it, so the instructions
+// stay in the same order. Anything that can't be nested is written on its own line.
+struct FoldFrame {
+    // Values no instruction has taken yet, oldest first
+    operands: Vec<String>,
+    // How deep the block's instructions are indented
+    indent: usize,
+    // An if or a try, whose instructions are in `(then ...)`/`(else ...)` or `(do ...)`/`(catch ...)` arms
+    has_arms: bool,
+}
+
+impl WatEmitter {
+    pub fn new(folded: bool) -> Self {
+        Self { folded, frames: vec![] }
+    }
+
+    fn flush(out: &mut dyn Write, frame: &mut FoldFrame) -> Result<(), WyrmError> {
+        for operand in frame.operands.drain(..) {
+            write_line(out, frame.indent, &operand)?;
+        }
+        Ok(())
+    }
+
+    fn top(&mut self) -> Result<&mut FoldFrame, WyrmError> {
+        self.frames.last_mut().ok_or_else(|| WyrmError::invalid("Instruction outside of a function body"))
+    }
 }
 
-pub fn emit_wat(wasm: &WasmFile) -> String {
-    let mut wat: String = "(module\n".to_string();
-    for (i, import) in wasm.import_section_header.imports.iter().enumerate() {
-        let type_str = match import.import_kind {
-            WasmImportType::Func => "func",
-            WasmImportType::Table => "table",
-            WasmImportType::Mem => "mem",
-            WasmImportType::Global => "global",
+// Function bodies start two levels in, inside the module and the function
+const BODY_INDENT: usize = 2;
+
+impl Emitter for WatEmitter {
+    fn begin_module(&mut self, out: &mut dyn Write, module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        match module.names.module_id() {
+            Some(id) => writeln!(out, "(module {}", id)?,
+            None => writeln!(out, "(module")?,
+        }
+        Ok(())
+    }
+
+    fn field(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, field: Field<'_>) -> Result<(), WyrmError> {
+        let (wasm, names) = (module.wasm, module.names);
+        let wat = match field {
+            Field::Type(i, sig) => wasm.type_to_wat(names, i, sig),
+            Field::Import(i, import) => wasm.import_to_wat(names, i, import)?,
+            Field::Table(i, table) => wasm.table_to_wat(names, i, table),
+            Field::Memory(i, memory) => wasm.memory_to_wat(names, i, memory),
+            Field::Tag(i, tag) => wasm.tag_to_wat(names, i, tag),
+            Field::Global(i, global) => wasm.global_to_wat(names, i, global),
+            Field::Export(export) => wasm.export_to_wat(names, export),
+            Field::Start(start) => wasm.start_to_wat(names, start),
+            Field::Elem(i, elem) => wasm.elem_to_wat(names, i, elem),
+            Field::Data(i, data) => wasm.data_to_wat(names, i, data),
         };
-        wat += &indent(format!("({} $import{} (import \"{}\" \"{}\") {})\n",
-            type_str, 
-            i,
-            vec_to_string(import.import_module_name.clone()),
-            vec_to_string(import.import_field.clone()),
-            wasm.sig_to_import_wat(wasm.get_import_sig(import))
-        ), 1);
+        for line in wat.lines() {
+            write_line(out, 1, line)?;
+        }
+        Ok(())
     }
-    
-    for (i, table) in wasm.table_section.tables.iter().enumerate() {
-        wat += &indent(wasm.table_to_wat(i, table), 1);
+
+    fn begin_func(&mut self, out: &mut dyn Write, module: ModuleContext<'_>, func: FuncContext<'_>) -> Result<(), WyrmError> {
+        for line in module.wasm.func_header_to_wat(module.names, func.funcidx, func.func)?.lines() {
+            write_line(out, 1, line)?;
+        }
+        self.frames = vec![FoldFrame { operands: vec![], indent: BODY_INDENT, has_arms: false }];
+        Ok(())
     }
 
-    for (i, memory) in wasm.memory_section.memories.iter().enumerate() {
-        wat += &indent(wasm.memory_to_wat(i, memory), 1);
+    fn instr(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.folded {
+            return write_line(out, BODY_INDENT + site.depth, &site.text);
+        }
+        let frame = self.top()?;
+        match site.effect {
+            Some((pops, pushes)) if pops <= frame.operands.len() => {
+                let args = frame.operands.split_off(frame.operands.len() - pops);
+                let folded = format!("({})", [site.text.clone()].into_iter().chain(args).collect::<Vec<String>>().join(" "));
+                if pushes == 1 {
+                    frame.operands.push(folded);
+                } else {
+                    Self::flush(out, frame)?;
+                    write_line(out, frame.indent, &folded)?;
+                }
+            }
+            _ => {
+                Self::flush(out, frame)?;
+                write_line(out, frame.indent, &format!("({})", site.text))?;
+            }
+        }
+        Ok(())
     }
-    
-    for (i, global) in wasm.global_section.globals.iter().enumerate() {
-        wat += &indent(wasm.global_to_wat(i, global), 1);
+
+    fn begin_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.folded {
+            return write_line(out, BODY_INDENT + site.depth, &site.text);
+        }
+        let is_if = site.info.prefix == 0 && site.info.instr == IF as u32;
+        let has_arms = is_if || (site.info.prefix == 0 && site.info.instr == TRY as u32);
+        let frame = self.top()?;
+        // An if takes its condition from the top of the stack
+        let condition = if is_if { frame.operands.pop() } else { None };
+        Self::flush(out, frame)?;
+        let indent = frame.indent;
+        write_line(out, indent, &format!("({}", site.text))?;
+        if is_if {
+            if let Some(condition) = condition {
+                write_line(out, indent + 1, &condition)?;
+            }
+            write_line(out, indent + 1, "(then")?;
+        } else if has_arms {
+            write_line(out, indent + 1, "(do")?;
+        }
+        let indent = if has_arms { indent + 2 } else { indent + 1 };
+        self.frames.push(FoldFrame { operands: vec![], indent, has_arms });
+        Ok(())
     }
 
-    for (_, export) in wasm.export_section.exports.iter().enumerate() {
-        wat += &indent(wasm.export_to_wat(export), 1);
+    fn else_arm(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.folded {
+            return write_line(out, BODY_INDENT + site.depth, &site.text);
+        }
+        let frame = self.top()?;
+        Self::flush(out, frame)?;
+        write_line(out, frame.indent - 1, ")")?;
+        write_line(out, frame.indent - 1, &format!("({}", site.text))
     }
 
-    for (i, elem) in wasm.elem_section.elems.iter().enumerate() {
-        wat += &indent(wasm.elem_to_wat(i, elem), 1);
+    fn end_block(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if !self.folded {
+            return write_line(out, BODY_INDENT + site.depth, &site.text);
+        }
+        let mut frame = self.frames.pop().ok_or_else(|| WyrmError::invalid("end without a matching block"))?;
+        Self::flush(out, &mut frame)?;
+        if frame.has_arms {
+            write_line(out, frame.indent - 1, ")")?;
+            if site.info.instr == DELEGATE as u32 {
+                write_line(out, frame.indent - 1, &format!("({})", site.text))?;
+            }
+            write_line(out, frame.indent - 2, ")")
+        } else {
+            write_line(out, frame.indent - 1, ")")
+        }
     }
 
-    for (i, func) in wasm.code_section.functions.iter().enumerate() {
-        wat += &indent(wasm.func_to_wat(i, func), 1);
+    fn end_func(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>, _func: FuncContext<'_>, _site: &InstrSite<'_>) -> Result<(), WyrmError> {
+        if let Some(mut frame) = self.frames.pop() {
+            Self::flush(out, &mut frame)?;
+        }
+        write_line(out, 1, ")")
     }
 
-    for (_, data) in wasm.data_section.data_segs.iter().enumerate() {
-        wat += &indent(wasm.data_to_wat(data), 1);
+    fn end_module(&mut self, out: &mut dyn Write, _module: ModuleContext<'_>) -> Result<(), WyrmError> {
+        write!(out, ")")?;
+        Ok(())
     }
+}
 
-    wat += ")";
+pub fn emit_wat(wasm: &WasmFile) -> Result<String, WyrmError> {
+    emit_to_string(wasm, &mut WatEmitter::new(false))
+}
 
-    wat
+pub fn emit_folded_wat(wasm: &WasmFile) -> Result<String, WyrmError> {
+    emit_to_string(wasm, &mut WatEmitter::new(true))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::wasm_deserialize_bytes;
+    use crate::file_writer::wasm_serialize_bytes;
+    use crate::wat_parser::parse_wat;
+    use std::path::Path;
+
+    // Reassembling fills the name section in from the ids, and the text format has no data
+    // count section, so modules are compared without either
+    fn without_extras(mut wasm: WasmFile) -> Vec<u8> {
+        wasm.name_section = WasmNameSection::default();
+        wasm.custom_sections.clear();
+        wasm.data_count_section = None;
+        wasm_serialize_bytes(&wasm).unwrap()
+    }
+
+    // Each module in tests/golden is emitted and compared with the .wat beside it. The text then
+    // has to assemble, with the reference assembler, back to the module it came from.
+    #[test]
+    fn golden_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wasm"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let bytes = std::fs::read(&path).unwrap();
+            let wat = emit_wat(&wasm_deserialize_bytes(&bytes).unwrap()).unwrap();
+            let golden = path.with_extension("wat");
+            assert_eq!(wat, std::fs::read_to_string(&golden).unwrap(), "{} changed", golden.display());
+
+            let assembled = wat::parse_str(&wat).unwrap_or_else(|e| panic!("{}: {}", golden.display(), e));
+            let assembled = wasm_deserialize_bytes(&assembled).unwrap();
+            assert_eq!(without_extras(assembled), without_extras(wasm_deserialize_bytes(&bytes).unwrap()), "{} assembled differently", golden.display());
+
+            // The ids become names, so emitting again gives the same text
+            let reassembled = parse_wat(&wat).unwrap_or_else(|e| panic!("{}: {:?}", golden.display(), e));
+            assert_eq!(emit_wat(&reassembled).unwrap(), wat, "{} isn't stable", golden.display());
+        }
+    }
+
+    // Nesting only ever moves text around, so the folded form reassembles to the same module too
+    #[test]
+    fn folded_corpus_reassembles() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "wasm") {
+                continue;
+            }
+            let wasm = wasm_deserialize_bytes(&std::fs::read(&path).unwrap()).unwrap();
+            let wat = emit_folded_wat(&wasm).unwrap();
+            let reassembled = parse_wat(&wat).unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+            assert_eq!(without_extras(reassembled), without_extras(wasm), "{} reassembled differently", path.display());
+        }
+    }
+
+    #[test]
+    fn folds_operands_into_instructions() {
+        let wasm = parse_wat(
+            "(module
+              (func $f (param $a i32) (result i32)
+                local.get $a
+                i32.const 1
+                i32.add
+                local.set $a
+                local.get $a
+                if (result i32)
+                  local.get $a
+                else
+                  local.get $a
+                  call $f
+                end))",
+        )
+        .unwrap();
+        let wat = emit_folded_wat(&wasm).unwrap();
+        let body = "    (local.set $a (i32.add (local.get $a) (i32.const 1)))
+    (if $label0 (result i32)
+      (local.get $a)
+      (then
+        (local.get $a)
+      )
+      (else
+        (call $f (local.get $a))
+      )
+    )
+";
+        assert!(wat.contains(body), "{}", wat);
+    }
+}
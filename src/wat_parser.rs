@@ -0,0 +1,2015 @@
+// Reads the WebAssembly text format into the same WasmFile that wasm_deserialize
+// builds from a binary, with instructions in either the flat or the folded form.
+//
+// The text is split into tokens and grouped into S-expressions, which are read by
+// hand rather than with prs, whose grammars are over single symbols like bytes.
+// Fields can refer to each other before they are defined, so a first pass gives
+// every field its index and records its id, then a second pass builds the sections.
+// Ids become names in the name section, so emitting the result gives them back.
+
+use std::collections::HashMap;
+
+use crate::error::*;
+use crate::file_writer::encode_function;
+use crate::instr_table::*;
+use crate::wasm_model::*;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    // Keywords, numbers and anything else that isn't an id or a string
+    Atom(String),
+    // An identifier, without its `$`
+    Id(String),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug)]
+enum SExpr {
+    Token { token: Token, offset: usize },
+    // `end` is the offset of the closing parenthesis
+    List { items: Vec<SExpr>, offset: usize, end: usize },
+}
+
+impl SExpr {
+    fn offset(&self) -> usize {
+        match self {
+            SExpr::Token { offset, .. } | SExpr::List { offset, .. } => *offset,
+        }
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match self {
+            SExpr::Token { token: Token::Atom(word), .. } => Some(word),
+            _ => None,
+        }
+    }
+
+    // The keyword a list starts with, e.g. `func` for `(func ...)`
+    fn head(&self) -> Option<&str> {
+        match self {
+            SExpr::List { items, .. } => items.first().and_then(SExpr::atom),
+            _ => None,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        self.atom().is_some_and(|word| word.trim_start_matches(['+', '-']).starts_with(|c: char| c.is_ascii_digit()))
+    }
+
+    // Indices can be written as numbers or ids
+    fn is_index(&self) -> bool {
+        matches!(self, SExpr::Token { token: Token::Id(_), .. }) || self.is_number()
+    }
+}
+
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text.as_bytes()[..offset.min(text.len())];
+    let line_start = before.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+    (line, before.len() - line_start + 1)
+}
+
+fn at(offset: usize) -> ErrorLocation {
+    ErrorLocation { offset: Some(offset), ..Default::default() }
+}
+
+// Offsets are into the text, and the message also gives the line and column for people reading it
+fn text_error(text: &str, offset: usize, message: impl Into<String>) -> WyrmError {
+    let (line, column) = position(text, offset);
+    WyrmError::InvalidData(at(offset), format!("{} (line {}, column {})", message.into(), line, column))
+}
+
+fn text_unsupported(text: &str, offset: usize, message: impl Into<String>) -> WyrmError {
+    let (line, column) = position(text, offset);
+    WyrmError::Unsupported(at(offset), format!("{} (line {}, column {})", message.into(), line, column))
+}
+
+fn is_idchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-./:<=>?@\\^_`|~".contains(&c)
+}
+
+// Reads the string whose opening quote is at `start`, giving its bytes and the offset after it
+fn lex_string(text: &str, start: usize) -> Result<(Vec<u8>, usize), WyrmError> {
+    let bytes = text.as_bytes();
+    let unterminated = || text_error(text, start, "Unterminated string");
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut out = vec![];
+    let mut i = start + 1;
+    loop {
+        let c = *bytes.get(i).ok_or_else(unterminated)?;
+        i += 1;
+        match c {
+            b'"' => return Ok((out, i)),
+            b'\\' => {
+                let escape = *bytes.get(i).ok_or_else(unterminated)?;
+                i += 1;
+                match escape {
+                    b't' => out.push(b'\t'),
+                    b'n' => out.push(b'\n'),
+                    b'r' => out.push(b'\r'),
+                    b'"' | b'\'' | b'\\' => out.push(escape),
+                    b'u' => {
+                        // \u{hex}, written out as UTF-8
+                        let close = text[i..].find('}').map(|len| i + len);
+                        let code = match (bytes.get(i), close) {
+                            (Some(b'{'), Some(close)) => u32::from_str_radix(&text[i + 1..close], 16).ok().map(|code| (code, close)),
+                            _ => None,
+                        };
+                        let Some((c, close)) = code.and_then(|(code, close)| Some((char::from_u32(code)?, close))) else {
+                            return Err(text_error(text, i - 2, "Bad unicode escape"));
+                        };
+                        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        i = close + 1;
+                    }
+                    high => {
+                        let (Some(high), Some(low)) = (hex(high), bytes.get(i).and_then(|b| hex(*b))) else {
+                            return Err(text_error(text, i - 2, "Unknown escape in string"));
+                        };
+                        out.push((high * 16 + low) as u8);
+                        i += 1;
+                    }
+                }
+            }
+            0x00..=0x1f | 0x7f => return Err(text_error(text, i - 1, "Control character in string")),
+            _ => out.push(c),
+        }
+    }
+}
+
+fn lex(text: &str) -> Result<Vec<(Token, usize)>, WyrmError> {
+    let bytes = text.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b' ' | b'\t' | b'\n' | b'\r' => i += 1,
+            b';' if bytes.get(i + 1) == Some(&b';') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' if bytes.get(i + 1) == Some(&b';') => {
+                // Block comments nest
+                let mut depth = 0;
+                loop {
+                    match (bytes.get(i), bytes.get(i + 1)) {
+                        (Some(b'('), Some(b';')) => {
+                            depth += 1;
+                            i += 2;
+                        }
+                        (Some(b';'), Some(b')')) => {
+                            depth -= 1;
+                            i += 2;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        (Some(_), _) => i += 1,
+                        (None, _) => return Err(text_error(text, start, "Unterminated block comment")),
+                    }
+                }
+            }
+            b'(' => {
+                tokens.push((Token::LParen, start));
+                i += 1;
+            }
+            b')' => {
+                tokens.push((Token::RParen, start));
+                i += 1;
+            }
+            b'"' => {
+                let (string, end) = lex_string(text, start)?;
+                tokens.push((Token::Str(string), start));
+                i = end;
+            }
+            c if is_idchar(c) => {
+                while i < bytes.len() && is_idchar(bytes[i]) {
+                    i += 1;
+                }
+                let word = &text[start..i];
+                let token = match word.strip_prefix('$') {
+                    Some("") => return Err(text_error(text, start, "Empty identifier")),
+                    Some(id) => Token::Id(id.to_string()),
+                    None => Token::Atom(word.to_string()),
+                };
+                tokens.push((token, start));
+            }
+            _ => {
+                let c = text[i..].chars().next().unwrap_or_default();
+                return Err(text_error(text, start, format!("Unexpected character {:?}", c)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn group(text: &str, tokens: Vec<(Token, usize)>) -> Result<Vec<SExpr>, WyrmError> {
+    let mut top = vec![];
+    // Lists that are still open, with the offset they started at
+    let mut open: Vec<(Vec<SExpr>, usize)> = vec![];
+    for (token, offset) in tokens {
+        match token {
+            Token::LParen => open.push((vec![], offset)),
+            Token::RParen => {
+                let Some((items, start)) = open.pop() else {
+                    return Err(text_error(text, offset, "Unmatched `)`"));
+                };
+                let list = SExpr::List { items, offset: start, end: offset };
+                match open.last_mut() {
+                    Some((items, _)) => items.push(list),
+                    None => top.push(list),
+                }
+            }
+            token => {
+                let token = SExpr::Token { token, offset };
+                match open.last_mut() {
+                    Some((items, _)) => items.push(token),
+                    None => top.push(token),
+                }
+            }
+        }
+    }
+    match open.last() {
+        Some((_, start)) => Err(text_error(text, *start, "Unclosed `(`")),
+        None => Ok(top),
+    }
+}
+
+// Digits in the given radix, with optional `_` separators between them
+fn parse_digits(digits: &str, radix: u32) -> Option<u64> {
+    if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return None;
+    }
+    digits.chars().filter(|c| *c != '_').try_fold(0u64, |value, c| {
+        value.checked_mul(radix as u64)?.checked_add(c.to_digit(radix)? as u64)
+    })
+}
+
+fn parse_nat(word: &str) -> Option<u64> {
+    match word.strip_prefix("0x") {
+        Some(hex) => parse_digits(hex, 16),
+        None => parse_digits(word, 10),
+    }
+}
+
+fn strip_sign(word: &str) -> (bool, &str) {
+    match word.as_bytes().first() {
+        Some(b'-') => (true, &word[1..]),
+        Some(b'+') => (false, &word[1..]),
+        _ => (false, word),
+    }
+}
+
+// An integer that fits in `bits` bits, either signed or unsigned, given as its two's complement bits
+fn parse_int_bits(word: &str, bits: u32) -> Option<u64> {
+    let (negative, digits) = strip_sign(word);
+    let magnitude = parse_nat(digits)?;
+    let mask = u64::MAX >> (64 - bits);
+    if negative {
+        (magnitude <= 1 << (bits - 1)).then_some(magnitude.wrapping_neg() & mask)
+    } else {
+        (magnitude <= mask).then_some(magnitude)
+    }
+}
+
+// How f32 or f64 values are laid out
+struct FloatFormat {
+    bits: u32,
+    mantissa_bits: u32,
+    bias: i64,
+}
+
+const F32_FORMAT: FloatFormat = FloatFormat { bits: 32, mantissa_bits: 23, bias: 127 };
+const F64_FORMAT: FloatFormat = FloatFormat { bits: 64, mantissa_bits: 52, bias: 1023 };
+
+impl FloatFormat {
+    fn infinity(&self) -> u64 {
+        ((2 * self.bias + 1) as u64) << self.mantissa_bits
+    }
+
+    // Rounds mantissa * 2^exponent to the nearest value, ties to even, giving its bits without
+    // the sign. `sticky` says if nonzero digits were dropped from below the mantissa.
+    fn round(&self, mantissa: u64, exponent: i64, sticky: bool) -> u64 {
+        if mantissa == 0 {
+            return 0;
+        }
+        let len = 64 - mantissa.leading_zeros() as i64;
+        // The exponent of the leading bit
+        let mut exp = exponent + len - 1;
+        let min_exp = 1 - self.bias;
+        // Subnormals have fewer bits of precision
+        let precision = self.mantissa_bits as i64 + 1 - (min_exp - exp).max(0);
+        let shift = len - precision;
+        let mut kept = if shift <= 0 {
+            mantissa << -shift
+        } else if shift > 64 {
+            0
+        } else {
+            let kept = mantissa.checked_shr(shift as u32).unwrap_or(0);
+            let rest = mantissa & (u64::MAX >> (64 - shift));
+            let half = 1 << (shift - 1);
+            if rest > half || (rest == half && (sticky || kept & 1 == 1)) {
+                kept + 1
+            } else {
+                kept
+            }
+        };
+
+        // A subnormal that rounded up to the smallest normal value is encoded the same way
+        if exp < min_exp {
+            return kept;
+        }
+        if kept >> (self.mantissa_bits + 1) != 0 {
+            kept >>= 1;
+            exp += 1;
+        }
+        let biased = exp + self.bias;
+        if biased > 2 * self.bias {
+            return self.infinity();
+        }
+        (biased as u64) << self.mantissa_bits | (kept & ((1 << self.mantissa_bits) - 1))
+    }
+
+    // e.g. 0x1.8p3, with the `0x` already removed
+    fn parse_hex(&self, hex: &str) -> Option<u64> {
+        let (digits, exponent) = match hex.split_once(['p', 'P']) {
+            Some((digits, exponent)) => {
+                let (negative, exponent) = strip_sign(exponent);
+                let exponent = parse_digits(exponent, 10)?.min(1 << 20) as i64;
+                (digits, if negative { -exponent } else { exponent })
+            }
+            None => (hex, 0),
+        };
+        let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+        parse_digits(int_part, 16)?;
+        if !frac_part.is_empty() {
+            parse_digits(frac_part, 16)?;
+        }
+
+        let mut mantissa = 0u64;
+        let mut exponent = exponent;
+        let mut sticky = false;
+        let int_digits = int_part.chars().map(|c| (c, false));
+        let frac_digits = frac_part.chars().map(|c| (c, true));
+        for (c, fractional) in int_digits.chain(frac_digits).filter(|(c, _)| *c != '_') {
+            let digit = c.to_digit(16)? as u64;
+            if mantissa >> 60 == 0 {
+                mantissa = mantissa * 16 + digit;
+                if fractional {
+                    exponent -= 4;
+                }
+            } else {
+                sticky |= digit != 0;
+                if !fractional {
+                    exponent += 4;
+                }
+            }
+        }
+        Some(self.round(mantissa, exponent, sticky))
+    }
+
+    // Gives the bits of a float literal. `decimal` parses plain decimal numbers, which std already
+    // rounds correctly.
+    fn parse(&self, word: &str, decimal: impl Fn(&str) -> Option<u64>) -> Option<u64> {
+        let (negative, body) = strip_sign(word);
+        let magnitude = if body == "inf" {
+            self.infinity()
+        } else if body == "nan" {
+            self.infinity() | 1 << (self.mantissa_bits - 1)
+        } else if let Some(payload) = body.strip_prefix("nan:0x") {
+            let payload = parse_digits(payload, 16)?;
+            if payload == 0 || payload >> self.mantissa_bits != 0 {
+                return None;
+            }
+            self.infinity() | payload
+        } else if let Some(hex) = body.strip_prefix("0x") {
+            self.parse_hex(hex)?
+        } else {
+            // Underscores can only go between digits
+            let valid = body.starts_with(|c: char| c.is_ascii_digit())
+                && body.chars().all(|c| c.is_ascii_digit() || "._eE+-".contains(c))
+                && body.split(['.', 'e', 'E', '+', '-']).all(|digits| !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__"));
+            if !valid {
+                return None;
+            }
+            decimal(&body.replace('_', ""))?
+        };
+        Some((negative as u64) << (self.bits - 1) | magnitude)
+    }
+}
+
+// Abstract heap types, their encoding, and the nullable reference type that abbreviates them
+const HEAP_TYPES: [(&str, i64, &str); 12] = [
+    ("func", -0x10, "funcref"),
+    ("extern", -0x11, "externref"),
+    ("any", -0x12, "anyref"),
+    ("eq", -0x13, "eqref"),
+    ("i31", -0x14, "i31ref"),
+    ("struct", -0x15, "structref"),
+    ("array", -0x16, "arrayref"),
+    ("exn", -0x17, "exnref"),
+    ("none", -0x0f, "nullref"),
+    ("nofunc", -0x0d, "nullfuncref"),
+    ("noextern", -0x0e, "nullexternref"),
+    ("noexn", -0x0c, "nullexnref"),
+];
+
+fn number_type(word: &str) -> Option<u8> {
+    match word {
+        "i32" => Some(0x7f),
+        "i64" => Some(0x7e),
+        "f32" => Some(0x7d),
+        "f64" => Some(0x7c),
+        "v128" => Some(0x7b),
+        _ => None,
+    }
+}
+
+// The items of a list, read from front to back
+#[derive(Clone, Copy)]
+struct Cursor<'a> {
+    items: &'a [SExpr],
+    pos: usize,
+    // Where the list ends, for errors about something missing from it
+    end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(items: &'a [SExpr], end: usize) -> Self {
+        Self { items, pos: 0, end }
+    }
+
+    // A cursor over the items of a list, after its keyword
+    fn of_list(list: &'a SExpr) -> Option<Self> {
+        match list {
+            SExpr::List { items, end, .. } => Some(Self { items, pos: 1, end: *end }),
+            _ => None,
+        }
+    }
+
+    fn peek(&self) -> Option<&'a SExpr> {
+        self.items.get(self.pos)
+    }
+
+    fn next_item(&mut self) -> Option<&'a SExpr> {
+        let item = self.peek();
+        if item.is_some() {
+            self.pos += 1;
+        }
+        item
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.items.len()
+    }
+
+    fn offset(&self) -> usize {
+        self.peek().map_or(self.end, SExpr::offset)
+    }
+
+    fn peek_atom(&self) -> Option<&'a str> {
+        self.peek().and_then(SExpr::atom)
+    }
+
+    fn peek_head(&self) -> Option<&'a str> {
+        self.peek().and_then(SExpr::head)
+    }
+
+    fn peek_index(&self) -> bool {
+        self.peek().is_some_and(SExpr::is_index)
+    }
+
+    fn peek_number(&self) -> bool {
+        self.peek().is_some_and(SExpr::is_number)
+    }
+
+    fn take_atom(&mut self, word: &str) -> bool {
+        let found = self.peek_atom() == Some(word);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn take_id(&mut self) -> Option<&'a str> {
+        match self.peek() {
+            Some(SExpr::Token { token: Token::Id(id), .. }) => {
+                self.pos += 1;
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+
+    // Takes the next item if it's a list starting with `head`, giving a cursor over the rest of it
+    fn take_list(&mut self, head: &str) -> Option<Cursor<'a>> {
+        if self.peek_head() != Some(head) {
+            return None;
+        }
+        self.next_item().and_then(Cursor::of_list)
+    }
+}
+
+// The ids and number of entries of an index space
+struct IdSpace {
+    // What the space holds, for errors
+    kind: &'static str,
+    ids: HashMap<String, u32>,
+    len: u32,
+    num_defined: usize,
+    // How many entries the second pass has built so far
+    built: u32,
+}
+
+impl IdSpace {
+    fn new(kind: &'static str) -> Self {
+        Self { kind, ids: HashMap::new(), len: 0, num_defined: 0, built: 0 }
+    }
+
+    // Imports have to come before definitions, as they take the first indices in the binary
+    fn push(&mut self, text: &str, offset: usize, id: Option<&str>, imported: bool) -> Result<u32, WyrmError> {
+        if imported && self.num_defined != 0 {
+            return Err(text_error(text, offset, format!("Imported {} after a defined one", self.kind)));
+        }
+        let idx = self.len;
+        if let Some(id) = id {
+            if self.ids.insert(id.to_string(), idx).is_some() {
+                return Err(text_error(text, offset, format!("Duplicate {} ${}", self.kind, id)));
+            }
+        }
+        self.len += 1;
+        self.num_defined += !imported as usize;
+        Ok(idx)
+    }
+
+    fn next_built(&mut self) -> u32 {
+        self.built += 1;
+        self.built - 1
+    }
+
+    fn resolve(&self, text: &str, cursor: &mut Cursor<'_>) -> Result<u32, WyrmError> {
+        let offset = cursor.offset();
+        match cursor.next_item() {
+            Some(SExpr::Token { token: Token::Id(id), .. }) => self
+                .ids
+                .get(id)
+                .copied()
+                .ok_or_else(|| text_error(text, offset, format!("Unknown {} ${}", self.kind, id))),
+            Some(item) if item.is_number() => item
+                .atom()
+                .and_then(parse_nat)
+                .and_then(|idx| u32::try_from(idx).ok())
+                .ok_or_else(|| text_error(text, offset, format!("Bad {} index", self.kind))),
+            _ => Err(text_error(text, offset, format!("Expected a {} index", self.kind))),
+        }
+    }
+}
+
+// What instructions refer to inside a function: its locals, and the blocks they are in
+struct FuncContext {
+    // The funcidx, unless this is a constant expression
+    func: Option<u32>,
+    locals: IdSpace,
+    // Innermost last
+    labels: Vec<Option<String>>,
+    num_labels: u32,
+}
+
+impl FuncContext {
+    fn new(func: Option<u32>) -> Self {
+        Self { func, locals: IdSpace::new("local"), labels: vec![], num_labels: 0 }
+    }
+}
+
+struct WatParser<'a> {
+    text: &'a str,
+    type_ids: IdSpace,
+    func_ids: IdSpace,
+    table_ids: IdSpace,
+    memory_ids: IdSpace,
+    global_ids: IdSpace,
+    elem_ids: IdSpace,
+    data_ids: IdSpace,
+    tag_ids: IdSpace,
+    types: Vec<WasmFunctionType>,
+    imports: Vec<WasmImportHeader>,
+    function_signature_indexes: Vec<u32>,
+    tables: Vec<WasmTable>,
+    memories: Vec<WasmMemoryStruct>,
+    globals: Vec<WasmGlobal>,
+    exports: Vec<WasmExportHeader>,
+    start: Option<u32>,
+    elems: Vec<WasmElem>,
+    tags: Vec<WasmTag>,
+    functions: Vec<WasmFunction>,
+    data_segs: Vec<WasmDataSeg>,
+    names: WasmNameSection,
+    // Set by instructions that need the data count section
+    uses_data_count: bool,
+}
+
+// Params, the ids given to them, and results
+type Signature<'a> = (Vec<u8>, Vec<Option<&'a str>>, Vec<u8>);
+// The module and field an import is from
+type ImportNames<'a> = (&'a str, &'a str);
+
+fn func_type(params: Vec<u8>, results: Vec<u8>) -> WasmFunctionType {
+    WasmFunctionType {
+        func: 0x60,
+        num_params: params.len(),
+        params: params.into_iter().map(|_type| WasmTypeAnnotation { _type }).collect(),
+        num_results: results.len(),
+        results: results.into_iter().map(|_type| WasmTypeAnnotation { _type }).collect(),
+    }
+}
+
+fn type_bytes(types: &[WasmTypeAnnotation]) -> Vec<u8> {
+    types.iter().map(|t| t._type).collect()
+}
+
+fn end_seg() -> ExprSeg {
+    ExprSeg::Operation(INSTRS[END as usize])
+}
+
+// `i32.const 0`, or `i64.const 0` for 64-bit tables and memories
+fn zero_offset(is_64: bool) -> WasmExpr {
+    let constant = if is_64 { I64_CONST } else { I32_CONST };
+    WasmExpr::from(vec![
+        ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[constant as usize]), ExprSeg::Int(0)]),
+        end_seg(),
+    ])
+}
+
+fn ref_func(func: u32) -> WasmExpr {
+    WasmExpr::from(vec![
+        ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[REF_FUNC as usize]), ExprSeg::Func(func)]),
+        end_seg(),
+    ])
+}
+
+fn is_branch(info: &InstrInfo) -> bool {
+    info.prefix == 0 && [BR, BR_IF, BR_ON_NULL, BR_ON_NON_NULL, RETHROW].contains(&(info.instr as u64))
+}
+
+impl<'a> WatParser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            type_ids: IdSpace::new("type"),
+            func_ids: IdSpace::new("func"),
+            table_ids: IdSpace::new("table"),
+            memory_ids: IdSpace::new("memory"),
+            global_ids: IdSpace::new("global"),
+            elem_ids: IdSpace::new("elem"),
+            data_ids: IdSpace::new("data"),
+            tag_ids: IdSpace::new("tag"),
+            types: vec![],
+            imports: vec![],
+            function_signature_indexes: vec![],
+            tables: vec![],
+            memories: vec![],
+            globals: vec![],
+            exports: vec![],
+            start: None,
+            elems: vec![],
+            tags: vec![],
+            functions: vec![],
+            data_segs: vec![],
+            names: WasmNameSection::default(),
+            uses_data_count: false,
+        }
+    }
+
+    fn error(&self, offset: usize, message: impl Into<String>) -> WyrmError {
+        text_error(self.text, offset, message)
+    }
+
+    fn unsupported(&self, offset: usize, message: impl Into<String>) -> WyrmError {
+        text_unsupported(self.text, offset, message)
+    }
+
+    fn expect_end(&self, cursor: &Cursor<'_>) -> Result<(), WyrmError> {
+        match cursor.peek() {
+            Some(item) => Err(self.error(item.offset(), "Unexpected item")),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_string(&self, cursor: &mut Cursor<'a>) -> Result<&'a [u8], WyrmError> {
+        let offset = cursor.offset();
+        match cursor.next_item() {
+            Some(SExpr::Token { token: Token::Str(string), .. }) => Ok(string),
+            _ => Err(self.error(offset, "Expected a string")),
+        }
+    }
+
+    // Import and export names, which have to be UTF-8
+    fn parse_name(&self, cursor: &mut Cursor<'a>) -> Result<&'a str, WyrmError> {
+        let offset = cursor.offset();
+        std::str::from_utf8(self.parse_string(cursor)?).map_err(|_| self.error(offset, "Name isn't valid UTF-8"))
+    }
+
+    fn parse_word(&self, cursor: &mut Cursor<'a>, what: &str) -> Result<(&'a str, usize), WyrmError> {
+        let offset = cursor.offset();
+        match cursor.next_item().and_then(SExpr::atom) {
+            Some(word) => Ok((word, offset)),
+            None => Err(self.error(offset, format!("Expected {}", what))),
+        }
+    }
+
+    fn parse_int(&self, cursor: &mut Cursor<'a>, bits: u32) -> Result<u64, WyrmError> {
+        let (word, offset) = self.parse_word(cursor, "an integer")?;
+        parse_int_bits(word, bits).ok_or_else(|| self.error(offset, format!("Bad i{} literal {}", bits, word)))
+    }
+
+    fn parse_u64(&self, cursor: &mut Cursor<'a>) -> Result<u64, WyrmError> {
+        let (word, offset) = self.parse_word(cursor, "a number")?;
+        parse_nat(word).ok_or_else(|| self.error(offset, format!("Bad number {}", word)))
+    }
+
+    fn parse_f32(&self, cursor: &mut Cursor<'a>) -> Result<f32, WyrmError> {
+        let (word, offset) = self.parse_word(cursor, "a float")?;
+        F32_FORMAT
+            .parse(word, |decimal| decimal.parse::<f32>().ok().map(|f| f.to_bits() as u64))
+            .map(|bits| f32::from_bits(bits as u32))
+            .ok_or_else(|| self.error(offset, format!("Bad f32 literal {}", word)))
+    }
+
+    fn parse_f64(&self, cursor: &mut Cursor<'a>) -> Result<f64, WyrmError> {
+        let (word, offset) = self.parse_word(cursor, "a float")?;
+        F64_FORMAT
+            .parse(word, |decimal| decimal.parse::<f64>().ok().map(f64::to_bits))
+            .map(f64::from_bits)
+            .ok_or_else(|| self.error(offset, format!("Bad f64 literal {}", word)))
+    }
+
+    fn parse_heap_type(&self, cursor: &mut Cursor<'a>) -> Result<i64, WyrmError> {
+        if let Some(word) = cursor.peek_atom() {
+            if let Some((_, heap, _)) = HEAP_TYPES.iter().find(|(name, _, _)| *name == word) {
+                cursor.next_item();
+                return Ok(*heap);
+            }
+        }
+        Ok(self.type_ids.resolve(self.text, cursor)? as i64)
+    }
+
+    // `(ref null? heaptype)`, or an abbreviation like funcref. Gives whether it's nullable and the heap type.
+    fn parse_ref_type(&self, cursor: &mut Cursor<'a>) -> Result<(bool, i64), WyrmError> {
+        let offset = cursor.offset();
+        if let Some(mut ref_type) = cursor.take_list("ref") {
+            let nullable = ref_type.take_atom("null");
+            let heap = self.parse_heap_type(&mut ref_type)?;
+            self.expect_end(&ref_type)?;
+            return Ok((nullable, heap));
+        }
+        let word = cursor.peek_atom().unwrap_or_default();
+        match HEAP_TYPES.iter().find(|(_, _, name)| *name == word) {
+            Some((_, heap, _)) => {
+                cursor.next_item();
+                Ok((true, *heap))
+            }
+            None => Err(self.error(offset, "Expected a reference type")),
+        }
+    }
+
+    fn is_ref_type(cursor: &Cursor<'_>) -> bool {
+        cursor.peek_head() == Some("ref") || cursor.peek_atom().is_some_and(|word| HEAP_TYPES.iter().any(|(_, _, name)| *name == word))
+    }
+
+    // Value types are kept as their single byte encoding, so only nullable abstract references fit
+    fn parse_value_type(&self, cursor: &mut Cursor<'a>) -> Result<u8, WyrmError> {
+        let offset = cursor.offset();
+        if let Some(byte) = cursor.peek_atom().and_then(number_type) {
+            cursor.next_item();
+            return Ok(byte);
+        }
+        if !Self::is_ref_type(cursor) {
+            return Err(self.error(offset, "Expected a value type"));
+        }
+        match self.parse_ref_type(cursor)? {
+            (true, heap) if heap < 0 => Ok((heap + 0x80) as u8),
+            _ => Err(self.unsupported(offset, "Reference types that need more than one byte")),
+        }
+    }
+
+    fn parse_elem_ref_type(&self, cursor: &mut Cursor<'a>) -> Result<WasmRefType, WyrmError> {
+        let offset = cursor.offset();
+        let byte = self.parse_value_type(cursor)?;
+        byte_to_reftype(byte).map_err(|_| self.unsupported(offset, "Element segments that aren't funcref or externref"))
+    }
+
+    // `(param $id? t*)*` and `(result t*)*`, giving the param ids as well
+    fn parse_signature(&self, cursor: &mut Cursor<'a>) -> Result<Signature<'a>, WyrmError> {
+        let (mut params, mut param_ids, mut results) = (vec![], vec![], vec![]);
+        while let Some(mut param) = cursor.take_list("param") {
+            if let Some(id) = param.take_id() {
+                params.push(self.parse_value_type(&mut param)?);
+                param_ids.push(Some(id));
+                self.expect_end(&param)?;
+            }
+            while !param.is_empty() {
+                params.push(self.parse_value_type(&mut param)?);
+                param_ids.push(None);
+            }
+        }
+        while let Some(mut result) = cursor.take_list("result") {
+            while !result.is_empty() {
+                results.push(self.parse_value_type(&mut result)?);
+            }
+        }
+        Ok((params, param_ids, results))
+    }
+
+    // The first type with this signature, which is added after the others if there isn't one
+    fn type_index(&mut self, params: Vec<u8>, results: Vec<u8>) -> u32 {
+        let existing = self.types.iter().position(|t| type_bytes(&t.params) == params && type_bytes(&t.results) == results);
+        let idx = existing.unwrap_or_else(|| {
+            self.types.push(func_type(params, results));
+            self.types.len() - 1
+        });
+        idx as u32
+    }
+
+    // `(type x)?` followed by a signature, either of which can be left out. Gives the type
+    // index, and the ids of any params written out.
+    fn parse_type_use(&mut self, cursor: &mut Cursor<'a>) -> Result<(u32, Vec<Option<&'a str>>), WyrmError> {
+        let offset = cursor.offset();
+        let explicit = match cursor.take_list("type") {
+            Some(mut type_use) => {
+                let idx = self.type_ids.resolve(self.text, &mut type_use)?;
+                self.expect_end(&type_use)?;
+                Some(idx)
+            }
+            None => None,
+        };
+        let (params, param_ids, results) = self.parse_signature(cursor)?;
+        let Some(idx) = explicit else {
+            return Ok((self.type_index(params, results), param_ids));
+        };
+        let Some(sig) = self.types.get(idx as usize) else {
+            return Err(self.error(offset, format!("Unknown type {}", idx)));
+        };
+        let written = !params.is_empty() || !results.is_empty();
+        if written && (type_bytes(&sig.params) != params || type_bytes(&sig.results) != results) {
+            return Err(self.error(offset, "Signature doesn't match its type"));
+        }
+        Ok((idx, param_ids))
+    }
+
+    // A block with no result or a single result is encoded on its own, anything else needs a type
+    fn parse_block_type(&mut self, cursor: &mut Cursor<'a>) -> Result<i64, WyrmError> {
+        if cursor.peek_head() == Some("type") {
+            return Ok(self.parse_type_use(cursor)?.0 as i64);
+        }
+        let (params, _, results) = self.parse_signature(cursor)?;
+        Ok(match (params.is_empty(), results.as_slice()) {
+            (true, []) => -0x40,
+            (true, [result]) => *result as i64 - 0x80,
+            _ => self.type_index(params, results) as i64,
+        })
+    }
+
+    fn parse_limits(&self, cursor: &mut Cursor<'a>, is_64: bool) -> Result<Limits, WyrmError> {
+        let offset = cursor.offset();
+        let initial = self.parse_u64(cursor)?;
+        let max = if cursor.peek_number() { Some(self.parse_u64(cursor)?) } else { None };
+        if !is_64 && initial.max(max.unwrap_or(0)) > u32::MAX as u64 {
+            return Err(self.error(offset, "Limits are too large for 32 bits"));
+        }
+        Ok(Limits { initial, max, shared: false, is_64 })
+    }
+
+    fn parse_table_type(&self, cursor: &mut Cursor<'a>) -> Result<WasmTable, WyrmError> {
+        let is_64 = cursor.take_atom("i64");
+        let limits = self.parse_limits(cursor, is_64)?;
+        let wasm_type = self.parse_value_type(cursor)?;
+        Ok(WasmTable { wasm_type, limits })
+    }
+
+    fn parse_memory_type(&self, cursor: &mut Cursor<'a>) -> Result<WasmMemoryStruct, WyrmError> {
+        let is_64 = cursor.take_atom("i64");
+        let mut limits = self.parse_limits(cursor, is_64)?;
+        limits.shared = cursor.take_atom("shared");
+        Ok(WasmMemoryStruct { limits })
+    }
+
+    // `t` or `(mut t)`
+    fn parse_global_type(&self, cursor: &mut Cursor<'a>) -> Result<(WasmTypeAnnotation, u8), WyrmError> {
+        match cursor.take_list("mut") {
+            Some(mut mutable) => {
+                let _type = self.parse_value_type(&mut mutable)?;
+                self.expect_end(&mutable)?;
+                Ok((WasmTypeAnnotation { _type }, 1))
+            }
+            None => Ok((WasmTypeAnnotation { _type: self.parse_value_type(cursor)? }, 0)),
+        }
+    }
+
+    fn parse_label(&self, cursor: &mut Cursor<'a>, ctx: &FuncContext) -> Result<u32, WyrmError> {
+        let offset = cursor.offset();
+        match cursor.peek() {
+            Some(SExpr::Token { token: Token::Id(id), .. }) => {
+                cursor.next_item();
+                ctx.labels
+                    .iter()
+                    .rev()
+                    .position(|label| label.as_deref() == Some(id.as_str()))
+                    .map(|depth| depth as u32)
+                    .ok_or_else(|| self.error(offset, format!("Unknown label ${}", id)))
+            }
+            _ => Ok(self.parse_int(cursor, 32)? as u32),
+        }
+    }
+
+    // After an `end` or `else`, a block's label can be repeated
+    fn check_label(&self, cursor: &mut Cursor<'a>, label: Option<&str>) -> Result<(), WyrmError> {
+        let offset = cursor.offset();
+        match cursor.take_id() {
+            Some(id) if Some(id) != label => Err(self.error(offset, format!("Label ${} doesn't match its block", id))),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_label(&mut self, ctx: &mut FuncContext, label: Option<&str>) {
+        if let (Some(func), Some(label)) = (ctx.func, label) {
+            self.names.label_names.entry(func).or_default().insert(ctx.num_labels, label.to_string());
+        }
+        ctx.labels.push(label.map(str::to_string));
+        ctx.num_labels += 1;
+    }
+
+    fn instr_info(&self, name: &str, offset: usize) -> Result<InstrInfo, WyrmError> {
+        get_instr(name).ok_or_else(|| self.error(offset, format!("Unknown instruction {}", name)))
+    }
+
+    // `$memory? offset=N? align=N?`. With a lane index after it, a lone number is the lane.
+    fn parse_memarg(&self, info: &InstrInfo, cursor: &mut Cursor<'a>) -> Result<MemArg, WyrmError> {
+        let lane_follows = |cursor: &Cursor<'_>| {
+            let mut after = *cursor;
+            after.next_item();
+            while after.peek_atom().is_some_and(|word| word.starts_with("offset=") || word.starts_with("align=")) {
+                after.next_item();
+            }
+            after.peek_number()
+        };
+        let takes_lane = info.constants.contains(&Prim::Lane);
+        let has_memory = match cursor.peek() {
+            Some(item) if item.is_number() => !takes_lane || lane_follows(cursor),
+            Some(item) => item.is_index(),
+            None => false,
+        };
+        let memory = if has_memory { self.memory_ids.resolve(self.text, cursor)? } else { 0 };
+
+        let mut memarg = MemArg { align: info.natural_align.unwrap_or(0) as u32, offset: 0, memory };
+        let offset = cursor.offset();
+        if let Some(value) = cursor.peek_atom().and_then(|word| word.strip_prefix("offset=")) {
+            memarg.offset = parse_nat(value).ok_or_else(|| self.error(offset, "Bad offset"))?;
+            cursor.next_item();
+        }
+        let offset = cursor.offset();
+        if let Some(value) = cursor.peek_atom().and_then(|word| word.strip_prefix("align=")) {
+            let align = parse_nat(value).filter(|align| align.is_power_of_two());
+            memarg.align = align.ok_or_else(|| self.error(offset, "Alignment has to be a power of two"))?.trailing_zeros();
+            cursor.next_item();
+        }
+        Ok(memarg)
+    }
+
+    fn parse_v128(&self, cursor: &mut Cursor<'a>) -> Result<u128, WyrmError> {
+        let (shape, offset) = self.parse_word(cursor, "a v128 shape")?;
+        let (lanes, bits) = match shape {
+            "i8x16" => (16, 8),
+            "i16x8" => (8, 16),
+            "i32x4" | "f32x4" => (4, 32),
+            "i64x2" | "f64x2" => (2, 64),
+            _ => return Err(self.error(offset, format!("Unknown v128 shape {}", shape))),
+        };
+        let mut value = 0u128;
+        for lane in 0..lanes {
+            let lane_bits = match shape {
+                "f32x4" => self.parse_f32(cursor)?.to_bits() as u64,
+                "f64x2" => self.parse_f64(cursor)?.to_bits(),
+                _ => self.parse_int(cursor, bits)?,
+            };
+            value |= (lane_bits as u128) << (lane * bits);
+        }
+        Ok(value)
+    }
+
+    // One immediate, as the segment read_expr would give for it
+    fn parse_immediate(&mut self, constant: Prim, info: &InstrInfo, cursor: &mut Cursor<'a>, ctx: &FuncContext) -> Result<Option<ExprSeg>, WyrmError> {
+        let text = self.text;
+        let optional = |space: &IdSpace, cursor: &mut Cursor<'a>| {
+            if cursor.peek_index() { space.resolve(text, cursor) } else { Ok(0) }
+        };
+        Ok(Some(match constant {
+            Prim::Void => return Ok(None),
+            Prim::I32 if is_branch(info) => ExprSeg::Int(self.parse_label(cursor, ctx)? as i64),
+            Prim::I64 => ExprSeg::Int(self.parse_int(cursor, 64)? as i64),
+            Prim::F32 => ExprSeg::Float32(self.parse_f32(cursor)?),
+            Prim::F64 => ExprSeg::Float64(self.parse_f64(cursor)?),
+            Prim::Local => ExprSeg::Local(ctx.locals.resolve(text, cursor)?),
+            Prim::Global => ExprSeg::Global(self.global_ids.resolve(text, cursor)?),
+            Prim::Func => ExprSeg::Func(self.func_ids.resolve(text, cursor)?),
+            Prim::Table => ExprSeg::Table(optional(&self.table_ids, cursor)?),
+            Prim::Memory => ExprSeg::Memory(optional(&self.memory_ids, cursor)?),
+            Prim::Type => ExprSeg::Type(self.type_ids.resolve(text, cursor)?),
+            Prim::Data => {
+                self.uses_data_count = true;
+                ExprSeg::Data(self.data_ids.resolve(text, cursor)?)
+            }
+            Prim::Elem => ExprSeg::Elem(self.elem_ids.resolve(text, cursor)?),
+            Prim::Tag => ExprSeg::Tag(self.tag_ids.resolve(text, cursor)?),
+            Prim::V128 => ExprSeg::V128(self.parse_v128(cursor)?),
+            Prim::Lane => ExprSeg::Int(self.parse_int(cursor, 8)? as i64),
+            Prim::HeapType => ExprSeg::HeapType(self.parse_heap_type(cursor)?),
+            Prim::RefType | Prim::NullRefType => {
+                let (nullable, heap) = self.parse_ref_type(cursor)?;
+                ExprSeg::RefType { nullable, heap }
+            }
+            Prim::MemArg => ExprSeg::MemArg(self.parse_memarg(info, cursor)?),
+            Prim::BlockType => ExprSeg::BlockType(self.parse_block_type(cursor)?),
+            // i32.const, and the field and length immediates of GC instructions
+            _ => ExprSeg::Int(self.parse_int(cursor, 32)? as u32 as i32 as i64),
+        }))
+    }
+
+    // An instruction that isn't a block, along with its immediates
+    fn parse_plain(&mut self, info: InstrInfo, offset: usize, cursor: &mut Cursor<'a>, ctx: &FuncContext) -> Result<Vec<ExprSeg>, WyrmError> {
+        // `select (result t)` is its own instruction
+        if info.prefix == 0 && info.instr == SELECT as u32 && cursor.peek_head() == Some("result") {
+            let mut layout = vec![ExprSeg::Operation(INSTRS[SELECT_1 as usize])];
+            while let Some(mut result) = cursor.take_list("result") {
+                while !result.is_empty() {
+                    layout.push(ExprSeg::Int(self.parse_value_type(&mut result)? as i64));
+                }
+            }
+            return Ok(layout);
+        }
+
+        let mut layout = vec![ExprSeg::Operation(info)];
+        match get_edge_case(info) {
+            SpecialInstr::BeginBlock | SpecialInstr::EndBlock => {
+                return Err(self.error(offset, format!("`{}` can't be used here", info.name)));
+            }
+            SpecialInstr::BrTable => {
+                let mut break_depths = vec![];
+                while cursor.peek_index() {
+                    break_depths.push(self.parse_label(cursor, ctx)?);
+                }
+                let Some(default) = break_depths.pop() else {
+                    return Err(self.error(cursor.offset(), "br_table needs a default label"));
+                };
+                layout.push(ExprSeg::BrTable(BrTableConst { break_depths, default }));
+                return Ok(layout);
+            }
+            SpecialInstr::CallIndirect => {
+                let table = if cursor.peek_index() { self.table_ids.resolve(self.text, cursor)? } else { 0 };
+                let (sig, _) = self.parse_type_use(cursor)?;
+                layout.push(ExprSeg::Int(sig as i64));
+                layout.push(ExprSeg::Int(table as i64));
+                return Ok(layout);
+            }
+            SpecialInstr::BrOnCast => {
+                layout.push(ExprSeg::Int(self.parse_label(cursor, ctx)? as i64));
+                for _ in 0..2 {
+                    let (nullable, heap) = self.parse_ref_type(cursor)?;
+                    layout.push(ExprSeg::RefType { nullable, heap });
+                }
+                return Ok(layout);
+            }
+            _ => {}
+        }
+
+        // memory.init and table.init take their memory or table first in the text, but last in the binary
+        if let [segment @ (Prim::Data | Prim::Elem), target @ (Prim::Memory | Prim::Table)] = info.constants {
+            let mut after = *cursor;
+            after.next_item();
+            let target_cursor = after.peek_index().then(|| {
+                let target_cursor = *cursor;
+                cursor.next_item();
+                target_cursor
+            });
+            layout.extend(self.parse_immediate(*segment, &info, cursor, ctx)?);
+            let target = match target_cursor {
+                Some(mut target_cursor) => self.parse_immediate(*target, &info, &mut target_cursor, ctx)?,
+                None if *target == Prim::Memory => Some(ExprSeg::Memory(0)),
+                None => Some(ExprSeg::Table(0)),
+            };
+            layout.extend(target);
+            return Ok(layout);
+        }
+
+        for constant in info.constants {
+            layout.extend(self.parse_immediate(*constant, &info, cursor, ctx)?);
+        }
+
+        // ref.test and ref.cast share their name with the instruction for nullable types,
+        // which is the opcode after them
+        if let [Prim::RefType | Prim::NullRefType] = info.constants {
+            if let Some(ExprSeg::RefType { nullable, .. }) = layout.get(1) {
+                let opcode = (info.instr & !1) | *nullable as u32;
+                layout[0] = ExprSeg::Operation(get_prefixed_instr(info.prefix, opcode).unwrap_or(info));
+            }
+        }
+        Ok(layout)
+    }
+
+    // A try finished by `delegate` gives its label
+    fn block(info: InstrInfo, block_type: i64, catches: Vec<CatchClause>, mut body: Vec<ExprSeg>, delegate: Option<u32>) -> ExprSeg {
+        let end = match delegate {
+            Some(label) => {
+                body.push(ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[DELEGATE as usize]), ExprSeg::Int(label as i64)]));
+                INSTRS[DELEGATE as usize]
+            }
+            None => {
+                body.push(end_seg());
+                INSTRS[END as usize]
+            }
+        };
+        ExprSeg::ControlFlow(info, block_type, Box::new(WasmExpr::from(body)), end, catches)
+    }
+
+    // A try_table's `(catch $tag label)`, `(catch_ref $tag label)`, `(catch_all label)` and
+    // `(catch_all_ref label)`, whose labels don't count the try_table's own
+    fn parse_catch_clauses(&mut self, cursor: &mut Cursor<'a>, ctx: &FuncContext) -> Result<Vec<CatchClause>, WyrmError> {
+        let mut catches = vec![];
+        while let Some(kind) = cursor.peek_head().filter(|head| head.starts_with("catch")) {
+            let Some(mut clause) = cursor.take_list(kind) else {
+                break;
+            };
+            let text = self.text;
+            let mut tag = || self.tag_ids.resolve(text, &mut clause);
+            let catch = match kind {
+                "catch" => CatchClause::Catch { tag: tag()?, label: self.parse_label(&mut clause, ctx)? },
+                "catch_ref" => CatchClause::CatchRef { tag: tag()?, label: self.parse_label(&mut clause, ctx)? },
+                "catch_all" => CatchClause::CatchAll { label: self.parse_label(&mut clause, ctx)? },
+                "catch_all_ref" => CatchClause::CatchAllRef { label: self.parse_label(&mut clause, ctx)? },
+                _ => return Err(self.error(clause.offset(), format!("Unknown catch clause {}", kind))),
+            };
+            self.expect_end(&clause)?;
+            catches.push(catch);
+        }
+        Ok(catches)
+    }
+
+    // A try's `catch $tag` and `catch_all` arms, each with the instructions after it
+    fn parse_catch_arms(&mut self, cursor: &mut Cursor<'a>, ctx: &mut FuncContext, label: Option<&str>, body: &mut Vec<ExprSeg>) -> Result<(), WyrmError> {
+        while cursor.peek_atom().is_some_and(|atom| atom == "catch" || atom == "catch_all") {
+            let arm = if cursor.take_atom("catch") {
+                // The block's label can be repeated before the tag, so a lone id is the tag
+                if cursor.items.get(cursor.pos + 1).and_then(|item| item.atom()).is_some_and(|atom| atom.starts_with('$')) {
+                    self.check_label(cursor, label)?;
+                }
+                vec![ExprSeg::Operation(INSTRS[CATCH as usize]), ExprSeg::Tag(self.tag_ids.resolve(self.text, cursor)?)]
+            } else {
+                cursor.take_atom("catch_all");
+                self.check_label(cursor, label)?;
+                vec![ExprSeg::Operation(INSTRS[CATCH_ALL as usize])]
+            };
+            body.push(ExprSeg::Instr(arm));
+            self.parse_instrs(cursor, ctx, body)?;
+        }
+        Ok(())
+    }
+
+    // Instructions until the list runs out, or until an `end`, `else`, `catch`, `catch_all` or
+    // `delegate` for the caller to handle
+    fn parse_instrs(&mut self, cursor: &mut Cursor<'a>, ctx: &mut FuncContext, out: &mut Vec<ExprSeg>) -> Result<(), WyrmError> {
+        while let Some(item) = cursor.peek() {
+            if matches!(item.atom(), Some("end" | "else" | "catch" | "catch_all" | "delegate")) {
+                return Ok(());
+            }
+            cursor.next_item();
+            match item {
+                SExpr::List { .. } => self.parse_folded(item, ctx, out)?,
+                SExpr::Token { token: Token::Atom(name), offset } => self.parse_flat(name, *offset, cursor, ctx, out)?,
+                _ => return Err(self.error(item.offset(), "Expected an instruction")),
+            }
+        }
+        Ok(())
+    }
+
+    // Instructions making up the rest of a list
+    fn parse_instr_list(&mut self, cursor: &mut Cursor<'a>, ctx: &mut FuncContext, out: &mut Vec<ExprSeg>) -> Result<(), WyrmError> {
+        self.parse_instrs(cursor, ctx, out)?;
+        self.expect_end(cursor)
+    }
+
+    fn parse_flat(&mut self, name: &str, offset: usize, cursor: &mut Cursor<'a>, ctx: &mut FuncContext, out: &mut Vec<ExprSeg>) -> Result<(), WyrmError> {
+        let info = self.instr_info(name, offset)?;
+        if get_edge_case(info) != SpecialInstr::BeginBlock {
+            let instr = self.parse_plain(info, offset, cursor, ctx)?;
+            out.push(ExprSeg::Instr(instr));
+            return Ok(());
+        }
+
+        let label = cursor.take_id();
+        let block_type = self.parse_block_type(cursor)?;
+        let catches = if info.instr == TRY_TABLE as u32 { self.parse_catch_clauses(cursor, ctx)? } else { vec![] };
+        self.push_label(ctx, label);
+        let mut body = vec![];
+        self.parse_instrs(cursor, ctx, &mut body)?;
+        if info.instr == IF as u32 && cursor.take_atom("else") {
+            self.check_label(cursor, label)?;
+            body.push(ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[ELSE as usize])]));
+            self.parse_instrs(cursor, ctx, &mut body)?;
+        }
+        if info.instr == TRY as u32 {
+            self.parse_catch_arms(cursor, ctx, label, &mut body)?;
+            // The delegate's label is outside of the try
+            if cursor.take_atom("delegate") {
+                ctx.labels.pop();
+                let delegate = self.parse_label(cursor, ctx)?;
+                out.push(Self::block(info, block_type, catches, body, Some(delegate)));
+                return Ok(());
+            }
+        }
+        if !cursor.take_atom("end") {
+            return Err(self.error(cursor.offset(), format!("Expected `end` for the {} at line {}", name, position(self.text, offset).0)));
+        }
+        self.check_label(cursor, label)?;
+        ctx.labels.pop();
+        out.push(Self::block(info, block_type, catches, body, None));
+        Ok(())
+    }
+
+    // `(instr immediates operands*)`, with the operands pushed before the instruction
+    fn parse_folded(&mut self, list: &'a SExpr, ctx: &mut FuncContext, out: &mut Vec<ExprSeg>) -> Result<(), WyrmError> {
+        let (Some(name), Some(mut cursor)) = (list.head(), Cursor::of_list(list)) else {
+            return Err(self.error(list.offset(), "Expected an instruction"));
+        };
+        let offset = list.offset() + 1;
+        let info = self.instr_info(name, offset)?;
+        if get_edge_case(info) != SpecialInstr::BeginBlock {
+            let instr = self.parse_plain(info, offset, &mut cursor, ctx)?;
+            while let Some(operand) = cursor.next_item() {
+                if !matches!(operand, SExpr::List { .. }) {
+                    return Err(self.error(operand.offset(), "Expected a folded instruction"));
+                }
+                self.parse_folded(operand, ctx, out)?;
+            }
+            out.push(ExprSeg::Instr(instr));
+            return Ok(());
+        }
+
+        let label = cursor.take_id();
+        let block_type = self.parse_block_type(&mut cursor)?;
+        let catches = if info.instr == TRY_TABLE as u32 { self.parse_catch_clauses(&mut cursor, ctx)? } else { vec![] };
+        let mut body = vec![];
+        let mut delegate = None;
+        if info.instr == IF as u32 {
+            // The condition comes first, outside of the if's own block
+            while cursor.peek().is_some_and(|item| item.head() != Some("then")) {
+                if let Some(condition) = cursor.next_item() {
+                    self.parse_folded(condition, ctx, out)?;
+                }
+            }
+            self.push_label(ctx, label);
+            let Some(mut then) = cursor.take_list("then") else {
+                return Err(self.error(cursor.offset(), "Expected (then ...)"));
+            };
+            self.parse_instr_list(&mut then, ctx, &mut body)?;
+            if let Some(mut otherwise) = cursor.take_list("else") {
+                body.push(ExprSeg::Instr(vec![ExprSeg::Operation(INSTRS[ELSE as usize])]));
+                self.parse_instr_list(&mut otherwise, ctx, &mut body)?;
+            }
+            self.expect_end(&cursor)?;
+        } else if info.instr == TRY as u32 {
+            // `(do ...)`, then `(catch $tag ...)`s and a `(catch_all ...)`, or a `(delegate label)`
+            self.push_label(ctx, label);
+            let Some(mut body_list) = cursor.take_list("do") else {
+                return Err(self.error(cursor.offset(), "Expected (do ...)"));
+            };
+            self.parse_instr_list(&mut body_list, ctx, &mut body)?;
+            while let Some(kind) = cursor.peek_head().filter(|head| *head == "catch" || *head == "catch_all") {
+                let Some(mut arm) = cursor.take_list(kind) else {
+                    break;
+                };
+                let mut seg = vec![ExprSeg::Operation(if kind == "catch" { INSTRS[CATCH as usize] } else { INSTRS[CATCH_ALL as usize] })];
+                if kind == "catch" {
+                    seg.push(ExprSeg::Tag(self.tag_ids.resolve(self.text, &mut arm)?));
+                }
+                body.push(ExprSeg::Instr(seg));
+                self.parse_instr_list(&mut arm, ctx, &mut body)?;
+            }
+            if let Some(mut target) = cursor.take_list("delegate") {
+                ctx.labels.pop();
+                delegate = Some(self.parse_label(&mut target, ctx)?);
+                self.expect_end(&target)?;
+                self.expect_end(&cursor)?;
+                out.push(Self::block(info, block_type, catches, body, delegate));
+                return Ok(());
+            }
+            self.expect_end(&cursor)?;
+        } else {
+            self.push_label(ctx, label);
+            self.parse_instr_list(&mut cursor, ctx, &mut body)?;
+        }
+        ctx.labels.pop();
+        out.push(Self::block(info, block_type, catches, body, delegate));
+        Ok(())
+    }
+
+    fn parse_const_expr(&mut self, cursor: &mut Cursor<'a>) -> Result<WasmExpr, WyrmError> {
+        let mut expr = vec![];
+        self.parse_instr_list(cursor, &mut FuncContext::new(None), &mut expr)?;
+        expr.push(end_seg());
+        Ok(WasmExpr::from(expr))
+    }
+
+    // `(offset instr*)`, or a single folded instruction
+    fn parse_offset(&mut self, cursor: &mut Cursor<'a>) -> Result<Option<WasmExpr>, WyrmError> {
+        if let Some(mut offset) = cursor.take_list("offset") {
+            return Ok(Some(self.parse_const_expr(&mut offset)?));
+        }
+        match cursor.peek() {
+            Some(item @ SExpr::List { .. }) if !matches!(item.head(), Some("ref" | "item")) => {
+                cursor.next_item();
+                Ok(Some(self.parse_const_expr(&mut Cursor::new(std::slice::from_ref(item), item.offset()))?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_inline_exports(&mut self, cursor: &mut Cursor<'a>, export_kind: u8, idx: u32) -> Result<(), WyrmError> {
+        while let Some(mut export) = cursor.take_list("export") {
+            let name = self.parse_name(&mut export)?;
+            self.expect_end(&export)?;
+            self.exports.push(WasmExportHeader {
+                export_name_len: name.len(),
+                export_name: name.to_string(),
+                export_kind,
+                export_signature_index: idx,
+            });
+        }
+        Ok(())
+    }
+
+    fn parse_inline_import(&self, cursor: &mut Cursor<'a>) -> Result<Option<ImportNames<'a>>, WyrmError> {
+        let Some(mut import) = cursor.take_list("import") else {
+            return Ok(None);
+        };
+        let names = (self.parse_name(&mut import)?, self.parse_name(&mut import)?);
+        self.expect_end(&import)?;
+        Ok(Some(names))
+    }
+
+    fn push_import(&mut self, (module, field): ImportNames<'_>, import_kind: WasmImportType, desc: WasmImportDesc) {
+        self.imports.push(WasmImportHeader {
+            mod_name_length: module.len(),
+            import_module_name: module.to_string(),
+            import_field_len: field.len(),
+            import_field: field.to_string(),
+            import_kind,
+            desc,
+        });
+    }
+
+    // Function indices, or expressions if the list has any, for an elem segment
+    fn parse_elem_items(&mut self, cursor: &mut Cursor<'a>, exprs: bool) -> Result<Vec<WasmExpr>, WyrmError> {
+        let mut init = vec![];
+        while let Some(item) = cursor.peek() {
+            init.push(if !exprs {
+                ref_func(self.func_ids.resolve(self.text, cursor)?)
+            } else if let Some(mut item_list) = cursor.take_list("item") {
+                self.parse_const_expr(&mut item_list)?
+            } else {
+                cursor.next_item();
+                self.parse_const_expr(&mut Cursor::new(std::slice::from_ref(item), item.offset()))?
+            });
+        }
+        Ok(init)
+    }
+
+    // Which of the encodings an elem segment will be written with
+    fn elem_flags(mode: &WasmElemMode, _type: &WasmRefType, exprs: bool) -> u32 {
+        let mode_flags = match mode {
+            WasmElemMode::Active(active) if active.table == 0 && matches!(_type, WasmRefType::FuncRef) => 0,
+            WasmElemMode::Active(_) => 2,
+            WasmElemMode::Passive => 1,
+            WasmElemMode::Declarative => 3,
+        };
+        mode_flags | if exprs { 4 } else { 0 }
+    }
+
+    // The first pass, which numbers the fields and records their ids
+    fn declare_field(&mut self, field: &'a SExpr) -> Result<(), WyrmError> {
+        let text = self.text;
+        let offset = field.offset();
+        let (Some(head), Some(mut cursor)) = (field.head(), Cursor::of_list(field)) else {
+            return Err(self.error(offset, "Expected a module field"));
+        };
+        if head == "import" {
+            self.parse_string(&mut cursor)?;
+            self.parse_string(&mut cursor)?;
+            let Some(mut desc) = cursor.peek().and_then(Cursor::of_list) else {
+                return Err(self.error(cursor.offset(), "Expected an import description"));
+            };
+            let kind = cursor.peek_head().unwrap_or_default();
+            return self.declare(kind, desc.take_id(), true, offset);
+        }
+
+        let id = cursor.take_id();
+        match head {
+            "type" => {
+                let idx = self.type_ids.push(text, offset, id, false)?;
+                let Some(mut func) = cursor.take_list("func") else {
+                    return Err(self.unsupported(cursor.offset(), "Types other than function types"));
+                };
+                let (params, _, results) = self.parse_signature(&mut func)?;
+                self.expect_end(&func)?;
+                self.types.push(func_type(params, results));
+                if let Some(id) = id {
+                    self.names.type_names.insert(idx, id.to_string());
+                }
+                Ok(())
+            }
+            "func" | "table" | "memory" | "global" | "tag" => {
+                let imported = cursor.items.iter().any(|item| item.head() == Some("import"));
+                self.declare(head, id, imported, offset)?;
+                // Inline elem and data segments come right after their table or memory
+                if cursor.items.iter().any(|item| item.head() == Some("elem")) && head == "table" {
+                    self.elem_ids.push(text, offset, None, false)?;
+                }
+                if cursor.items.iter().any(|item| item.head() == Some("data")) && head == "memory" {
+                    self.data_ids.push(text, offset, None, false)?;
+                }
+                Ok(())
+            }
+            "elem" => self.declare(head, id, false, offset),
+            "data" => self.declare(head, id, false, offset),
+            "export" | "start" => Ok(()),
+            _ => Err(self.error(offset, format!("Unknown module field {}", head))),
+        }
+    }
+
+    fn declare(&mut self, kind: &str, id: Option<&str>, imported: bool, offset: usize) -> Result<(), WyrmError> {
+        let text = self.text;
+        let (space, names) = match kind {
+            "func" => (&mut self.func_ids, &mut self.names.function_names),
+            "table" => (&mut self.table_ids, &mut self.names.table_names),
+            "memory" => (&mut self.memory_ids, &mut self.names.memory_names),
+            "global" => (&mut self.global_ids, &mut self.names.global_names),
+            "tag" => (&mut self.tag_ids, &mut self.names.tag_names),
+            "elem" => (&mut self.elem_ids, &mut self.names.elem_names),
+            "data" => (&mut self.data_ids, &mut self.names.data_names),
+            _ => return Err(text_error(text, offset, format!("Unknown kind {}", kind))),
+        };
+        let idx = space.push(text, offset, id, imported)?;
+        if let Some(id) = id {
+            names.insert(idx, id.to_string());
+        }
+        Ok(())
+    }
+
+    // The second pass, which builds the sections
+    fn build_field(&mut self, field: &'a SExpr) -> Result<(), WyrmError> {
+        let (Some(head), Some(mut cursor)) = (field.head(), Cursor::of_list(field)) else {
+            return Err(self.error(field.offset(), "Expected a module field"));
+        };
+        match head {
+            "type" => Ok(()),
+            "import" => self.build_import(&mut cursor),
+            "func" => self.build_func(&mut cursor),
+            "table" => self.build_table(&mut cursor),
+            "memory" => self.build_memory(&mut cursor),
+            "global" => self.build_global(&mut cursor),
+            "tag" => self.build_tag(&mut cursor),
+            "export" => self.build_export(&mut cursor),
+            "start" => {
+                self.start = Some(self.func_ids.resolve(self.text, &mut cursor)?);
+                self.expect_end(&cursor)
+            }
+            "elem" => self.build_elem(&mut cursor),
+            "data" => self.build_data(&mut cursor),
+            _ => Err(self.error(field.offset(), format!("Unknown module field {}", head))),
+        }
+    }
+
+    fn build_import(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let names = (self.parse_name(cursor)?, self.parse_name(cursor)?);
+        let kind = cursor.peek_head().unwrap_or_default();
+        let Some(mut desc) = cursor.next_item().and_then(Cursor::of_list) else {
+            return Err(self.error(cursor.offset(), "Expected an import description"));
+        };
+        desc.take_id();
+        let (import_kind, desc_value) = match kind {
+            "func" => {
+                self.func_ids.next_built();
+                (WasmImportType::Func, WasmImportDesc::Func(self.parse_type_use(&mut desc)?.0))
+            }
+            "table" => {
+                self.table_ids.next_built();
+                (WasmImportType::Table, WasmImportDesc::Table(self.parse_table_type(&mut desc)?))
+            }
+            "memory" => {
+                self.memory_ids.next_built();
+                (WasmImportType::Mem, WasmImportDesc::Mem(self.parse_memory_type(&mut desc)?))
+            }
+            "global" => {
+                self.global_ids.next_built();
+                let (wasm_type, mutability) = self.parse_global_type(&mut desc)?;
+                (WasmImportType::Global, WasmImportDesc::Global { wasm_type, mutability })
+            }
+            _ => {
+                self.tag_ids.next_built();
+                let type_index = self.parse_type_use(&mut desc)?.0;
+                (WasmImportType::Tag, WasmImportDesc::Tag(WasmTag { attribute: 0, type_index }))
+            }
+        };
+        self.expect_end(&desc)?;
+        self.expect_end(cursor)?;
+        self.push_import(names, import_kind, desc_value);
+        Ok(())
+    }
+
+    fn build_func(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let func = self.func_ids.next_built();
+        cursor.take_id();
+        self.parse_inline_exports(cursor, 0, func)?;
+        if let Some(names) = self.parse_inline_import(cursor)? {
+            let sig = self.parse_type_use(cursor)?.0;
+            self.expect_end(cursor)?;
+            self.push_import(names, WasmImportType::Func, WasmImportDesc::Func(sig));
+            return Ok(());
+        }
+
+        let (sig, param_ids) = self.parse_type_use(cursor)?;
+        self.function_signature_indexes.push(sig);
+        let mut ctx = FuncContext::new(Some(func));
+        let offset = cursor.offset();
+        let num_params = self.types[sig as usize].params.len();
+        for i in 0..num_params {
+            let id = param_ids.get(i).copied().flatten();
+            let idx = ctx.locals.push(self.text, offset, id, false)?;
+            if let Some(id) = id {
+                self.names.local_names.entry(func).or_default().insert(idx, id.to_string());
+            }
+        }
+
+        let mut locals = vec![];
+        while let Some(mut local) = cursor.take_list("local") {
+            let offset = local.offset();
+            if let Some(id) = local.take_id() {
+                let idx = ctx.locals.push(self.text, offset, Some(id), false)?;
+                self.names.local_names.entry(func).or_default().insert(idx, id.to_string());
+                locals.push(self.parse_value_type(&mut local)?);
+                self.expect_end(&local)?;
+            }
+            while !local.is_empty() {
+                ctx.locals.push(self.text, offset, None, false)?;
+                locals.push(self.parse_value_type(&mut local)?);
+            }
+        }
+
+        let mut body = vec![];
+        self.parse_instr_list(cursor, &mut ctx, &mut body)?;
+        body.push(end_seg());
+        let body = WasmExpr::from(body);
+
+        // Locals are declared as runs of the same type
+        let mut local_types: Vec<(u8, usize)> = vec![];
+        for _type in locals.iter() {
+            match local_types.last_mut() {
+                Some((last, count)) if last == _type => *count += 1,
+                _ => local_types.push((*_type, 1)),
+            }
+        }
+        self.functions.push(encode_function(local_types, body)?);
+        Ok(())
+    }
+
+    fn build_table(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let table = self.table_ids.next_built();
+        cursor.take_id();
+        self.parse_inline_exports(cursor, 1, table)?;
+        if let Some(names) = self.parse_inline_import(cursor)? {
+            let table_type = self.parse_table_type(cursor)?;
+            self.expect_end(cursor)?;
+            self.push_import(names, WasmImportType::Table, WasmImportDesc::Table(table_type));
+            return Ok(());
+        }
+
+        let mut ahead = *cursor;
+        ahead.take_atom("i64");
+        if !Self::is_ref_type(&ahead) {
+            let table_type = self.parse_table_type(cursor)?;
+            self.expect_end(cursor)?;
+            self.tables.push(table_type);
+            return Ok(());
+        }
+
+        // `(table reftype (elem ...))` sizes the table to fit a segment written inside it
+        let is_64 = cursor.take_atom("i64");
+        let _type = self.parse_elem_ref_type(cursor)?;
+        let Some(mut items) = cursor.take_list("elem") else {
+            return Err(self.error(cursor.offset(), "Expected (elem ...)"));
+        };
+        self.expect_end(cursor)?;
+        self.elem_ids.next_built();
+        let exprs = matches!(items.peek(), Some(SExpr::List { .. }));
+        let init = self.parse_elem_items(&mut items, exprs)?;
+        let size = init.len() as u64;
+        let limits = Limits { initial: size, max: Some(size), shared: false, is_64 };
+        self.tables.push(WasmTable { wasm_type: reftype_to_byte(&_type), limits });
+        let mode = WasmElemMode::Active(AcvtiveStruct { table, offset_expr: zero_offset(is_64) });
+        let flags = Self::elem_flags(&mode, &_type, exprs);
+        self.elems.push(WasmElem { flags, _type, init, mode });
+        Ok(())
+    }
+
+    fn build_memory(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let memory = self.memory_ids.next_built();
+        cursor.take_id();
+        self.parse_inline_exports(cursor, 2, memory)?;
+        if let Some(names) = self.parse_inline_import(cursor)? {
+            let memory_type = self.parse_memory_type(cursor)?;
+            self.expect_end(cursor)?;
+            self.push_import(names, WasmImportType::Mem, WasmImportDesc::Mem(memory_type));
+            return Ok(());
+        }
+
+        let mut ahead = *cursor;
+        ahead.take_atom("i64");
+        if ahead.peek_head() != Some("data") {
+            let memory_type = self.parse_memory_type(cursor)?;
+            self.expect_end(cursor)?;
+            self.memories.push(memory_type);
+            return Ok(());
+        }
+
+        // `(memory (data ...))` is given just enough pages to hold the data
+        let is_64 = cursor.take_atom("i64");
+        let Some(mut strings) = cursor.take_list("data") else {
+            return Err(self.error(cursor.offset(), "Expected (data ...)"));
+        };
+        self.expect_end(cursor)?;
+        self.data_ids.next_built();
+        let mut data = vec![];
+        while !strings.is_empty() {
+            data.extend_from_slice(self.parse_string(&mut strings)?);
+        }
+        let pages = (data.len() as u64).div_ceil(0x10000);
+        self.memories.push(WasmMemoryStruct { limits: Limits { initial: pages, max: Some(pages), shared: false, is_64 } });
+        let mode = WasmDataMode::Active { memory, offset_expr: zero_offset(is_64) };
+        let header_flags = if memory == 0 { 0 } else { 2 };
+        self.data_segs.push(WasmDataSeg { header: WasmDataSegHeader { header_flags, mode, data_size: data.len() }, data });
+        Ok(())
+    }
+
+    fn build_global(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let global = self.global_ids.next_built();
+        cursor.take_id();
+        self.parse_inline_exports(cursor, 3, global)?;
+        let import = self.parse_inline_import(cursor)?;
+        let (wasm_type, mutability) = self.parse_global_type(cursor)?;
+        match import {
+            Some(names) => {
+                self.expect_end(cursor)?;
+                self.push_import(names, WasmImportType::Global, WasmImportDesc::Global { wasm_type, mutability });
+            }
+            None => {
+                let expr = self.parse_const_expr(cursor)?;
+                self.globals.push(WasmGlobal { wasm_type, mutability, expr });
+            }
+        }
+        Ok(())
+    }
+
+    fn build_tag(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let tag = self.tag_ids.next_built();
+        cursor.take_id();
+        self.parse_inline_exports(cursor, 4, tag)?;
+        let import = self.parse_inline_import(cursor)?;
+        let type_index = self.parse_type_use(cursor)?.0;
+        self.expect_end(cursor)?;
+        let tag = WasmTag { attribute: 0, type_index };
+        match import {
+            Some(names) => self.push_import(names, WasmImportType::Tag, WasmImportDesc::Tag(tag)),
+            None => self.tags.push(tag),
+        }
+        Ok(())
+    }
+
+    fn build_export(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        let name = self.parse_name(cursor)?;
+        let offset = cursor.offset();
+        let kind = cursor.peek_head().unwrap_or_default();
+        let Some(mut desc) = cursor.next_item().and_then(Cursor::of_list) else {
+            return Err(self.error(offset, "Expected an export description"));
+        };
+        let (export_kind, space) = match kind {
+            "func" => (0, &self.func_ids),
+            "table" => (1, &self.table_ids),
+            "memory" => (2, &self.memory_ids),
+            "global" => (3, &self.global_ids),
+            "tag" => (4, &self.tag_ids),
+            _ => return Err(self.error(offset, format!("Unknown export kind {}", kind))),
+        };
+        let idx = space.resolve(self.text, &mut desc)?;
+        self.expect_end(&desc)?;
+        self.expect_end(cursor)?;
+        self.exports.push(WasmExportHeader {
+            export_name_len: name.len(),
+            export_name: name.to_string(),
+            export_kind,
+            export_signature_index: idx,
+        });
+        Ok(())
+    }
+
+    fn build_elem(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        self.elem_ids.next_built();
+        cursor.take_id();
+        let declare = cursor.take_atom("declare");
+        let table = match cursor.take_list("table") {
+            Some(mut table) => {
+                let idx = self.table_ids.resolve(self.text, &mut table)?;
+                self.expect_end(&table)?;
+                Some(idx)
+            }
+            None => None,
+        };
+        let offset_expr = if declare { None } else { self.parse_offset(cursor)? };
+        let mode = match (declare, offset_expr) {
+            (true, _) => WasmElemMode::Declarative,
+            (false, Some(offset_expr)) => WasmElemMode::Active(AcvtiveStruct { table: table.unwrap_or(0), offset_expr }),
+            (false, None) if table.is_some() => return Err(self.error(cursor.offset(), "Expected an offset")),
+            (false, None) => WasmElemMode::Passive,
+        };
+
+        // `func` and a list of functions, a reference type and a list of expressions, or
+        // for active segments, the list of functions on its own
+        let (_type, exprs) = if cursor.take_atom("func") || cursor.peek_index() || cursor.is_empty() {
+            (WasmRefType::FuncRef, false)
+        } else {
+            (self.parse_elem_ref_type(cursor)?, true)
+        };
+        let init = self.parse_elem_items(cursor, exprs)?;
+        let flags = Self::elem_flags(&mode, &_type, exprs);
+        self.elems.push(WasmElem { flags, _type, init, mode });
+        Ok(())
+    }
+
+    fn build_data(&mut self, cursor: &mut Cursor<'a>) -> Result<(), WyrmError> {
+        self.data_ids.next_built();
+        cursor.take_id();
+        let memory = match cursor.take_list("memory") {
+            Some(mut memory) => {
+                let idx = self.memory_ids.resolve(self.text, &mut memory)?;
+                self.expect_end(&memory)?;
+                Some(idx)
+            }
+            None => None,
+        };
+        let mode = match self.parse_offset(cursor)? {
+            Some(offset_expr) => WasmDataMode::Active { memory: memory.unwrap_or(0), offset_expr },
+            None if memory.is_some() => return Err(self.error(cursor.offset(), "Expected an offset")),
+            None => WasmDataMode::Passive,
+        };
+        let header_flags = match mode {
+            WasmDataMode::Passive => 1,
+            WasmDataMode::Active { memory: 0, .. } => 0,
+            WasmDataMode::Active { .. } => 2,
+        };
+        let mut data = vec![];
+        while !cursor.is_empty() {
+            data.extend_from_slice(self.parse_string(cursor)?);
+        }
+        self.data_segs.push(WasmDataSeg { header: WasmDataSegHeader { header_flags, mode, data_size: data.len() }, data });
+        Ok(())
+    }
+
+    fn finish(self) -> WasmFile {
+        let import_section_header = WasmImportSection { section_size: 0, num_imports: self.imports.len(), imports: self.imports };
+        let function_section = WasmFunctionSection {
+            section_size: 0,
+            num_functions: self.function_signature_indexes.len(),
+            function_signature_indexes: self.function_signature_indexes,
+        };
+        let table_section = WasmTableSection { section_size: 0, num_tables: self.tables.len(), tables: self.tables };
+        let memory_section = WasmMemorySection { section_size: 0, num_memories: self.memories.len(), memories: self.memories };
+        let global_section = WasmGlobalSection { section_size: 0, num_globals: self.globals.len(), globals: self.globals };
+        let tag_section = WasmTagSection { section_size: 0, num_tags: self.tags.len(), tags: self.tags };
+
+        // The data count section is only needed when instructions refer to data segments
+        let data_count_section = self.uses_data_count.then_some(WasmDataCountSection { section_size: 0, datacount: self.data_segs.len() });
+
+        WasmFile {
+            wasm_header: WasmHeader { magic_number: 0x6d736100, version: 1 },
+            type_section: WasmTypeSection { section_size: 0, num_types: self.types.len(), function_signatures: self.types },
+            import_section_header,
+            function_section,
+            table_section,
+            memory_section,
+            global_section,
+            export_section: WasmExportSection { section_size: 0, num_exports: self.exports.len(), exports: self.exports },
+            elem_section: WasmElemSection { section_size: 0, num_elems: self.elems.len(), elems: self.elems },
+            start_section: self.start.map(|func| WasmStartSection { section_size: 0, func }),
+            tag_section,
+            code_section: WasmCodeSection { section_size: 0, num_functions: self.functions.len(), functions: self.functions },
+            data_count_section,
+            data_section: WasmDataSection { section_size: 0, num_data_segs: self.data_segs.len(), data_segs: self.data_segs },
+            name_section: self.names,
+            custom_sections: vec![],
+        }
+    }
+}
+
+/// Reads a module in the text format. The fields can be wrapped in `(module $id? ...)`
+/// or written on their own.
+pub fn parse_wat(text: &str) -> Result<WasmFile, WyrmError> {
+    let top = group(text, lex(text)?)?;
+    let mut parser = WatParser::new(text);
+
+    let fields = match top.as_slice() {
+        [module] if module.head() == Some("module") => {
+            let Some(mut cursor) = Cursor::of_list(module) else {
+                return Err(parser.error(module.offset(), "Expected a module"));
+            };
+            if let Some(id) = cursor.take_id() {
+                parser.names.module_name = Some(id.to_string());
+            }
+            &cursor.items[cursor.pos..]
+        }
+        fields => fields,
+    };
+
+    for field in fields {
+        parser.declare_field(field)?;
+    }
+    for field in fields {
+        parser.build_field(field)?;
+    }
+    Ok(parser.finish())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_reader::wasm_deserialize_bytes;
+    use crate::file_writer::wasm_serialize_bytes;
+    use crate::wat_emitter::{emit_folded_wat, emit_wat};
+
+    fn assemble(text: &str) -> Vec<u8> {
+        wasm_serialize_bytes(&parse_wat(text).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn flat_and_folded_forms_match() {
+        let flat = r#"(module
+          (memory 1)
+          (func $f (param $x i32) (result i64)
+            block $done (result i64)
+              local.get $x
+              i32.eqz
+              if $zero
+                i64.const -1
+                br $done
+              else $zero
+                local.get $x
+                i64.load8_u offset=4 align=1
+                br_if $done
+                drop
+              end $zero
+              i64.const 0x10
+            end
+          ))"#;
+        let folded = r#"(module
+          (memory 1)
+          (func $f (param $x i32) (result i64)
+            (block $done (result i64)
+              (if $zero (i32.eqz (local.get $x))
+                (then (br $done (i64.const -1)))
+                (else (drop (br_if $done (i64.load8_u offset=4 align=1 (local.get $x))))))
+              (i64.const 0x10))))"#;
+        assert_eq!(assemble(flat), assemble(folded));
+    }
+
+    #[test]
+    fn resolves_ids_and_inline_forms() {
+        let file = parse_wat(
+            r#"(module $m
+              (type $binop (func (param i32 i32) (result i32)))
+              (import "env" "log" (func $log (param i32)))
+              (func $add (export "add") (type $binop) (local.get 0) (local.get 1) (i32.add))
+              (func $main (export "main")
+                (call $log (call $add (i32.const 1) (i32.const 2)))
+                (call $later))
+              (func $later (param i32 i32) (result i32) (i32.const 0))
+              (export "mem" (memory $mem))
+              (memory $mem (data "hi")))"#,
+        )
+        .unwrap();
+
+        // $later's signature reuses $binop, and $main's is added after the others
+        assert_eq!(file.type_section.function_signatures.len(), 3);
+        assert_eq!(file.function_section.function_signature_indexes, vec![0, 2, 0]);
+        let exports: Vec<_> = file.export_section.exports.iter().map(|e| (e.export_name.as_str(), e.export_kind, e.export_signature_index)).collect();
+        assert_eq!(exports, vec![("add", 0, 1), ("main", 0, 2), ("mem", 2, 0)]);
+        assert_eq!(file.memory_section.memories[0].limits.initial, 1);
+        assert_eq!(file.data_section.data_segs[0].data, b"hi");
+        assert_eq!(file.name_section.module_name.as_deref(), Some("m"));
+        assert_eq!(file.name_section.function_names.get(&3).map(String::as_str), Some("later"));
+        assert_eq!(file.name_section.type_names.get(&0).map(String::as_str), Some("binop"));
+
+        let error = parse_wat("(module\n  (func (call $missing)))").unwrap_err();
+        assert!(format!("{:?}", error).contains("Unknown func $missing (line 2, column 15)"));
+        let error = parse_wat("(module (func (export \"\\ff\")))").unwrap_err();
+        assert!(format!("{:?}", error).contains("Name isn't valid UTF-8 (line 1, column 23)"), "{:?}", error);
+    }
+
+    #[test]
+    fn parses_number_literals() {
+        assert_eq!(parse_int_bits("-1", 32), Some(0xffff_ffff));
+        assert_eq!(parse_int_bits("4_294_967_295", 32), Some(u32::MAX as u64));
+        assert_eq!(parse_int_bits("-0x8000_0000", 32), Some(0x8000_0000));
+        assert_eq!(parse_int_bits("4294967296", 32), None);
+        assert_eq!(parse_int_bits("1__0", 32), None);
+
+        let f32_bits = |word| F32_FORMAT.parse(word, |d| d.parse::<f32>().ok().map(|f| f.to_bits() as u64));
+        assert_eq!(f32_bits("0x1.8p1"), Some(3.0f32.to_bits() as u64));
+        assert_eq!(f32_bits("-0x1p-149"), Some(0x8000_0001));
+        assert_eq!(f32_bits("0x1.fffffep127"), Some(f32::MAX.to_bits() as u64));
+        assert_eq!(f32_bits("0x1.ffffffp127"), Some(0x7f80_0000));
+        assert_eq!(f32_bits("nan:0x200000"), Some(0x7fa0_0000));
+        assert_eq!(f32_bits("1_000.5e-1"), Some(100.05f32.to_bits() as u64));
+        assert_eq!(f32_bits("0x"), None);
+        // Exactly halfway between two values, rounded to the even one
+        assert_eq!(F64_FORMAT.parse("0x1.00000000000008p0", |_| None), Some(1.0f64.to_bits()));
+        assert_eq!(F64_FORMAT.parse("0x1.00000000000018p0", |_| None), Some(1.0f64.to_bits() + 2));
+    }
+
+    #[test]
+    fn parses_exception_handling() {
+        let flat = r#"(module
+          (tag $e (param i32))
+          (tag $f)
+          (func $g (result i32)
+            try $outer (result i32)
+              try $inner
+                i32.const 1
+                throw $e
+              delegate $outer
+            catch $e
+            catch_all
+              i32.const 0
+            end
+            try_table $t (result i32) (catch $e 0) (catch_all_ref 0)
+              throw $f
+            end
+            drop
+          )
+          (func $h
+            try $r
+            catch_all
+              rethrow $r
+            end))"#;
+        let folded = r#"(module
+          (tag $e (param i32))
+          (tag $f)
+          (func $g (result i32)
+            (try $outer (result i32)
+              (do (try $inner (do (throw $e (i32.const 1))) (delegate $outer)))
+              (catch $e)
+              (catch_all (i32.const 0)))
+            (drop (try_table $t (result i32) (catch $e 0) (catch_all_ref 0) (throw $f))))
+          (func $h (try $r (do) (catch_all (rethrow $r)))))"#;
+        let bytes = assemble(flat);
+        assert_eq!(bytes, assemble(folded));
+
+        let file = wasm_deserialize_bytes(&bytes).unwrap();
+        assert_eq!(assemble(&emit_wat(&file).unwrap()), bytes);
+        assert_eq!(assemble(&emit_folded_wat(&file).unwrap()), bytes);
+    }
+
+    #[test]
+    fn round_trips_through_the_binary() {
+        let bytes = assemble(
+            r#"(module
+              (table $t 2 funcref)
+              (memory $m 1 4)
+              (memory $n i64 1)
+              (elem $init (table $t) (i32.const 0) func $f $g)
+              (elem $e funcref (item (ref.null func)))
+              (func $f (param $p f64) (local $l i64)
+                (local.set $l (i64.const 9_223_372_036_854_775_807))
+                (drop (f64.add (local.get $p) (f64.const -0x1.8p-3)))
+                (drop (v128.const i32x4 1 2 3 -4))
+                (memory.init $m $d (i32.const 0) (i32.const 0) (i32.const 1))
+                (data.drop $d)
+                (table.init $e (i32.const 0) (i32.const 0) (i32.const 0)))
+              (func $g)
+              (data $d "\00\ff")
+              (data (memory $n) (i64.const 8) "x"))"#,
+        );
+        let file = wasm_deserialize_bytes(&bytes).unwrap();
+        assert_eq!(file.data_count_section.as_ref().map(|section| section.datacount), Some(2));
+        assert_eq!(assemble(&emit_wat(&file).unwrap()), bytes);
+    }
+}
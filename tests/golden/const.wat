@@ -0,0 +1,12 @@
+(module
+  (type (func))
+  (global $base (import "env" "base") i32)
+  (table $table0 10 funcref)
+  (memory $memory0 1)
+  (global $end i32 (global.get $base) (i32.const 4) (i32.add))
+  (global $big i64 (i64.const 3) (i64.const 10) (i64.const 2) (i64.sub) (i64.mul))
+  (elem $elem0 (offset (global.get $base) (i32.const 1) (i32.add)) func $f)
+  (func $f (type 0)
+  )
+  (data (offset (global.get $base) (i32.const 8) (i32.sub)) "x")
+)
\ No newline at end of file
@@ -0,0 +1,96 @@
+(module $golden
+  (type (func))
+  (type (func (param i32) (result i32)))
+  (type $pair (func (param i32 i32) (result i32 i64)))
+  (type (func (param f32)))
+  (func $func0 (import "env" "print\0a\"x\"") (type 1) (param i32) (result i32))
+  (table $table0 2 funcref)
+  (table $table1 3 funcref)
+  (memory $memory0 1)
+  (memory $memory1 1 2)
+  (tag $tag0 (type 3))
+  (global $answer i32 (i32.const 42))
+  (global $global1 (mut f64) (f64.const nan:0x1))
+  (global $global2 externref (ref.null extern))
+  (export "run" (func $dup))
+  (export "tab" (table $table1))
+  (export "mem" (memory $memory1))
+  (export "g" (global $answer))
+  (export "exn" (tag $tag0))
+  (export "\c3\a9t\c3\a9" (func $dup_1))
+  (elem $elem0 func $dup_1 $func1_1)
+  (elem $elem1 (table $table1) (offset (i32.const 0)) func $dup)
+  (func $dup (type 1) (param $var0 i32) (result i32)
+    block $label0 (result i32)
+      local.get $var0
+      local.get $var0
+      block $label1 (type $pair)
+        i32.add
+        i64.const 7
+      end $label1
+      drop
+      loop $label1
+        local.get $var0
+        br_if 0
+      end $label1
+      local.get $var0
+      if $label1 (result i32)
+        i32.const 1
+      else
+        local.get $var0
+        i32.const 0
+        call_indirect $table1 (type 1)
+      end $label1
+      drop
+      local.get $var0
+      br_table 0 0 0
+    end $label0
+    local.get $var0
+    return_call_indirect (type 1)
+  )
+  (func $dup_1 (type 0)
+    (local $v v128) (local $v_1 f32)
+    f32.const nan
+    drop
+    f32.const -nan:0x1
+    drop
+    f64.const -inf
+    drop
+    f64.const 0.1
+    drop
+    f32.const -0
+    drop
+    v128.const i32x4 0x03020100 0x07060504 0x0b0a0908 0x0f0e0d0c
+    local.set $v
+    i32.const 0
+    i32.const 0
+    i32.const 3
+    memory.init $memory1 0
+    data.drop 0
+    i32.const 0
+    i32.const 0
+    i32.const 1
+    memory.copy $memory1 $memory0
+    i32.const 0
+    i32.const 0
+    i32.const 1
+    table.init $table1 $elem0
+    i32.const 0
+    i32.const 0
+    i32.const 1
+    table.copy $table0 $table1
+    elem.drop $elem0
+    i32.const 0
+    f32.load $memory1
+    drop
+    i32.const 1
+    i32.const 2
+    i32.const 0
+    select (result i32)
+    drop
+  )
+  (func $func1_1 (type 0)
+  )
+  (data "abc")
+  (data (memory $memory1) (offset (i32.const 8)) "\00\ff")
+)
\ No newline at end of file
@@ -0,0 +1,13 @@
+(module
+  (type (func))
+  (type (func (param i32)))
+  (memory $memory0 1)
+  (memory $memory1 1)
+  (tag $tag0 (type 1))
+  (start $func0)
+  (func $func0 (type 0)
+  )
+  (data (i32.const 16) "hi\"\0a")
+  (data "\00p")
+  (data (memory $memory1) (offset (i32.const 16)) "x")
+)
\ No newline at end of file
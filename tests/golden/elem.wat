@@ -0,0 +1,17 @@
+(module
+  (type (func))
+  (table $table0 10 10 funcref)
+  (table $table1 10 funcref)
+  (elem $elem0 (offset (i32.const 0)) func $func0 $func1)
+  (elem $elem1 func $func1)
+  (elem $elem2 (table $table1) (offset (i32.const 0)) func $func0)
+  (elem $elem3 declare func $func1)
+  (elem $elem4 (offset (i32.const 0)) funcref (item (ref.func $func0)) (item (ref.null func)))
+  (elem $elem5 funcref (item (ref.null func)) (item (ref.func $func1)))
+  (elem $elem6 (table $table1) (offset (i32.const 0)) funcref (item (ref.func $func1)))
+  (elem $elem7 declare funcref (item (ref.func $func0)))
+  (func $func0 (type 0)
+  )
+  (func $func1 (type 0)
+  )
+)
\ No newline at end of file
@@ -0,0 +1,31 @@
+(module
+  (type (func (param anyref) (result i32)))
+  (type (func (param externref) (result externref)))
+  (func $func0 (type 0) (param $var0 anyref) (result i32)
+    block $label0 (result anyref)
+      local.get $var0
+      br_on_cast 0 (ref null any) (ref i31)
+      drop
+      i32.const 7
+      ref.i31
+    end $label0
+    ref.test (ref i31)
+    if $label0
+      local.get $var0
+      ref.cast (ref i31)
+      i31.get_s
+      return
+    end $label0
+    ref.null eq
+    ref.test (ref null eq)
+    i32.const -1
+    ref.i31
+    i31.get_u
+    i32.add
+  )
+  (func $func1 (type 1) (param $var0 externref) (result externref)
+    local.get $var0
+    any.convert_extern
+    extern.convert_any
+  )
+)
\ No newline at end of file
@@ -0,0 +1,14 @@
+(module
+  (type (func))
+  (type (func (param i32)))
+  (func $func0 (import "env" "f") (type 1) (param i32))
+  (table $table0 (import "env" "t") 1 2 funcref)
+  (memory $memory0 (import "env" "m") 17 256 shared)
+  (global $global0 (import "env" "g") (mut i32))
+  (tag $tag0 (import "env" "e") (type 1))
+  (tag $tag1 (type 1))
+  (func $func1 (type 0)
+    i32.const 0
+    call $func0
+  )
+)
\ No newline at end of file
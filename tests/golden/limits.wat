@@ -0,0 +1,6 @@
+(module
+  (table $table0 i64 5 funcref)
+  (table $table1 i64 1 1099511627776 externref)
+  (memory $memory0 i64 1 8589934592 shared)
+  (memory $memory1 2)
+)
\ No newline at end of file
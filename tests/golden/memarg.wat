@@ -0,0 +1,23 @@
+(module
+  (type (func))
+  (memory $memory0 1)
+  (memory $memory1 1)
+  (func $func0 (type 0)
+    i32.const 0
+    i32.load
+    drop
+    i32.const 0
+    i32.load offset=305419896 align=1
+    drop
+    i32.const 0
+    i32.load8_u offset=16
+    drop
+    i32.const 0
+    i64.load $memory1 offset=8
+    drop
+    i32.const 0
+    i32.const 0
+    i32.atomic.rmw.add offset=4
+    drop
+  )
+)
\ No newline at end of file
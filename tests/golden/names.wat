@@ -0,0 +1,28 @@
+(module $demo
+  (type (func (param i32 i32) (result i32)))
+  (type (func (param i32)))
+  (func $log (import "env" "log") (type 1) (param i32))
+  (memory $memory0 1 2)
+  (global $sp (mut i32) (i32.const -5))
+  (export "add" (func $add))
+  (export "mem" (memory $memory0))
+  (func $add (type 0) (param $a i32) (param $b i32) (result i32)
+    (local $tmp i32)
+    local.get $a
+    local.get $b
+    i32.add
+    block $inner
+      local.get $a
+      call $log
+    end $inner
+    global.get $sp
+    local.tee $tmp
+    drop
+  )
+  (func $call_add (type 0) (param $var0 i32) (param $var1 i32) (result i32)
+    local.get $var0
+    local.get $var1
+    call $add
+  )
+  (data (i32.const 16) "hi\0a")
+)
\ No newline at end of file